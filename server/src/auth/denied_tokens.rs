@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::cli::CliArgs;
+use crate::error::EdgeError;
+use crate::types::EdgeResult;
+
+/// Tokens denied with --deny-tokens and --deny-tokens-file. They're refused in every mode before upstream is asked about
+/// them, and never refreshed. Unlike revocations they aren't persisted, the configuration is what keeps them denied
+#[derive(Clone, Debug, Default)]
+pub struct DeniedTokens(Arc<HashSet<String>>);
+
+impl DeniedTokens {
+    pub fn new(tokens: impl IntoIterator<Item = String>) -> Self {
+        DeniedTokens(Arc::new(
+            tokens
+                .into_iter()
+                .map(|token| token.trim().to_string())
+                .filter(|token| !token.is_empty())
+                .collect(),
+        ))
+    }
+
+    /// Unlike the pretrusted tokens file, a deny tokens file that can't be read stops startup, since going on without it
+    /// would let the tokens it denies in
+    pub fn from_args(args: &CliArgs) -> EdgeResult<Self> {
+        let mut tokens = args.deny_tokens.clone();
+        if let Some(path) = &args.deny_tokens_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| EdgeError::DenyTokensFileError(format!("{path:?}: {e}")))?;
+            tokens.extend(
+                contents
+                    .lines()
+                    .filter(|line| !line.trim_start().starts_with('#'))
+                    .map(String::from),
+            );
+        }
+        Ok(DeniedTokens::new(tokens))
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.0.contains(token)
+    }
+
+    pub fn retain_allowed(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens
+            .into_iter()
+            .filter(|token| !self.contains(token))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::EdgeMode;
+
+    #[test]
+    fn denied_tokens_are_read_from_the_flag_and_the_file_and_kept_out_of_the_startup_tokens() {
+        let path = std::env::temp_dir().join("denied_tokens_are_read_from_the_flag_and_the_file");
+        std::fs::write(&path, "# leaked in incident 42\n*:production.leaked\n\n").unwrap();
+        let args = CliArgs::try_parse_from([
+            "unleash-edge",
+            "--deny-tokens",
+            "*:development.leaked",
+            "--deny-tokens-file",
+            path.to_str().unwrap(),
+            "edge",
+            "-u",
+            "http://localhost:4242",
+            "--tokens",
+            "*:development.leaked,*:development.fine",
+            "--pretrusted-tokens",
+            "*:production.leaked",
+        ])
+        .unwrap();
+
+        let denied_tokens = DeniedTokens::from_args(&args).unwrap();
+        assert_eq!(denied_tokens.len(), 2);
+        assert!(denied_tokens.contains("*:production.leaked"));
+        let EdgeMode::Edge(edge_args) = args.without_denied_tokens(&denied_tokens).mode else {
+            unreachable!()
+        };
+        assert_eq!(edge_args.tokens, vec!["*:development.fine"]);
+        assert!(edge_args.pretrusted_tokens.is_empty());
+
+        let unreadable = CliArgs::try_parse_from([
+            "unleash-edge",
+            "--deny-tokens-file",
+            "/no/such/denylist",
+            "offline",
+        ])
+        .unwrap();
+        assert!(matches!(
+            DeniedTokens::from_args(&unreadable),
+            Err(EdgeError::DenyTokensFileError(_))
+        ));
+    }
+}
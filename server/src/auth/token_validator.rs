@@ -1,9 +1,14 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use dashmap::DashMap;
-use tracing::{instrument, trace};
+use dashmap::{DashMap, DashSet};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use tracing::{debug, instrument, trace, warn};
 use unleash_types::Upsert;
 
+use crate::error::EdgeError;
 use crate::http::feature_refresher::FeatureRefresher;
 use crate::http::unleash_client::UnleashClient;
 use crate::persistence::EdgePersistence;
@@ -11,14 +16,74 @@ use crate::types::{
     EdgeResult, EdgeToken, TokenType, TokenValidationStatus, ValidateTokensRequest,
 };
 
-#[derive(Clone)]
+lazy_static! {
+    pub static ref TOKENS_REFUSED_AT_CAPACITY: IntCounter = register_int_counter!(
+        "tokens_refused_at_capacity",
+        "Number of unknown tokens refused because Edge already tracks --max-tokens tokens"
+    )
+    .unwrap();
+}
+
+/// Upstream has the final say on a token's type. The type the token gives away itself is only used when upstream
+/// doesn't say, and a disagreement gets logged, since it means the token is being used for something it isn't
+fn reconcile_token_type(token: &EdgeToken, upstream: Option<TokenType>) -> Option<TokenType> {
+    match (token.embedded_token_type(), upstream) {
+        (Some(embedded), Some(upstream)) if embedded != upstream => {
+            warn!("{token:?} looks like a {embedded:?} token, but upstream says it's a {upstream:?} token. Going with upstream");
+            Some(upstream)
+        }
+        (Some(embedded), None) => {
+            warn!("Upstream didn't say what type {token:?} is. Treating it as the {embedded:?} token it looks like");
+            Some(embedded)
+        }
+        (_, upstream) => upstream,
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct TokenValidator {
     pub unleash_client: Arc<UnleashClient>,
     pub token_cache: Arc<DashMap<String, EdgeToken>>,
     pub persistence: Option<Arc<dyn EdgePersistence>>,
+    /// Tokens revoked locally. They stay revoked until upstream no longer accepts them
+    pub revoked_tokens: Arc<DashSet<String>>,
+    /// Refuse tokens that aren't already validated instead of validating them against upstream on first use
+    pub strict_token_validation: bool,
+    /// How many tokens to keep track of at most. Unknown tokens beyond this are refused instead of validated and cached
+    pub max_tokens: Option<usize>,
+    /// How long upstream rejecting a token is remembered before the token is validated again. Remembered for good if not set
+    pub invalid_token_ttl: Option<Duration>,
+    /// When upstream last rejected each token it rejected, for the rejected tokens still in the token cache
+    pub invalidated_at: Arc<DashMap<String, Instant>>,
+    /// Room in the token cache held for tokens being validated, so validations running at once can't overshoot max_tokens
+    pub reserved_slots: Arc<AtomicUsize>,
+}
+
+/// Gives back the room held for tokens being validated once they're in the token cache, or failed to get there
+struct ReservedSlots<'a> {
+    reserved_slots: &'a AtomicUsize,
+    count: usize,
+}
+
+impl Drop for ReservedSlots<'_> {
+    fn drop(&mut self) {
+        self.reserved_slots.fetch_sub(self.count, Ordering::SeqCst);
+    }
 }
 
 impl TokenValidator {
+    /// Validates against upstream on first use and keeps every token it's given, with nothing revoked or persisted
+    pub fn new(
+        unleash_client: Arc<UnleashClient>,
+        token_cache: Arc<DashMap<String, EdgeToken>>,
+    ) -> Self {
+        TokenValidator {
+            unleash_client,
+            token_cache,
+            ..Default::default()
+        }
+    }
+
     async fn get_unknown_and_known_tokens(
         &self,
         tokens: Vec<String>,
@@ -37,6 +102,7 @@ impl TokenValidator {
                     .token_cache
                     .get(&token.token.clone())
                     .map(|t| t.value().clone())
+                    .filter(|known| !self.rejection_has_expired(known))
                     .unwrap_or_else(|| token.clone());
                 tokens.push(owned_token);
             }
@@ -44,6 +110,56 @@ impl TokenValidator {
         }
     }
 
+    /// Tokens upstream rejected are refused from the cache without asking upstream again until the TTL is over. Locally
+    /// revoked tokens stay refused regardless
+    fn rejection_has_expired(&self, token: &EdgeToken) -> bool {
+        let Some(ttl) = self.invalid_token_ttl else {
+            return false;
+        };
+        token.status == TokenValidationStatus::Invalid
+            && !self.is_revoked(&token.token)
+            && self
+                .invalidated_at
+                .get(&token.token)
+                .is_none_or(|rejected| rejected.elapsed() >= ttl)
+    }
+
+    /// Rejections are only needed while they can still expire, and only for tokens that haven't been evicted
+    fn forget_expired_rejections(&self) {
+        self.invalidated_at.retain(|token, rejected| {
+            self.token_cache.contains_key(token)
+                && self
+                    .invalid_token_ttl
+                    .is_some_and(|ttl| rejected.elapsed() < ttl)
+        });
+    }
+
+    /// Holds room in the token cache for tokens about to be validated, counting what other validations hold as taken.
+    /// Checking and holding are one step, so two validations can't both take the last of the room
+    fn reserve_slots(&self, count: usize) -> EdgeResult<Option<ReservedSlots<'_>>> {
+        let Some(max_tokens) = self.max_tokens.filter(|_| count > 0) else {
+            return Ok(None);
+        };
+        self.reserved_slots
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reserved| {
+                (self.token_cache.len() + reserved + count <= max_tokens).then_some(reserved + count)
+            })
+            .map(|_| {
+                Some(ReservedSlots {
+                    reserved_slots: &self.reserved_slots,
+                    count,
+                })
+            })
+            .map_err(|reserved| {
+                TOKENS_REFUSED_AT_CAPACITY.inc_by(count as u64);
+                debug!(
+                    "Already tracking {} tokens and validating {reserved}, refusing to validate {count} more",
+                    self.token_cache.len()
+                );
+                EdgeError::TooManyTokens(max_tokens)
+            })
+    }
+
     pub async fn register_token(&self, token: String) -> EdgeResult<EdgeToken> {
         Ok(self
             .register_tokens(vec![token])
@@ -58,6 +174,13 @@ impl TokenValidator {
         if unknown_tokens.is_empty() {
             Ok(known_tokens)
         } else {
+            // Tokens validated again after their rejection expired overwrite the entry they already have
+            let new_tokens = unknown_tokens
+                .iter()
+                .filter(|token| !self.token_cache.contains_key(&token.token))
+                .count();
+            let _reserved = self.reserve_slots(new_tokens)?;
+            self.forget_expired_rejections();
             let token_strings_to_validate: Vec<String> =
                 unknown_tokens.iter().map(|t| t.token.clone()).collect();
 
@@ -75,12 +198,21 @@ impl TokenValidator {
                         .find(|v| maybe_valid.token == v.token)
                     {
                         trace!("Validated token");
+                        self.invalidated_at.remove(&maybe_valid.token);
                         EdgeToken {
                             status: TokenValidationStatus::Validated,
+                            token_type: reconcile_token_type(
+                                &maybe_valid,
+                                validated_token.token_type.clone(),
+                            ),
                             ..validated_token.clone()
                         }
                     } else {
                         trace!("Invalid token");
+                        if self.invalid_token_ttl.is_some() {
+                            self.invalidated_at
+                                .insert(maybe_valid.token.clone(), Instant::now());
+                        }
                         EdgeToken {
                             status: TokenValidationStatus::Invalid,
                             token_type: Some(TokenType::Invalid),
@@ -100,6 +232,44 @@ impl TokenValidator {
         }
     }
 
+    pub fn is_revoked(&self, token: &str) -> bool {
+        self.revoked_tokens.contains(token)
+    }
+
+    /// Marks the tokens as invalid immediately, without waiting for upstream to stop accepting them
+    pub async fn revoke_tokens(&self, tokens: Vec<String>) -> EdgeResult<Vec<EdgeToken>> {
+        let revoked: Vec<EdgeToken> = tokens
+            .into_iter()
+            .filter_map(|t| EdgeToken::try_from(t).ok())
+            .map(|token| {
+                self.revoked_tokens.insert(token.token.clone());
+                self.token_cache
+                    .entry(token.token.clone())
+                    .and_modify(|known| known.status = TokenValidationStatus::Invalid)
+                    .or_insert(EdgeToken {
+                        status: TokenValidationStatus::Invalid,
+                        token_type: Some(TokenType::Invalid),
+                        ..token
+                    })
+                    .clone()
+            })
+            .collect();
+        self.persist_revoked_tokens().await;
+        Ok(revoked)
+    }
+
+    async fn persist_revoked_tokens(&self) {
+        if let Some(persist) = self.persistence.clone() {
+            match persist
+                .save_revoked_tokens(self.revoked_tokens.iter().map(|t| t.clone()).collect())
+                .await
+            {
+                Ok(()) => debug!("Persisted revoked tokens"),
+                Err(save_error) => warn!("Could not persist revoked tokens: {save_error:?}"),
+            }
+        }
+    }
+
     #[instrument(skip(self))]
     pub async fn schedule_validation_of_known_tokens(&self, validation_interval_seconds: u64) {
         let sleep_duration = tokio::time::Duration::from_secs(validation_interval_seconds);
@@ -141,7 +311,9 @@ impl TokenValidator {
             .iter()
             .filter(|t| t.value().status == TokenValidationStatus::Validated)
             .map(|e| e.key().clone())
+            .chain(self.revoked_tokens.iter().map(|t| t.clone()))
             .collect();
+        self.forget_expired_rejections();
         if !tokens_to_validate.is_empty() {
             let validation_result = self
                 .unleash_client
@@ -154,11 +326,19 @@ impl TokenValidator {
                 let invalid = tokens_to_validate
                     .into_iter()
                     .filter(|t| !valid_tokens.iter().any(|e| &e.token == t));
+                let mut confirmed_revocations = false;
                 for token in invalid {
+                    confirmed_revocations |= self.revoked_tokens.remove(&token).is_some();
+                    if self.invalid_token_ttl.is_some() {
+                        self.invalidated_at.insert(token.clone(), Instant::now());
+                    }
                     self.token_cache
                         .entry(token)
                         .and_modify(|t| t.status = TokenValidationStatus::Invalid);
                 }
+                if confirmed_revocations {
+                    self.persist_revoked_tokens().await;
+                }
             }
         }
         Ok(())
@@ -177,11 +357,12 @@ mod tests {
     use serde::{Deserialize, Serialize};
 
     use crate::{
+        error::EdgeError,
         http::unleash_client::UnleashClient,
         types::{EdgeToken, TokenType, TokenValidationStatus},
     };
 
-    use super::TokenValidator;
+    use super::{TokenValidator, TOKENS_REFUSED_AT_CAPACITY};
 
     #[derive(Clone, Debug, Serialize, Deserialize)]
     pub struct EdgeTokens {
@@ -204,6 +385,28 @@ mod tests {
         }]
     }
 
+    /// One admin looking token upstream says is a client token, and one upstream doesn't give a type for
+    async fn return_tokens_with_disputed_types() -> HttpResponse {
+        HttpResponse::Ok().json(EdgeTokens {
+            tokens: vec![
+                EdgeToken {
+                    token: "*:*.disputed123".into(),
+                    projects: vec!["*".into()],
+                    environment: Some("development".into()),
+                    token_type: Some(TokenType::Client),
+                    status: TokenValidationStatus::Validated,
+                },
+                EdgeToken {
+                    token: "*:*.untyped123".into(),
+                    projects: vec!["*".into()],
+                    environment: None,
+                    token_type: None,
+                    status: TokenValidationStatus::Validated,
+                },
+            ],
+        })
+    }
+
     async fn test_validation_server() -> TestServer {
         test_server(move || {
             HttpService::new(map_config(
@@ -221,11 +424,10 @@ mod tests {
         token_cache: Arc<DashMap<String, EdgeToken>>,
     ) -> TestServer {
         let token_cache_wrapper = web::Data::from(token_cache.clone());
-        let token_validator = web::Data::new(TokenValidator {
-            token_cache: token_cache.clone(),
-            persistence: None,
-            unleash_client: Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap()),
-        });
+        let token_validator = web::Data::new(TokenValidator::new(
+            Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap()),
+            token_cache.clone(),
+        ));
         test_server(move || {
             HttpService::new(map_config(
                 App::new()
@@ -247,8 +449,7 @@ mod tests {
                 .expect("Couldn't build client");
         let validation_holder = TokenValidator {
             unleash_client: Arc::new(unleash_client),
-            token_cache: Arc::new(DashMap::default()),
-            persistence: None,
+            ..Default::default()
         };
 
         let tokens_to_validate = vec![
@@ -270,6 +471,167 @@ mod tests {
                 && t.value().status == TokenValidationStatus::Invalid));
     }
 
+    #[tokio::test]
+    pub async fn unknown_tokens_are_refused_once_max_tokens_are_tracked() {
+        let srv = test_validation_server().await;
+        let unleash_client =
+            UnleashClient::new(srv.url("/").as_str(), None).expect("Couldn't build client");
+        let validation_holder = TokenValidator {
+            unleash_client: Arc::new(unleash_client),
+            max_tokens: Some(1),
+            ..Default::default()
+        };
+        let known = "*:development.1d38eefdd7bf72676122b008dcf330f2f2aa2f3031438e1b7e8f0d1f";
+        let refused_before = TOKENS_REFUSED_AT_CAPACITY.get();
+
+        validation_holder
+            .register_token(known.into())
+            .await
+            .expect("Couldn't register token");
+        let refused = validation_holder
+            .register_token("*:production.abcdef1234567890".into())
+            .await;
+        assert!(matches!(refused, Err(EdgeError::TooManyTokens(1))));
+        assert_eq!(validation_holder.token_cache.len(), 1);
+        assert!(TOKENS_REFUSED_AT_CAPACITY.get() > refused_before);
+
+        let still_known = validation_holder
+            .register_token(known.into())
+            .await
+            .expect("Known tokens are exempt from the limit");
+        assert_eq!(still_known.status, TokenValidationStatus::Validated);
+    }
+
+    #[tokio::test]
+    pub async fn tokens_whose_rejection_expired_are_validated_again_at_max_tokens() {
+        let validations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = validations.clone();
+        let srv = test_server(move || {
+            let counter = counter.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/edge/validate",
+                    web::post().to(move || {
+                        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        async { HttpResponse::Ok().json(EdgeTokens { tokens: vec![] }) }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client =
+            UnleashClient::new(srv.url("/").as_str(), None).expect("Couldn't build client");
+        let validation_holder = TokenValidator {
+            unleash_client: Arc::new(unleash_client),
+            max_tokens: Some(1),
+            invalid_token_ttl: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let bad_token = "*:development.notknownupstream123";
+
+        validation_holder
+            .register_token(bad_token.into())
+            .await
+            .expect("Couldn't register token");
+        assert_eq!(validation_holder.token_cache.len(), 1);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let rejected_again = validation_holder
+            .register_token(bad_token.into())
+            .await
+            .expect("Known tokens are exempt from the limit");
+        assert_eq!(rejected_again.status, TokenValidationStatus::Invalid);
+        assert_eq!(validations.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(validation_holder.token_cache.len(), 1);
+    }
+
+    #[test]
+    pub fn validations_running_at_once_cannot_share_the_last_room_for_tokens() {
+        let validation_holder = TokenValidator {
+            max_tokens: Some(2),
+            ..Default::default()
+        };
+        validation_holder.token_cache.insert(
+            "*:development.secret123".into(),
+            EdgeToken::validated_client_token("*:development.secret123"),
+        );
+
+        let first = validation_holder.reserve_slots(1).unwrap();
+        assert!(matches!(
+            validation_holder.reserve_slots(1),
+            Err(EdgeError::TooManyTokens(2))
+        ));
+        drop(first);
+        assert!(validation_holder.reserve_slots(1).is_ok());
+        assert_eq!(
+            validation_holder
+                .reserved_slots
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+    }
+
+    #[tokio::test]
+    pub async fn rejected_tokens_are_not_validated_upstream_again_until_the_ttl_is_over() {
+        let validations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = validations.clone();
+        let srv = test_server(move || {
+            let counter = counter.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/edge/validate",
+                    web::post().to(move || {
+                        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        async { HttpResponse::Ok().json(EdgeTokens { tokens: vec![] }) }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client =
+            UnleashClient::new(srv.url("/").as_str(), None).expect("Couldn't build client");
+        let validation_holder = TokenValidator {
+            unleash_client: Arc::new(unleash_client),
+            invalid_token_ttl: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let bad_token = "*:development.notknownupstream123";
+
+        for _ in 0..3 {
+            let rejected = validation_holder
+                .register_token(bad_token.into())
+                .await
+                .expect("Couldn't register token");
+            assert_eq!(rejected.status, TokenValidationStatus::Invalid);
+        }
+        assert_eq!(validations.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        let rejected = validation_holder
+            .register_token(bad_token.into())
+            .await
+            .expect("Couldn't register token");
+        assert_eq!(rejected.status, TokenValidationStatus::Invalid);
+        assert_eq!(validations.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(validation_holder.invalidated_at.len(), 1);
+
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        validation_holder.revalidate_known_tokens().await.unwrap();
+        assert!(validation_holder.invalidated_at.is_empty());
+
+        validation_holder
+            .register_token(bad_token.into())
+            .await
+            .expect("Couldn't register token");
+        validation_holder.token_cache.remove(bad_token);
+        validation_holder.revalidate_known_tokens().await.unwrap();
+        assert!(validation_holder.invalidated_at.is_empty());
+    }
+
     #[tokio::test]
     pub async fn tokens_with_wrong_format_is_not_included() {
         let srv = test_validation_server().await;
@@ -277,8 +639,7 @@ mod tests {
             UnleashClient::new(srv.url("/").as_str(), None).expect("Couldn't build client");
         let validation_holder = TokenValidator {
             unleash_client: Arc::new(unleash_client),
-            token_cache: Arc::new(DashMap::default()),
-            persistence: None,
+            ..Default::default()
         };
         let invalid_tokens = vec!["jamesbond".into(), "invalidtoken".into()];
         let validated_tokens = validation_holder
@@ -320,11 +681,8 @@ mod tests {
             previously_valid_token.token.clone(),
             previously_valid_token.clone(),
         );
-        let validation_holder = TokenValidator {
-            unleash_client: Arc::new(unleash_client),
-            token_cache: local_token_cache.clone(),
-            persistence: None,
-        };
+        let validation_holder =
+            TokenValidator::new(Arc::new(unleash_client), local_token_cache.clone());
         let _ = validation_holder.revalidate_known_tokens().await;
         assert!(validation_holder
             .token_cache
@@ -362,11 +720,7 @@ mod tests {
             valid_token_production.token.clone(),
             valid_token_production.clone(),
         );
-        let validator = TokenValidator {
-            token_cache: Arc::new(local_tokens),
-            unleash_client: Arc::new(client),
-            persistence: None,
-        };
+        let validator = TokenValidator::new(Arc::new(client), Arc::new(local_tokens));
         let _ = validator.revalidate_known_tokens().await;
         assert_eq!(validator.token_cache.len(), 2);
         assert!(validator
@@ -374,4 +728,100 @@ mod tests {
             .iter()
             .all(|t| t.value().status == TokenValidationStatus::Validated));
     }
+
+    #[tokio::test]
+    pub async fn revoked_tokens_stay_revoked_until_upstream_rejects_them() {
+        let upstream_tokens: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut still_accepted_upstream =
+            EdgeToken::try_from("*:development.secret123".to_string()).expect("Bad Test Data");
+        still_accepted_upstream.status = TokenValidationStatus::Validated;
+        still_accepted_upstream.token_type = Some(TokenType::Client);
+        upstream_tokens.insert(
+            still_accepted_upstream.token.clone(),
+            still_accepted_upstream.clone(),
+        );
+        let mut gone_upstream =
+            EdgeToken::try_from("*:production.gone123".to_string()).expect("Bad Test Data");
+        gone_upstream.status = TokenValidationStatus::Invalid;
+        gone_upstream.token_type = Some(TokenType::Invalid);
+        upstream_tokens.insert(gone_upstream.token.clone(), gone_upstream.clone());
+        let server = validation_server_with_valid_tokens(upstream_tokens).await;
+        let client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let local_tokens: DashMap<String, EdgeToken> = DashMap::default();
+        local_tokens.insert(
+            still_accepted_upstream.token.clone(),
+            still_accepted_upstream.clone(),
+        );
+        let validator = TokenValidator::new(Arc::new(client), Arc::new(local_tokens));
+
+        let revoked = validator
+            .revoke_tokens(vec![
+                still_accepted_upstream.token.clone(),
+                gone_upstream.token.clone(),
+                "notatoken".into(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(revoked.len(), 2);
+        assert!(revoked
+            .iter()
+            .all(|t| t.status == TokenValidationStatus::Invalid));
+        let known = validator
+            .register_token(still_accepted_upstream.token.clone())
+            .await
+            .unwrap();
+        assert_eq!(known.status, TokenValidationStatus::Invalid);
+        assert_eq!(known.token_type, Some(TokenType::Client));
+
+        let _ = validator.revalidate_known_tokens().await;
+        assert!(validator.is_revoked(&still_accepted_upstream.token));
+        assert!(!validator.is_revoked(&gone_upstream.token));
+        assert_eq!(
+            validator
+                .token_cache
+                .get(&still_accepted_upstream.token)
+                .unwrap()
+                .status,
+            TokenValidationStatus::Invalid
+        );
+    }
+
+    #[tokio::test]
+    pub async fn upstream_decides_token_type_when_it_disagrees_with_the_token() {
+        let srv = test_server(move || {
+            HttpService::new(map_config(
+                App::new().service(
+                    web::resource("/edge/validate")
+                        .route(web::post().to(return_tokens_with_disputed_types)),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let validator = TokenValidator {
+            unleash_client: Arc::new(UnleashClient::new(srv.url("/").as_str(), None).unwrap()),
+            ..Default::default()
+        };
+        let tokens = validator
+            .register_tokens(vec![
+                "*:*.disputed123".into(),
+                "*:*.untyped123".into(),
+                "*:development.unknown123".into(),
+            ])
+            .await
+            .unwrap();
+        let type_of = |token: &str| {
+            tokens
+                .iter()
+                .find(|t| t.token == token)
+                .and_then(|t| t.token_type.clone())
+        };
+        assert_eq!(type_of("*:*.disputed123"), Some(TokenType::Client));
+        assert_eq!(type_of("*:*.untyped123"), Some(TokenType::Admin));
+        assert_eq!(
+            type_of("*:development.unknown123"),
+            Some(TokenType::Invalid)
+        );
+    }
 }
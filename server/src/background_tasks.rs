@@ -0,0 +1,103 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec, Opts};
+use tracing::error;
+
+const INITIAL_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    pub static ref BACKGROUND_TASK_PANICS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "background_task_panics",
+            "Number of times a background task panicked and was restarted"
+        ),
+        &["task"]
+    )
+    .unwrap();
+}
+
+/// Runs the task `start` makes until it finishes. If it panics, the panic is logged and counted, and a new task is
+/// started after a backoff that doubles with every panic, up to a minute. Without this a panic in e.g. the feature
+/// refresher would end it for good, leaving Edge serving the features it had
+pub async fn supervise<F, Fut>(task: &'static str, start: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    supervise_with_backoff(task, INITIAL_RESTART_BACKOFF, MAX_RESTART_BACKOFF, start).await
+}
+
+async fn supervise_with_backoff<F, Fut>(
+    task: &'static str,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    mut start: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut backoff = initial_backoff;
+    loop {
+        let started = Instant::now();
+        let Err(panic) = AssertUnwindSafe(start()).catch_unwind().await else {
+            return;
+        };
+        // A task that ran well past the backoff before panicking isn't crash looping, so it starts over from the beginning
+        if started.elapsed() > max_backoff {
+            backoff = initial_backoff;
+        }
+        BACKGROUND_TASK_PANICS.with_label_values(&[task]).inc();
+        error!(
+            task,
+            "Background task panicked: {}. Restarting it in {backoff:?}",
+            panic_message(&panic)
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn panicking_tasks_are_counted_and_restarted_until_they_finish() {
+        let starts = AtomicUsize::new(0);
+        supervise_with_backoff(
+            "test-task",
+            Duration::from_millis(1),
+            Duration::from_millis(5),
+            || async {
+                if starts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("refresh went wrong");
+                }
+            },
+        )
+        .await;
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            BACKGROUND_TASK_PANICS
+                .with_label_values(&["test-task"])
+                .get(),
+            2
+        );
+    }
+}
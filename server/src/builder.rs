@@ -1,27 +1,39 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use chrono::Duration;
+use actix_web::http::header::EntityTag;
+use chrono::{DateTime, Duration, Utc};
 use dashmap::DashMap;
 use reqwest::Url;
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
 use unleash_types::client_features::ClientFeatures;
+use unleash_types::client_metrics::ConnectVia;
 use unleash_yggdrasil::EngineState;
 
-use crate::cli::RedisMode;
-use crate::http::unleash_client::new_reqwest_client;
+use crate::cli::{PostgresArgs, ReadReplicaArgs, RedisArgs, RedisMode};
+use crate::http::change_webhook::ChangeWebhook;
+use crate::http::circuit_breaker::CircuitBreaker;
+use crate::http::refresh_shard::RefreshShard;
+use crate::http::unleash_client::{new_reqwest_client, UpstreamConnectionPool};
+use crate::http::upstream_failover::UpstreamFailover;
+use crate::metrics::client_metrics::{MetricsBatch, MetricsCache};
 use crate::offline::offline_hotload::{load_bootstrap, load_offline_engine_cache};
 use crate::persistence::file::FilePersister;
+use crate::persistence::postgres::PostgresPersister;
 use crate::persistence::redis::RedisPersister;
 use crate::persistence::EdgePersistence;
+use crate::tokens::{anonymize_token, cache_key};
+use crate::urls::UnleashUrls;
 use crate::{
-    auth::token_validator::TokenValidator,
+    auth::{denied_tokens::DeniedTokens, token_validator::TokenValidator},
     cli::{CliArgs, EdgeArgs, EdgeMode, OfflineArgs},
     error::EdgeError,
     http::{feature_refresher::FeatureRefresher, unleash_client::UnleashClient},
-    types::{EdgeResult, EdgeToken, TokenType},
+    types::{EdgeResult, EdgeToken, TokenType, TokenValidationStatus},
 };
 
 type CacheContainer = (
@@ -36,10 +48,12 @@ type EdgeInfo = (
     Option<Arc<dyn EdgePersistence>>,
 );
 
-fn build_caches() -> CacheContainer {
-    let token_cache: DashMap<String, EdgeToken> = DashMap::default();
-    let features_cache: DashMap<String, ClientFeatures> = DashMap::default();
-    let engine_cache: DashMap<String, EngineState> = DashMap::default();
+/// Capacities of 0 leave the caches to grow as they're filled
+fn build_caches(expected_tokens: usize, expected_environments: usize) -> CacheContainer {
+    let token_cache: DashMap<String, EdgeToken> = DashMap::with_capacity(expected_tokens);
+    let features_cache: DashMap<String, ClientFeatures> =
+        DashMap::with_capacity(expected_environments);
+    let engine_cache: DashMap<String, EngineState> = DashMap::with_capacity(expected_environments);
     (
         Arc::new(token_cache),
         Arc::new(features_cache),
@@ -47,7 +61,13 @@ fn build_caches() -> CacheContainer {
     )
 }
 
-async fn hydrate_from_persistent_storage(cache: CacheContainer, storage: Arc<dyn EdgePersistence>) {
+/// With a max age, features persisted longer ago than that are left out, as are features we can't tell the age of.
+/// Returns when the restored features were persisted, where we know it
+async fn hydrate_from_persistent_storage(
+    cache: CacheContainer,
+    storage: Arc<dyn EdgePersistence>,
+    max_persisted_age: Option<Duration>,
+) -> HashMap<String, DateTime<Utc>> {
     let (token_cache, features_cache, engine_cache) = cache;
     let tokens = storage.load_tokens().await.unwrap_or_else(|error| {
         warn!("Failed to load tokens from cache {error:?}");
@@ -57,12 +77,39 @@ async fn hydrate_from_persistent_storage(cache: CacheContainer, storage: Arc<dyn
         warn!("Failed to load features from cache {error:?}");
         Default::default()
     });
+    let mut persisted_at = match max_persisted_age {
+        Some(_) => storage
+            .load_features_persisted_at()
+            .await
+            .unwrap_or_else(|error| {
+                warn!("Failed to load when features were persisted {error:?}");
+                Default::default()
+            }),
+        None => HashMap::new(),
+    };
     for token in tokens {
         tracing::debug!("Hydrating tokens {token:?}");
         token_cache.insert(token.token.clone(), token);
     }
 
+    let now = Utc::now();
     for (key, features) in features {
+        if let Some(max_age) = max_persisted_age {
+            match persisted_at.get(&key) {
+                Some(at) if now.signed_duration_since(*at) <= max_age => {}
+                Some(at) => {
+                    info!(
+                        "Not restoring features for {key:?}, they were persisted {} seconds ago",
+                        now.signed_duration_since(*at).num_seconds()
+                    );
+                    continue;
+                }
+                None => {
+                    info!("Not restoring features for {key:?}, we can't tell when they were persisted");
+                    continue;
+                }
+            }
+        }
         tracing::debug!("Hydrating features for {key:?}");
         features_cache.insert(key.clone(), features.clone());
         let mut engine_state = EngineState::default();
@@ -73,13 +120,34 @@ async fn hydrate_from_persistent_storage(cache: CacheContainer, storage: Arc<dyn
         }
         engine_cache.insert(key.clone(), engine_state);
     }
+    persisted_at.retain(|key, _| features_cache.contains_key(key));
+    persisted_at
+}
+
+/// ETags are only reused for tokens where we also restored the features they describe, otherwise a 304 from upstream would leave us without features
+async fn load_persisted_etags(
+    storage: Arc<dyn EdgePersistence>,
+    features_cache: Arc<DashMap<String, ClientFeatures>>,
+) -> HashMap<String, EntityTag> {
+    let refresh_targets = storage
+        .load_refresh_targets()
+        .await
+        .unwrap_or_else(|error| {
+            warn!("Failed to load refresh targets from cache {error:?}");
+            vec![]
+        });
+    refresh_targets
+        .into_iter()
+        .filter(|refresh| features_cache.contains_key(&cache_key(&refresh.token)))
+        .filter_map(|refresh| refresh.etag.map(|etag| (refresh.token.token, etag)))
+        .collect()
 }
 
 pub(crate) fn build_offline_mode(
     client_features: ClientFeatures,
     tokens: Vec<String>,
 ) -> EdgeResult<CacheContainer> {
-    let (token_cache, features_cache, engine_cache) = build_caches();
+    let (token_cache, features_cache, engine_cache) = build_caches(0, 0);
 
     let edge_tokens: Vec<EdgeToken> = tokens
         .iter()
@@ -101,9 +169,7 @@ pub(crate) fn build_offline_mode(
 
 fn build_offline(offline_args: OfflineArgs) -> EdgeResult<CacheContainer> {
     if offline_args.tokens.is_empty() {
-        return Err(EdgeError::NoTokens(
-            "No tokens provided. Tokens must be specified when running in offline mode".into(),
-        ));
+        info!("No tokens provided, accepting any token in offline mode");
     }
 
     if let Some(bootstrap) = offline_args.bootstrap_file {
@@ -124,8 +190,42 @@ fn build_offline(offline_args: OfflineArgs) -> EdgeResult<CacheContainer> {
     }
 }
 
-async fn get_data_source(args: &EdgeArgs) -> Option<Arc<dyn EdgePersistence>> {
-    if let Some(redis_args) = args.redis.clone() {
+/// Without --persistence-required, a persistence backend we can't use only costs us the warm start after a restart, so
+/// we carry on with in-memory caches rather than take Edge down
+fn persistence_unavailable(
+    args: &EdgeArgs,
+    error: EdgeError,
+) -> EdgeResult<Option<Arc<dyn EdgePersistence>>> {
+    if args.persistence_required {
+        Err(error)
+    } else {
+        error!("Failed to configure persistence, falling back to in-memory caches: {error:?}");
+        Ok(None)
+    }
+}
+
+async fn get_data_source(args: &EdgeArgs) -> EdgeResult<Option<Arc<dyn EdgePersistence>>> {
+    match persistence_from(
+        args.redis.clone(),
+        args.postgres.clone(),
+        args.backup_folder.clone(),
+        true,
+    )
+    .await
+    {
+        Ok(persistence) => Ok(persistence),
+        Err(error) => persistence_unavailable(args, error),
+    }
+}
+
+/// Errors are left to the caller, since edge nodes can carry on without persistence and read replicas can't
+async fn persistence_from(
+    redis: Option<RedisArgs>,
+    postgres: Option<PostgresArgs>,
+    backup_folder: Option<PathBuf>,
+    writable: bool,
+) -> EdgeResult<Option<Arc<dyn EdgePersistence>>> {
+    if let Some(redis_args) = redis {
         let mut filtered_redis_args = redis_args.clone();
         if filtered_redis_args.redis_password.is_some() {
             filtered_redis_args.redis_password = Some("[redacted]".to_string());
@@ -134,7 +234,6 @@ async fn get_data_source(args: &EdgeArgs) -> Option<Arc<dyn EdgePersistence>> {
         let redis_persister = match redis_args.redis_mode {
             RedisMode::Single => redis_args.to_url().map(|url| {
                 RedisPersister::new(&url, redis_args.read_timeout(), redis_args.write_timeout())
-                    .expect("Failed to connect to redis")
             }),
             RedisMode::Cluster => redis_args.redis_url.clone().map(|urls| {
                 RedisPersister::new_with_cluster(
@@ -142,28 +241,102 @@ async fn get_data_source(args: &EdgeArgs) -> Option<Arc<dyn EdgePersistence>> {
                     redis_args.read_timeout(),
                     redis_args.write_timeout(),
                 )
-                .expect("Failed to connect to redis cluster")
             }),
         }
         .unwrap_or_else(|| {
-            panic!(
-                "Could not build a redis persister from redis_args {:?}",
-                args.redis
-            )
-        });
-        return Some(Arc::new(redis_persister));
+            panic!("Could not build a redis persister from redis_args {filtered_redis_args:?}")
+        })?;
+        redis_persister.ping().await?;
+        return Ok(Some(Arc::new(redis_persister.with_ttl(redis_args.ttl()))));
     }
 
-    if let Some(backup_folder) = args.backup_folder.clone() {
+    if let Some(postgres_args) = postgres {
+        if let Some(postgres_url) = postgres_args.postgres_url {
+            debug!("Configuring Postgres persistence");
+            let postgres_persister =
+                PostgresPersister::new(&postgres_url, postgres_args.postgres_pool_size).await?;
+            return Ok(Some(Arc::new(postgres_persister)));
+        }
+    }
+
+    if let Some(backup_folder) = backup_folder {
         debug!("Configuring file persistence {backup_folder:?}");
         let backup_client = FilePersister::new(&backup_folder);
-        return Some(Arc::new(backup_client));
+        if writable {
+            backup_client.check_writable()?;
+        }
+        return Ok(Some(Arc::new(backup_client)));
     }
 
-    None
+    Ok(None)
 }
 
-async fn build_edge(args: &EdgeArgs, app_name: &str) -> EdgeResult<EdgeInfo> {
+/// Reading the file is best effort like the rest of the warmup, a missing file only means we start with a cold cache
+fn read_pretrusted_tokens(args: &EdgeArgs, denied_tokens: &DeniedTokens) -> Vec<String> {
+    let mut tokens = args.pretrusted_tokens.clone();
+    if let Some(path) = &args.pretrusted_tokens_file {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => tokens.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            ),
+            Err(e) => error!("Could not read pretrusted tokens from {path:?}: {e:?}"),
+        }
+    }
+    denied_tokens.retain_allowed(tokens)
+}
+
+/// Validates the pretrusted tokens and fetches features for the client tokens among them before we start serving.
+/// Tokens that fail validation are logged and skipped rather than stopping startup
+async fn warm_up_pretrusted_tokens(
+    tokens: Vec<String>,
+    token_validator: &TokenValidator,
+    feature_refresher: &FeatureRefresher,
+) {
+    if tokens.is_empty() {
+        return;
+    }
+    let known_tokens = match token_validator.register_tokens(tokens.clone()).await {
+        Ok(known_tokens) => known_tokens,
+        Err(e) => {
+            warn!("Could not validate pretrusted tokens, starting with a cold cache: {e:?}");
+            return;
+        }
+    };
+    for token in tokens
+        .iter()
+        .filter(|token| !known_tokens.iter().any(|known| &known.token == *token))
+    {
+        warn!(
+            "Skipping pretrusted token {} since it is not a valid token",
+            anonymize_token(&EdgeToken::offline_token(token)).token
+        );
+    }
+    for token in known_tokens {
+        match (&token.status, &token.token_type) {
+            (TokenValidationStatus::Validated, Some(TokenType::Client)) => {
+                feature_refresher
+                    .register_token_for_refresh(token, None)
+                    .await
+            }
+            (TokenValidationStatus::Validated, _) => {}
+            _ => warn!(
+                "Skipping pretrusted token {} since it failed validation",
+                anonymize_token(&token).token
+            ),
+        }
+    }
+    feature_refresher.hydrate_new_tokens().await;
+}
+
+async fn build_edge(
+    args: &EdgeArgs,
+    connect_via: &ConnectVia,
+    denied_tokens: &DeniedTokens,
+) -> EdgeResult<EdgeInfo> {
     if !args.strict {
         if !args.dynamic {
             error!("You should explicitly opt into either strict or dynamic behavior. Edge has defaulted to dynamic to preserve legacy behavior, however we recommend using strict from now on. Not explicitly opting into a behavior will return an error on startup in a future release");
@@ -177,65 +350,224 @@ async fn build_edge(args: &EdgeArgs, app_name: &str) -> EdgeResult<EdgeInfo> {
         ));
     }
 
-    let (token_cache, feature_cache, engine_cache) = build_caches();
+    let refresh_shard = args
+        .shard_count
+        .map(|count| RefreshShard::new(args.shard_index.unwrap_or_default(), count))
+        .transpose()?;
+    if let Some(shard) = refresh_shard {
+        info!(
+            "Refreshing shard {} of {} from upstream, other environments are read from the database",
+            shard.index, shard.count
+        );
+    }
+
+    let (token_cache, feature_cache, engine_cache) =
+        build_caches(args.expected_tokens, args.expected_environments);
+
+    let persistence = get_data_source(args).await?;
 
-    let persistence = get_data_source(args).await;
+    if args.skip_ssl_verification {
+        warn!("Not verifying the certificate of upstream. Anyone able to intercept the connection can read our tokens and send us their own features. Don't do this in production, trust the upstream's CA with --upstream-ca-cert instead");
+    }
 
-    let http_client = new_reqwest_client(
-        "unleash_edge".into(),
-        args.skip_ssl_verification,
-        args.client_identity.clone(),
-        args.upstream_certificate_file.clone(),
-        Duration::seconds(args.upstream_request_timeout),
-        Duration::seconds(args.upstream_socket_timeout),
-        app_name.into(),
-    )?;
+    let build_http_client = {
+        let args = args.clone();
+        let connect_via = connect_via.clone();
+        move || {
+            new_reqwest_client(
+                connect_via.instance_id.clone(),
+                args.skip_ssl_verification,
+                args.client_identity.clone(),
+                args.upstream_certificate_file.clone(),
+                args.upstream_connect_timeout_ms
+                    .map(|ms| Duration::milliseconds(ms as i64))
+                    .unwrap_or_else(|| Duration::seconds(args.upstream_request_timeout)),
+                args.upstream_request_timeout_ms
+                    .map(|ms| Duration::milliseconds(ms as i64))
+                    .unwrap_or_else(|| Duration::seconds(args.upstream_socket_timeout)),
+                connect_via.app_name.clone(),
+                !args.disable_upstream_compression,
+                UpstreamConnectionPool {
+                    idle_timeout: Some(std::time::Duration::from_secs(
+                        args.upstream_pool_idle_timeout_seconds,
+                    )),
+                    max_idle_per_host: args.upstream_pool_max_idle_per_host.unwrap_or(usize::MAX),
+                },
+            )
+        }
+    };
+    let http_client = build_http_client()?;
 
-    let unleash_client = Url::parse(&args.upstream_url.clone())
+    let upstream_urls = args
+        .upstream_url
+        .iter()
+        .map(|url| Url::parse(url).map_err(|_| EdgeError::InvalidServerUrl(url.clone())))
+        .collect::<EdgeResult<Vec<Url>>>()?;
+    let primary_url = upstream_urls
+        .first()
+        .cloned()
+        .ok_or_else(|| EdgeError::InvalidServerUrl("No upstream URL configured".into()))?;
+    let upstream_failover = UpstreamFailover::new(
+        upstream_urls
+            .into_iter()
+            .map(UnleashUrls::from_base_url)
+            .collect(),
+    )
+    .with_failover_threshold(args.upstream_failover_threshold)
+    .with_primary_cooldown(Duration::seconds(args.upstream_primary_cooldown_seconds));
+    let change_webhook = args
+        .change_webhook_url
+        .as_ref()
         .map(|url| {
-            UnleashClient::from_url(url, args.token_header.token_header.clone(), http_client)
+            Url::parse(url)
+                .map(|url| ChangeWebhook::spawn(url, http_client.clone()))
+                .map_err(|_| EdgeError::InvalidServerUrl(url.clone()))
         })
-        .map(|c| c.with_custom_client_headers(args.custom_client_headers.clone()))
-        .map(Arc::new)
-        .map_err(|_| EdgeError::InvalidServerUrl(args.upstream_url.clone()))?;
+        .transpose()?;
+    let unleash_client = Arc::new(
+        UnleashClient::from_url(
+            primary_url,
+            args.token_header.token_header.clone(),
+            http_client,
+        )
+        .with_custom_client_headers(args.custom_client_headers.clone())
+        .with_upstream_failover(upstream_failover)
+        .with_circuit_breaker(CircuitBreaker::new(
+            args.upstream_circuit_breaker_threshold,
+            Duration::seconds(args.upstream_circuit_breaker_cooldown_seconds),
+        ))
+        .with_max_feature_document_bytes(args.max_feature_document_bytes),
+    );
+
+    if let Some(seconds) = args.upstream_dns_refresh_seconds {
+        tokio::spawn(
+            unleash_client
+                .clone()
+                .refresh_on_dns_change(build_http_client, std::time::Duration::from_secs(seconds)),
+        );
+    }
 
     let token_validator = Arc::new(TokenValidator {
         token_cache: token_cache.clone(),
         unleash_client: unleash_client.clone(),
         persistence: persistence.clone(),
+        strict_token_validation: args.strict_token_validation,
+        max_tokens: args.max_tokens,
+        invalid_token_ttl: Some(std::time::Duration::from_secs(
+            args.invalid_token_ttl_seconds,
+        )),
+        ..Default::default()
     });
 
-    let feature_refresher = Arc::new(FeatureRefresher::new(
-        unleash_client,
-        feature_cache.clone(),
-        engine_cache.clone(),
-        Duration::seconds(args.features_refresh_interval_seconds.try_into().unwrap()),
-        persistence.clone(),
-        args.strict,
-        app_name,
-    ));
-    let _ = token_validator.register_tokens(args.tokens.clone()).await;
+    let max_refresh_backoff = Duration::seconds(
+        args.features_refresh_max_backoff_seconds
+            .try_into()
+            .unwrap(),
+    );
+    let feature_refresher = Arc::new(
+        FeatureRefresher::new(
+            unleash_client,
+            feature_cache.clone(),
+            engine_cache.clone(),
+            Duration::seconds(args.features_refresh_interval_seconds.try_into().unwrap()),
+            persistence.clone(),
+            args.strict,
+            &connect_via.app_name,
+        )
+        .with_max_refresh_backoff(max_refresh_backoff)
+        .with_denied_tokens(denied_tokens.clone())
+        .with_startup_jitter(Duration::seconds(
+            args.startup_jitter_seconds.try_into().unwrap(),
+        ))
+        .with_token_idle_ttl(
+            args.token_idle_ttl_seconds
+                .map(|ttl| Duration::seconds(ttl.try_into().unwrap())),
+        )
+        .with_change_webhook(change_webhook)
+        .with_delta_api(args.use_delta_api)
+        .with_max_staleness(
+            args.max_stale_seconds
+                .map(|max_stale| Duration::seconds(max_stale.try_into().unwrap())),
+        )
+        .with_token_cache(token_cache.clone())
+        .with_refresh_shard(refresh_shard)
+        .with_default_environment(args.default_environment.clone())
+        .with_allowed_environments(args.allowed_environments.clone())
+        .with_environment_refresh_intervals(
+            args.environment_refresh_intervals
+                .iter()
+                .map(|(environment, seconds)| {
+                    (
+                        environment.clone(),
+                        Duration::seconds((*seconds).try_into().unwrap()),
+                    )
+                })
+                .collect(),
+        ),
+    );
+    check_startup_tokens(
+        &token_validator,
+        args.tokens.clone(),
+        args.fail_on_bad_upstream_token,
+    )
+    .await?;
 
     if let Some(persistence) = persistence.clone() {
-        hydrate_from_persistent_storage(
+        let restored_at = hydrate_from_persistent_storage(
             (
                 token_cache.clone(),
                 feature_cache.clone(),
                 engine_cache.clone(),
             ),
             persistence,
+            args.max_persisted_age_seconds
+                .map(|max_age| Duration::seconds(max_age.try_into().unwrap())),
         )
         .await;
+        for (environment, persisted_at) in restored_at {
+            feature_refresher.record_restored_features(&environment, persisted_at);
+        }
     }
 
+    let mut revoked_tokens = args.revoked_tokens.clone();
+    if let Some(persistence) = persistence.clone() {
+        revoked_tokens.extend(
+            persistence
+                .load_revoked_tokens()
+                .await
+                .unwrap_or_else(|error| {
+                    debug!("No revoked tokens restored from cache {error:?}");
+                    vec![]
+                }),
+        );
+    }
+    if !revoked_tokens.is_empty() {
+        token_validator.revoke_tokens(revoked_tokens).await?;
+    }
+
+    let persisted_etags = match persistence.clone() {
+        Some(persistence) => load_persisted_etags(persistence, feature_cache.clone()).await,
+        None => HashMap::new(),
+    };
+
     for validated_token in token_cache
         .iter()
         .filter(|candidate| candidate.value().token_type == Some(TokenType::Client))
+        .filter(|candidate| !token_validator.is_revoked(candidate.key()))
     {
         feature_refresher
-            .register_token_for_refresh(validated_token.clone(), None)
+            .register_token_for_refresh(
+                validated_token.clone(),
+                persisted_etags.get(&validated_token.token).cloned(),
+            )
             .await;
     }
+    warm_up_pretrusted_tokens(
+        read_pretrusted_tokens(args, denied_tokens),
+        &token_validator,
+        &feature_refresher,
+    )
+    .await;
     Ok((
         (token_cache, feature_cache, engine_cache),
         Some(token_validator),
@@ -244,25 +576,162 @@ async fn build_edge(args: &EdgeArgs, app_name: &str) -> EdgeResult<EdgeInfo> {
     ))
 }
 
-pub async fn build_caches_and_refreshers(args: CliArgs) -> EdgeResult<EdgeInfo> {
+/// A startup token upstream turns down is almost always a misconfiguration, so it's reported before serving instead of when the first refresh fails.
+/// If upstream can't be asked, the tokens are validated again later
+async fn check_startup_tokens(
+    token_validator: &TokenValidator,
+    tokens: Vec<String>,
+    fail_on_rejected: bool,
+) -> EdgeResult<()> {
+    let known_tokens = match token_validator.register_tokens(tokens).await {
+        Ok(known_tokens) => known_tokens,
+        Err(e) => {
+            warn!("Could not validate the startup tokens against upstream: {e:?}");
+            return Ok(());
+        }
+    };
+    let rejected: Vec<String> = known_tokens
+        .iter()
+        .filter(|token| token.status == TokenValidationStatus::Invalid)
+        .map(|token| anonymize_token(token).token)
+        .collect();
+    if rejected.is_empty() {
+        Ok(())
+    } else if fail_on_rejected {
+        Err(EdgeError::StartupTokensRejected(rejected.join(", ")))
+    } else {
+        warn!(
+            "Upstream rejected the startup tokens {}, Edge won't have features for them",
+            rejected.join(", ")
+        );
+        Ok(())
+    }
+}
+
+/// Read replicas only have persistence to serve from, so they refuse to start without it. At startup they load what's
+/// there, after that they reload it on an interval
+async fn build_read_replica(args: &ReadReplicaArgs) -> EdgeResult<EdgeInfo> {
+    let persistence = persistence_from(
+        args.redis.clone(),
+        args.postgres.clone(),
+        args.backup_folder.clone(),
+        false,
+    )
+    .await?
+    .ok_or_else(|| {
+        EdgeError::PersistenceError("Read replicas need Redis, Postgres or a backup folder".into())
+    })?;
+    let caches = build_caches(0, 0);
+    hydrate_from_persistent_storage(caches.clone(), persistence.clone(), None).await;
+    info!(
+        "Serving {} tokens and {} environments from persistence as a read replica",
+        caches.0.len(),
+        caches.1.len()
+    );
+    Ok((caches, None, None, Some(persistence)))
+}
+
+/// Metrics that were persisted on shutdown are merged into `metrics_cache`, so they still get posted upstream
+pub async fn build_caches_and_refreshers(
+    args: CliArgs,
+    metrics_cache: Arc<MetricsCache>,
+    denied_tokens: &DeniedTokens,
+) -> EdgeResult<EdgeInfo> {
     match args.mode {
         EdgeMode::Offline(offline_args) => {
             build_offline(offline_args).map(|cache| (cache, None, None, None))
         }
-        EdgeMode::Edge(edge_args) => build_edge(&edge_args, &args.app_name).await,
+        EdgeMode::ReadReplica(read_replica_args) => build_read_replica(&read_replica_args).await,
+        EdgeMode::Edge(edge_args) => {
+            let connect_via = ConnectVia {
+                app_name: args.app_name,
+                instance_id: args.instance_id,
+            };
+            let edge_info = build_edge(&edge_args, &connect_via, denied_tokens).await?;
+            if let Some(persistence) = edge_info.3.clone() {
+                restore_metrics(&metrics_cache, persistence).await;
+            }
+            Ok(edge_info)
+        }
         _ => unreachable!(),
     }
 }
 
+/// The persisted metrics are cleared once restored, so a crash before the next clean shutdown doesn't count them twice
+async fn restore_metrics(metrics_cache: &MetricsCache, persistence: Arc<dyn EdgePersistence>) {
+    match persistence.load_metrics().await {
+        Ok(metrics) if metrics.metrics.is_empty() && metrics.applications.is_empty() => {
+            debug!("No persisted metrics to restore");
+        }
+        Ok(metrics) => {
+            info!(
+                "Restoring {} persisted metrics and {} applications",
+                metrics.metrics.len(),
+                metrics.applications.len()
+            );
+            metrics_cache.reinsert_batch(metrics);
+            if let Err(e) = persistence.save_metrics(MetricsBatch::default()).await {
+                warn!("Could not clear persisted metrics after restoring them: {e:?}");
+            }
+        }
+        Err(e) => debug!("Could not load persisted metrics: {e:?}"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use actix_web::http::header::EntityTag;
+    use chrono::{Duration, Utc};
+    use dashmap::DashMap;
+    use unleash_types::client_features::ClientFeatures;
+    use unleash_types::client_metrics::{ClientMetricsEnv, ConnectVia, MetricsMetadata};
+
     use crate::{
-        builder::{build_edge, build_offline},
-        cli::{EdgeArgs, OfflineArgs, TokenHeader},
+        auth::{denied_tokens::DeniedTokens, token_validator::TokenValidator},
+        builder::{
+            build_caches, build_edge, build_offline, check_startup_tokens, get_data_source,
+            hydrate_from_persistent_storage, load_persisted_etags, read_pretrusted_tokens,
+            restore_metrics, warm_up_pretrusted_tokens,
+        },
+        cli::{
+            EdgeArgs, OfflineArgs, PostgresArgs, RedisArgs, RedisMode, RedisScheme, TokenHeader,
+        },
+        error::EdgeError,
+        http::{feature_refresher::FeatureRefresher, unleash_client::UnleashClient},
+        metrics::client_metrics::{MetricsBatch, MetricsCache},
+        persistence::{file::FilePersister, EdgePersistence},
+        tests::{features_from_disk, upstream_server},
+        tokens::cache_key,
+        types::{EdgeToken, TokenRefresh},
     };
 
     #[test]
-    fn should_fail_with_empty_tokens_when_offline_mode() {
+    fn offline_mode_without_tokens_starts_with_empty_caches() {
+        let args = OfflineArgs {
+            bootstrap_file: Some(PathBuf::from("../examples/features.json")),
+            tokens: vec![],
+            reload_interval: Default::default(),
+        };
+
+        let (token_cache, features_cache, engine_cache) = build_offline(args).unwrap();
+        assert!(token_cache.is_empty());
+        assert!(features_cache.is_empty());
+        assert!(engine_cache.is_empty());
+    }
+
+    #[test]
+    fn caches_are_sized_for_the_expected_tokens_and_environments() {
+        let (token_cache, features_cache, engine_cache) = build_caches(1000, 50);
+        assert!(token_cache.capacity() >= 1000);
+        assert!(features_cache.capacity() >= 50);
+        assert!(engine_cache.capacity() >= 50);
+    }
+
+    #[test]
+    fn should_fail_without_bootstrap_file_when_offline_mode() {
         let args = OfflineArgs {
             bootstrap_file: None,
             tokens: vec![],
@@ -273,38 +742,414 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(
             result.err().unwrap().to_string(),
-            "No tokens provided. Tokens must be specified when running in offline mode"
+            EdgeError::NoFeaturesFile.to_string()
         );
     }
 
     #[tokio::test]
     async fn should_fail_with_empty_tokens_when_strict() {
-        let args = EdgeArgs {
+        let args = edge_args();
+
+        let result = build_edge(
+            &args,
+            &ConnectVia {
+                app_name: "test-app".into(),
+                instance_id: "test-instance".into(),
+            },
+            &DeniedTokens::default(),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "No tokens provided. Tokens must be specified when running with strict behavior"
+        );
+    }
+
+    fn edge_args() -> EdgeArgs {
+        EdgeArgs {
             upstream_url: Default::default(),
+            upstream_failover_threshold: 3,
+            upstream_primary_cooldown_seconds: 300,
+            upstream_circuit_breaker_threshold: 0,
+            upstream_circuit_breaker_cooldown_seconds: 30,
+            max_feature_document_bytes: None,
             backup_folder: None,
             metrics_interval_seconds: Default::default(),
+            metrics_send_retries: Default::default(),
+            metrics_max_age_seconds: Default::default(),
             features_refresh_interval_seconds: Default::default(),
+            environment_refresh_intervals: vec![],
+            features_refresh_max_backoff_seconds: Default::default(),
+            startup_jitter_seconds: Default::default(),
+            use_delta_api: false,
+            max_stale_seconds: None,
             strict: true,
             dynamic: false,
+            strict_token_validation: false,
             tokens: vec![],
+            upstream_token_file: None,
+            revoked_tokens: vec![],
+            pretrusted_tokens: vec![],
+            pretrusted_tokens_file: None,
             redis: None,
+            postgres: None,
+            persistence_required: false,
+            max_persisted_age_seconds: None,
+            persistence_integrity_check_interval_seconds: None,
+            persistence_integrity_repair: false,
+            shard_count: None,
+            shard_index: None,
             client_identity: Default::default(),
             skip_ssl_verification: false,
             upstream_request_timeout: Default::default(),
             upstream_socket_timeout: Default::default(),
+            upstream_request_timeout_ms: None,
+            upstream_connect_timeout_ms: None,
+            upstream_pool_idle_timeout_seconds: 30,
+            upstream_pool_max_idle_per_host: None,
+            upstream_dns_refresh_seconds: None,
+            max_tokens: None,
+            invalid_token_ttl_seconds: 60,
+            disable_upstream_compression: false,
+            change_webhook_url: None,
             custom_client_headers: Default::default(),
             token_header: TokenHeader {
                 token_header: "Authorization".into(),
             },
             upstream_certificate_file: Default::default(),
             token_revalidation_interval_seconds: Default::default(),
+            token_idle_ttl_seconds: None,
+            expected_tokens: 0,
+            expected_environments: 0,
+            default_environment: None,
+            allowed_environments: vec![],
+            fail_on_bad_upstream_token: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_in_memory_caches_when_redis_cannot_be_configured() {
+        let args = EdgeArgs {
+            redis: Some(RedisArgs {
+                redis_url: Some(vec!["http://not-a-redis-server".into()]),
+                redis_mode: RedisMode::Single,
+                redis_password: None,
+                redis_username: None,
+                redis_port: None,
+                redis_host: None,
+                redis_secure: false,
+                redis_scheme: RedisScheme::Redis,
+                redis_read_connection_timeout_milliseconds: 200,
+                redis_write_connection_timeout_milliseconds: 200,
+                redis_ttl_seconds: None,
+            }),
+            ..edge_args()
+        };
+
+        assert!(get_data_source(&args).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn redis_that_does_not_answer_a_ping_is_not_used_for_persistence() {
+        let args = EdgeArgs {
+            redis: Some(RedisArgs {
+                redis_url: Some(vec!["redis://127.0.0.1:1".into()]),
+                redis_mode: RedisMode::Single,
+                redis_password: None,
+                redis_username: None,
+                redis_port: None,
+                redis_host: None,
+                redis_secure: false,
+                redis_scheme: RedisScheme::Redis,
+                redis_read_connection_timeout_milliseconds: 200,
+                redis_write_connection_timeout_milliseconds: 200,
+                redis_ttl_seconds: None,
+            }),
+            ..edge_args()
+        };
+        assert!(get_data_source(&args).await.unwrap().is_none());
+
+        let required = EdgeArgs {
+            persistence_required: true,
+            ..args
+        };
+        assert!(matches!(
+            get_data_source(&required).await,
+            Err(EdgeError::PersistenceError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_in_memory_caches_when_postgres_is_unreachable() {
+        let args = EdgeArgs {
+            postgres: Some(PostgresArgs {
+                postgres_url: Some("postgres://edge@127.0.0.1:1/unleash_edge".into()),
+                postgres_pool_size: 1,
+            }),
+            ..edge_args()
+        };
+        assert!(get_data_source(&args).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unwritable_backup_folder_only_fails_startup_when_persistence_is_required() {
+        let not_a_folder =
+            std::env::temp_dir().join("unwritable_backup_folder_only_fails_startup_when_required");
+        std::fs::write(&not_a_folder, "a file where the backup folder should be").unwrap();
+        let args = EdgeArgs {
+            backup_folder: Some(not_a_folder.clone()),
+            ..edge_args()
+        };
+        assert!(get_data_source(&args).await.unwrap().is_none());
+
+        let required = EdgeArgs {
+            persistence_required: true,
+            ..args
+        };
+        assert!(matches!(
+            get_data_source(&required).await,
+            Err(EdgeError::PersistenceError(_))
+        ));
+
+        let writable = EdgeArgs {
+            backup_folder: Some(std::env::temp_dir().join("writable_backup_folder_for_startup")),
+            persistence_required: true,
+            ..edge_args()
+        };
+        assert!(get_data_source(&writable).await.unwrap().is_some());
+        let _ = std::fs::remove_file(not_a_folder);
+    }
+
+    #[tokio::test]
+    async fn features_persisted_longer_ago_than_the_max_age_are_not_restored() {
+        let persister = FilePersister::new(
+            &std::env::temp_dir()
+                .join("features_persisted_longer_ago_than_the_max_age_are_not_restored"),
+        );
+        persister
+            .save_features(vec![(
+                "development".into(),
+                features_from_disk("../examples/features.json"),
+            )])
+            .await
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(persister.features_path())
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200))
+            .unwrap();
+        let persister: Arc<dyn EdgePersistence> = Arc::new(persister);
+
+        let caches = build_caches(0, 0);
+        let restored_at = hydrate_from_persistent_storage(
+            caches.clone(),
+            persister.clone(),
+            Some(Duration::hours(1)),
+        )
+        .await;
+        assert!(restored_at.is_empty());
+        assert!(caches.1.is_empty());
+        assert!(caches.2.is_empty());
+
+        let caches = build_caches(0, 0);
+        let restored_at = hydrate_from_persistent_storage(
+            caches.clone(),
+            persister.clone(),
+            Some(Duration::hours(3)),
+        )
+        .await;
+        assert!(caches.1.contains_key("development"));
+        let age = Utc::now().signed_duration_since(restored_at["development"]);
+        assert!(age >= Duration::hours(2) && age < Duration::hours(3));
+
+        let caches = build_caches(0, 0);
+        let restored_at = hydrate_from_persistent_storage(caches.clone(), persister, None).await;
+        assert!(restored_at.is_empty());
+        assert!(caches.1.contains_key("development"));
+    }
+
+    #[tokio::test]
+    async fn only_restores_etags_for_tokens_with_persisted_features() {
+        let persister = Arc::new(FilePersister::new(
+            &std::env::temp_dir().join("only_restores_etags_for_tokens_with_persisted_features"),
+        ));
+        let production = EdgeToken::validated_client_token("*:production.abcdef");
+        let development = EdgeToken::validated_client_token("*:development.abcdef");
+        let etag = EntityTag::new_weak("1d-2a7f".into());
+        persister
+            .save_refresh_targets(vec![
+                TokenRefresh::new(production.clone(), Some(etag.clone())),
+                TokenRefresh::new(development.clone(), Some(etag.clone())),
+            ])
+            .await
+            .unwrap();
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        features_cache.insert(
+            "production".into(),
+            ClientFeatures {
+                version: 2,
+                features: vec![],
+                segments: None,
+                query: None,
+            },
+        );
+
+        let etags = load_persisted_etags(persister, features_cache).await;
+
+        assert_eq!(etags.get(&production.token), Some(&etag));
+        assert!(!etags.contains_key(&development.token));
+    }
+
+    #[test]
+    fn reads_pretrusted_tokens_from_args_and_file() {
+        let path = std::env::temp_dir().join("reads_pretrusted_tokens_from_args_and_file");
+        std::fs::write(
+            &path,
+            "# warm these up\n*:production.abcdef\n\n  *:staging.abcdef  \n",
+        )
+        .unwrap();
+        let args = EdgeArgs {
+            pretrusted_tokens: vec!["*:development.abcdef".into()],
+            pretrusted_tokens_file: Some(path),
+            ..edge_args()
         };
 
-        let result = build_edge(&args, "test-app").await;
-        assert!(result.is_err());
         assert_eq!(
-            result.err().unwrap().to_string(),
-            "No tokens provided. Tokens must be specified when running with strict behavior"
+            read_pretrusted_tokens(&args, &DeniedTokens::default()),
+            vec![
+                "*:development.abcdef",
+                "*:production.abcdef",
+                "*:staging.abcdef"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn warming_up_fetches_features_for_valid_tokens_and_skips_the_rest() {
+        let token = EdgeToken::validated_client_token("*:development.abcdef");
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        upstream_features_cache.insert(
+            cache_key(&token),
+            features_from_disk("../examples/features.json"),
         );
+        let server = upstream_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            Arc::new(DashMap::default()),
+        )
+        .await;
+        let unleash_client = Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap());
+        let token_validator = TokenValidator {
+            unleash_client: unleash_client.clone(),
+            ..Default::default()
+        };
+        let feature_refresher = FeatureRefresher::with_client(unleash_client);
+
+        warm_up_pretrusted_tokens(
+            vec![token.token.clone(), "notatoken".into()],
+            &token_validator,
+            &feature_refresher,
+        )
+        .await;
+
+        assert!(feature_refresher
+            .tokens_to_refresh
+            .contains_key(&token.token));
+        assert!(feature_refresher
+            .features_cache
+            .contains_key(&cache_key(&token)));
+        assert!(feature_refresher
+            .engine_cache
+            .contains_key(&cache_key(&token)));
+    }
+
+    #[tokio::test]
+    async fn startup_tokens_rejected_upstream_only_fail_startup_when_asked_to() {
+        let valid = EdgeToken::validated_client_token("*:development.valid123");
+        let validated = valid.clone();
+        let server = actix_http_test::test_server(move || {
+            let validated = validated.clone();
+            actix_http::HttpService::new(actix_service::map_config(
+                actix_web::App::new().route(
+                    "/edge/validate",
+                    actix_web::web::post().to(move || {
+                        let validated = validated.clone();
+                        async move {
+                            actix_web::HttpResponse::Ok()
+                                .json(serde_json::json!({ "tokens": [validated] }))
+                        }
+                    }),
+                ),
+                |_| actix_web::dev::AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let token_validator = TokenValidator {
+            unleash_client: Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap()),
+            ..Default::default()
+        };
+        let tokens = vec![valid.token.clone(), "*:development.wrong123".to_string()];
+
+        assert!(
+            check_startup_tokens(&token_validator, vec![valid.token.clone()], true)
+                .await
+                .is_ok()
+        );
+        assert!(
+            check_startup_tokens(&token_validator, tokens.clone(), false)
+                .await
+                .is_ok()
+        );
+        token_validator.token_cache.clear();
+        let rejected = check_startup_tokens(&token_validator, tokens, true)
+            .await
+            .unwrap_err();
+        assert!(matches!(rejected, EdgeError::StartupTokensRejected(_)));
+        assert!(!rejected.to_string().contains("wrong123"));
+    }
+
+    #[tokio::test]
+    async fn restoring_metrics_merges_them_into_the_cache_and_clears_the_backup() {
+        let persister = Arc::new(FilePersister::new(
+            &std::env::temp_dir().join("restoring_metrics_merges_them_into_the_cache"),
+        ));
+        let metric = ClientMetricsEnv {
+            app_name: "some-app".into(),
+            feature_name: "some-feature".into(),
+            environment: "development".into(),
+            timestamp: Utc::now(),
+            yes: 2,
+            no: 1,
+            variants: Default::default(),
+            metadata: MetricsMetadata {
+                platform_name: None,
+                platform_version: None,
+                sdk_version: None,
+                yggdrasil_version: None,
+            },
+        };
+        persister
+            .save_metrics(MetricsBatch {
+                applications: vec![],
+                metrics: vec![metric.clone()],
+            })
+            .await
+            .unwrap();
+        let metrics_cache = MetricsCache::default();
+        metrics_cache.sink_metrics(&[metric]);
+
+        restore_metrics(&metrics_cache, persister.clone()).await;
+
+        let restored = metrics_cache.snapshot().metrics;
+        assert_eq!(restored.len(), 1);
+        assert_eq!((restored[0].yes, restored[0].no), (4, 2));
+        let backup = persister.load_metrics().await.unwrap();
+        assert!(backup.metrics.is_empty());
     }
 }
@@ -1,26 +1,48 @@
+use crate::cli::EmptyCacheBehavior;
 use crate::error::EdgeError;
+use crate::feature_overrides::FeatureOverrides;
 use crate::filters::{
-    filter_client_features, name_match_filter, name_prefix_filter, project_filter, FeatureFilterSet,
+    filter_client_features, name_match_filter, name_prefix_filter, project_filter, retain_enabled,
+    FeatureFilterSet,
 };
+use crate::frontend_api::get_all_features;
+use crate::http::broadcaster::{Broadcaster, Subscription};
 use crate::http::feature_refresher::FeatureRefresher;
-use crate::metrics::client_metrics::MetricsCache;
+use crate::metrics::client_metrics::{IncomingConnectVia, MetricsCache};
 use crate::tokens::cache_key;
 use crate::types::{
-    self, BatchMetricsRequestBody, EdgeJsonResult, EdgeResult, EdgeToken, FeatureFilters,
+    self, BatchMetricsRequestBody, ClientIp, EdgeJsonResult, EdgeResult, EdgeToken, FeatureFilters,
+    FeatureFormat, FeatureFormatQuery, FrontendResult, LongPoll,
 };
+use actix_middleware_etag::Etag;
+use actix_web::dev::{fn_service, Service, ServiceRequest, Transform};
+use actix_web::http::header::{self, Header, IfNoneMatch};
+use actix_web::http::StatusCode;
 use actix_web::web::{self, Data, Json, Query};
-use actix_web::{get, post, HttpRequest, HttpResponse};
+use actix_web::{get, post, HttpMessage, HttpRequest, HttpResponse};
 use dashmap::DashMap;
+use iter_tools::Itertools;
+use serde::Serialize;
+use std::time::Duration;
 use unleash_types::client_features::{ClientFeature, ClientFeatures};
 use unleash_types::client_metrics::{ClientApplication, ClientMetrics, ConnectVia};
+use unleash_yggdrasil::EngineState;
+
+pub const DEFAULT_MAX_METRICS_BODY_BYTES: usize = 1024 * 1024;
+
+/// Long polls are held no longer than this, to stay clear of the idle timeouts of proxies in between
+pub const MAX_LONG_POLL_SECONDS: u64 = 60;
 
 #[utoipa::path(
     context_path = "/api/client",
-    params(FeatureFilters),
+    params(FeatureFilters, LongPoll, FeatureFormatQuery, ("tag" = Option<Vec<String>>, Query, description = "Only serve the features tagged with any of these, on the form type:value. Can be repeated")),
     responses(
-        (status = 200, description = "Return feature toggles for this token", body = ClientFeatures),
-        (status = 403, description = "Was not allowed to access features"),
-        (status = 400, description = "Invalid parameters used")
+        (status = 200, description = "Return feature toggles for this token. With format=evaluated, or an Accept header asking for application/vnd.unleash.evaluated+json, every toggle evaluated against the context in the query string instead", body = ClientFeatures),
+        (status = 304, description = "The features haven't changed from the ETag in If-None-Match"),
+        (status = 406, description = "The format asked for isn't one Edge knows", body = crate::error::ErrorResponse),
+        (status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+        (status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse),
+        (status = 503, description = "Features have not been refreshed from upstream for longer than the max staleness, none are cached for the environment with --empty-cache-behavior unavailable, or upstream could not say which features carry the tags asked for", body = crate::error::ErrorResponse)
     ),
     security(
         ("Authorization" = [])
@@ -32,17 +54,149 @@ pub async fn get_features(
     features_cache: Data<DashMap<String, ClientFeatures>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     filter_query: Query<FeatureFilters>,
+    long_poll: Query<LongPoll>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    if FeatureFormat::requested(&req)? == Some(FeatureFormat::Evaluated) {
+        return evaluated_features(edge_token, token_cache, filter_query, &req).map(negotiated);
+    }
+    poll_features(
+        edge_token,
+        features_cache,
+        token_cache,
+        filter_query,
+        long_poll,
+        req,
+    )
+    .await
+    .map(negotiated)
+}
+
+/// Responses depend on the Accept header, so caches mustn't hand them out to requests asking for another format
+fn negotiated<T: Serialize>(Json(body): Json<T>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::VARY, "Accept"))
+        .json(body)
+}
+
+/// Every toggle the token has access to, evaluated against the context in the query string the way the frontend API's
+/// `/all` does it. Long polling only applies to the raw features
+fn evaluated_features(
+    edge_token: EdgeToken,
+    token_cache: Data<DashMap<String, EdgeToken>>,
+    filter_query: Query<FeatureFilters>,
+    req: &HttpRequest,
+) -> EdgeJsonResult<FrontendResult> {
+    let engine_cache = req
+        .app_data::<Data<DashMap<String, EngineState>>>()
+        .ok_or(EdgeError::ClientCacheError)?;
+    let validated_token = token_cache
+        .get(&edge_token.token)
+        .map(|e| e.value().clone())
+        .ok_or(EdgeError::AuthorizationDenied)?;
+    if let Some(refresher) = req.app_data::<Data<FeatureRefresher>>() {
+        refresher.check_staleness(&validated_token)?;
+    }
+    // What the features endpoint itself takes from the query string isn't part of the context
+    let context_query = req
+        .query_string()
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or_default();
+            !["format", "namePrefix", "enabledOnly", "wait"].contains(&key)
+        })
+        .join("&");
+    let Json(mut evaluated) = get_all_features(
+        edge_token,
+        engine_cache.clone(),
+        token_cache,
+        &context_query,
+        req.extensions().get::<ClientIp>(),
+        req.app_data::<Data<FeatureOverrides>>().map(Data::get_ref),
+    )?;
+    if let Some(name_prefix) = filter_query.name_prefix.as_ref().filter(|p| !p.is_empty()) {
+        evaluated
+            .toggles
+            .retain(|toggle| toggle.name.starts_with(name_prefix.as_str()));
+    }
+    Ok(Json(evaluated))
+}
+
+async fn poll_features(
+    edge_token: EdgeToken,
+    features_cache: Data<DashMap<String, ClientFeatures>>,
+    token_cache: Data<DashMap<String, EdgeToken>>,
+    filter_query: Query<FeatureFilters>,
+    long_poll: Query<LongPoll>,
     req: HttpRequest,
 ) -> EdgeJsonResult<ClientFeatures> {
+    let features = resolve_features(
+        edge_token.clone(),
+        features_cache.clone(),
+        token_cache.clone(),
+        filter_query.clone(),
+        req.clone(),
+    )
+    .await?;
+    let Some(wait) = long_poll.wait.filter(|wait| *wait > 0) else {
+        return Ok(features);
+    };
+    if !client_has(&req, &features).await {
+        return Ok(features);
+    }
+    let Some(subscription) = req
+        .app_data::<Data<Broadcaster>>()
+        .and_then(|broadcaster| broadcaster.subscribe().ok())
+    else {
+        return Ok(features);
+    };
+    let key = token_cache
+        .get(&edge_token.token)
+        .map(|token| cache_key(token.value()))
+        .unwrap_or_else(|| cache_key(&edge_token));
+    let wait = Duration::from_secs(wait.min(MAX_LONG_POLL_SECONDS));
+    let _ = tokio::time::timeout(wait, wait_for_change(subscription, &key)).await;
     resolve_features(edge_token, features_cache, token_cache, filter_query, req).await
 }
+
+/// Whether the client already has these features, going by the ETag it sent in If-None-Match. The features are run
+/// through the ETag middleware that tags the response, so the tag compared is the one the client was sent
+async fn client_has(req: &HttpRequest, features: &ClientFeatures) -> bool {
+    if IfNoneMatch::parse(req).is_err() {
+        return false;
+    }
+    let Ok(body) = serde_json::to_vec(features) else {
+        return false;
+    };
+    let respond_with_features = fn_service(move |req: ServiceRequest| {
+        let response = HttpResponse::Ok().body(body.clone());
+        async move { Ok::<_, actix_web::Error>(req.into_response(response)) }
+    });
+    let Ok(etag) = Etag.new_transform(respond_with_features).await else {
+        return false;
+    };
+    etag.call(ServiceRequest::from_request(req.clone()))
+        .await
+        .is_ok_and(|response| response.status() == StatusCode::NOT_MODIFIED)
+}
+
+/// A lagging subscription has missed updates, which may have been for us, so it counts as a change too
+async fn wait_for_change(mut subscription: Subscription, key: &str) {
+    loop {
+        match subscription.recv().await {
+            Ok(update) if update.cache_key != key => continue,
+            _ => return,
+        }
+    }
+}
 #[utoipa::path(
     context_path = "/api/client",
-    params(FeatureFilters),
+    params(FeatureFilters, ("tag" = Option<Vec<String>>, Query, description = "Only serve the features tagged with any of these, on the form type:value. Can be repeated")),
     responses(
         (status = 200, description = "Return feature toggles for this token", body = ClientFeatures),
-        (status = 403, description = "Was not allowed to access features"),
-        (status = 400, description = "Invalid parameters used")
+        (status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+        (status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse),
+        (status = 503, description = "Features have not been refreshed from upstream for longer than the max staleness, none are cached for the environment with --empty-cache-behavior unavailable, or upstream could not say which features carry the tags asked for", body = crate::error::ErrorResponse)
     ),
     security(
         ("Authorization" = [])
@@ -71,16 +225,22 @@ async fn resolve_features(
         .map(|e| e.value().clone())
         .ok_or(EdgeError::AuthorizationDenied)?;
 
-    let query_filters = filter_query.into_inner();
+    let filter_query = filter_query.into_inner();
+    let name_prefix = filter_query.name_prefix.filter(|prefix| !prefix.is_empty());
+    let tags = tag_filters(&req)?;
     let query = unleash_types::client_features::Query {
-        tags: None,
+        tags: (!tags.is_empty()).then(|| {
+            tags.iter()
+                .map(|tag| tag.splitn(2, ':').map(String::from).collect())
+                .collect()
+        }),
         projects: Some(validated_token.projects.clone()),
-        name_prefix: query_filters.name_prefix.clone(),
+        name_prefix: name_prefix.clone(),
         environment: validated_token.environment.clone(),
         inline_segment_constraints: Some(false),
     };
 
-    let filter_set = if let Some(name_prefix) = query_filters.name_prefix {
+    let filter_set = if let Some(name_prefix) = name_prefix {
         FeatureFilterSet::from(Box::new(name_prefix_filter(name_prefix)))
     } else {
         FeatureFilterSet::default()
@@ -88,30 +248,102 @@ async fn resolve_features(
     .with_filter(project_filter(&validated_token));
 
     let client_features = match req.app_data::<Data<FeatureRefresher>>() {
-        Some(refresher) => {
-            refresher
-                .features_for_filter(validated_token.clone(), &filter_set)
-                .await
-        }
+        Some(refresher) => refresher
+            .features_for_filter(validated_token.clone(), &filter_set)
+            .await
+            .and_then(|(features, cache_status)| {
+                refresher.check_staleness(&validated_token)?;
+                req.extensions_mut().insert(cache_status);
+                Ok(features)
+            }),
         None => features_cache
             .get(&cache_key(&validated_token))
             .map(|client_features| filter_client_features(&client_features, &filter_set))
             .ok_or(EdgeError::ClientCacheError),
-    }?;
+    };
+    let mut client_features = when_not_cached(client_features, &validated_token, &req)?;
+    if !tags.is_empty() {
+        if let Some(refresher) = req.app_data::<Data<FeatureRefresher>>() {
+            let tagged = refresher
+                .tagged_feature_names(&validated_token, &tags)
+                .await?;
+            client_features
+                .features
+                .retain(|feature| tagged.contains(&feature.name));
+        }
+    }
+    if let Some(overrides) = req.app_data::<Data<FeatureOverrides>>() {
+        overrides.apply_to_features(&cache_key(&validated_token), &mut client_features);
+    }
+    if filter_query.enabled_only == Some(true) {
+        retain_enabled(&mut client_features);
+    }
 
     Ok(Json(ClientFeatures {
         query: Some(query),
         ..client_features
     }))
 }
+
+/// The `tag` query parameters, each on the form `type:value`. Repeating the parameter asks for the features carrying any
+/// of the tags, like upstream does
+fn tag_filters(req: &HttpRequest) -> EdgeResult<Vec<String>> {
+    let mut tags: Vec<String> = Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(Query::into_inner)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(key, _)| key == "tag")
+        .map(|(_, tag)| tag)
+        .collect();
+    if let Some(invalid) = tags.iter().find(|tag| {
+        !matches!(tag.split_once(':'), Some((kind, value)) if !kind.is_empty() && !value.is_empty())
+    }) {
+        return Err(EdgeError::InvalidTagFilter(invalid.clone()));
+    }
+    tags.sort();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Edge having no features for the token's environment, not even after asking upstream, is answered as
+/// --empty-cache-behavior says. Any other error is passed on as it is
+fn when_not_cached(
+    client_features: EdgeResult<ClientFeatures>,
+    token: &EdgeToken,
+    req: &HttpRequest,
+) -> EdgeResult<ClientFeatures> {
+    match client_features {
+        Err(EdgeError::ClientCacheError | EdgeError::ClientHydrationFailed(_)) => {
+            match req
+                .app_data::<Data<EmptyCacheBehavior>>()
+                .map(|behavior| *behavior.get_ref())
+                .unwrap_or_default()
+            {
+                EmptyCacheBehavior::EmptyDocument => Ok(ClientFeatures {
+                    version: 2,
+                    features: vec![],
+                    segments: None,
+                    query: None,
+                }),
+                EmptyCacheBehavior::Unavailable => Err(EdgeError::NoFeaturesForEnvironment(
+                    cache_key(token),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                )),
+            }
+        }
+        client_features => client_features,
+    }
+}
+
 #[utoipa::path(
     context_path = "/api/client",
     params(("feature_name" = String, Path,)),
     responses(
         (status = 200, description = "Return feature toggles for this token", body = ClientFeature),
-        (status = 403, description = "Was not allowed to access feature"),
-        (status = 400, description = "Invalid parameters used"),
-        (status = 404, description = "Feature did not exist or token used was not allowed to access it")
+        (status = 403, description = "Was not allowed to access feature", body = crate::error::ErrorResponse),
+        (status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse),
+        (status = 404, description = "Feature did not exist or token used was not allowed to access it", body = crate::error::ErrorResponse),
+        (status = 503, description = "Features have not been refreshed from upstream for longer than the max staleness, or none are cached for the environment with --empty-cache-behavior unavailable", body = crate::error::ErrorResponse)
     ),
     security(
         ("Authorization" = [])
@@ -133,27 +365,37 @@ pub async fn get_feature(
     let filter_set = FeatureFilterSet::from(Box::new(name_match_filter(feature_name.clone())))
         .with_filter(project_filter(&validated_token));
 
-    match req.app_data::<Data<FeatureRefresher>>() {
-        Some(refresher) => {
-            refresher
-                .features_for_filter(validated_token.clone(), &filter_set)
-                .await
-        }
+    let client_features = match req.app_data::<Data<FeatureRefresher>>() {
+        Some(refresher) => refresher
+            .features_for_filter(validated_token.clone(), &filter_set)
+            .await
+            .and_then(|(features, cache_status)| {
+                refresher.check_staleness(&validated_token)?;
+                req.extensions_mut().insert(cache_status);
+                Ok(features)
+            }),
         None => features_cache
             .get(&cache_key(&validated_token))
             .map(|client_features| filter_client_features(&client_features, &filter_set))
             .ok_or(EdgeError::ClientCacheError),
+    };
+    let mut client_features = when_not_cached(client_features, &validated_token, &req)?;
+    if let Some(overrides) = req.app_data::<Data<FeatureOverrides>>() {
+        overrides.apply_to_features(&cache_key(&validated_token), &mut client_features);
     }
-    .map(|client_features| client_features.features.into_iter().next())?
-    .ok_or(EdgeError::FeatureNotFound(feature_name.into_inner()))
-    .map(Json)
+    client_features
+        .features
+        .into_iter()
+        .next()
+        .ok_or(EdgeError::FeatureNotFound(feature_name.into_inner()))
+        .map(Json)
 }
 
 #[utoipa::path(
     context_path = "/api/client",
     responses(
         (status = 202, description = "Accepted client application registration"),
-        (status = 403, description = "Was not allowed to register client application"),
+        (status = 403, description = "Was not allowed to register client application", body = crate::error::ErrorResponse),
     ),
     request_body = ClientApplication,
     security(
@@ -164,13 +406,14 @@ pub async fn get_feature(
 pub async fn register(
     edge_token: EdgeToken,
     connect_via: Data<ConnectVia>,
+    incoming_connect_via: IncomingConnectVia,
     client_application: Json<ClientApplication>,
     metrics_cache: Data<MetricsCache>,
 ) -> EdgeResult<HttpResponse> {
     crate::metrics::client_metrics::register_client_application(
         edge_token,
         &connect_via,
-        client_application.into_inner(),
+        incoming_connect_via.seed(client_application.into_inner()),
         metrics_cache,
     );
     Ok(HttpResponse::Accepted()
@@ -182,7 +425,7 @@ pub async fn register(
     context_path = "/api/client",
     responses(
         (status = 202, description = "Accepted client metrics"),
-        (status = 403, description = "Was not allowed to post metrics"),
+        (status = 403, description = "Was not allowed to post metrics", body = crate::error::ErrorResponse),
     ),
     request_body = ClientMetrics,
     security(
@@ -207,7 +450,7 @@ pub async fn metrics(
 context_path = "/api/client",
 responses(
 (status = 202, description = "Accepted bulk metrics"),
-(status = 403, description = "Was not allowed to post bulk metrics")
+(status = 403, description = "Was not allowed to post bulk metrics", body = crate::error::ErrorResponse)
 ),
 request_body = BatchMetricsRequestBody,
 security(
@@ -219,27 +462,45 @@ pub async fn post_bulk_metrics(
     edge_token: EdgeToken,
     bulk_metrics: Json<BatchMetricsRequestBody>,
     connect_via: Data<ConnectVia>,
+    incoming_connect_via: IncomingConnectVia,
     metrics_cache: Data<MetricsCache>,
 ) -> EdgeResult<HttpResponse> {
+    let bulk_metrics = bulk_metrics.into_inner();
     crate::metrics::client_metrics::register_bulk_metrics(
         metrics_cache.get_ref(),
         connect_via.get_ref(),
         &edge_token,
-        bulk_metrics.into_inner(),
+        BatchMetricsRequestBody {
+            applications: bulk_metrics
+                .applications
+                .into_iter()
+                .map(|application| incoming_connect_via.seed(application))
+                .collect(),
+            ..bulk_metrics
+        },
     );
     Ok(HttpResponse::Accepted().finish())
 }
-pub fn configure_client_api(cfg: &mut web::ServiceConfig) {
+/// Only the metrics and registrations SDKs post are limited to `max_metrics_body_bytes`
+pub fn configure_client_api(cfg: &mut web::ServiceConfig, max_metrics_body_bytes: usize) {
     cfg.service(
         web::scope("/client")
+            .wrap(crate::middleware::as_async_middleware::as_async_middleware(
+                crate::middleware::rate_limit::rate_limit,
+            ))
             .wrap(crate::middleware::as_async_middleware::as_async_middleware(
                 crate::middleware::validate_token::validate_token,
             ))
             .service(get_features)
             .service(get_feature)
-            .service(register)
-            .service(metrics)
-            .service(post_bulk_metrics),
+            // An empty prefix matches every path, so this has to come after the other services
+            .service(
+                web::scope("")
+                    .app_data(web::JsonConfig::default().limit(max_metrics_body_bytes))
+                    .service(register)
+                    .service(metrics)
+                    .service(post_bulk_metrics),
+            ),
     );
 }
 
@@ -256,7 +517,7 @@ pub fn configure_experimental_post_features(
 mod tests {
 
     use crate::metrics::client_metrics::{ApplicationKey, MetricsBatch, MetricsKey};
-    use crate::types::{TokenType, TokenValidationStatus};
+    use crate::types::{TokenRefresh, TokenType, TokenValidationStatus};
     use std::collections::HashMap;
     use std::path::PathBuf;
     use std::str::FromStr;
@@ -266,12 +527,13 @@ mod tests {
 
     use crate::auth::token_validator::TokenValidator;
     use crate::cli::{OfflineArgs, TokenHeader};
+    use crate::http::feature_refresher::FeatureDelta;
     use crate::http::unleash_client::UnleashClient;
     use crate::middleware;
     use crate::tests::{features_from_disk, upstream_server};
     use actix_http::{Request, StatusCode};
     use actix_web::{
-        http::header::ContentType,
+        http::header::{self, ContentType, HeaderValue},
         test,
         web::{self, Data},
         App, ResponseError,
@@ -280,7 +542,7 @@ mod tests {
     use maplit::hashmap;
     use ulid::Ulid;
     use unleash_types::client_features::{
-        ClientFeature, Constraint, Operator, Strategy, StrategyVariant,
+        ClientFeature, Constraint, FeatureDependency, Operator, Strategy, StrategyVariant,
     };
     use unleash_types::client_metrics::{
         ClientMetricsEnv, ConnectViaBuilder, MetricBucket, MetricsMetadata, ToggleStats,
@@ -382,6 +644,52 @@ mod tests {
             .to_request()
     }
 
+    #[actix_web::test]
+    async fn metrics_bodies_over_the_json_limit_are_refused_with_payload_too_large() {
+        let metrics_cache = Arc::new(MetricsCache::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut token = EdgeToken::from_str(
+            "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+        )
+        .unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token);
+        let app = |limit: usize| {
+            test::init_service(
+                App::new()
+                    .app_data(Data::new(ConnectVia {
+                        app_name: "test".into(),
+                        instance_id: Ulid::new().to_string(),
+                    }))
+                    .app_data(Data::from(metrics_cache.clone()))
+                    .app_data(Data::from(token_cache.clone()))
+                    .service(
+                        web::scope("/api")
+                            .configure(|cfg| configure_client_api(cfg, limit))
+                            .route(
+                                "/echo",
+                                web::post().to(|body: Json<serde_json::Value>| async move { body }),
+                            ),
+                    ),
+            )
+        };
+
+        let limited = app(64).await;
+        let res = test::call_service(&limited, make_metrics_post_request().await).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(metrics_cache.metrics.is_empty());
+        let mut other_body = make_metrics_post_request().await;
+        other_body.head_mut().uri = "/api/echo".parse().unwrap();
+        let res = test::call_service(&limited, other_body).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let default_limit = app(DEFAULT_MAX_METRICS_BODY_BYTES).await;
+        let res = test::call_service(&default_limit, make_metrics_post_request().await).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert!(!metrics_cache.metrics.is_empty());
+    }
+
     #[actix_web::test]
     async fn metrics_endpoint_correctly_aggregates_data() {
         let metrics_cache = Arc::new(MetricsCache::default());
@@ -616,6 +924,115 @@ mod tests {
         assert_eq!(saved_app.connect_via, Some(vec![our_app]));
     }
 
+    #[tokio::test]
+    async fn registrations_through_another_edge_carry_both_hops_upstream() {
+        let metrics_cache = Arc::new(MetricsCache::default());
+        let our_app = ConnectVia {
+            app_name: "unleash-edge".into(),
+            instance_id: "upstream-edge".into(),
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(our_app.clone()))
+                .app_data(Data::from(metrics_cache.clone()))
+                .service(web::scope("/api/client").service(register)),
+        )
+        .await;
+        let mut client_app = ClientApplication::new("test_application", 15);
+        client_app.instance_id = Some("test_instance".into());
+        let req = test::TestRequest::post()
+            .uri("/api/client/register")
+            .insert_header(ContentType::json())
+            .insert_header((
+                "Authorization",
+                "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+            ))
+            .insert_header((
+                crate::metrics::client_metrics::CONNECT_VIA_HEADER,
+                "unleash-edge;downstream-edge",
+            ))
+            .set_json(Json(client_app))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), actix_http::StatusCode::ACCEPTED);
+
+        let batch = metrics_cache.get_appropriately_sized_batches().remove(0);
+        assert_eq!(
+            batch.applications[0].connect_via,
+            Some(vec![
+                ConnectVia {
+                    app_name: "unleash-edge".into(),
+                    instance_id: "downstream-edge".into(),
+                },
+                our_app
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn disabled_metrics_are_accepted_and_dropped() {
+        let metrics_cache = Arc::new(MetricsCache::disabled());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(ConnectVia {
+                    app_name: "test".into(),
+                    instance_id: Ulid::new().to_string(),
+                }))
+                .app_data(Data::from(metrics_cache.clone()))
+                .service(web::scope("/api/client").service(metrics).service(register)),
+        )
+        .await;
+
+        let res = test::call_service(&app, make_metrics_post_request().await).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let mut client_app = ClientApplication::new("test_application", 15);
+        client_app.instance_id = Some("test_instance".into());
+        let res = test::call_service(&app, make_register_post_request(client_app).await).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+        assert!(metrics_cache.metrics.is_empty());
+        assert!(metrics_cache.applications.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn metrics_without_an_environment_get_the_environment_of_the_token() {
+        let metrics_cache = Arc::new(MetricsCache::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(metrics_cache.clone()))
+                .service(web::scope("/api/client").service(metrics)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/api/client/metrics")
+            .insert_header(ContentType::json())
+            .insert_header(("Authorization", "*:production.somesecret"))
+            .set_payload(
+                serde_json::json!({
+                    "appName": "some-app",
+                    "instanceId": "some-instance",
+                    "bucket": {
+                        "start": "1867-11-07T12:00:00Z",
+                        "stop": "1934-11-07T12:00:00Z",
+                        "toggles": {
+                            "some-feature": { "yes": 1, "no": 0, "variants": {} }
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+        let environments: Vec<String> = metrics_cache
+            .metrics
+            .iter()
+            .map(|metric| metric.key().environment.clone())
+            .collect();
+        assert_eq!(environments, vec!["production".to_string()]);
+    }
+
     #[tokio::test]
     async fn bulk_metrics_endpoint_correctly_accepts_data() {
         let metrics_cache = MetricsCache::default();
@@ -758,6 +1175,108 @@ mod tests {
         assert_eq!(res.features.len(), example_features.features.len());
     }
 
+    #[tokio::test]
+    async fn environments_without_cached_features_get_an_empty_document_or_503_as_configured() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut token = EdgeToken::try_from("*:development.nothingcachedyet".to_string()).unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token.clone());
+        let app_with = |behavior: Option<EmptyCacheBehavior>| {
+            let app = App::new()
+                .app_data(Data::from(Arc::new(
+                    DashMap::<String, ClientFeatures>::default(),
+                )))
+                .app_data(Data::from(token_cache.clone()));
+            let app = match behavior {
+                Some(behavior) => app.app_data(Data::new(behavior)),
+                None => app,
+            };
+            test::init_service(
+                app.service(
+                    web::scope("/api/client")
+                        .service(get_features)
+                        .service(get_feature),
+                ),
+            )
+        };
+
+        let app = app_with(None).await;
+        let req = make_features_request_with_token(token.clone()).await;
+        let res: ClientFeatures = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.version, 2);
+        assert!(res.features.is_empty());
+        let req = test::TestRequest::get()
+            .uri("/api/client/features/some-feature")
+            .insert_header(("Authorization", token.token.clone()))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let app = app_with(Some(EmptyCacheBehavior::Unavailable)).await;
+        let req = make_features_request_with_token(token.clone()).await;
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let req = test::TestRequest::get()
+            .uri("/api/client/features/some-feature")
+            .insert_header(("Authorization", token.token.clone()))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn client_features_older_than_the_max_staleness_are_refused() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let feature_refresher = Arc::new(
+            FeatureRefresher {
+                features_cache: features_cache.clone(),
+                ..Default::default()
+            }
+            .with_max_staleness(Some(Duration::seconds(60))),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .app_data(Data::from(feature_refresher.clone()))
+                .service(web::scope("/api/client").service(get_features)),
+        )
+        .await;
+        features_cache.insert(
+            "development".into(),
+            features_from_disk("../examples/features.json"),
+        );
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        token_cache.insert(token.token.clone(), token.clone());
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+
+        feature_refresher
+            .last_successful_refresh
+            .insert("development".into(), Utc::now() - Duration::seconds(120));
+        let res =
+            test::call_service(&app, make_features_request_with_token(token.clone()).await).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            res.headers().get(crate::error::EDGE_STALE_HEADER).unwrap(),
+            "true"
+        );
+
+        feature_refresher
+            .last_successful_refresh
+            .insert("development".into(), Utc::now());
+        let res =
+            test::call_service(&app, make_features_request_with_token(token.clone()).await).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
     #[tokio::test]
     async fn post_request_to_client_features_does_the_same_as_get_when_mounted() {
         let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
@@ -865,6 +1384,73 @@ mod tests {
             .all(|f| token.projects.contains(&f.project.clone().unwrap())));
     }
 
+    #[tokio::test]
+    async fn client_features_endpoint_does_not_filter_for_wildcard_tokens() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .service(web::scope("/api/client").service(get_features)),
+        )
+        .await;
+        let mut token =
+            EdgeToken::try_from("*:production.puff_the_magic_dragon".to_string()).unwrap();
+        token.status = TokenValidationStatus::Validated;
+        token.token_type = Some(TokenType::Client);
+        token_cache.insert(token.token.clone(), token.clone());
+        let example_features = features_from_disk("../examples/hostedexample.json");
+        features_cache.insert("production".into(), example_features.clone());
+        let req = make_features_request_with_token(token.clone()).await;
+        let res: ClientFeatures = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.features.len(), example_features.features.len());
+    }
+
+    #[tokio::test]
+    async fn feature_dependencies_are_served_as_upstream_sent_them() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .service(web::scope("/api/client").service(get_features)),
+        )
+        .await;
+        let token = EdgeToken::validated_client_token("*:production.secret123");
+        token_cache.insert(token.token.clone(), token.clone());
+        let dependencies = serde_json::json!([
+            { "feature": "parent", "enabled": true, "variants": ["blue"] },
+            { "feature": "switched-off", "enabled": false }
+        ]);
+        let features: ClientFeatures = serde_json::from_value(serde_json::json!({
+            "version": 2,
+            "features": [
+                { "name": "parent", "project": "default", "enabled": true },
+                { "name": "switched-off", "project": "default", "enabled": false },
+                {
+                    "name": "child",
+                    "project": "default",
+                    "enabled": true,
+                    "dependencies": dependencies
+                }
+            ]
+        }))
+        .unwrap();
+        features_cache.insert("production".into(), features);
+
+        let req = make_features_request_with_token(token).await;
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let child = res["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|feature| feature["name"] == "child")
+            .unwrap();
+        assert_eq!(child["dependencies"], dependencies);
+    }
+
     #[tokio::test]
     async fn client_features_endpoint_filters_correctly_when_token_has_access_to_multiple_projects()
     {
@@ -936,52 +1522,109 @@ mod tests {
         let token = EdgeToken::offline_token("secret-123");
         token_cache.insert(token.token.clone(), token.clone());
         let example_features = features_from_disk("../examples/features.json");
-        features_cache.insert(token.token.clone(), example_features.clone());
+        features_cache.insert(cache_key(&token), example_features.clone());
         let req = make_features_request_with_token(token.clone()).await;
         let res: ClientFeatures = test::call_and_read_body_json(&app, req).await;
         assert_eq!(res.features.len(), example_features.features.len());
     }
 
     #[tokio::test]
-    async fn calling_client_features_endpoint_with_new_token_hydrates_from_upstream_when_dynamic() {
-        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
-            Arc::new(DashMap::default());
-        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
-        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
-        let server = upstream_server(
-            upstream_token_cache.clone(),
-            upstream_features_cache.clone(),
-            upstream_engine_cache.clone(),
-        )
-        .await;
-        let upstream_features = features_from_disk("../examples/hostedexample.json");
-        let mut upstream_known_token = EdgeToken::from_str("dx:development.secret123").unwrap();
-        upstream_known_token.status = TokenValidationStatus::Validated;
-        upstream_known_token.token_type = Some(TokenType::Client);
-        upstream_token_cache.insert(
-            upstream_known_token.token.clone(),
-            upstream_known_token.clone(),
-        );
-        upstream_features_cache.insert(cache_key(&upstream_known_token), upstream_features.clone());
-        let unleash_client = Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap());
+    async fn offline_mode_without_tokens_accepts_any_token() {
         let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
         let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
         let engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
-        let feature_refresher = Arc::new(FeatureRefresher {
-            unleash_client: unleash_client.clone(),
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(engine_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .app_data(Data::new(crate::cli::EdgeMode::Offline(OfflineArgs {
+                    bootstrap_file: Some(PathBuf::from("../examples/features.json")),
+                    tokens: vec![],
+                    reload_interval: 0,
+                })))
+                .wrap(middleware::as_async_middleware::as_async_middleware(
+                    middleware::validate_token::validate_token,
+                ))
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
+        )
+        .await;
+        let example_features = features_from_disk("../examples/features.json");
+        for token in ["*:development.anything", "whatever-i-like"] {
+            let request = test::TestRequest::get()
+                .uri("/api/client/features")
+                .insert_header(ContentType::json())
+                .insert_header(("Authorization", token))
+                .to_request();
+            let res: ClientFeatures = test::call_and_read_body_json(&app, request).await;
+            assert_eq!(res.features.len(), example_features.features.len());
+        }
+        assert_eq!(token_cache.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn calling_client_features_endpoint_with_new_token_hydrates_from_upstream_when_dynamic() {
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let server = upstream_server(
+            upstream_token_cache.clone(),
+            upstream_features_cache.clone(),
+            upstream_engine_cache.clone(),
+        )
+        .await;
+        let upstream_features = features_from_disk("../examples/hostedexample.json");
+        let mut upstream_known_token = EdgeToken::from_str("dx:development.secret123").unwrap();
+        upstream_known_token.status = TokenValidationStatus::Validated;
+        upstream_known_token.token_type = Some(TokenType::Client);
+        upstream_token_cache.insert(
+            upstream_known_token.token.clone(),
+            upstream_known_token.clone(),
+        );
+        upstream_features_cache.insert(cache_key(&upstream_known_token), upstream_features.clone());
+        let unleash_client = Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap());
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let feature_refresher = Arc::new(FeatureRefresher {
+            unleash_client: unleash_client.clone(),
             tokens_to_refresh: Arc::new(Default::default()),
             features_cache: features_cache.clone(),
             engine_cache: engine_cache.clone(),
-            refresh_interval: Duration::seconds(6000),
+            refresh_interval: Duration::seconds(6000).into(),
+            max_refresh_backoff: Duration::seconds(6000).into(),
             persistence: None,
             strict: false,
             app_name: "test-app".into(),
-        });
-        let token_validator = Arc::new(TokenValidator {
-            unleash_client: unleash_client.clone(),
+            broadcaster: Default::default(),
+            last_successful_refresh: Default::default(),
             token_cache: token_cache.clone(),
-            persistence: None,
+            startup_jitter: Duration::zero(),
+            refreshes_in_flight: Default::default(),
+            token_idle_ttl: None,
+            token_last_access: Default::default(),
+            change_webhook: None,
+            fetches_in_flight: Default::default(),
+            use_delta_api: false,
+            delta_api_unsupported: Default::default(),
+            max_staleness: None,
+            started_at: Utc::now(),
+            refresh_shard: None,
+            persisted_features: Default::default(),
+            default_environment: None,
+            allowed_environments: vec![],
+            environment_refresh_intervals: Default::default(),
+            denied_tokens: Default::default(),
+            tagged_features: Default::default(),
         });
+        let token_validator = Arc::new(TokenValidator::new(
+            unleash_client.clone(),
+            token_cache.clone(),
+        ));
         let local_app = test::init_service(
             App::new()
                 .app_data(Data::from(token_validator.clone()))
@@ -992,7 +1635,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let req = test::TestRequest::get()
@@ -1033,14 +1679,13 @@ mod tests {
             unleash_client: unleash_client.clone(),
             features_cache: features_cache.clone(),
             engine_cache: engine_cache.clone(),
-            refresh_interval: Duration::seconds(6000),
+            refresh_interval: Duration::seconds(6000).into(),
             ..Default::default()
         });
-        let token_validator = Arc::new(TokenValidator {
-            unleash_client: unleash_client.clone(),
-            token_cache: token_cache.clone(),
-            persistence: None,
-        });
+        let token_validator = Arc::new(TokenValidator::new(
+            unleash_client.clone(),
+            token_cache.clone(),
+        ));
         let local_app = test::init_service(
             App::new()
                 .app_data(Data::from(token_validator.clone()))
@@ -1051,7 +1696,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let req = test::TestRequest::get()
@@ -1082,7 +1730,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let desired_toggle = "projectStatusApi";
@@ -1114,7 +1765,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let desired_toggle = "serviceAccounts";
@@ -1157,15 +1811,14 @@ mod tests {
             unleash_client: unleash_client.clone(),
             features_cache: features_cache.clone(),
             engine_cache: engine_cache.clone(),
-            refresh_interval: Duration::seconds(6000),
+            refresh_interval: Duration::seconds(6000).into(),
             strict: false,
             ..Default::default()
         });
-        let token_validator = Arc::new(TokenValidator {
-            unleash_client: unleash_client.clone(),
-            token_cache: token_cache.clone(),
-            persistence: None,
-        });
+        let token_validator = Arc::new(TokenValidator::new(
+            unleash_client.clone(),
+            token_cache.clone(),
+        ));
         let local_app = test::init_service(
             App::new()
                 .app_data(Data::from(token_validator.clone()))
@@ -1176,7 +1829,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let dx_req = test::TestRequest::get()
@@ -1216,7 +1872,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let request = test::TestRequest::get()
@@ -1229,6 +1888,212 @@ mod tests {
         assert_eq!(result.query.unwrap().name_prefix.unwrap(), "embed");
     }
 
+    #[tokio::test]
+    pub async fn client_features_can_be_asked_for_evaluated_with_format_or_accept() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let features = features_from_disk("../examples/hostedexample.json");
+        let mut dx_token = EdgeToken::from_str("dx:development.secret123").unwrap();
+        dx_token.status = TokenValidationStatus::Validated;
+        dx_token.token_type = Some(TokenType::Client);
+        token_cache.insert(dx_token.token.clone(), dx_token.clone());
+        features_cache.insert(cache_key(&dx_token), features.clone());
+        let mut engine = EngineState::default();
+        engine.take_state(features.clone());
+        engine_cache.insert(cache_key(&dx_token), engine);
+        let local_app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(engine_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .wrap(middleware::as_async_middleware::as_async_middleware(
+                    middleware::validate_token::validate_token,
+                ))
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
+        )
+        .await;
+        let features_for = |uri: &str, accept: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Accept", accept))
+                .insert_header(("Authorization", dx_token.token.clone()))
+                .to_request()
+        };
+
+        let by_format = test::call_service(
+            &local_app,
+            features_for(
+                "/api/client/features?format=evaluated&namePrefix=embed&userId=7",
+                "application/json",
+            ),
+        )
+        .await;
+        assert_eq!(by_format.headers().get("Vary").unwrap(), "Accept");
+        let by_format: FrontendResult = test::read_body_json(by_format).await;
+        assert_eq!(by_format.toggles.len(), 2);
+        assert!(by_format
+            .toggles
+            .iter()
+            .all(|toggle| toggle.name.starts_with("embed")));
+
+        let raw: ClientFeatures = test::call_and_read_body_json(
+            &local_app,
+            features_for(
+                "/api/client/features",
+                "application/vnd.unleash.evaluated+json;q=0.5, application/vnd.unleash.raw+json",
+            ),
+        )
+        .await;
+        let by_accept: FrontendResult = test::call_and_read_body_json(
+            &local_app,
+            features_for(
+                "/api/client/features",
+                "application/vnd.unleash.evaluated+json, application/json;q=0.5",
+            ),
+        )
+        .await;
+        assert_eq!(by_accept.toggles.len(), raw.features.len());
+
+        let unknown = test::call_service(
+            &local_app,
+            features_for("/api/client/features?format=yaml", "application/json"),
+        )
+        .await;
+        assert_eq!(unknown.status(), StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[tokio::test]
+    pub async fn name_prefix_filter_is_case_sensitive_and_ignored_when_empty() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let features = features_from_disk("../examples/hostedexample.json");
+        let mut dx_token = EdgeToken::from_str("dx:development.secret123").unwrap();
+        dx_token.status = TokenValidationStatus::Validated;
+        dx_token.token_type = Some(TokenType::Client);
+        token_cache.insert(dx_token.token.clone(), dx_token.clone());
+        features_cache.insert(cache_key(&dx_token), features.clone());
+        let local_app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .wrap(middleware::as_async_middleware::as_async_middleware(
+                    middleware::validate_token::validate_token,
+                ))
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
+        )
+        .await;
+        let features_for = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(ContentType::json())
+                .insert_header(("Authorization", dx_token.token.clone()))
+                .to_request()
+        };
+        let all: ClientFeatures =
+            test::call_and_read_body_json(&local_app, features_for("/api/client/features")).await;
+        let upper_case: ClientFeatures = test::call_and_read_body_json(
+            &local_app,
+            features_for("/api/client/features?namePrefix=EMBED"),
+        )
+        .await;
+        assert!(upper_case.features.is_empty());
+        let empty_prefix: ClientFeatures = test::call_and_read_body_json(
+            &local_app,
+            features_for("/api/client/features?namePrefix="),
+        )
+        .await;
+        assert_eq!(empty_prefix.features.len(), all.features.len());
+        assert!(empty_prefix.query.unwrap().name_prefix.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn enabled_only_leaves_out_disabled_features_but_keeps_the_parents_of_enabled_ones() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut dx_token = EdgeToken::from_str("dx:development.secret123").unwrap();
+        dx_token.status = TokenValidationStatus::Validated;
+        dx_token.token_type = Some(TokenType::Client);
+        token_cache.insert(dx_token.token.clone(), dx_token.clone());
+        let feature = |name: &str, enabled: bool, parent: Option<&str>| ClientFeature {
+            name: name.into(),
+            enabled,
+            project: Some("dx".into()),
+            dependencies: parent.map(|parent| {
+                vec![FeatureDependency {
+                    feature: parent.into(),
+                    enabled: Some(false),
+                    variants: None,
+                }]
+            }),
+            ..ClientFeature::default()
+        };
+        features_cache.insert(
+            cache_key(&dx_token),
+            ClientFeatures {
+                version: 2,
+                features: vec![
+                    feature("on", true, None),
+                    feature("off", false, None),
+                    feature("parent", false, None),
+                    feature("child", true, Some("parent")),
+                ],
+                segments: None,
+                query: None,
+            },
+        );
+        let local_app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .wrap(middleware::as_async_middleware::as_async_middleware(
+                    middleware::validate_token::validate_token,
+                ))
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
+        )
+        .await;
+        let names_for = |uri: &str| {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(ContentType::json())
+                .insert_header(("Authorization", dx_token.token.clone()))
+                .to_request();
+            let local_app = &local_app;
+            async move {
+                let features: ClientFeatures =
+                    test::call_and_read_body_json(local_app, request).await;
+                assert_eq!(features.version, 2);
+                features
+                    .features
+                    .into_iter()
+                    .map(|feature| feature.name)
+                    .collect::<Vec<String>>()
+            }
+        };
+
+        assert_eq!(
+            names_for("/api/client/features?enabledOnly=true").await,
+            vec!["on", "parent", "child"]
+        );
+        assert_eq!(
+            names_for("/api/client/features?enabledOnly=false").await,
+            vec!["on", "off", "parent", "child"]
+        );
+        assert_eq!(
+            names_for("/api/client/features").await,
+            vec!["on", "off", "parent", "child"]
+        );
+    }
+
     #[tokio::test]
     pub async fn only_gets_correct_feature_by_name() {
         let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
@@ -1286,7 +2151,10 @@ mod tests {
                 .wrap(middleware::as_async_middleware::as_async_middleware(
                     middleware::validate_token::validate_token,
                 ))
-                .service(web::scope("/api").configure(configure_client_api)),
+                .service(
+                    web::scope("/api")
+                        .configure(|cfg| configure_client_api(cfg, DEFAULT_MAX_METRICS_BODY_BYTES)),
+                ),
         )
         .await;
         let successful_request = test::TestRequest::get()
@@ -1346,4 +2214,374 @@ mod tests {
         let res = test::call_service(&app, request).await;
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
     }
+
+    #[tokio::test]
+    async fn client_features_are_compressed_as_negotiated_with_etag_over_uncompressed_body() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .service(
+                    web::scope("/api/client")
+                        .wrap(actix_middleware_etag::Etag)
+                        .wrap(actix_web::middleware::Compress::default())
+                        .service(get_features),
+                ),
+        )
+        .await;
+        features_cache.insert(
+            "production".into(),
+            features_from_disk("../examples/features.json"),
+        );
+        let mut token = EdgeToken::try_from(
+            "*:production.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7".to_string(),
+        )
+        .unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token.clone());
+        let request = |encoding: &str| {
+            test::TestRequest::get()
+                .uri("/api/client/features")
+                .insert_header(("Authorization", token.token.clone()))
+                .insert_header((header::ACCEPT_ENCODING, encoding))
+                .to_request()
+        };
+
+        let identity = test::call_service(&app, request("identity")).await;
+        assert_eq!(identity.status(), StatusCode::OK);
+        assert!(identity.headers().get(header::CONTENT_ENCODING).is_none());
+        let etag = identity.headers().get(header::ETAG).unwrap().clone();
+        let uncompressed_size = test::read_body(identity).await.len();
+
+        for encoding in ["gzip", "br"] {
+            let compressed = test::call_service(&app, request(encoding)).await;
+            assert_eq!(compressed.status(), StatusCode::OK);
+            assert_eq!(
+                compressed.headers().get(header::CONTENT_ENCODING).unwrap(),
+                encoding
+            );
+            assert_eq!(compressed.headers().get(header::ETAG).unwrap(), &etag);
+            assert!(test::read_body(compressed).await.len() < uncompressed_size);
+        }
+
+        let revalidate = test::TestRequest::get()
+            .uri("/api/client/features")
+            .insert_header(("Authorization", token.token.clone()))
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_request();
+        let not_modified = test::call_service(&app, revalidate).await;
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn etags_only_match_the_features_the_token_and_filters_get() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .service(
+                    web::scope("/api/client")
+                        .wrap(actix_middleware_etag::Etag)
+                        .wrap(actix_web::middleware::Compress::default())
+                        .service(get_features),
+                ),
+        )
+        .await;
+        features_cache.insert(
+            "development".into(),
+            features_from_disk("../examples/hostedexample.json"),
+        );
+        let mut dx_token = EdgeToken::from_str("dx:development.secret123").unwrap();
+        dx_token.token_type = Some(TokenType::Client);
+        dx_token.status = TokenValidationStatus::Validated;
+        token_cache.insert(dx_token.token.clone(), dx_token.clone());
+        let mut eg_token = EdgeToken::from_str("eg:development.secret123").unwrap();
+        eg_token.token_type = Some(TokenType::Client);
+        eg_token.status = TokenValidationStatus::Validated;
+        token_cache.insert(eg_token.token.clone(), eg_token.clone());
+        let request = |uri: &str, token: &EdgeToken, etag: Option<HeaderValue>| {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", token.token.clone()))
+                .insert_header((header::ACCEPT_ENCODING, "gzip"));
+            match etag {
+                Some(etag) => request.insert_header((header::IF_NONE_MATCH, etag)),
+                None => request,
+            }
+            .to_request()
+        };
+
+        let first =
+            test::call_service(&app, request("/api/client/features", &dx_token, None)).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers().get(header::ETAG).unwrap().clone();
+
+        let unchanged = test::call_service(
+            &app,
+            request("/api/client/features", &dx_token, Some(etag.clone())),
+        )
+        .await;
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert!(test::read_body(unchanged).await.is_empty());
+
+        let other_project = test::call_service(
+            &app,
+            request("/api/client/features", &eg_token, Some(etag.clone())),
+        )
+        .await;
+        assert_eq!(other_project.status(), StatusCode::OK);
+        assert_ne!(other_project.headers().get(header::ETAG).unwrap(), &etag);
+
+        let filtered = test::call_service(
+            &app,
+            request(
+                "/api/client/features?namePrefix=embed",
+                &dx_token,
+                Some(etag.clone()),
+            ),
+        )
+        .await;
+        assert_eq!(filtered.status(), StatusCode::OK);
+        assert_ne!(filtered.headers().get(header::ETAG).unwrap(), &etag);
+    }
+
+    #[tokio::test]
+    async fn long_polls_recognize_the_etag_the_middleware_sent() {
+        let features = cached_client_features();
+        let served_features = features.clone();
+        let app = test::init_service(App::new().wrap(actix_middleware_etag::Etag).route(
+            "/",
+            web::get().to(move || {
+                let features = served_features.clone();
+                async move { Json(features) }
+            }),
+        ))
+        .await;
+        let served = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        let etag = served.headers().get(header::ETAG).unwrap().clone();
+
+        let sent_back = test::TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, etag))
+            .to_http_request();
+        assert!(client_has(&sent_back, &features).await);
+
+        let stale = test::TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, "W/\"stale\""))
+            .to_http_request();
+        assert!(!client_has(&stale, &features).await);
+        assert!(!client_has(&test::TestRequest::get().to_http_request(), &features).await);
+    }
+
+    #[tokio::test]
+    async fn long_polls_return_early_when_the_features_of_the_environment_change() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let broadcaster = Broadcaster::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .app_data(Data::new(broadcaster.clone()))
+                .service(
+                    web::scope("/api/client")
+                        .wrap(actix_middleware_etag::Etag)
+                        .service(get_features),
+                ),
+        )
+        .await;
+        features_cache.insert("production".into(), cached_client_features());
+        let mut token = EdgeToken::try_from(
+            "*:production.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7".to_string(),
+        )
+        .unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token.clone());
+        let request = |wait: u64, etag: HeaderValue| {
+            test::TestRequest::get()
+                .uri(&format!("/api/client/features?wait={wait}"))
+                .insert_header(("Authorization", token.token.clone()))
+                .insert_header((header::IF_NONE_MATCH, etag))
+                .to_request()
+        };
+        let first =
+            test::call_service(&app, make_features_request_with_token(token.clone()).await).await;
+        let etag = first.headers().get(header::ETAG).unwrap().clone();
+
+        let started = std::time::Instant::now();
+        let stale =
+            test::call_service(&app, request(30, HeaderValue::from_static("W/\"stale\""))).await;
+        assert_eq!(stale.status(), StatusCode::OK);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        let started = std::time::Instant::now();
+        let unchanged = test::call_service(&app, request(1, etag.clone())).await;
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        assert!(started.elapsed() >= std::time::Duration::from_secs(1));
+
+        let updater = {
+            let broadcaster = broadcaster.clone();
+            let features_cache = features_cache.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                broadcaster.notify("development", FeatureDelta::default());
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                features_cache.insert(
+                    "production".into(),
+                    features_from_disk("../examples/features.json"),
+                );
+                broadcaster.notify("production", FeatureDelta::default());
+            })
+        };
+        let started = std::time::Instant::now();
+        let changed = test::call_service(&app, request(30, etag)).await;
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(300));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        let body: ClientFeatures = test::read_body_json(changed).await;
+        assert_eq!(
+            body.features.len(),
+            features_from_disk("../examples/features.json")
+                .features
+                .len()
+        );
+        updater.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tag_filters_serve_only_the_features_upstream_has_tagged() {
+        let upstream_calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = {
+            let upstream_calls = upstream_calls.clone();
+            actix_http_test::test_server(move || {
+                let upstream_calls = upstream_calls.clone();
+                actix_http::HttpService::new(actix_service::map_config(
+                    App::new().route(
+                        "/api/client/features",
+                        web::get().to(move |req: HttpRequest| {
+                            upstream_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            let tags =
+                                Query::<Vec<(String, String)>>::from_query(req.query_string())
+                                    .unwrap()
+                                    .into_inner();
+                            let mut features = cached_client_features();
+                            if tags.contains(&("tag".into(), "simple:beta".into())) {
+                                features.features.retain(|f| f.name == "feature_one");
+                            }
+                            async move { Json(features) }
+                        }),
+                    ),
+                    |_| actix_web::dev::AppConfig::default(),
+                ))
+                .tcp()
+            })
+            .await
+        };
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut token = EdgeToken::from_str("*:production.secret123").unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token.clone());
+        features_cache.insert(cache_key(&token), cached_client_features());
+        let feature_refresher = FeatureRefresher {
+            unleash_client: Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap()),
+            features_cache: features_cache.clone(),
+            token_cache: token_cache.clone(),
+            refresh_interval: Duration::seconds(6000).into(),
+            ..Default::default()
+        };
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .app_data(Data::new(feature_refresher))
+                .service(web::scope("/api/client").service(get_features)),
+        )
+        .await;
+        let request = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", token.token.clone()))
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let tagged: ClientFeatures = test::call_and_read_body_json(
+                &app,
+                request("/api/client/features?tag=simple:beta"),
+            )
+            .await;
+            let names: Vec<String> = tagged.features.into_iter().map(|f| f.name).collect();
+            assert_eq!(names, vec!["feature_one".to_string()]);
+            assert_eq!(
+                tagged.query.unwrap().tags,
+                Some(vec![vec!["simple".to_string(), "beta".to_string()]])
+            );
+        }
+        assert_eq!(upstream_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let untagged: ClientFeatures =
+            test::call_and_read_body_json(&app, request("/api/client/features")).await;
+        assert_eq!(
+            untagged.features.len(),
+            cached_client_features().features.len()
+        );
+
+        let invalid = test::call_service(&app, request("/api/client/features?tag=beta")).await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn tag_filters_refuse_features_when_upstream_cannot_say_which_are_tagged() {
+        let features_cache: Arc<DashMap<String, ClientFeatures>> = Arc::new(DashMap::default());
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut token = EdgeToken::from_str("*:production.secret123").unwrap();
+        token.token_type = Some(TokenType::Client);
+        token.status = TokenValidationStatus::Validated;
+        token_cache.insert(token.token.clone(), token.clone());
+        features_cache.insert(cache_key(&token), cached_client_features());
+        let feature_refresher = FeatureRefresher {
+            unleash_client: Arc::new(UnleashClient::new("http://127.0.0.1:1", None).unwrap()),
+            features_cache: features_cache.clone(),
+            token_cache: token_cache.clone(),
+            ..Default::default()
+        };
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(features_cache.clone()))
+                .app_data(Data::from(token_cache.clone()))
+                .app_data(Data::new(feature_refresher))
+                .service(web::scope("/api/client").service(get_features)),
+        )
+        .await;
+        let request = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", token.token.clone()))
+                .to_request()
+        };
+
+        let tagged =
+            test::call_service(&app, request("/api/client/features?tag=simple:beta")).await;
+        assert_eq!(tagged.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(tagged).await;
+        assert_eq!(body["status"], 503);
+        assert!(body["error"].as_str().unwrap().contains("simple:beta"));
+
+        let untagged = test::call_service(&app, request("/api/client/features")).await;
+        assert_eq!(untagged.status(), StatusCode::OK);
+    }
 }
@@ -0,0 +1,111 @@
+use chrono::Duration;
+use tracing::{error, info, warn};
+
+use crate::cli::{CliArgs, EdgeMode};
+use crate::http::feature_refresher::FeatureRefresher;
+use crate::prom_metrics;
+
+/// Applies a freshly parsed configuration to a running Edge, as done on SIGHUP, and records what was applied in `running`.
+/// The log level, feature refresh interval and refresh backoff take effect right away. Everything else stays as Edge was
+/// started with, and changes to the options operators are most likely to expect to be picked up are logged as ignored
+pub fn reload(running: &mut CliArgs, reloaded: &CliArgs, refresher: Option<&FeatureRefresher>) {
+    match prom_metrics::reload_log_level(reloaded.log_level.as_deref()) {
+        Ok(()) => info!(
+            "Log level is now {}",
+            prom_metrics::log_directives_in_use(reloaded.log_level.as_deref())
+        ),
+        Err(e) => error!("Could not change the log level: {e}"),
+    }
+    running.log_level.clone_from(&reloaded.log_level);
+    if let (EdgeMode::Edge(edge_args), Some(refresher)) = (&reloaded.mode, refresher) {
+        let refresh_interval = Duration::seconds(
+            edge_args
+                .features_refresh_interval_seconds
+                .try_into()
+                .unwrap_or(i64::MAX),
+        );
+        let max_refresh_backoff = Duration::seconds(
+            edge_args
+                .features_refresh_max_backoff_seconds
+                .try_into()
+                .unwrap_or(i64::MAX),
+        );
+        refresher.refresh_interval.set(refresh_interval);
+        refresher.max_refresh_backoff.set(max_refresh_backoff);
+        if let EdgeMode::Edge(running_edge_args) = &mut running.mode {
+            running_edge_args.features_refresh_interval_seconds =
+                edge_args.features_refresh_interval_seconds;
+            running_edge_args.features_refresh_max_backoff_seconds =
+                edge_args.features_refresh_max_backoff_seconds;
+        }
+        info!(
+            "Refreshing features every {} seconds, backing off for up to {} seconds",
+            refresh_interval.num_seconds(),
+            max_refresh_backoff.num_seconds()
+        );
+    }
+    if reloaded.http != running.http {
+        warn!("HTTP server options like the listening address and TLS can't change without a restart, ignoring changes to them");
+    }
+    if reloaded.cors != running.cors {
+        warn!("CORS options can't change without a restart, ignoring changes to them");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use tracing_test::traced_test;
+
+    use super::*;
+
+    fn args(top_level: &[&str], edge: &[&str]) -> CliArgs {
+        let mut args = vec!["unleash-edge"];
+        args.extend_from_slice(top_level);
+        args.extend_from_slice(&["edge", "-u", "http://localhost:4242"]);
+        args.extend_from_slice(edge);
+        CliArgs::try_parse_from(args).unwrap()
+    }
+
+    #[test]
+    fn refresh_interval_and_backoff_change_for_every_clone_of_the_refresher() {
+        let refresher = FeatureRefresher::default();
+        let serving_requests = refresher.clone();
+        let mut running = args(&[], &[]);
+        reload(
+            &mut running,
+            &args(
+                &[],
+                &[
+                    "--features-refresh-interval-seconds",
+                    "30",
+                    "--features-refresh-max-backoff-seconds",
+                    "600",
+                ],
+            ),
+            Some(&refresher),
+        );
+        assert_eq!(
+            serving_requests.refresh_interval.get(),
+            Duration::seconds(30)
+        );
+        assert_eq!(
+            serving_requests.max_refresh_backoff.get(),
+            Duration::seconds(600)
+        );
+        let EdgeMode::Edge(edge_args) = running.mode else {
+            panic!("Expected edge mode");
+        };
+        assert_eq!(edge_args.features_refresh_interval_seconds, 30);
+    }
+
+    #[test]
+    #[traced_test]
+    fn changes_to_options_that_need_a_restart_are_logged_as_ignored() {
+        let mut running = args(&[], &[]);
+        reload(&mut running, &args(&["--port", "4000"], &[]), None);
+        assert_eq!(running.http.port, 3063);
+        assert!(logs_contain("HTTP server options"));
+        assert!(!logs_contain("CORS options"));
+    }
+}
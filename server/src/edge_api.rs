@@ -1,57 +1,90 @@
 use actix_web::{
+    error::{InternalError, JsonPayloadError},
     post,
     web::{self, Data, Json},
-    HttpRequest,
+    HttpRequest, ResponseError,
 };
 use dashmap::DashMap;
 use utoipa;
 
+use crate::auth::denied_tokens::DeniedTokens;
 use crate::auth::token_validator::TokenValidator;
+use crate::error::json_error_response;
 use crate::types::{
-    EdgeJsonResult, EdgeToken, TokenStrings, TokenValidationStatus, ValidatedTokens,
+    EdgeJsonResult, EdgeToken, TokenType, TokenValidationStatus, ValidateTokensBody,
+    ValidatedTokens,
 };
 
 #[utoipa::path(
     path = "/edge/validate",
     responses(
-        (status = 200, description = "Return valid tokens from list of tokens passed in to validate", body = ValidatedTokens)
+        (status = 200, description = "Return valid tokens from list of tokens passed in to validate, and the status of the ones that aren't", body = ValidatedTokens),
+        (status = 400, description = "The body wasn't a valid list of tokens", body = crate::error::ErrorResponse)
     ),
-    request_body = TokenStrings
+    request_body = ValidateTokensBody
 )]
 #[post("/validate")]
 pub async fn validate(
     token_cache: web::Data<DashMap<String, EdgeToken>>,
     req: HttpRequest,
-    tokens: Json<TokenStrings>,
+    tokens: Json<ValidateTokensBody>,
 ) -> EdgeJsonResult<ValidatedTokens> {
+    let tokens_to_check = tokens.into_inner().into_tokens();
+    // Denied tokens are reported as invalid without asking upstream about them
+    let tokens_to_look_up = match req.app_data::<Data<DeniedTokens>>() {
+        Some(denied_tokens) => denied_tokens.retain_allowed(tokens_to_check.clone()),
+        None => tokens_to_check.clone(),
+    };
     let maybe_validator = req.app_data::<Data<TokenValidator>>();
-    match maybe_validator {
+    let (valid_tokens, known_tokens) = match maybe_validator {
         Some(validator) => {
-            let known_tokens = validator
-                .register_tokens(tokens.into_inner().tokens)
-                .await?;
-            Ok(Json(ValidatedTokens {
-                tokens: known_tokens
-                    .into_iter()
-                    .filter(|t| t.status == TokenValidationStatus::Validated)
-                    .collect(),
-            }))
+            let known_tokens = validator.register_tokens(tokens_to_look_up).await?;
+            let valid_tokens = known_tokens
+                .iter()
+                .filter(|t| t.status == TokenValidationStatus::Validated)
+                .cloned()
+                .collect();
+            (valid_tokens, known_tokens)
         }
         None => {
-            let tokens_to_check = tokens.into_inner().tokens;
-            let valid_tokens: Vec<EdgeToken> = tokens_to_check
+            let valid_tokens: Vec<EdgeToken> = tokens_to_look_up
                 .iter()
                 .filter_map(|t| token_cache.get(t).map(|e| e.value().clone()))
                 .collect();
-            Ok(Json(ValidatedTokens {
-                tokens: valid_tokens,
-            }))
+            (valid_tokens.clone(), valid_tokens)
         }
-    }
+    };
+    let invalid_tokens = tokens_to_check
+        .into_iter()
+        .filter(|t| !valid_tokens.iter().any(|valid| &valid.token == t))
+        .map(|t| {
+            known_tokens
+                .iter()
+                .find(|known| known.token == t)
+                .cloned()
+                .unwrap_or_else(|| EdgeToken {
+                    status: TokenValidationStatus::Invalid,
+                    token_type: Some(TokenType::Invalid),
+                    ..EdgeToken::no_project_or_environment(&t)
+                })
+        })
+        .collect();
+    Ok(Json(ValidatedTokens {
+        tokens: valid_tokens,
+        invalid_tokens,
+    }))
+}
+
+/// Bodies that aren't valid JSON get their usual status, with what was wrong with them in the same `{ "error": ... }` body
+/// as the rest of our errors, so SDK authors can tell a malformed body from a token problem
+fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = json_error_response(err.status_code(), format!("Invalid request body: {err}"));
+    InternalError::from_response(err, response).into()
 }
 
 pub fn configure_edge_api(cfg: &mut web::ServiceConfig) {
-    cfg.service(validate);
+    cfg.app_data(web::JsonConfig::default().error_handler(json_error_handler))
+        .service(validate);
 }
 
 #[cfg(test)]
@@ -59,12 +92,16 @@ mod tests {
     use std::sync::Arc;
 
     use actix_web::http::header::ContentType;
+    use actix_web::http::StatusCode;
     use actix_web::web::Json;
     use actix_web::{test, web, App};
     use dashmap::DashMap;
+    use serde_json::Value;
 
     use crate::auth::token_validator::TokenValidator;
-    use crate::edge_api::validate;
+    use crate::edge_api::{configure_edge_api, validate};
+    use crate::http::unleash_client::UnleashClient;
+    use crate::tests::upstream_server;
     use crate::types::{
         EdgeToken, TokenStrings, TokenType, TokenValidationStatus, ValidatedTokens,
     };
@@ -130,9 +167,8 @@ mod tests {
     pub async fn adding_a_token_validator_filters_so_only_validated_tokens_are_returned() {
         let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
         let token_validator = TokenValidator {
-            unleash_client: Arc::new(Default::default()),
             token_cache: token_cache.clone(),
-            persistence: None,
+            ..Default::default()
         };
         let app = test::init_service(
             App::new()
@@ -168,4 +204,110 @@ mod tests {
         assert_eq!(res.tokens.len(), 1);
         assert!(res.tokens.iter().any(|t| t.token == valid_token.token));
     }
+
+    #[tokio::test]
+    pub async fn a_bare_array_of_tokens_is_accepted_and_invalid_tokens_are_reported() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(token_cache.clone()))
+                .service(web::scope("/edge").service(validate)),
+        )
+        .await;
+        let valid_token = EdgeToken::validated_client_token("test-app:development.abcdefghijklm");
+        token_cache.insert(valid_token.token.clone(), valid_token.clone());
+        let req = test::TestRequest::post()
+            .uri("/edge/validate")
+            .insert_header(ContentType::json())
+            .set_json(vec![valid_token.token.clone(), "not-a-token".into()])
+            .to_request();
+        let res: ValidatedTokens = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.tokens.len(), 1);
+        assert_eq!(res.tokens[0].token, valid_token.token);
+        assert_eq!(res.invalid_tokens.len(), 1);
+        assert_eq!(res.invalid_tokens[0].token, "not-a-token");
+        assert_eq!(res.invalid_tokens[0].status, TokenValidationStatus::Invalid);
+    }
+
+    #[tokio::test]
+    pub async fn unknown_tokens_are_validated_upstream_and_returned_with_their_access() {
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut upstream_valid =
+            EdgeToken::validated_client_token("dx:production.abcdefghijklmnopqrstu");
+        upstream_valid.projects = vec!["dx".into()];
+        let mut upstream_invalid =
+            EdgeToken::try_from("dx:production.revokedsecret".to_string()).unwrap();
+        upstream_invalid.status = TokenValidationStatus::Invalid;
+        upstream_invalid.token_type = Some(TokenType::Invalid);
+        upstream_token_cache.insert(upstream_valid.token.clone(), upstream_valid.clone());
+        upstream_token_cache.insert(upstream_invalid.token.clone(), upstream_invalid.clone());
+        let server = upstream_server(
+            upstream_token_cache,
+            Arc::new(DashMap::default()),
+            Arc::new(DashMap::default()),
+        )
+        .await;
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let token_validator = TokenValidator::new(
+            Arc::new(UnleashClient::new(server.url("/").as_str(), None).unwrap()),
+            token_cache.clone(),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(token_cache.clone()))
+                .app_data(web::Data::new(token_validator))
+                .service(web::scope("/edge").service(validate)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/edge/validate")
+            .insert_header(ContentType::json())
+            .set_json(TokenStrings {
+                tokens: vec![upstream_valid.token.clone(), upstream_invalid.token.clone()],
+            })
+            .to_request();
+        let res: ValidatedTokens = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(res.tokens.len(), 1);
+        let validated = &res.tokens[0];
+        assert_eq!(validated.token, upstream_valid.token);
+        assert_eq!(validated.status, TokenValidationStatus::Validated);
+        assert_eq!(validated.token_type, Some(TokenType::Client));
+        assert_eq!(validated.environment, Some("production".into()));
+        assert_eq!(validated.projects, vec!["dx".to_string()]);
+        assert_eq!(res.invalid_tokens.len(), 1);
+        assert_eq!(res.invalid_tokens[0].token, upstream_invalid.token);
+        assert_eq!(res.invalid_tokens[0].status, TokenValidationStatus::Invalid);
+        assert_eq!(
+            token_cache.get(&upstream_invalid.token).unwrap().status,
+            TokenValidationStatus::Invalid
+        );
+    }
+
+    #[actix_web::test]
+    async fn malformed_bodies_are_refused_with_a_descriptive_error() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(token_cache))
+                .service(web::scope("/edge").configure(configure_edge_api)),
+        )
+        .await;
+        for body in [
+            r#"{"tokens": ["unterminated"#,
+            r#"{"tokens": "not-a-list"}"#,
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/edge/validate")
+                .insert_header(ContentType::json())
+                .set_payload(body)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{body}");
+            let error: Value = test::read_body_json(res).await;
+            assert!(error["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid request body: "));
+        }
+    }
 }
@@ -1,21 +1,40 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 
-use actix_web::{http::StatusCode, HttpResponseBuilder, ResponseError};
-use serde::Serialize;
+use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tracing::debug;
+use utoipa::ToSchema;
 
-use crate::types::{EdgeToken, Status, UnleashBadRequest};
+use crate::types::{EdgeToken, UnleashBadRequest};
 
 pub const TRUST_PROXY_PARSE_ERROR: &str =
     "needs to be a valid ip address (ipv4 or ipv6) or a valid cidr (ipv4 or ipv6)";
+pub const CORS_ORIGIN_PARSE_ERROR: &str =
+    "needs to be a valid origin with an http or https scheme and no path, e.g. https://example.com";
+pub const CORS_METHOD_PARSE_ERROR: &str = "needs to be a valid HTTP method, e.g. GET";
+pub const OTLP_HEADER_PARSE_ERROR: &str = "needs to be a header on the form key=value";
+pub const UPSTREAM_HEADER_PARSE_ERROR: &str =
+    "needs to be a valid HTTP header name followed by a valid HTTP header value";
+pub const TRACE_SAMPLING_RATIO_PARSE_ERROR: &str = "needs to be a number between 0.0 and 1.0";
+pub const WORKERS_PARSE_ERROR: &str = "needs to be `auto` or a number of workers larger than 0";
+pub const ENVIRONMENT_REFRESH_INTERVAL_PARSE_ERROR: &str =
+    "needs to be an environment and a number of seconds from 1 up to a year on the form environment=seconds";
+pub const METRICS_AGGREGATION_WINDOW_PARSE_ERROR: &str =
+    "needs to be a number of seconds that an hour divides evenly into, e.g. 10, 60 or 300";
+
+/// Set on responses refused because the features are older than the max staleness
+pub const EDGE_STALE_HEADER: &str = "X-Edge-Stale";
 
 #[derive(Debug)]
 pub enum FeatureError {
     AccessDenied,
     NotFound,
     Retriable(reqwest::StatusCode),
+    /// Upstream answered 429 or 503 with a Retry-After saying how long to wait before asking again
+    RateLimited(reqwest::StatusCode, chrono::Duration),
+    Timeout,
 }
 
 #[derive(Debug, Serialize)]
@@ -45,6 +64,8 @@ pub enum CertificateError {
     Pem8IdentityGeneration(String),
     NoCertificateFiles,
     RootCertificatesError(String),
+    UnsupportedClientKeyFormat,
+    ClientIdentityValidation(String),
 }
 
 impl Display for CertificateError {
@@ -80,10 +101,38 @@ impl Display for CertificateError {
             CertificateError::RootCertificatesError(e) => {
                 write!(f, "Could not load root certificate {e:?}")
             }
+            CertificateError::UnsupportedClientKeyFormat => {
+                write!(
+                    f,
+                    "Client key needs to be a PEM encoded PKCS#8 or PKCS#1 (RSA) private key"
+                )
+            }
+            CertificateError::ClientIdentityValidation(e) => {
+                write!(
+                    f,
+                    "Client certificate and key could not be used together, make sure the key belongs to the certificate. {e:?}"
+                )
+            }
         }
     }
 }
 
+/// The body of every error response from the API
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    /// What went wrong
+    pub error: String,
+    /// The HTTP status code of the response
+    pub status: u16,
+}
+
+pub fn json_error_response(status: StatusCode, error: impl Into<String>) -> HttpResponse {
+    HttpResponseBuilder::new(status).json(ErrorResponse {
+        error: error.into(),
+        status: status.as_u16(),
+    })
+}
+
 #[derive(Debug)]
 pub enum EdgeError {
     AuthorizationDenied,
@@ -95,20 +144,32 @@ pub enum EdgeError {
     ClientFeaturesParseError(String),
     ClientHydrationFailed(String),
     ClientRegisterError,
+    DenyTokensFileError(String),
     FrontendNotYetHydrated(FrontendHydrationMissing),
     FrontendExpectedToBeHydrated(String),
     FeatureNotFound(String),
+    FeatureFormatNotAcceptable(String),
+    FeatureDocumentTooLarge(usize),
+    FeaturesTooStale(String, i64),
     PersistenceError(String),
+    RequestTimedOut(u64),
+    StartupTokensRejected(String),
     EdgeMetricsError,
     EdgeMetricsRequestError(reqwest::StatusCode, Option<UnleashBadRequest>),
     EdgeTokenError,
     EdgeTokenParseError,
+    EnvironmentNotServed(Vec<String>),
     InvalidBackupFile(String, String),
+    InvalidRefreshShard(u32, u32),
     InvalidServerUrl(String),
+    InvalidTagFilter(String),
+    InvalidTlsConfig(String),
     InvalidTokenWithStrictBehavior,
     HealthCheckError(String),
     JsonParseError(String),
     NoFeaturesFile,
+    /// The environment and the status to answer with, which depends on who's asking
+    NoFeaturesForEnvironment(String, StatusCode),
     NoTokenProvider,
     NoTokens(String),
     NotReady,
@@ -117,6 +178,13 @@ pub enum EdgeError {
     TokenParseError(String),
     ContextParseError,
     TokenValidationError(reqwest::StatusCode),
+    TaggedFeaturesUnavailable(Vec<String>),
+    TooManyStreamSubscribers(usize),
+    TooManyTokens(usize),
+    UnixSocketError(String),
+    UpstreamTokenFileError(String),
+    UpstreamUnreachable(String),
+    UpstreamCircuitOpen(i64),
 }
 
 impl Error for EdgeError {}
@@ -140,6 +208,11 @@ impl Display for EdgeError {
                     f,
                     "Could not fetch client features. Will retry {status_code}"
                 ),
+                FeatureError::RateLimited(status_code, retry_after) => write!(
+                    f,
+                    "Could not fetch client features. Upstream answered {status_code} and asked to retry in {} seconds",
+                    retry_after.num_seconds()
+                ),
                 FeatureError::AccessDenied => write!(
                     f,
                     "Could not fetch client features because api key was not allowed"
@@ -148,11 +221,26 @@ impl Display for EdgeError {
                     f,
                     "Could not fetch features because upstream url was not found"
                 ),
+                FeatureError::Timeout => write!(
+                    f,
+                    "Could not fetch features because upstream did not answer in time"
+                ),
             },
 
             EdgeError::FeatureNotFound(name) => {
                 write!(f, "Failed to find feature with name {name}")
             }
+            EdgeError::FeatureFormatNotAcceptable(reason) => {
+                write!(f, "Can't answer with the requested feature format: {reason}")
+            }
+            EdgeError::FeatureDocumentTooLarge(limit) => write!(
+                f,
+                "Upstream sent a feature document larger than the limit of {limit} bytes"
+            ),
+            EdgeError::FeaturesTooStale(environment, age_seconds) => write!(
+                f,
+                "Features for environment {environment} were last refreshed {age_seconds} seconds ago, which is older than allowed"
+            ),
             EdgeError::ClientFeaturesParseError(error) => {
                 write!(f, "Failed to parse client features: [{error}]")
             }
@@ -163,7 +251,26 @@ impl Display for EdgeError {
                 write!(f, "Failed to build cert {cert_error:?}")
             }
             EdgeError::ClientBuildError(e) => write!(f, "Failed to build client {e:?}"),
+            EdgeError::StartupTokensRejected(tokens) => {
+                write!(f, "Upstream rejected the startup tokens {tokens}")
+            }
+            EdgeError::EnvironmentNotServed(environments) => match environments.as_slice() {
+                [environment] => write!(f, "This Edge only serves the {environment} environment"),
+                environments => write!(
+                    f,
+                    "This Edge only serves the {} environments",
+                    environments.join(", ")
+                ),
+            },
+            EdgeError::InvalidRefreshShard(index, count) => write!(
+                f,
+                "--shard-index {index} needs to be less than --shard-count {count}"
+            ),
             EdgeError::InvalidServerUrl(msg) => write!(f, "Failed to parse server url: [{msg}]"),
+            EdgeError::InvalidTagFilter(tag) => {
+                write!(f, "Tags to filter by need to be on the form type:value, got {tag}")
+            }
+            EdgeError::InvalidTlsConfig(msg) => write!(f, "Invalid TLS configuration: {msg}"),
             EdgeError::EdgeTokenError => write!(f, "Edge token error"),
             EdgeError::EdgeTokenParseError => write!(f, "Failed to parse token response"),
             EdgeError::EdgeMetricsRequestError(status_code, message) => {
@@ -207,6 +314,35 @@ impl Display for EdgeError {
             EdgeError::NotReady => {
                 write!(f, "Edge is not ready to serve requests")
             }
+            EdgeError::NoFeaturesForEnvironment(environment, _) => {
+                write!(f, "Edge has no features cached for environment {environment}")
+            }
+            EdgeError::UnixSocketError(msg) => write!(f, "Could not set up unix socket: {msg}"),
+            EdgeError::UpstreamTokenFileError(msg) => {
+                write!(f, "Could not read upstream token file: {msg}")
+            }
+            EdgeError::DenyTokensFileError(msg) => {
+                write!(f, "Could not read deny tokens file: {msg}")
+            }
+            EdgeError::UpstreamUnreachable(msg) => write!(f, "Could not reach upstream: {msg}"),
+            EdgeError::UpstreamCircuitOpen(seconds) => write!(
+                f,
+                "Upstream failed too many times in a row, not contacting it for another {seconds} seconds"
+            ),
+            EdgeError::TaggedFeaturesUnavailable(tags) => write!(
+                f,
+                "Could not ask upstream which features are tagged {}, and has no earlier answer",
+                tags.join(", ")
+            ),
+            EdgeError::TooManyStreamSubscribers(max) => {
+                write!(f, "Edge is already serving the maximum of {max} streaming subscribers")
+            }
+            EdgeError::TooManyTokens(max) => {
+                write!(f, "Edge is already tracking the maximum of {max} tokens")
+            }
+            EdgeError::RequestTimedOut(timeout_ms) => {
+                write!(f, "Edge did not finish handling the request within {timeout_ms} ms")
+            }
             EdgeError::InvalidTokenWithStrictBehavior => write!(f, "Edge is running with strict behavior and the token is not subsumed by any registered tokens"),
         }
     }
@@ -218,6 +354,7 @@ impl ResponseError for EdgeError {
             EdgeError::InvalidBackupFile(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::TlsError => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::NoFeaturesFile => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::NoFeaturesForEnvironment(_, status) => *status,
             EdgeError::AuthorizationDenied => StatusCode::FORBIDDEN,
             EdgeError::NoTokenProvider => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::NoTokens(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -225,14 +362,22 @@ impl ResponseError for EdgeError {
             EdgeError::ClientBuildError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::ClientFeaturesParseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::ClientFeaturesFetchError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::InvalidRefreshShard(_, _) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::InvalidServerUrl(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::InvalidTagFilter(_) => StatusCode::BAD_REQUEST,
+            EdgeError::InvalidTlsConfig(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::PersistenceError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::StartupTokensRejected(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::JsonParseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::EdgeTokenError => StatusCode::BAD_REQUEST,
             EdgeError::EdgeTokenParseError => StatusCode::BAD_REQUEST,
+            EdgeError::EnvironmentNotServed(_) => StatusCode::FORBIDDEN,
             EdgeError::TokenValidationError(_) => StatusCode::BAD_REQUEST,
             EdgeError::AuthorizationPending => StatusCode::UNAUTHORIZED,
             EdgeError::FeatureNotFound(_) => StatusCode::NOT_FOUND,
+            EdgeError::FeatureFormatNotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            EdgeError::FeatureDocumentTooLarge(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::FeaturesTooStale(_, _) => StatusCode::SERVICE_UNAVAILABLE,
             EdgeError::EdgeMetricsError => StatusCode::BAD_REQUEST,
             EdgeError::ClientRegisterError => StatusCode::BAD_REQUEST,
             EdgeError::ClientCertificateError(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -248,6 +393,15 @@ impl ResponseError for EdgeError {
             EdgeError::FrontendExpectedToBeHydrated(_) => StatusCode::INTERNAL_SERVER_ERROR,
             EdgeError::NotReady => StatusCode::SERVICE_UNAVAILABLE,
             EdgeError::InvalidTokenWithStrictBehavior => StatusCode::FORBIDDEN,
+            EdgeError::TaggedFeaturesUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EdgeError::TooManyStreamSubscribers(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EdgeError::TooManyTokens(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EdgeError::RequestTimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            EdgeError::UnixSocketError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::UpstreamTokenFileError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::DenyTokensFileError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            EdgeError::UpstreamUnreachable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            EdgeError::UpstreamCircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
@@ -255,6 +409,8 @@ impl ResponseError for EdgeError {
         match self {
             EdgeError::FrontendNotYetHydrated(hydration_info) => {
                 HttpResponseBuilder::new(self.status_code()).json(json!({
+                    "error": self.to_string(),
+                    "status": self.status_code().as_u16(),
                     "explanation": "Edge does not yet have data for this token. Please make a call against /api/client/features with a client token that has the same access as your token",
                     "access": hydration_info
                 }))
@@ -262,25 +418,27 @@ impl ResponseError for EdgeError {
             EdgeError::TokenParseError(token) => {
                 debug!("Failed to parse token: {}", token);
                 HttpResponseBuilder::new(self.status_code()).json(json!({
+                    "error": self.to_string(),
+                    "status": self.status_code().as_u16(),
                     "explanation": format!("Edge could not parse token: {}", token),
                 }))
             },
             EdgeError::TokenValidationError(status_code) => {
                 debug!("Failed to validate token upstream");
                 HttpResponseBuilder::new(self.status_code()).json(json!({
+                    "error": self.to_string(),
+                    "status": self.status_code().as_u16(),
                     "explanation": format!("Received a non 200 status code when trying to validate token upstream"),
                     "status_code": status_code.as_str()
                 }))
             }
-            EdgeError::NotReady => {
-                HttpResponseBuilder::new(self.status_code()).json(json!({
-                    "error": "Edge is not ready to serve requests",
-                    "status": Status::NotReady
-                }))
-            }
-            _ => HttpResponseBuilder::new(self.status_code()).json(json!({
-                "error": self.to_string()
-            }))
+            EdgeError::FeaturesTooStale(_, _) => HttpResponseBuilder::new(self.status_code())
+                .insert_header((EDGE_STALE_HEADER, "true"))
+                .json(ErrorResponse {
+                    error: self.to_string(),
+                    status: self.status_code().as_u16(),
+                }),
+            _ => json_error_response(self.status_code(), self.to_string()),
         }
     }
 }
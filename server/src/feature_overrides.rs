@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use unleash_types::client_features::{ClientFeatures, Strategy, Variant, WeightType};
+use unleash_yggdrasil::{ExtendedVariantDef, ResolvedToggle};
+
+use crate::persistence::EdgePersistence;
+
+/// Forces a feature on or off, and optionally to a single variant, in one environment of this Edge only. Meant for
+/// testing against a real Edge without touching the feature in Unleash
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureOverride {
+    pub environment: String,
+    pub feature: String,
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// Overrides are kept apart from the feature caches and applied to what's read from them just before it's served, so
+/// refreshes from upstream replace the features underneath without wiping the overrides.
+#[derive(Clone, Default)]
+pub struct FeatureOverrides {
+    overrides: Arc<DashMap<(String, String), FeatureOverride>>,
+    persistence: Option<Arc<dyn EdgePersistence>>,
+}
+
+impl FeatureOverrides {
+    pub fn new(persistence: Option<Arc<dyn EdgePersistence>>) -> Self {
+        FeatureOverrides {
+            overrides: Default::default(),
+            persistence,
+        }
+    }
+
+    /// Picks up the overrides persisted before a restart. Without them, we start out with none
+    pub async fn restore(self) -> Self {
+        if let Some(persistence) = &self.persistence {
+            match persistence.load_feature_overrides().await {
+                Ok(overrides) => {
+                    for feature_override in overrides {
+                        self.insert(feature_override);
+                    }
+                }
+                Err(error) => warn!("Could not restore feature overrides: {error:?}"),
+            }
+        }
+        self
+    }
+
+    pub fn list(&self) -> Vec<FeatureOverride> {
+        let mut overrides: Vec<FeatureOverride> =
+            self.overrides.iter().map(|o| o.value().clone()).collect();
+        overrides.sort_by(|a, b| (&a.environment, &a.feature).cmp(&(&b.environment, &b.feature)));
+        overrides
+    }
+
+    pub async fn set(&self, feature_override: FeatureOverride) {
+        self.insert(feature_override);
+        self.persist().await;
+    }
+
+    /// Clears the overrides matching `environment` and `feature`, leaving out either matches any. Returns what was
+    /// cleared
+    pub async fn clear(
+        &self,
+        environment: Option<&str>,
+        feature: Option<&str>,
+    ) -> Vec<FeatureOverride> {
+        let cleared: Vec<FeatureOverride> = self
+            .list()
+            .into_iter()
+            .filter(|o| environment.is_none_or(|environment| o.environment == environment))
+            .filter(|o| feature.is_none_or(|feature| o.feature == feature))
+            .collect();
+        for feature_override in &cleared {
+            self.overrides.remove(&(
+                feature_override.environment.clone(),
+                feature_override.feature.clone(),
+            ));
+        }
+        if !cleared.is_empty() {
+            self.persist().await;
+        }
+        cleared
+    }
+
+    /// Overridden features are switched on for everyone by a lone default strategy, or off. A variant replaces all
+    /// the others. Features the environment doesn't have are left out rather than made up
+    pub fn apply_to_features(&self, environment: &str, features: &mut ClientFeatures) {
+        if self.overrides.is_empty() {
+            return;
+        }
+        for feature in features.features.iter_mut() {
+            let Some(feature_override) = self.get(environment, &feature.name) else {
+                continue;
+            };
+            feature.enabled = feature_override.enabled;
+            if feature_override.enabled {
+                feature.strategies = Some(vec![Strategy {
+                    name: "default".into(),
+                    sort_order: None,
+                    segments: None,
+                    constraints: None,
+                    parameters: None,
+                    variants: None,
+                }]);
+                feature.dependencies = None;
+            }
+            if let Some(variant) = feature_override.variant {
+                feature.variants = Some(vec![Variant {
+                    name: variant,
+                    weight: 1000,
+                    weight_type: Some(WeightType::Fix),
+                    stickiness: None,
+                    payload: None,
+                    overrides: None,
+                }]);
+            }
+        }
+    }
+
+    /// The same overrides for toggles the engine already evaluated
+    pub fn apply_to_resolved(
+        &self,
+        environment: &str,
+        toggles: &mut HashMap<String, ResolvedToggle>,
+    ) {
+        if self.overrides.is_empty() {
+            return;
+        }
+        for (name, toggle) in toggles.iter_mut() {
+            self.apply_to_toggle(environment, name, toggle);
+        }
+    }
+
+    pub fn apply_to_toggle(&self, environment: &str, name: &str, toggle: &mut ResolvedToggle) {
+        let Some(feature_override) = self.get(environment, name) else {
+            return;
+        };
+        toggle.enabled = feature_override.enabled;
+        toggle.variant = match (feature_override.enabled, feature_override.variant) {
+            (false, _) => ExtendedVariantDef {
+                name: "disabled".into(),
+                payload: None,
+                enabled: false,
+                feature_enabled: false,
+            },
+            (true, Some(variant)) => ExtendedVariantDef {
+                name: variant,
+                payload: None,
+                enabled: true,
+                feature_enabled: true,
+            },
+            (true, None) => ExtendedVariantDef {
+                feature_enabled: true,
+                ..toggle.variant.clone()
+            },
+        };
+    }
+
+    fn get(&self, environment: &str, feature: &str) -> Option<FeatureOverride> {
+        self.overrides
+            .get(&(environment.to_string(), feature.to_string()))
+            .map(|o| o.value().clone())
+    }
+
+    fn insert(&self, feature_override: FeatureOverride) {
+        self.overrides.insert(
+            (
+                feature_override.environment.clone(),
+                feature_override.feature.clone(),
+            ),
+            feature_override,
+        );
+    }
+
+    /// Saves this Edge's overrides in place of whatever was saved before. Edges sharing redis or postgres share the
+    /// saved overrides too, so the last one to change its overrides decides what every one of them restores
+    async fn persist(&self) {
+        if let Some(persistence) = &self.persistence {
+            match persistence.save_feature_overrides(self.list()).await {
+                Ok(()) => debug!("Persisted feature overrides"),
+                Err(save_error) => warn!("Could not persist feature overrides: {save_error:?}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::file::FilePersister;
+
+    fn enabled_in(environment: &str, feature: &str) -> FeatureOverride {
+        FeatureOverride {
+            environment: environment.into(),
+            feature: feature.into(),
+            enabled: true,
+            variant: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn overrides_are_restored_after_a_restart_and_cleared_by_what_matches() {
+        let persistence: Arc<dyn EdgePersistence> = Arc::new(FilePersister::new(
+            &std::env::temp_dir()
+                .join("overrides_are_restored_after_a_restart_and_cleared_by_what_matches"),
+        ));
+        let overrides = FeatureOverrides::new(Some(persistence.clone()));
+        overrides.set(enabled_in("development", "checkout")).await;
+        overrides.set(enabled_in("production", "checkout")).await;
+        overrides.set(enabled_in("development", "search")).await;
+
+        let restored = FeatureOverrides::new(Some(persistence)).restore().await;
+        assert_eq!(restored.list(), overrides.list());
+
+        let cleared = restored.clear(None, Some("checkout")).await;
+        assert_eq!(
+            cleared,
+            vec![
+                enabled_in("development", "checkout"),
+                enabled_in("production", "checkout")
+            ]
+        );
+        assert_eq!(restored.list(), vec![enabled_in("development", "search")]);
+    }
+}
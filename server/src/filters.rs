@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use dashmap::mapref::one::Ref;
 use unleash_types::client_features::{ClientFeature, ClientFeatures};
 
@@ -51,6 +53,21 @@ pub(crate) fn filter_client_features(
     }
 }
 
+/// Drops the features that are turned off, except for those an enabled feature depends on, since SDKs evaluate the
+/// dependency against the parent and would treat a missing one differently
+pub(crate) fn retain_enabled(client_features: &mut ClientFeatures) {
+    let parents: HashSet<String> = client_features
+        .features
+        .iter()
+        .filter(|feature| feature.enabled)
+        .flat_map(|feature| feature.dependencies.iter().flatten())
+        .map(|dependency| dependency.feature.clone())
+        .collect();
+    client_features
+        .features
+        .retain(|feature| feature.enabled || parents.contains(&feature.name));
+}
+
 pub(crate) fn name_prefix_filter(name_prefix: String) -> FeatureFilter {
     Box::new(move |f| f.name.starts_with(&name_prefix))
 }
@@ -241,4 +258,65 @@ mod tests {
         assert_eq!(filtered_features[0].name, "feature-one".to_string());
         assert_eq!(filtered_features[1].name, "feature-two".to_string());
     }
+
+    fn features_in_three_projects() -> ClientFeatures {
+        ClientFeatures {
+            version: 0,
+            features: vec![
+                ClientFeature {
+                    name: "feature-one".to_string(),
+                    project: Some("default".to_string()),
+                    ..ClientFeature::default()
+                },
+                ClientFeature {
+                    name: "feature-two".to_string(),
+                    project: Some("dx".to_string()),
+                    ..ClientFeature::default()
+                },
+                ClientFeature {
+                    name: "feature-three".to_string(),
+                    project: Some("eg".to_string()),
+                    ..ClientFeature::default()
+                },
+            ],
+            query: None,
+            segments: None,
+        }
+    }
+
+    #[test]
+    fn project_filter_keeps_all_projects_listed_in_token() {
+        let map: DashMap<String, ClientFeatures> = DashMap::default();
+        map.insert("some-key".into(), features_in_three_projects());
+        let features = map.get("some-key").unwrap();
+
+        let token = EdgeToken {
+            projects: vec!["dx".to_string(), "eg".to_string()],
+            ..Default::default()
+        };
+
+        let filter = FeatureFilterSet::from(project_filter(&token));
+        let filtered_features = filter_features(&features, &filter);
+
+        assert_eq!(filtered_features.len(), 2);
+        assert_eq!(filtered_features[0].name, "feature-two".to_string());
+        assert_eq!(filtered_features[1].name, "feature-three".to_string());
+    }
+
+    #[test]
+    fn project_filter_keeps_everything_for_wildcard_tokens() {
+        let map: DashMap<String, ClientFeatures> = DashMap::default();
+        map.insert("some-key".into(), features_in_three_projects());
+        let features = map.get("some-key").unwrap();
+
+        let token = EdgeToken {
+            projects: vec!["*".to_string()],
+            ..Default::default()
+        };
+
+        let filter = FeatureFilterSet::from(project_filter(&token));
+        let filtered_features = filter_features(&features, &filter);
+
+        assert_eq!(filtered_features.len(), 3);
+    }
 }
@@ -1,31 +1,36 @@
 use actix_http::body::MessageBody;
 use actix_http::HttpMessage;
 use actix_service::ServiceFactory;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use actix_web::dev::{ServiceRequest, ServiceResponse};
 use actix_web::{
-    get, post,
-    web::{self, Data, Json, Path},
+    get,
+    http::header,
+    post,
+    web::{self, Bytes, Data, Json, Path},
     HttpRequest, HttpResponse, Scope,
 };
 use dashmap::DashMap;
+use futures::StreamExt;
+use serde::Serialize;
 use serde_qs::actix::QsQuery;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, instrument};
 use unleash_types::client_features::Context;
-use unleash_types::client_metrics::{ClientApplication, ConnectVia};
-use unleash_types::{
-    client_metrics::ClientMetrics,
-    frontend::{EvaluatedToggle, EvaluatedVariant, FrontendResult},
-};
+use unleash_types::client_metrics::{ClientApplication, ClientMetrics, ConnectVia};
 use unleash_yggdrasil::{EngineState, ResolvedToggle};
 
 use crate::error::EdgeError::ContextParseError;
-use crate::types::{ClientIp, IncomingContext};
+use crate::feature_overrides::FeatureOverrides;
+use crate::http::broadcaster::{Broadcaster, Subscription};
+use crate::types::{
+    ClientIp, EvaluatedToggle, EvaluatedVariant, FeatureFormat, FrontendResult, IncomingContext,
+};
 use crate::{
     error::{EdgeError, FrontendHydrationMissing},
-    metrics::client_metrics::MetricsCache,
-    tokens::{self, cache_key},
+    metrics::client_metrics::{IncomingConnectVia, MetricsCache},
+    tokens::cache_key,
     types::{EdgeJsonResult, EdgeResult, EdgeToken},
 };
 
@@ -35,8 +40,9 @@ use crate::{
 context_path = "/api/proxy",
 responses(
 (status = 200, description = "Return all known feature toggles for this token in evaluated (true|false) state", body = FrontendResult),
-(status = 400, description = "Bad data in query parameters"),
-(status = 403, description = "Was not allowed to access features")
+(status = 400, description = "Bad data in query parameters", body = crate::error::ErrorResponse),
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 406, description = "Raw features were asked for, which frontend tokens don't get", body = crate::error::ErrorResponse)
 ),
 params(Context),
 security(
@@ -50,12 +56,14 @@ pub async fn get_proxy_all_features(
     token_cache: Data<DashMap<String, EdgeToken>>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
+    evaluated_only(&req)?;
     get_all_features(
         edge_token,
         engine_cache,
         token_cache,
         req.query_string(),
         req.extensions().get::<ClientIp>(),
+        feature_overrides(&req),
     )
 }
 
@@ -63,7 +71,8 @@ pub async fn get_proxy_all_features(
 context_path = "/api/frontend",
 responses(
 (status = 200, description = "Return all known feature toggles for this token in evaluated (true|false) state", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 406, description = "Raw features were asked for, which frontend tokens don't get", body = crate::error::ErrorResponse)
 ),
 params(Context),
 security(
@@ -77,12 +86,14 @@ pub async fn get_frontend_all_features(
     token_cache: Data<DashMap<String, EdgeToken>>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
+    evaluated_only(&req)?;
     get_all_features(
         edge_token,
         engine_cache,
         token_cache,
         req.query_string(),
         req.extensions().get::<ClientIp>(),
+        feature_overrides(&req),
     )
 }
 
@@ -90,8 +101,8 @@ pub async fn get_frontend_all_features(
 context_path = "/api/proxy",
 responses(
 (status = 200, description = "Return all known feature toggles for this token in evaluated (true|false) state", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 request_body = Context,
 security(
@@ -104,14 +115,16 @@ async fn post_proxy_all_features(
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     context: Json<IncomingContext>,
+    query: QsQuery<IncomingContext>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
     post_all_features(
         edge_token,
         engine_cache,
         token_cache,
-        context,
+        posted_context(query, context),
         req.extensions().get::<ClientIp>(),
+        feature_overrides(&req),
     )
 }
 
@@ -119,7 +132,7 @@ async fn post_proxy_all_features(
     context_path = "/api/frontend",
     responses(
     (status = 202, description = "Accepted client metrics"),
-    (status = 403, description = "Was not allowed to post metrics"),
+    (status = 403, description = "Was not allowed to post metrics", body = crate::error::ErrorResponse),
     ),
     request_body = ClientMetrics,
     security(
@@ -145,7 +158,7 @@ async fn post_all_proxy_metrics(
     context_path = "/api/frontend",
     responses(
     (status = 202, description = "Accepted client metrics"),
-    (status = 403, description = "Was not allowed to post metrics"),
+    (status = 403, description = "Was not allowed to post metrics", body = crate::error::ErrorResponse),
     ),
     request_body = ClientMetrics,
     security(
@@ -171,8 +184,8 @@ async fn post_all_frontend_metrics(
 context_path = "/api/frontend",
 responses(
 (status = 200, description = "Return all known feature toggles for this token in evaluated (true|false) state", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 request_body = Context,
 security(
@@ -185,14 +198,16 @@ async fn post_frontend_all_features(
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     context: Json<IncomingContext>,
+    query: QsQuery<IncomingContext>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
     post_all_features(
         edge_token,
         engine_cache,
         token_cache,
-        context,
+        posted_context(query, context),
         req.extensions().get::<ClientIp>(),
+        feature_overrides(&req),
     )
 }
 
@@ -200,10 +215,11 @@ fn post_all_features(
     edge_token: EdgeToken,
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
-    context: Json<IncomingContext>,
+    context: IncomingContext,
     client_ip: Option<&ClientIp>,
+    overrides: Option<&FeatureOverrides>,
 ) -> EdgeJsonResult<FrontendResult> {
-    let context: Context = context.into_inner().into();
+    let context: Context = context.into();
     let context_with_ip = if context.remote_address.is_none() {
         Context {
             remote_address: client_ip.map(|ip| ip.to_string()),
@@ -225,15 +241,21 @@ fn post_all_features(
             "Feature cache has not been hydrated yet, but it was expected to be. This can be due to a race condition from calling edge before it's ready. This error might auto resolve as soon as edge is able to fetch from upstream".into(),
         )
     })?;
-    Ok(Json(frontend_from_yggdrasil(feature_results, true, &token)))
+    Ok(Json(frontend_from_yggdrasil(
+        feature_results,
+        true,
+        &token,
+        overrides,
+    )))
 }
 
 #[utoipa::path(
 context_path = "/api/proxy",
 responses(
 (status = 200, description = "Return feature toggles for this token that evaluated to true", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 406, description = "Raw features were asked for, which frontend tokens don't get", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 params(Context),
 security(
@@ -249,12 +271,14 @@ async fn get_enabled_proxy(
     context: QsQuery<IncomingContext>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
+    evaluated_only(&req)?;
     get_enabled_features(
         edge_token,
         engine_cache,
         token_cache,
         context.into_inner(),
         req.extensions().get::<ClientIp>().cloned(),
+        feature_overrides(&req),
     )
 }
 
@@ -262,8 +286,9 @@ async fn get_enabled_proxy(
 context_path = "/api/frontend",
 responses(
 (status = 200, description = "Return feature toggles for this token that evaluated to true", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 406, description = "Raw features were asked for, which frontend tokens don't get", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 params(Context),
 security(
@@ -280,6 +305,7 @@ async fn get_enabled_frontend(
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
     debug!("getting enabled features");
+    evaluated_only(&req)?;
     let client_ip = req.extensions().get::<ClientIp>().cloned();
     get_enabled_features(
         edge_token,
@@ -287,15 +313,38 @@ async fn get_enabled_frontend(
         token_cache,
         context.into_inner(),
         client_ip,
+        feature_overrides(&req),
     )
 }
 
+/// SDKs POST their context in the body, but some also carry part of it in the query string. The two are merged, with
+/// the body winning where both have a value
+fn posted_context(query: QsQuery<IncomingContext>, body: Json<IncomingContext>) -> IncomingContext {
+    query.into_inner().merge(body.into_inner())
+}
+
+fn feature_overrides(req: &HttpRequest) -> Option<&FeatureOverrides> {
+    req.app_data::<Data<FeatureOverrides>>().map(Data::get_ref)
+}
+
+/// Frontend tokens are public, so they only ever get toggles evaluated for them, never the strategies and constraints
+/// behind them. Asking for the raw features is refused rather than quietly answered with evaluated ones
+fn evaluated_only(req: &HttpRequest) -> EdgeResult<()> {
+    match FeatureFormat::requested(req)? {
+        Some(FeatureFormat::Raw) => Err(EdgeError::FeatureFormatNotAcceptable(
+            "frontend tokens only get evaluated toggles".into(),
+        )),
+        _ => Ok(()),
+    }
+}
+
 fn get_enabled_features(
     edge_token: EdgeToken,
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     incoming_context: IncomingContext,
     client_ip: Option<ClientIp>,
+    overrides: Option<&FeatureOverrides>,
 ) -> EdgeJsonResult<FrontendResult> {
     let context: Context = incoming_context.into();
     let context_with_ip = if context.remote_address.is_none() {
@@ -323,15 +372,184 @@ fn get_enabled_features(
         feature_results,
         false,
         &token,
+        overrides,
     )))
 }
 
+#[derive(Serialize, Debug)]
+struct FrontendDelta {
+    updated: Vec<EvaluatedToggle>,
+    removed: Vec<String>,
+}
+
+impl FrontendDelta {
+    fn between(previous: &FrontendResult, current: &FrontendResult) -> Self {
+        let previous_toggles: HashMap<&str, serde_json::Value> = previous
+            .toggles
+            .iter()
+            .map(|toggle| {
+                (
+                    toggle.name.as_str(),
+                    serde_json::to_value(toggle).unwrap_or_default(),
+                )
+            })
+            .collect();
+        let current_names: HashSet<&str> = current
+            .toggles
+            .iter()
+            .map(|toggle| toggle.name.as_str())
+            .collect();
+        let updated = current
+            .toggles
+            .iter()
+            .filter(|toggle| {
+                previous_toggles.get(toggle.name.as_str())
+                    != serde_json::to_value(toggle).ok().as_ref()
+            })
+            .cloned()
+            .collect();
+        let removed = previous
+            .toggles
+            .iter()
+            .filter(|toggle| !current_names.contains(toggle.name.as_str()))
+            .map(|toggle| toggle.name.clone())
+            .collect();
+        FrontendDelta { updated, removed }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn server_sent_event<T: Serialize>(event: &str, data: &T) -> EdgeResult<Bytes> {
+    let data = serde_json::to_string(data)?;
+    Ok(Bytes::from(format!("event: {event}\ndata: {data}\n\n")))
+}
+
+struct FeatureStream {
+    subscription: Subscription,
+    edge_token: EdgeToken,
+    engine_cache: Data<DashMap<String, EngineState>>,
+    token_cache: Data<DashMap<String, EdgeToken>>,
+    context: IncomingContext,
+    client_ip: Option<ClientIp>,
+    overrides: Option<Data<FeatureOverrides>>,
+    cache_key: String,
+    previous: FrontendResult,
+}
+
+impl FeatureStream {
+    fn evaluate(&self) -> EdgeResult<FrontendResult> {
+        get_enabled_features(
+            self.edge_token.clone(),
+            self.engine_cache.clone(),
+            self.token_cache.clone(),
+            self.context.clone(),
+            self.client_ip.clone(),
+            self.overrides.as_ref().map(Data::get_ref),
+        )
+        .map(|result| result.into_inner())
+    }
+
+    /// Waits for the next change to this stream's environment that actually changes the evaluated toggles.
+    /// Lagging behind the broadcast means we missed notifications, and with them possibly changes we can't tell apart
+    /// from what we last sent, so the subscriber is sent a fresh snapshot to resync instead of an update.
+    async fn next_update(mut self) -> Option<(Result<Bytes, actix_web::Error>, Self)> {
+        let mut lagged = false;
+        loop {
+            match self.subscription.recv().await {
+                Ok(update) if update.cache_key != self.cache_key => continue,
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => lagged = true,
+                Err(RecvError::Closed) => return None,
+            }
+            let current = match self.evaluate() {
+                Ok(current) => current,
+                Err(e) => {
+                    debug!("Could not evaluate features for stream: {e:?}");
+                    continue;
+                }
+            };
+            if lagged {
+                let event = server_sent_event("snapshot", &current).map_err(actix_web::Error::from);
+                self.previous = current;
+                return Some((event, self));
+            }
+            let delta = FrontendDelta::between(&self.previous, &current);
+            self.previous = current;
+            if delta.is_empty() {
+                continue;
+            }
+            let event = server_sent_event("update", &delta).map_err(actix_web::Error::from);
+            return Some((event, self));
+        }
+    }
+}
+
+///
+/// Opens a Server-Sent Events stream of the toggles that evaluate to true for this token.
+/// The first event is a `snapshot` of the full result, subsequent `update` events only contain
+/// the toggles that changed and the names of toggles that are no longer enabled. Subscribers that fall
+/// too far behind to have been told about every change get another `snapshot` to start over from.
+#[utoipa::path(
+context_path = "/api/frontend",
+responses(
+(status = 200, description = "Stream of feature toggles for this token that evaluated to true", content_type = "text/event-stream"),
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 503, description = "Edge is already serving the maximum number of streaming subscribers", body = crate::error::ErrorResponse)
+),
+params(Context),
+security(
+("Authorization" = [])
+)
+)]
+#[get("/stream")]
+async fn stream_frontend_features(
+    edge_token: EdgeToken,
+    engine_cache: Data<DashMap<String, EngineState>>,
+    token_cache: Data<DashMap<String, EdgeToken>>,
+    broadcaster: Data<Broadcaster>,
+    context: QsQuery<IncomingContext>,
+    req: HttpRequest,
+) -> EdgeResult<HttpResponse> {
+    let subscription = broadcaster.subscribe()?;
+    let token = token_cache
+        .get(&edge_token.token)
+        .map(|e| e.value().clone())
+        .unwrap_or_else(|| edge_token.clone());
+    let stream = FeatureStream {
+        subscription,
+        cache_key: cache_key(&token),
+        edge_token,
+        engine_cache,
+        token_cache,
+        context: context.into_inner(),
+        client_ip: req.extensions().get::<ClientIp>().cloned(),
+        overrides: req.app_data::<Data<FeatureOverrides>>().cloned(),
+        previous: FrontendResult { toggles: vec![] },
+    };
+    let snapshot = stream.evaluate()?;
+    let snapshot_event = server_sent_event("snapshot", &snapshot)?;
+    let stream = FeatureStream {
+        previous: snapshot,
+        ..stream
+    };
+    let events = futures::stream::once(async move { Ok(snapshot_event) })
+        .chain(futures::stream::unfold(stream, FeatureStream::next_update));
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::CONTENT_ENCODING, "identity"))
+        .streaming(events))
+}
+
 #[utoipa::path(
 context_path = "/api/proxy",
 responses(
 (status = 200, description = "Return feature toggles for this token that evaluated to true", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 request_body = Context,
 security(
@@ -344,18 +562,27 @@ async fn post_proxy_enabled_features(
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     context: Json<IncomingContext>,
+    query: QsQuery<IncomingContext>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
     let client_ip = req.extensions().get::<ClientIp>().cloned();
-    post_enabled_features(edge_token, engine_cache, token_cache, context, client_ip).await
+    post_enabled_features(
+        edge_token,
+        engine_cache,
+        token_cache,
+        posted_context(query, context),
+        client_ip,
+        feature_overrides(&req),
+    )
+    .await
 }
 
 #[utoipa::path(
 context_path = "/api/frontend",
 responses(
 (status = 200, description = "Return feature toggles for this token that evaluated to true", body = FrontendResult),
-(status = 403, description = "Was not allowed to access features"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 request_body = Context,
 security(
@@ -368,10 +595,19 @@ async fn post_frontend_enabled_features(
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     context: Json<IncomingContext>,
+    query: QsQuery<IncomingContext>,
     req: HttpRequest,
 ) -> EdgeJsonResult<FrontendResult> {
     let client_ip = req.extensions().get::<ClientIp>().cloned();
-    post_enabled_features(edge_token, engine_cache, token_cache, context, client_ip).await
+    post_enabled_features(
+        edge_token,
+        engine_cache,
+        token_cache,
+        posted_context(query, context),
+        client_ip,
+        feature_overrides(&req),
+    )
+    .await
 }
 
 #[utoipa::path(
@@ -379,9 +615,9 @@ context_path = "/api/frontend",
 params(("feature_name" = String, Path, description = "Name of the feature")),
 responses(
 (status = 200, description = "Return the feature toggle with name `name`", body = EvaluatedToggle),
-(status = 403, description = "Was not allowed to access features"),
-(status = 404, description = "Feature was not found"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 404, description = "Feature was not found", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 request_body = Context,
 security(
@@ -393,6 +629,7 @@ pub async fn post_frontend_evaluate_single_feature(
     edge_token: EdgeToken,
     feature_name: Path<String>,
     context: Json<IncomingContext>,
+    query: QsQuery<IncomingContext>,
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     req: HttpRequest,
@@ -400,10 +637,11 @@ pub async fn post_frontend_evaluate_single_feature(
     evaluate_feature(
         edge_token,
         feature_name.into_inner(),
-        &context.into_inner(),
+        &posted_context(query, context),
         token_cache,
         engine_cache,
         req.extensions().get::<ClientIp>().cloned(),
+        feature_overrides(&req),
     )
     .map(Json)
 }
@@ -416,9 +654,9 @@ params(
 ),
 responses(
 (status = 200, description = "Return the feature toggle with name `name`", body = EvaluatedToggle),
-(status = 403, description = "Was not allowed to access features"),
-(status = 404, description = "Feature was not found"),
-(status = 400, description = "Invalid parameters used")
+(status = 403, description = "Was not allowed to access features", body = crate::error::ErrorResponse),
+(status = 404, description = "Feature was not found", body = crate::error::ErrorResponse),
+(status = 400, description = "Invalid parameters used", body = crate::error::ErrorResponse)
 ),
 security(
 ("Authorization" = [])
@@ -440,6 +678,7 @@ pub async fn get_frontend_evaluate_single_feature(
         token_cache,
         engine_cache,
         req.extensions().get::<ClientIp>().cloned(),
+        feature_overrides(&req),
     )
     .map(Json)
 }
@@ -451,6 +690,7 @@ pub fn evaluate_feature(
     token_cache: Data<DashMap<String, EdgeToken>>,
     engine_cache: Data<DashMap<String, EngineState>>,
     client_ip: Option<ClientIp>,
+    overrides: Option<&FeatureOverrides>,
 ) -> EdgeResult<EvaluatedToggle> {
     let context: Context = incoming_context.clone().into();
     let context_with_ip = if context.remote_address.is_none() {
@@ -469,6 +709,16 @@ pub fn evaluate_feature(
     engine_cache
         .get(&cache_key(&validated_token))
         .and_then(|engine| engine.resolve(&feature_name, &context_with_ip, &None))
+        .map(|mut resolved_toggle| {
+            if let Some(overrides) = overrides {
+                overrides.apply_to_toggle(
+                    &cache_key(&validated_token),
+                    &feature_name,
+                    &mut resolved_toggle,
+                );
+            }
+            resolved_toggle
+        })
         .and_then(|resolved_toggle| {
             if validated_token.projects.contains(&"*".into())
                 || validated_token.projects.contains(&resolved_toggle.project)
@@ -481,11 +731,7 @@ pub fn evaluate_feature(
         .map(|r| EvaluatedToggle {
             name: feature_name.clone(),
             enabled: r.enabled,
-            variant: EvaluatedVariant {
-                name: r.variant.name,
-                enabled: r.variant.enabled,
-                payload: r.variant.payload,
-            },
+            variant: EvaluatedVariant::from(&r),
             impression_data: r.impression_data,
             impressionData: r.impression_data,
         })
@@ -496,10 +742,11 @@ async fn post_enabled_features(
     edge_token: EdgeToken,
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
-    context: Json<IncomingContext>,
+    context: IncomingContext,
     client_ip: Option<ClientIp>,
+    overrides: Option<&FeatureOverrides>,
 ) -> EdgeJsonResult<FrontendResult> {
-    let context: Context = context.into_inner().into();
+    let context: Context = context.into();
     let context_with_ip = if context.remote_address.is_none() {
         Context {
             remote_address: client_ip.map(|ip| ip.to_string()),
@@ -512,11 +759,9 @@ async fn post_enabled_features(
         .get(&edge_token.token)
         .map(|e| e.value().clone())
         .unwrap_or_else(|| edge_token.clone());
-    let engine = engine_cache
-        .get(&tokens::cache_key(&edge_token))
-        .ok_or_else(|| {
-            EdgeError::FrontendNotYetHydrated(FrontendHydrationMissing::from(&edge_token))
-        })?;
+    let engine = engine_cache.get(&cache_key(&token)).ok_or_else(|| {
+        EdgeError::FrontendNotYetHydrated(FrontendHydrationMissing::from(&edge_token))
+    })?;
     let feature_results = engine.resolve_all(&context_with_ip, &None).ok_or_else(|| {
         EdgeError::FrontendExpectedToBeHydrated(
             "Feature cache has not been hydrated yet, but it was expected to be. This can be due to a race condition from calling edge before it's ready. This error might auto resolve as soon as edge is able to fetch from upstream".into(),
@@ -527,6 +772,7 @@ async fn post_enabled_features(
         feature_results,
         false,
         &token,
+        overrides,
     )))
 }
 
@@ -534,7 +780,7 @@ async fn post_enabled_features(
 context_path = "/api/proxy",
 responses(
 (status = 202, description = "Accepted client metrics"),
-(status = 403, description = "Was not allowed to post metrics"),
+(status = 403, description = "Was not allowed to post metrics", body = crate::error::ErrorResponse),
 ),
 request_body = ClientMetrics,
 security(
@@ -560,7 +806,7 @@ async fn post_proxy_metrics(
 context_path = "/api/frontend",
 responses(
 (status = 202, description = "Accepted client metrics"),
-(status = 403, description = "Was not allowed to post metrics"),
+(status = 403, description = "Was not allowed to post metrics", body = crate::error::ErrorResponse),
 ),
 request_body = ClientMetrics,
 security(
@@ -586,7 +832,7 @@ async fn post_frontend_metrics(
 context_path = "/api/proxy",
 responses(
 (status = 202, description = "Accepted client application registration"),
-(status = 403, description = "Was not allowed to register client"),
+(status = 403, description = "Was not allowed to register client", body = crate::error::ErrorResponse),
 ),
 request_body = ClientApplication,
 security(
@@ -597,13 +843,14 @@ security(
 pub async fn post_proxy_register(
     edge_token: EdgeToken,
     connect_via: Data<ConnectVia>,
+    incoming_connect_via: IncomingConnectVia,
     client_application: Json<ClientApplication>,
     metrics_cache: Data<MetricsCache>,
 ) -> EdgeResult<HttpResponse> {
     crate::metrics::client_metrics::register_client_application(
         edge_token,
         &connect_via,
-        client_application.into_inner(),
+        incoming_connect_via.seed(client_application.into_inner()),
         metrics_cache,
     );
     Ok(HttpResponse::Accepted().finish())
@@ -613,7 +860,7 @@ pub async fn post_proxy_register(
 context_path = "/api/frontend",
 responses(
 (status = 202, description = "Accepted client application registration"),
-(status = 403, description = "Was not allowed to register client"),
+(status = 403, description = "Was not allowed to register client", body = crate::error::ErrorResponse),
 ),
 request_body = ClientApplication,
 security(
@@ -624,13 +871,14 @@ security(
 pub async fn post_frontend_register(
     edge_token: EdgeToken,
     connect_via: Data<ConnectVia>,
+    incoming_connect_via: IncomingConnectVia,
     client_application: Json<ClientApplication>,
     metrics_cache: Data<MetricsCache>,
 ) -> EdgeResult<HttpResponse> {
     crate::metrics::client_metrics::register_client_application(
         edge_token,
         &connect_via,
-        client_application.into_inner(),
+        incoming_connect_via.seed(client_application.into_inner()),
         metrics_cache,
     );
     Ok(HttpResponse::Accepted().finish())
@@ -648,6 +896,7 @@ fn configure_frontend_endpoints(cfg: &mut web::ServiceConfig, disable_all_endpoi
                 .service(post_frontend_register)
                 .service(post_frontend_evaluate_single_feature)
                 .service(get_frontend_evaluate_single_feature)
+                .service(stream_frontend_features)
                 .service(post_all_frontend_metrics),
         );
     } else {
@@ -658,7 +907,8 @@ fn configure_frontend_endpoints(cfg: &mut web::ServiceConfig, disable_all_endpoi
                 .service(post_frontend_enabled_features)
                 .service(post_frontend_register)
                 .service(post_frontend_evaluate_single_feature)
-                .service(get_frontend_evaluate_single_feature),
+                .service(get_frontend_evaluate_single_feature)
+                .service(stream_frontend_features),
         );
     }
 }
@@ -681,6 +931,9 @@ fn scope_with_auth(
         .wrap(crate::middleware::as_async_middleware::as_async_middleware(
             crate::middleware::client_token_from_frontend_token::client_token_from_frontend_token,
         ))
+        .wrap(crate::middleware::as_async_middleware::as_async_middleware(
+            crate::middleware::rate_limit::rate_limit,
+        ))
         .wrap(crate::middleware::as_async_middleware::as_async_middleware(
             crate::middleware::validate_token::validate_token,
         ))
@@ -715,10 +968,14 @@ pub fn configure_frontend_api(cfg: &mut web::ServiceConfig, disable_all_endpoint
 }
 
 pub fn frontend_from_yggdrasil(
-    res: HashMap<String, ResolvedToggle>,
+    mut res: HashMap<String, ResolvedToggle>,
     include_all: bool,
     edge_token: &EdgeToken,
+    overrides: Option<&FeatureOverrides>,
 ) -> FrontendResult {
+    if let Some(overrides) = overrides {
+        overrides.apply_to_resolved(&cache_key(edge_token), &mut res);
+    }
     let toggles: Vec<EvaluatedToggle> = res
         .iter()
         .filter(|(_, resolved)| include_all || resolved.enabled)
@@ -730,11 +987,7 @@ pub fn frontend_from_yggdrasil(
         .map(|(name, resolved)| EvaluatedToggle {
             name: name.into(),
             enabled: resolved.enabled,
-            variant: EvaluatedVariant {
-                name: resolved.variant.name.clone(),
-                enabled: resolved.variant.enabled,
-                payload: resolved.variant.payload.clone(),
-            },
+            variant: EvaluatedVariant::from(resolved),
             impression_data: resolved.impression_data,
             impressionData: resolved.impression_data,
         })
@@ -742,14 +995,21 @@ pub fn frontend_from_yggdrasil(
     FrontendResult { toggles }
 }
 
+/// How context is read from query strings, by the extractor and where a handler parses the query string itself, so
+/// nested properties like `properties[tags][]` mean the same everywhere
+pub fn context_query_config() -> serde_qs::Config {
+    serde_qs::Config::new(5, false)
+}
+
 pub fn get_all_features(
     edge_token: EdgeToken,
     engine_cache: Data<DashMap<String, EngineState>>,
     token_cache: Data<DashMap<String, EdgeToken>>,
     query_string: &str,
     client_ip: Option<&ClientIp>,
+    overrides: Option<&FeatureOverrides>,
 ) -> EdgeJsonResult<FrontendResult> {
-    let raw_context: IncomingContext = serde_qs::Config::new(0, false)
+    let raw_context: IncomingContext = context_query_config()
         .deserialize_str(query_string)
         .map_err(|_| ContextParseError)?;
     let context: Context = raw_context.into();
@@ -774,11 +1034,17 @@ pub fn get_all_features(
             "Feature cache has not been hydrated yet, but it was expected to be. This can be due to a race condition from calling edge before it's ready. This error might auto resolve as soon as edge is able to fetch from upstream".into(),
         )
     })?;
-    Ok(Json(frontend_from_yggdrasil(feature_results, true, &token)))
+    Ok(Json(frontend_from_yggdrasil(
+        feature_results,
+        true,
+        &token,
+        overrides,
+    )))
 }
 
 #[cfg(test)]
 mod tests {
+    use actix_http::body::MessageBody;
     use actix_http::{Request, StatusCode};
     use actix_web::{
         http::header::ContentType,
@@ -791,22 +1057,29 @@ mod tests {
     use serde_json::json;
     use std::collections::HashMap;
     use std::net::SocketAddr;
+    use std::pin::{pin, Pin};
     use std::str::FromStr;
     use std::sync::Arc;
     use tracing_test::traced_test;
-    use unleash_types::client_metrics::{ClientMetricsEnv, MetricsMetadata};
-    use unleash_types::{
-        client_features::{ClientFeature, ClientFeatures, Constraint, Operator, Strategy},
-        frontend::{EvaluatedToggle, EvaluatedVariant, FrontendResult},
+    use unleash_types::client_features::{
+        ClientFeature, ClientFeatures, Constraint, Operator, Payload, Strategy, Variant,
     };
+    use unleash_types::client_metrics::{ClientMetricsEnv, MetricsMetadata};
     use unleash_yggdrasil::EngineState;
 
     use crate::builder::build_offline_mode;
     use crate::cli::{EdgeMode, OfflineArgs, TrustProxy};
+    use crate::http::broadcaster::Broadcaster;
+    use crate::http::feature_refresher::FeatureDelta;
     use crate::metrics::client_metrics::MetricsCache;
     use crate::metrics::client_metrics::MetricsKey;
     use crate::middleware;
-    use crate::types::{EdgeToken, TokenType, TokenValidationStatus};
+    use crate::persistence::file::FilePersister;
+    use crate::persistence::EdgePersistence;
+    use crate::types::{
+        EdgeToken, EvaluatedToggle, EvaluatedVariant, FrontendResult, TokenType,
+        TokenValidationStatus,
+    };
 
     async fn make_test_request() -> Request {
         make_test_request_to("/api/proxy/client/metrics").await
@@ -966,6 +1239,114 @@ mod tests {
         assert!(result.toggles.first().unwrap().enabled)
     }
 
+    #[actix_web::test]
+    async fn variants_say_whether_their_feature_is_enabled() {
+        let variant = |name: &str| Variant {
+            name: name.into(),
+            weight: 1000,
+            weight_type: None,
+            stickiness: Some("default".into()),
+            payload: Some(Payload {
+                payload_type: "string".into(),
+                value: format!("{name} payload"),
+            }),
+            overrides: None,
+        };
+        let default_strategy = Strategy {
+            name: "default".into(),
+            sort_order: None,
+            segments: None,
+            variants: None,
+            constraints: None,
+            parameters: None,
+        };
+        let features = ClientFeatures {
+            version: 2,
+            features: vec![
+                ClientFeature {
+                    name: "with-variants".into(),
+                    enabled: true,
+                    strategies: Some(vec![default_strategy.clone()]),
+                    variants: Some(vec![variant("blue")]),
+                    ..ClientFeature::default()
+                },
+                ClientFeature {
+                    name: "disabled-with-variants".into(),
+                    enabled: false,
+                    strategies: Some(vec![default_strategy.clone()]),
+                    variants: Some(vec![variant("red")]),
+                    ..ClientFeature::default()
+                },
+                ClientFeature {
+                    name: "without-variants".into(),
+                    enabled: true,
+                    strategies: Some(vec![default_strategy]),
+                    ..ClientFeature::default()
+                },
+            ],
+            segments: None,
+            query: None,
+        };
+        let token = "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7";
+        let (token_cache, feature_cache, engine_cache) =
+            build_offline_mode(features, vec![token.to_string()]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(web::scope("/api/frontend").service(super::post_frontend_all_features)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/api/frontend/all")
+            .insert_header(ContentType::json())
+            .insert_header(("Authorization", token))
+            .set_json(json!({ "userId": "7" }))
+            .to_request();
+
+        let result: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let variant_of = |name: &str| {
+            result["toggles"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|toggle| toggle["name"] == name)
+                .map(|toggle| toggle["variant"].clone())
+                .unwrap()
+        };
+        assert_eq!(
+            variant_of("with-variants"),
+            json!({
+                "name": "blue",
+                "enabled": true,
+                "payload": { "type": "string", "value": "blue payload" },
+                "feature_enabled": true,
+                "featureEnabled": true
+            })
+        );
+        assert_eq!(
+            variant_of("disabled-with-variants"),
+            json!({
+                "name": "disabled",
+                "enabled": false,
+                "payload": null,
+                "feature_enabled": false,
+                "featureEnabled": false
+            })
+        );
+        assert_eq!(
+            variant_of("without-variants"),
+            json!({
+                "name": "disabled",
+                "enabled": false,
+                "payload": null,
+                "feature_enabled": true,
+                "featureEnabled": true
+            })
+        );
+    }
+
     #[actix_web::test]
     #[traced_test]
     async fn calling_get_requests_resolves_context_values_correctly() {
@@ -1005,6 +1386,8 @@ mod tests {
                     name: "disabled".into(),
                     enabled: false,
                     payload: None,
+                    feature_enabled: true,
+                    featureEnabled: true,
                 },
                 impression_data: false,
                 impressionData: false,
@@ -1014,6 +1397,394 @@ mod tests {
         assert_eq!(result, serde_json::to_vec(&expected).unwrap());
     }
 
+    #[actix_web::test]
+    async fn impression_data_survives_persistence_and_reaches_frontend_evaluations() {
+        let mut features = client_features_with_constraint_requiring_user_id_of_seven();
+        features.features[0].impression_data = Some(true);
+        let persister = FilePersister::new(
+            &std::env::temp_dir()
+                .join("impression_data_survives_persistence_and_reaches_frontend_evaluations"),
+        );
+        persister
+            .save_features(vec![("development".into(), features)])
+            .await
+            .unwrap();
+        let restored = persister
+            .load_features()
+            .await
+            .unwrap()
+            .remove("development")
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&restored).unwrap()["features"][0]["impressionData"],
+            json!(true)
+        );
+
+        let token = "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7";
+        let (token_cache, feature_cache, engine_cache) =
+            build_offline_mode(restored, vec![token.to_string()]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(web::scope("/api/frontend").service(super::get_frontend_all_features)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/frontend/all?userId=7")
+            .insert_header(("Authorization", token))
+            .to_request();
+        let result: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(result["toggles"][0]["impressionData"], json!(true));
+        assert_eq!(result["toggles"][0]["impression_data"], json!(true));
+    }
+
+    #[actix_web::test]
+    async fn proxy_evaluates_context_against_the_engine_for_the_tokens_environment() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let development_features = client_features_with_constraint_requiring_user_id_of_seven();
+        let mut production_features = development_features.clone();
+        production_features.features[0].enabled = false;
+        for (environment, features) in [
+            ("development", development_features),
+            ("production", production_features),
+        ] {
+            let mut token = EdgeToken::from_str(&format!("*:{environment}.secret123")).unwrap();
+            token.status = TokenValidationStatus::Validated;
+            token.token_type = Some(TokenType::Frontend);
+            token_cache.insert(token.token.clone(), token);
+            let mut engine = EngineState::default();
+            engine.take_state(features);
+            engine_cache.insert(environment.into(), engine);
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(engine_cache))
+                .service(
+                    web::scope("/api/proxy")
+                        .service(super::get_proxy_all_features)
+                        .service(super::post_proxy_all_features)
+                        .service(super::get_enabled_proxy)
+                        .service(super::post_proxy_enabled_features),
+                ),
+        )
+        .await;
+
+        for (environment, expected_enabled) in [("development", true), ("production", false)] {
+            let token = format!("*:{environment}.secret123");
+            let get = test::TestRequest::get()
+                .uri("/api/proxy/all?userId=7")
+                .insert_header(("Authorization", token.clone()))
+                .to_request();
+            let post = test::TestRequest::post()
+                .uri("/api/proxy/all")
+                .insert_header(("Authorization", token.clone()))
+                .set_json(json!({ "userId": "7" }))
+                .to_request();
+            for req in [get, post] {
+                let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+                assert_eq!(result.toggles.len(), 1);
+                assert_eq!(result.toggles[0].enabled, expected_enabled);
+            }
+
+            let get = test::TestRequest::get()
+                .uri("/api/proxy?userId=7")
+                .insert_header(("Authorization", token.clone()))
+                .to_request();
+            let post = test::TestRequest::post()
+                .uri("/api/proxy")
+                .insert_header(("Authorization", token.clone()))
+                .set_json(json!({ "userId": "7" }))
+                .to_request();
+            for req in [get, post] {
+                let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+                let enabled: Vec<&str> = result.toggles.iter().map(|t| t.name.as_str()).collect();
+                let expected: Vec<&str> = if expected_enabled {
+                    vec!["test"]
+                } else {
+                    vec![]
+                };
+                assert_eq!(enabled, expected);
+            }
+        }
+    }
+
+    fn rollout_feature(name: &str, strategy: &str, parameters: &[(&str, &str)]) -> ClientFeature {
+        ClientFeature {
+            name: name.into(),
+            enabled: true,
+            strategies: Some(vec![Strategy {
+                name: strategy.into(),
+                sort_order: None,
+                segments: None,
+                variants: None,
+                constraints: None,
+                parameters: Some(
+                    parameters
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+            }]),
+            ..ClientFeature::default()
+        }
+    }
+
+    /// The Unleash server and SDKs put a context with userId 123 in group `gr1` at 73 and userId 999 in group `groupX` at
+    /// 25 (murmur3 of `groupId:stickiness`, as in the client specification). Edge has to agree, or the same user would
+    /// see a feature flip depending on whether it's evaluated by Edge or an SDK
+    #[actix_web::test]
+    async fn rollouts_hash_the_context_like_unleash_and_the_sdks() {
+        let features = ClientFeatures {
+            version: 1,
+            features: vec![
+                rollout_feature(
+                    "flexible-at-73",
+                    "flexibleRollout",
+                    &[
+                        ("rollout", "73"),
+                        ("stickiness", "userId"),
+                        ("groupId", "gr1"),
+                    ],
+                ),
+                rollout_feature(
+                    "flexible-at-72",
+                    "flexibleRollout",
+                    &[
+                        ("rollout", "72"),
+                        ("stickiness", "userId"),
+                        ("groupId", "gr1"),
+                    ],
+                ),
+                rollout_feature(
+                    "flexible-default-stickiness-at-25",
+                    "flexibleRollout",
+                    &[
+                        ("rollout", "25"),
+                        ("stickiness", "default"),
+                        ("groupId", "groupX"),
+                    ],
+                ),
+                rollout_feature(
+                    "flexible-default-stickiness-at-24",
+                    "flexibleRollout",
+                    &[
+                        ("rollout", "24"),
+                        ("stickiness", "default"),
+                        ("groupId", "groupX"),
+                    ],
+                ),
+                rollout_feature(
+                    "gradual-user-at-73",
+                    "gradualRolloutUserId",
+                    &[("percentage", "73"), ("groupId", "gr1")],
+                ),
+                rollout_feature(
+                    "gradual-user-at-72",
+                    "gradualRolloutUserId",
+                    &[("percentage", "72"), ("groupId", "gr1")],
+                ),
+                rollout_feature(
+                    "gradual-session-at-25",
+                    "gradualRolloutSessionId",
+                    &[("percentage", "25"), ("groupId", "groupX")],
+                ),
+                rollout_feature(
+                    "gradual-session-at-24",
+                    "gradualRolloutSessionId",
+                    &[("percentage", "24"), ("groupId", "groupX")],
+                ),
+                rollout_feature("user-with-id", "userWithId", &[("userIds", "123, 456")]),
+            ],
+            segments: None,
+            query: None,
+        };
+        let (token_cache, feature_cache, engine_cache) =
+            build_offline_mode(features, vec!["*:development.secret123".to_string()]).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(
+                    web::scope("/api/frontend")
+                        .service(super::get_frontend_all_features)
+                        .service(super::post_frontend_all_features),
+                ),
+        )
+        .await;
+
+        let user_123 = [
+            ("flexible-at-73", true),
+            ("flexible-at-72", false),
+            ("gradual-user-at-73", true),
+            ("gradual-user-at-72", false),
+            ("user-with-id", true),
+        ];
+        let user_999 = [
+            ("flexible-default-stickiness-at-25", true),
+            ("flexible-default-stickiness-at-24", false),
+            ("user-with-id", false),
+        ];
+        let session_999 = [
+            ("flexible-default-stickiness-at-25", true),
+            ("flexible-default-stickiness-at-24", false),
+            ("gradual-session-at-25", true),
+            ("gradual-session-at-24", false),
+        ];
+        for (field, value, expectations) in [
+            ("userId", "123", &user_123[..]),
+            ("userId", "999", &user_999[..]),
+            ("sessionId", "999", &session_999[..]),
+        ] {
+            for req in [
+                test::TestRequest::get()
+                    .uri(&format!("/api/frontend/all?{field}={value}"))
+                    .insert_header(("Authorization", "*:development.secret123"))
+                    .to_request(),
+                test::TestRequest::post()
+                    .uri("/api/frontend/all")
+                    .insert_header(("Authorization", "*:development.secret123"))
+                    .set_json(json!({ field: value }))
+                    .to_request(),
+            ] {
+                let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+                for (feature, expected_enabled) in expectations {
+                    let toggle = result.toggles.iter().find(|toggle| &toggle.name == feature);
+                    assert_eq!(
+                        toggle.is_some_and(|toggle| toggle.enabled),
+                        *expected_enabled,
+                        "{feature} for {field} {value}"
+                    );
+                }
+            }
+        }
+    }
+
+    fn features_with_dependencies() -> ClientFeatures {
+        serde_json::from_value(json!({
+            "version": 2,
+            "features": [
+                { "name": "parent", "enabled": true, "strategies": [{ "name": "default" }] },
+                { "name": "disabled-parent", "enabled": false, "strategies": [{ "name": "default" }] },
+                {
+                    "name": "child",
+                    "enabled": true,
+                    "strategies": [{ "name": "default" }],
+                    "dependencies": [{ "feature": "parent" }]
+                },
+                {
+                    "name": "child-of-disabled-parent",
+                    "enabled": true,
+                    "strategies": [{ "name": "default" }],
+                    "dependencies": [{ "feature": "disabled-parent" }]
+                },
+                {
+                    "name": "child-while-parent-is-disabled",
+                    "enabled": true,
+                    "strategies": [{ "name": "default" }],
+                    "dependencies": [{ "feature": "disabled-parent", "enabled": false }]
+                }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn dependent_features_are_only_enabled_when_their_parent_is_as_required() {
+        let (token_cache, feature_cache, engine_cache) = build_offline_mode(
+            features_with_dependencies(),
+            vec!["*:development.secret123".to_string()],
+        )
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(
+                    web::scope("/api/proxy")
+                        .service(super::get_proxy_all_features)
+                        .service(super::get_enabled_proxy),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/proxy/all")
+            .insert_header(("Authorization", "*:development.secret123"))
+            .to_request();
+        let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+        let enabled = |name: &str| {
+            result
+                .toggles
+                .iter()
+                .find(|toggle| toggle.name == name)
+                .unwrap()
+                .enabled
+        };
+        assert!(enabled("parent"));
+        assert!(!enabled("disabled-parent"));
+        assert!(enabled("child"));
+        assert!(!enabled("child-of-disabled-parent"));
+        assert!(enabled("child-while-parent-is-disabled"));
+
+        let req = test::TestRequest::get()
+            .uri("/api/proxy")
+            .insert_header(("Authorization", "*:development.secret123"))
+            .to_request();
+        let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+        let mut enabled: Vec<&str> = result.toggles.iter().map(|t| t.name.as_str()).collect();
+        enabled.sort();
+        assert_eq!(
+            enabled,
+            vec!["child", "child-while-parent-is-disabled", "parent"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn frontend_tokens_are_refused_the_raw_features() {
+        let (token_cache, feature_cache, engine_cache) = build_offline_mode(
+            client_features_with_constraint_requiring_user_id_of_seven(),
+            vec!["*:development.secret123".to_string()],
+        )
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(
+                    web::scope("/api/frontend")
+                        .service(super::get_enabled_frontend)
+                        .service(super::get_frontend_all_features),
+                ),
+        )
+        .await;
+
+        for (uri, accept) in [
+            ("/api/frontend?format=raw", "application/json"),
+            ("/api/frontend/all", "application/vnd.unleash.raw+json"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("Accept", accept))
+                .insert_header(("Authorization", "*:development.secret123"))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        }
+        let evaluated = test::TestRequest::get()
+            .uri("/api/frontend?format=evaluated&userId=7")
+            .insert_header(("Authorization", "*:development.secret123"))
+            .to_request();
+        let result: FrontendResult = test::call_and_read_body_json(&app, evaluated).await;
+        assert_eq!(result.toggles.len(), 1);
+    }
+
     #[actix_web::test]
     #[traced_test]
     async fn calling_get_requests_resolves_top_level_properties_correctly() {
@@ -1063,6 +1834,8 @@ mod tests {
                     name: "disabled".into(),
                     enabled: false,
                     payload: None,
+                    feature_enabled: true,
+                    featureEnabled: true,
                 },
                 impression_data: false,
                 impressionData: false,
@@ -1119,6 +1892,8 @@ mod tests {
                     name: "disabled".into(),
                     enabled: false,
                     payload: None,
+                    feature_enabled: true,
+                    featureEnabled: true,
                 },
                 impression_data: false,
                 impressionData: false,
@@ -1161,6 +1936,154 @@ mod tests {
         assert_eq!(result.toggles.len(), 1);
     }
 
+    async fn next_event(body: &mut Pin<&mut impl MessageBody>) -> String {
+        let chunk = futures::future::poll_fn(|cx| body.as_mut().poll_next(cx))
+            .await
+            .unwrap()
+            .ok()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn stream_sends_snapshot_and_then_changed_toggles() {
+        let (token_cache, features_cache, engine_cache) = build_offline_mode(
+            client_features_with_constraint_one_enabled_toggle_and_one_disabled_toggle(),
+            vec![
+                "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7"
+                    .to_string(),
+            ],
+        )
+        .unwrap();
+        let broadcaster = Broadcaster::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(features_cache))
+                .app_data(Data::from(engine_cache.clone()))
+                .app_data(Data::new(broadcaster.clone()))
+                .service(web::scope("/api/frontend").service(super::stream_frontend_features)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/frontend/stream")
+            .insert_header((
+                "Authorization",
+                "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+            ))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+        let body = response.into_body();
+        let mut body = pin!(body);
+
+        let snapshot = next_event(&mut body).await;
+        assert!(snapshot.starts_with("event: snapshot\n"));
+        assert!(snapshot.contains(r#""name":"test""#));
+        assert!(!snapshot.contains(r#""name":"test2""#));
+
+        let mut flipped =
+            client_features_with_constraint_one_enabled_toggle_and_one_disabled_toggle();
+        flipped
+            .features
+            .iter_mut()
+            .for_each(|feature| feature.enabled = !feature.enabled);
+        let mut engine = EngineState::default();
+        engine.take_state(flipped);
+        engine_cache.insert("development".into(), engine);
+        let delta = FeatureDelta {
+            changed: vec!["test".into(), "test2".into()],
+            ..Default::default()
+        };
+        broadcaster.notify("production", delta.clone());
+        broadcaster.notify("development", delta);
+
+        let update = next_event(&mut body).await;
+        let data: serde_json::Value =
+            serde_json::from_str(update.strip_prefix("event: update\ndata: ").unwrap().trim())
+                .unwrap();
+        assert_eq!(data["updated"].as_array().unwrap().len(), 1);
+        assert_eq!(data["updated"][0]["name"], "test2");
+        assert_eq!(data["removed"], json!(["test"]));
+    }
+
+    #[actix_web::test]
+    async fn streams_that_fall_behind_are_resynced_with_a_snapshot() {
+        let token = "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7";
+        let (token_cache, features_cache, engine_cache) = build_offline_mode(
+            client_features_with_constraint_one_enabled_toggle_and_one_disabled_toggle(),
+            vec![token.to_string()],
+        )
+        .unwrap();
+        let broadcaster = Broadcaster::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(features_cache))
+                .app_data(Data::from(engine_cache))
+                .app_data(Data::new(broadcaster.clone()))
+                .service(web::scope("/api/frontend").service(super::stream_frontend_features)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/frontend/stream")
+            .insert_header(("Authorization", token))
+            .to_request();
+        let body = test::call_service(&app, req).await.into_body();
+        let mut body = pin!(body);
+        assert!(next_event(&mut body).await.starts_with("event: snapshot\n"));
+
+        // Nothing changed, but the stream can't know that once it has missed notifications
+        for _ in 0..1000 {
+            broadcaster.notify("development", FeatureDelta::default());
+        }
+
+        let resync = next_event(&mut body).await;
+        assert!(resync.starts_with("event: snapshot\n"));
+        assert!(resync.contains(r#""name":"test""#));
+    }
+
+    #[actix_web::test]
+    async fn stream_rejects_subscribers_above_the_limit() {
+        let (token_cache, features_cache, engine_cache) = build_offline_mode(
+            client_features_with_constraint_one_enabled_toggle_and_one_disabled_toggle(),
+            vec![
+                "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7"
+                    .to_string(),
+            ],
+        )
+        .unwrap();
+        let broadcaster = Broadcaster::default().with_max_subscribers(1);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(features_cache))
+                .app_data(Data::from(engine_cache))
+                .app_data(Data::new(broadcaster.clone()))
+                .service(web::scope("/api/frontend").service(super::stream_frontend_features)),
+        )
+        .await;
+        let stream_request = || {
+            test::TestRequest::get()
+                .uri("/api/frontend/stream")
+                .insert_header((
+                    "Authorization",
+                    "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+                ))
+                .to_request()
+        };
+        let first = test::call_service(&app, stream_request()).await;
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = test::call_service(&app, stream_request()).await;
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(first);
+        assert_eq!(broadcaster.subscriber_count(), 0);
+    }
+
     #[actix_web::test]
     async fn frontend_metrics_endpoint_correctly_aggregates_data() {
         let metrics_cache = Arc::new(MetricsCache::default());
@@ -1551,6 +2474,72 @@ mod tests {
         assert_eq!(result.toggles.len(), 1);
     }
 
+    #[tokio::test]
+    async fn browser_sdk_gets_and_posts_evaluate_the_same_context() {
+        let mut features = client_features_with_constraint_requiring_user_id_of_seven();
+        features.features[0].strategies.as_mut().unwrap()[0]
+            .constraints
+            .as_mut()
+            .unwrap()
+            .push(Constraint {
+                context_name: "tags".into(),
+                operator: Operator::In,
+                case_insensitive: false,
+                inverted: false,
+                values: Some(vec!["beta,eu".into()]),
+                value: None,
+            });
+        let auth_key = "default:development.secret123".to_string();
+        let (token_cache, feature_cache, engine_cache) =
+            build_offline_mode(features, vec![auth_key.clone()]).unwrap();
+        let config =
+            serde_qs::actix::QsQueryConfig::default().qs_config(serde_qs::Config::new(5, false));
+        let app = test::init_service(
+            App::new()
+                .app_data(config)
+                .app_data(Data::from(token_cache))
+                .app_data(Data::from(feature_cache))
+                .app_data(Data::from(engine_cache))
+                .service(
+                    web::scope("/api").configure(|cfg| super::configure_frontend_api(cfg, false)),
+                ),
+        )
+        .await;
+        let get = test::TestRequest::get()
+            .uri("/api/frontend?appName=web&environment=default&userId=7&sessionId=1234&properties%5Btags%5D%5B%5D=beta&properties%5Btags%5D%5B%5D=eu")
+            .insert_header(("Authorization", auth_key.clone()))
+            .to_request();
+        let post = test::TestRequest::post()
+            .uri("/api/frontend?appName=web")
+            .insert_header(("Authorization", auth_key.clone()))
+            .set_json(json!({
+                "context": {
+                    "environment": "default",
+                    "userId": 7,
+                    "sessionId": "1234",
+                    "properties": {"tags": ["beta", "eu"]}
+                }
+            }))
+            .to_request();
+        let other_user = test::TestRequest::post()
+            .uri("/api/frontend")
+            .insert_header(("Authorization", auth_key.clone()))
+            .set_json(json!({"context": {"userId": 8, "properties": {"tags": ["beta", "eu"]}}}))
+            .to_request();
+        for req in [get, post] {
+            let result: FrontendResult = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(result.toggles.len(), 1);
+        }
+        let all = test::TestRequest::get()
+            .uri("/api/frontend/all?userId=7&properties[tags][]=beta&properties[tags][]=eu")
+            .insert_header(("Authorization", auth_key.clone()))
+            .to_request();
+        let result: FrontendResult = test::call_and_read_body_json(&app, all).await;
+        assert!(result.toggles[0].enabled);
+        let result: FrontendResult = test::call_and_read_body_json(&app, other_user).await;
+        assert!(result.toggles.is_empty());
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn will_evaluate_ip_strategy_populated_from_middleware() {
@@ -1,17 +1,20 @@
 use std::cmp::max;
 use std::sync::Arc;
 
-use chrono::Duration;
-use dashmap::DashMap;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::{DashMap, DashSet};
+use futures::future::join_all;
 use lazy_static::lazy_static;
 use prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec, Opts};
 use reqwest::StatusCode;
-use tracing::{error, info, trace, warn};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace, warn};
+use unleash_types::client_metrics::ClientMetricsEnv;
 
-use crate::types::TokenRefresh;
+use crate::types::{EdgeResult, TokenRefresh};
 use crate::{
     error::EdgeError,
-    metrics::client_metrics::{size_of_batch, MetricsCache},
+    metrics::client_metrics::{size_of_batch, MetricsBatch, MetricsCache},
 };
 
 use super::feature_refresher::FeatureRefresher;
@@ -48,6 +51,11 @@ lazy_static! {
         "Interval between sending metrics"
     ))
     .unwrap();
+    pub static ref METRICS_UPSTREAM_EXPIRED: IntGauge = register_int_gauge!(Opts::new(
+        "metrics_upstream_expired",
+        "Number of metrics dropped because they could not be sent upstream before reaching max age"
+    ))
+    .unwrap();
 }
 
 fn decide_where_to_post(
@@ -67,101 +75,356 @@ fn decide_where_to_post(
     }
 }
 
+/// Upstream being busy is handled by backing off the whole send interval, so we don't hammer it with immediate retries
+fn is_retriable(error: &EdgeError) -> bool {
+    match error {
+        EdgeError::EdgeMetricsRequestError(status_code, _) => matches!(
+            *status_code,
+            StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        EdgeError::EdgeMetricsError => true,
+        _ => false,
+    }
+}
+
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    std::time::Duration::from_millis(500 * 2u64.pow(attempt.min(5)))
+}
+
+async fn post_batch(
+    feature_refresher: &FeatureRefresher,
+    batch: &MetricsBatch,
+    use_new_endpoint: bool,
+    token: &str,
+) -> EdgeResult<()> {
+    if use_new_endpoint {
+        feature_refresher
+            .unleash_client
+            .send_bulk_metrics_to_client_endpoint(batch.clone(), token)
+            .await
+    } else {
+        feature_refresher
+            .unleash_client
+            .send_batch_metrics(batch.clone())
+            .await
+    }
+}
+
+async fn post_batch_with_retries(
+    feature_refresher: &FeatureRefresher,
+    batch: &MetricsBatch,
+    use_new_endpoint: bool,
+    token: &str,
+    retries: u32,
+) -> EdgeResult<()> {
+    let mut attempt = 0;
+    loop {
+        match post_batch(feature_refresher, batch, use_new_endpoint, token).await {
+            Err(e) if attempt < retries && is_retriable(&e) => {
+                debug!(
+                    "Posting metrics failed with {e:?}, retrying ({}/{retries})",
+                    attempt + 1
+                );
+                tokio::time::sleep(retry_delay(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Keeps unsent metrics around for the next cycle unless their bucket is older than `max_age`, which bounds how much an unreachable upstream can make us hold on to
+fn reinsert_unexpired(metrics_cache: &MetricsCache, batch: MetricsBatch, max_age: Duration) {
+    let cutoff = Utc::now() - max_age;
+    let (metrics, expired): (Vec<ClientMetricsEnv>, Vec<ClientMetricsEnv>) = batch
+        .metrics
+        .into_iter()
+        .partition(|metric| metric.timestamp >= cutoff);
+    if !expired.is_empty() {
+        warn!(
+            "Dropping {} metrics that were older than {} seconds and still not accepted upstream",
+            expired.len(),
+            max_age.num_seconds()
+        );
+        METRICS_UPSTREAM_EXPIRED.add(expired.len() as i64);
+    }
+    metrics_cache.reinsert_batch(MetricsBatch {
+        applications: batch.applications,
+        metrics,
+    });
+}
+
+/// Updates the failure count after a batch couldn't be posted, and keeps the metrics for another attempt if upstream might take them later
+fn handle_send_failure(
+    edge_error: EdgeError,
+    batch: MetricsBatch,
+    metrics_cache: &MetricsCache,
+    environment: &str,
+    failures: i64,
+    max_age: Duration,
+) -> i64 {
+    match edge_error {
+        EdgeError::EdgeMetricsRequestError(status_code, message) => {
+            METRICS_UPSTREAM_HTTP_ERRORS
+                .with_label_values(&[status_code.as_str()])
+                .inc();
+            match status_code {
+                StatusCode::PAYLOAD_TOO_LARGE => {
+                    error!(
+                        "Metrics for {environment} were too large. They were {}",
+                        size_of_batch(&batch)
+                    );
+                    failures
+                }
+                StatusCode::BAD_REQUEST => {
+                    error!("Unleash said [{message:?}]. Dropping this metric bucket for {environment} to avoid consuming too much memory");
+                    failures
+                }
+                StatusCode::NOT_FOUND => {
+                    error!("Upstream said we are trying to post metrics for {environment} to an endpoint that doesn't exist. Backing off");
+                    10
+                }
+                StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => {
+                    error!("Upstream said we were not allowed to post metrics for {environment}. Backing off");
+                    10
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    info!("Upstream said it was too busy to take metrics for {environment}. Backing off");
+                    reinsert_unexpired(metrics_cache, batch, max_age);
+                    max(10, failures + 1)
+                }
+                StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT => {
+                    info!("Upstream said it is struggling. It returned Http status {status_code} for metrics for {environment}. Backing off");
+                    reinsert_unexpired(metrics_cache, batch, max_age);
+                    max(10, failures + 1)
+                }
+                _ => {
+                    warn!("Failed to send metrics for {environment}. Status code was {status_code}. Will reinsert metrics for next attempt");
+                    reinsert_unexpired(metrics_cache, batch, max_age);
+                    failures
+                }
+            }
+        }
+        EdgeError::UpstreamCircuitOpen(_) => {
+            info!("Not sending metrics for {environment} while the circuit breaker is open. Backing off");
+            reinsert_unexpired(metrics_cache, batch, max_age);
+            max(10, failures + 1)
+        }
+        EdgeError::EdgeMetricsError => {
+            warn!("Could not reach upstream to send metrics for {environment}. Will reinsert metrics for next attempt");
+            METRICS_UNEXPECTED_ERRORS.inc();
+            reinsert_unexpired(metrics_cache, batch, max_age);
+            failures
+        }
+        _ => {
+            warn!("Failed to send metrics for {environment}: {edge_error:?}");
+            METRICS_UNEXPECTED_ERRORS.inc();
+            failures
+        }
+    }
+}
+
+/// How sending metrics for one environment is going, so an environment upstream is failing for backs off on its own
+#[derive(Clone, Copy, Debug)]
+struct EnvironmentSendState {
+    failures: i64,
+    next_send: DateTime<Utc>,
+}
+
+/// Posts the metrics of each environment in its own task. An environment is skipped while its previous post is still
+/// in flight or it's backing off, and its metrics stay in the cache for a later round
+#[derive(Clone)]
+struct MetricsSender {
+    metrics_cache: Arc<MetricsCache>,
+    feature_refresher: Arc<FeatureRefresher>,
+    send_interval: i64,
+    retries: u32,
+    max_age: Duration,
+    send_states: Arc<DashMap<String, EnvironmentSendState>>,
+    in_flight: Arc<DashSet<String>>,
+}
+
+impl MetricsSender {
+    fn send_due_environments(&self) -> Vec<JoinHandle<()>> {
+        let now = Utc::now();
+        let mut sends = vec![];
+        for (environment, batch) in self.metrics_cache.get_metrics_by_environment() {
+            let due = self
+                .send_states
+                .get(&environment)
+                .is_none_or(|state| state.next_send <= now);
+            if !due || !self.in_flight.insert(environment.clone()) {
+                trace!(
+                    "Not posting metrics for {environment} yet, it's backing off or still posting"
+                );
+                continue;
+            }
+            let batches = self
+                .metrics_cache
+                .get_appropriately_sized_env_batches(&batch);
+            let sender = self.clone();
+            sends.push(tokio::spawn(async move {
+                sender.send_environment(&environment, batches).await;
+                sender.in_flight.remove(&environment);
+            }));
+        }
+        sends
+    }
+
+    async fn send_environment(&self, environment: &String, batches: Vec<MetricsBatch>) {
+        let failures = self
+            .send_states
+            .get(environment)
+            .map_or(0, |state| state.failures);
+        trace!("Posting {} batches for {environment}", batches.len());
+        let (failures, _) = self
+            .send_batches(environment, batches, failures, None)
+            .await;
+        let backoff =
+            new_interval(self.send_interval, failures) - Duration::seconds(self.send_interval);
+        if failures > 0 {
+            info!(
+                "Backing off posting metrics for {environment} to every {} seconds",
+                new_interval(self.send_interval, failures).num_seconds()
+            );
+        }
+        self.send_states.insert(
+            environment.clone(),
+            EnvironmentSendState {
+                failures,
+                next_send: Utc::now() + backoff,
+            },
+        );
+    }
+
+    /// Posts the batches one after the other, counting failures on from `failures`. A batch still being posted at
+    /// `deadline` goes back in the cache. The failures afterwards, and whether every batch was posted
+    async fn send_batches(
+        &self,
+        environment: &String,
+        batches: Vec<MetricsBatch>,
+        mut failures: i64,
+        deadline: Option<tokio::time::Instant>,
+    ) -> (i64, bool) {
+        let (use_new_endpoint, token) = decide_where_to_post(
+            environment,
+            self.feature_refresher.tokens_to_refresh.clone(),
+        );
+        let mut sent = true;
+        for batch in batches {
+            if batch.applications.is_empty() && batch.metrics.is_empty() {
+                continue;
+            }
+            let post = post_batch_with_retries(
+                &self.feature_refresher,
+                &batch,
+                use_new_endpoint,
+                &token,
+                self.retries,
+            );
+            let result = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, post).await,
+                None => Ok(post.await),
+            };
+            failures = match result {
+                Ok(Ok(())) => max(0, failures - 1),
+                Ok(Err(edge_error)) => {
+                    sent = false;
+                    handle_send_failure(
+                        edge_error,
+                        batch,
+                        &self.metrics_cache,
+                        environment,
+                        failures,
+                        self.max_age,
+                    )
+                }
+                Err(_) => {
+                    sent = false;
+                    warn!("Sending metrics for {environment} did not finish in time");
+                    reinsert_unexpired(&self.metrics_cache, batch, self.max_age);
+                    failures
+                }
+            };
+        }
+        (failures, sent)
+    }
+
+    fn longest_interval(&self) -> i64 {
+        let failures = self
+            .send_states
+            .iter()
+            .map(|state| state.failures)
+            .max()
+            .unwrap_or(0);
+        new_interval(self.send_interval, failures).num_seconds()
+    }
+}
+
 pub async fn send_metrics_task(
     metrics_cache: Arc<MetricsCache>,
     feature_refresher: Arc<FeatureRefresher>,
     send_interval: i64,
+    retries: u32,
+    max_age: Duration,
 ) {
-    let mut failures = 0;
-    let mut interval = Duration::seconds(send_interval);
+    let sender = MetricsSender {
+        metrics_cache,
+        feature_refresher,
+        send_interval,
+        retries,
+        max_age,
+        send_states: Default::default(),
+        in_flight: Default::default(),
+    };
     loop {
         trace!("Looping metrics");
-        let envs = metrics_cache.get_metrics_by_environment();
-        for (env, batch) in envs.iter() {
-            let (use_new_endpoint, token) =
-                decide_where_to_post(env, feature_refresher.tokens_to_refresh.clone());
-            let batches = metrics_cache.get_appropriately_sized_env_batches(batch);
-            trace!("Posting {} batches for {env}", batches.len());
-            for batch in batches {
-                if !batch.applications.is_empty() || !batch.metrics.is_empty() {
-                    let result = if use_new_endpoint {
-                        feature_refresher
-                            .unleash_client
-                            .send_bulk_metrics_to_client_endpoint(batch.clone(), &token)
-                            .await
-                    } else {
-                        feature_refresher
-                            .unleash_client
-                            .send_batch_metrics(batch.clone())
-                            .await
-                    };
-                    if let Err(edge_error) = result {
-                        match edge_error {
-                            EdgeError::EdgeMetricsRequestError(status_code, message) => {
-                                METRICS_UPSTREAM_HTTP_ERRORS
-                                    .with_label_values(&[status_code.as_str()])
-                                    .inc();
-                                match status_code {
-                                    StatusCode::PAYLOAD_TOO_LARGE => error!(
-                                        "Metrics were too large. They were {}",
-                                        size_of_batch(&batch)
-                                    ),
-                                    StatusCode::BAD_REQUEST => {
-                                        error!("Unleash said [{message:?}]. Dropping this metric bucket to avoid consuming too much memory");
-                                    }
-                                    StatusCode::NOT_FOUND => {
-                                        failures = 10;
-                                        interval = new_interval(send_interval, failures);
-                                        error!("Upstream said we are trying to post to an endpoint that doesn't exist. backing off to {} seconds", interval.num_seconds());
-                                    }
-                                    StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => {
-                                        failures = 10;
-                                        interval = new_interval(send_interval, failures);
-                                        error!("Upstream said we were not allowed to post metrics, backing off to {} seconds", interval.num_seconds());
-                                    }
-                                    StatusCode::TOO_MANY_REQUESTS => {
-                                        failures = max(10, failures + 1);
-                                        interval = new_interval(send_interval, failures);
-                                        info!(
-                                            "Upstream said it was too busy, backing off to {} seconds",
-                                            interval.num_seconds()
-                                        );
-                                        metrics_cache.reinsert_batch(batch);
-                                    }
-                                    StatusCode::INTERNAL_SERVER_ERROR
-                                    | StatusCode::BAD_GATEWAY
-                                    | StatusCode::SERVICE_UNAVAILABLE
-                                    | StatusCode::GATEWAY_TIMEOUT => {
-                                        failures = max(10, failures + 1);
-                                        interval = new_interval(send_interval, failures);
-                                        info!("Upstream said it is struggling. It returned Http status {}. Backing off to {} seconds", status_code, interval.num_seconds());
-                                        metrics_cache.reinsert_batch(batch);
-                                    }
-                                    _ => {
-                                        warn!("Failed to send metrics. Status code was {status_code}. Will reinsert metrics for next attempt");
-                                        metrics_cache.reinsert_batch(batch);
-                                    }
-                                }
-                            }
-                            _ => {
-                                warn!("Failed to send metrics: {edge_error:?}");
-                                METRICS_UNEXPECTED_ERRORS.inc();
-                            }
-                        }
-                    } else {
-                        failures = max(0, failures - 1);
-                        interval = new_interval(send_interval, failures);
-                    }
-                }
-            }
-        }
+        sender.send_due_environments();
         trace!(
-            "Done posting traces. Sleeping for {} seconds and then going again",
-            interval.num_seconds()
+            "Started posting metrics. Sleeping for {} seconds and then going again",
+            send_interval
         );
-        METRICS_INTERVAL_BETWEEN_SEND.set(interval.num_seconds());
-        tokio::time::sleep(std::time::Duration::from_secs(interval.num_seconds() as u64)).await;
+        METRICS_INTERVAL_BETWEEN_SEND.set(sender.longest_interval());
+        tokio::time::sleep(std::time::Duration::from_secs(send_interval as u64)).await;
+    }
+}
+
+/// Sends what's left in the metrics cache upstream one last time, for shutting down. Metrics that couldn't be sent before the
+/// timeout are put back in the cache, so they can be persisted instead. Returns whether everything was sent
+pub async fn flush_metrics(
+    metrics_cache: Arc<MetricsCache>,
+    feature_refresher: Arc<FeatureRefresher>,
+    max_age: Duration,
+    timeout: std::time::Duration,
+) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    // Shutting down, so there's no time for retries and no later send to back off for
+    let sender = MetricsSender {
+        metrics_cache: metrics_cache.clone(),
+        feature_refresher,
+        send_interval: 0,
+        retries: 0,
+        max_age,
+        send_states: Default::default(),
+        in_flight: Default::default(),
+    };
+    let mut flushes = vec![];
+    for (environment, batch) in metrics_cache.get_metrics_by_environment() {
+        let batches = metrics_cache.get_appropriately_sized_env_batches(&batch);
+        let sender = sender.clone();
+        flushes.push(async move {
+            sender
+                .send_batches(&environment, batches, 0, Some(deadline))
+                .await
+        });
     }
+    join_all(flushes).await.into_iter().all(|(_, sent)| sent)
 }
 
 fn new_interval(send_interval: i64, failures: i64) -> Duration {
@@ -171,11 +434,253 @@ fn new_interval(send_interval: i64, failures: i64) -> Duration {
 
 #[cfg(test)]
 mod tests {
-    use crate::http::background_send_metrics::new_interval;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_http::HttpService;
+    use actix_http_test::{test_server, TestServer};
+    use actix_service::map_config;
+    use actix_web::dev::AppConfig;
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+    use chrono::{Duration, Utc};
+    use unleash_types::client_metrics::{ClientMetricsEnv, MetricsMetadata};
+
+    use crate::http::background_send_metrics::{
+        flush_metrics, new_interval, post_batch_with_retries, reinsert_unexpired, MetricsSender,
+    };
+    use crate::http::feature_refresher::FeatureRefresher;
+    use crate::http::unleash_client::UnleashClient;
+    use crate::metrics::client_metrics::{MetricsBatch, MetricsCache};
+    use crate::types::{EdgeToken, TokenRefresh};
+
+    async fn upstream_failing_first(failures: usize, calls: Arc<AtomicUsize>) -> TestServer {
+        test_server(move || {
+            let calls = calls.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/edge/metrics",
+                    web::post().to(move || {
+                        let calls = calls.clone();
+                        async move {
+                            if calls.fetch_add(1, Ordering::SeqCst) < failures {
+                                HttpResponse::ServiceUnavailable().finish()
+                            } else {
+                                HttpResponse::Accepted().finish()
+                            }
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await
+    }
+
+    fn metric_in(
+        environment: &str,
+        feature_name: &str,
+        timestamp: chrono::DateTime<Utc>,
+    ) -> ClientMetricsEnv {
+        ClientMetricsEnv {
+            feature_name: feature_name.into(),
+            app_name: "some-app".into(),
+            environment: environment.into(),
+            timestamp,
+            yes: 1,
+            no: 0,
+            variants: Default::default(),
+            metadata: MetricsMetadata {
+                platform_name: None,
+                platform_version: None,
+                sdk_version: None,
+                yggdrasil_version: None,
+            },
+        }
+    }
+
+    fn metric_at(feature_name: &str, timestamp: chrono::DateTime<Utc>) -> ClientMetricsEnv {
+        metric_in("development", feature_name, timestamp)
+    }
+
+    fn batch() -> MetricsBatch {
+        MetricsBatch {
+            applications: vec![],
+            metrics: vec![metric_at("some-feature", Utc::now())],
+        }
+    }
+
+    #[derive(Default)]
+    struct BulkCalls {
+        slow_started: AtomicUsize,
+        slow_finished: AtomicUsize,
+        fast: AtomicUsize,
+    }
+
+    /// Takes its time answering for the token of the slow environment
+    async fn upstream_slow_for_one_environment(calls: Arc<BulkCalls>) -> TestServer {
+        test_server(move || {
+            let calls = calls.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/metrics/bulk",
+                    web::post().to(move |req: HttpRequest| {
+                        let calls = calls.clone();
+                        async move {
+                            let token = req
+                                .headers()
+                                .get("Authorization")
+                                .unwrap()
+                                .to_str()
+                                .unwrap();
+                            if token.contains(":slow.") {
+                                calls.slow_started.fetch_add(1, Ordering::SeqCst);
+                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                                calls.slow_finished.fetch_add(1, Ordering::SeqCst);
+                            } else {
+                                calls.fast.fetch_add(1, Ordering::SeqCst);
+                            }
+                            HttpResponse::Accepted().finish()
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await
+    }
 
     #[tokio::test]
     pub async fn new_interval_does_not_overflow() {
         let metrics = new_interval(300, 10);
         assert!(metrics.num_seconds() < 3305);
     }
+
+    #[tokio::test]
+    pub async fn failed_posts_are_retried_within_the_budget() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = upstream_failing_first(2, calls.clone()).await;
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ));
+        let result = post_batch_with_retries(&refresher, &batch(), false, "", 2).await;
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    pub async fn gives_up_when_the_retry_budget_is_spent() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = upstream_failing_first(5, calls.clone()).await;
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ));
+        let result = post_batch_with_retries(&refresher, &batch(), false, "", 1).await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    pub async fn flushing_on_shutdown_sends_the_metrics_or_keeps_them_for_persisting() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = upstream_failing_first(1, calls.clone()).await;
+        let refresher = Arc::new(FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        )));
+        let cache = Arc::new(MetricsCache::default());
+        cache.sink_metrics(&[metric_in("development", "some-feature", Utc::now())]);
+
+        let flushed = flush_metrics(
+            cache.clone(),
+            refresher.clone(),
+            Duration::hours(1),
+            std::time::Duration::from_secs(5),
+        )
+        .await;
+        assert!(!flushed);
+        assert_eq!(cache.snapshot().metrics.len(), 1);
+
+        let flushed = flush_metrics(
+            cache.clone(),
+            refresher,
+            Duration::hours(1),
+            std::time::Duration::from_secs(5),
+        )
+        .await;
+        assert!(flushed);
+        assert!(cache.snapshot().metrics.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    pub fn reinserting_unsent_metrics_drops_those_older_than_max_age() {
+        let cache = MetricsCache::default();
+        let batch = MetricsBatch {
+            applications: vec![],
+            metrics: vec![
+                metric_at("fresh", Utc::now() - Duration::minutes(5)),
+                metric_at("stale", Utc::now() - Duration::hours(2)),
+            ],
+        };
+        reinsert_unexpired(&cache, batch, Duration::hours(1));
+        let kept: Vec<String> = cache
+            .metrics
+            .iter()
+            .map(|m| m.value().feature_name.clone())
+            .collect();
+        assert_eq!(kept, vec!["fresh".to_string()]);
+    }
+
+    #[tokio::test]
+    pub async fn a_slow_environment_does_not_hold_up_posting_the_others() {
+        let calls = Arc::new(BulkCalls::default());
+        let server = upstream_slow_for_one_environment(calls.clone()).await;
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ));
+        for token in ["*:slow.abc123", "*:fast.abc123"] {
+            let token = EdgeToken::validated_client_token(token);
+            refresher
+                .tokens_to_refresh
+                .insert(token.token.clone(), TokenRefresh::new(token, None));
+        }
+        let metrics_cache = Arc::new(MetricsCache::default());
+        metrics_cache.sink_metrics(&[
+            metric_in("slow", "some-feature", Utc::now()),
+            metric_in("fast", "some-feature", Utc::now()),
+        ]);
+        let sender = MetricsSender {
+            metrics_cache: metrics_cache.clone(),
+            feature_refresher: Arc::new(refresher),
+            send_interval: 1,
+            retries: 0,
+            max_age: Duration::hours(1),
+            send_states: Default::default(),
+            in_flight: Default::default(),
+        };
+
+        let sends = sender.send_due_environments();
+        assert_eq!(sends.len(), 2);
+        for _ in 0..50 {
+            if calls.fast.load(Ordering::SeqCst) == 1
+                && calls.slow_started.load(Ordering::SeqCst) == 1
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        assert_eq!(calls.fast.load(Ordering::SeqCst), 1);
+        assert_eq!(calls.slow_finished.load(Ordering::SeqCst), 0);
+
+        metrics_cache.sink_metrics(&[metric_in("slow", "other-feature", Utc::now())]);
+        assert!(sender.send_due_environments().is_empty());
+
+        for send in sends {
+            send.await.unwrap();
+        }
+        assert_eq!(calls.slow_started.load(Ordering::SeqCst), 1);
+        assert_eq!(calls.slow_finished.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics_cache.metrics.len(), 1);
+    }
 }
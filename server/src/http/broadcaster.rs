@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::error::EdgeError;
+use crate::types::EdgeResult;
+
+use super::feature_refresher::FeatureDelta;
+
+const UPDATE_CHANNEL_CAPACITY: usize = 128;
+pub const DEFAULT_MAX_SUBSCRIBERS: usize = 1000;
+
+lazy_static! {
+    pub static ref STREAM_NOTIFICATIONS_DROPPED: IntCounter = register_int_counter!(
+        "stream_notifications_dropped",
+        "Number of feature change notifications streaming subscribers missed by falling behind"
+    )
+    .unwrap();
+}
+
+/// A change to the features cached for one cache key (environment)
+#[derive(Debug)]
+pub struct FeatureUpdate {
+    pub cache_key: String,
+    pub delta: FeatureDelta,
+}
+
+/// Fans out notifications about updated feature caches to streaming clients.
+/// Each notification carries the cache key (environment) that changed and what changed in it,
+/// subscribers decide themselves whether the change is relevant to them.
+#[derive(Clone, Debug)]
+pub struct Broadcaster {
+    updates: broadcast::Sender<Arc<FeatureUpdate>>,
+    subscribers: Arc<AtomicUsize>,
+    max_subscribers: usize,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        let (updates, _) = broadcast::channel(UPDATE_CHANNEL_CAPACITY);
+        Self {
+            updates,
+            subscribers: Arc::new(AtomicUsize::new(0)),
+            max_subscribers: DEFAULT_MAX_SUBSCRIBERS,
+        }
+    }
+}
+
+impl Broadcaster {
+    pub fn with_max_subscribers(self, max_subscribers: usize) -> Self {
+        Self {
+            max_subscribers,
+            ..self
+        }
+    }
+
+    /// Having no subscribers is the normal state of affairs, so failed sends are ignored.
+    pub fn notify(&self, cache_key: &str, delta: FeatureDelta) {
+        let _ = self.updates.send(Arc::new(FeatureUpdate {
+            cache_key: cache_key.into(),
+            delta,
+        }));
+    }
+
+    pub fn subscribe(&self) -> EdgeResult<Subscription> {
+        self.subscribers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                (current < self.max_subscribers).then_some(current + 1)
+            })
+            .map_err(|_| EdgeError::TooManyStreamSubscribers(self.max_subscribers))?;
+        Ok(Subscription {
+            updates: self.updates.subscribe(),
+            subscribers: self.subscribers.clone(),
+        })
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.load(Ordering::SeqCst)
+    }
+}
+
+/// Holds one of the subscriber slots of a [`Broadcaster`] until dropped.
+pub struct Subscription {
+    updates: broadcast::Receiver<Arc<FeatureUpdate>>,
+    subscribers: Arc<AtomicUsize>,
+}
+
+impl Subscription {
+    /// Subscribers that fall more than the channel capacity behind get `RecvError::Lagged` with the number of
+    /// notifications they missed, which are counted. The next call picks up with the oldest one still buffered
+    pub async fn recv(&mut self) -> Result<Arc<FeatureUpdate>, RecvError> {
+        let update = self.updates.recv().await;
+        if let Err(RecvError::Lagged(missed)) = update {
+            STREAM_NOTIFICATIONS_DROPPED.inc_by(missed);
+        }
+        update
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    pub async fn subscribers_receive_notified_cache_keys() {
+        let broadcaster = Broadcaster::default();
+        let mut subscription = broadcaster.subscribe().unwrap();
+        let delta = FeatureDelta {
+            added: vec!["new.toggle".into()],
+            ..Default::default()
+        };
+        broadcaster.notify("development", delta.clone());
+        let update = subscription.recv().await.unwrap();
+        assert_eq!(update.cache_key, "development");
+        assert_eq!(update.delta, delta);
+    }
+
+    #[tokio::test]
+    pub async fn lagging_subscribers_are_told_and_counted_before_catching_up() {
+        let broadcaster = Broadcaster::default();
+        let mut subscription = broadcaster.subscribe().unwrap();
+        let dropped_before = STREAM_NOTIFICATIONS_DROPPED.get();
+        for _ in 0..UPDATE_CHANNEL_CAPACITY + 3 {
+            broadcaster.notify("development", FeatureDelta::default());
+        }
+        assert!(matches!(
+            subscription.recv().await,
+            Err(RecvError::Lagged(3))
+        ));
+        assert!(STREAM_NOTIFICATIONS_DROPPED.get() >= dropped_before + 3);
+        assert_eq!(subscription.recv().await.unwrap().cache_key, "development");
+    }
+
+    #[test]
+    pub fn subscribing_beyond_the_limit_fails_until_a_slot_is_released() {
+        let broadcaster = Broadcaster::default().with_max_subscribers(1);
+        let first = broadcaster.subscribe().unwrap();
+        assert!(matches!(
+            broadcaster.subscribe(),
+            Err(EdgeError::TooManyStreamSubscribers(1))
+        ));
+        drop(first);
+        assert_eq!(broadcaster.subscriber_count(), 0);
+        assert!(broadcaster.subscribe().is_ok());
+    }
+}
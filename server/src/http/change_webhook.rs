@@ -0,0 +1,246 @@
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec, Opts};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, warn};
+
+use super::feature_refresher::FeatureDelta;
+
+const CHANGE_WEBHOOK_QUEUE_CAPACITY: usize = 256;
+const CHANGE_WEBHOOK_RETRIES: u32 = 3;
+const CHANGE_WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    pub static ref CHANGE_WEBHOOK_DELIVERIES: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "change_webhook_deliveries",
+            "Feature change events for the change webhook, by whether they were delivered, failed after retrying or dropped because the queue was full"
+        ),
+        &["outcome"]
+    )
+    .unwrap();
+}
+
+/// What gets posted to the change webhook when an update from upstream changed the features of an environment
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureChangeEvent {
+    pub environment: Option<String>,
+    /// Every added, changed or removed feature
+    pub changed_features: Vec<String>,
+    pub segments_changed: bool,
+}
+
+impl FeatureChangeEvent {
+    fn new(environment: Option<String>, delta: &FeatureDelta) -> Self {
+        let mut changed_features: Vec<String> = delta
+            .added
+            .iter()
+            .chain(delta.changed.iter())
+            .chain(delta.removed.iter())
+            .cloned()
+            .collect();
+        changed_features.sort();
+        FeatureChangeEvent {
+            environment,
+            changed_features,
+            segments_changed: delta.segments_changed,
+        }
+    }
+}
+
+/// Queues feature change events for delivery to a webhook by a background task, so a slow or failing webhook never holds up refreshing features.
+/// Events that don't fit in the queue are dropped
+#[derive(Clone, Debug)]
+pub struct ChangeWebhook {
+    events: mpsc::Sender<FeatureChangeEvent>,
+}
+
+impl ChangeWebhook {
+    pub fn spawn(url: Url, client: reqwest::Client) -> Self {
+        ChangeWebhook::spawn_with_retry_delay(url, client, CHANGE_WEBHOOK_RETRY_DELAY)
+    }
+
+    fn spawn_with_retry_delay(url: Url, client: reqwest::Client, retry_delay: Duration) -> Self {
+        let (events, receiver) = mpsc::channel(CHANGE_WEBHOOK_QUEUE_CAPACITY);
+        tokio::spawn(deliver_events(receiver, url, client, retry_delay));
+        ChangeWebhook { events }
+    }
+
+    pub fn notify(&self, environment: Option<String>, delta: &FeatureDelta) {
+        match self
+            .events
+            .try_send(FeatureChangeEvent::new(environment, delta))
+        {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => {
+                warn!(
+                    "Change webhook queue is full, dropping change event for {:?}",
+                    event.environment
+                );
+                CHANGE_WEBHOOK_DELIVERIES
+                    .with_label_values(&["dropped"])
+                    .inc();
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("Change webhook delivery has stopped, dropping change event");
+                CHANGE_WEBHOOK_DELIVERIES
+                    .with_label_values(&["dropped"])
+                    .inc();
+            }
+        }
+    }
+}
+
+async fn deliver_events(
+    mut receiver: mpsc::Receiver<FeatureChangeEvent>,
+    url: Url,
+    client: reqwest::Client,
+    retry_delay: Duration,
+) {
+    while let Some(event) = receiver.recv().await {
+        if post_event(&client, &url, &event, retry_delay).await {
+            CHANGE_WEBHOOK_DELIVERIES
+                .with_label_values(&["delivered"])
+                .inc();
+        } else {
+            warn!(
+                "Giving up delivering change event for {:?} to the change webhook after {} retries",
+                event.environment, CHANGE_WEBHOOK_RETRIES
+            );
+            CHANGE_WEBHOOK_DELIVERIES
+                .with_label_values(&["failed"])
+                .inc();
+        }
+    }
+}
+
+/// Retries with a doubling delay, since the event is useless to the receiver once it's long out of date
+async fn post_event(
+    client: &reqwest::Client,
+    url: &Url,
+    event: &FeatureChangeEvent,
+    retry_delay: Duration,
+) -> bool {
+    let mut delay = retry_delay;
+    for attempt in 0..=CHANGE_WEBHOOK_RETRIES {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        match client.post(url.clone()).json(event).send().await {
+            Ok(response) if response.status().is_success() => return true,
+            Ok(response) => debug!(
+                "Change webhook answered {} on attempt {}",
+                response.status(),
+                attempt + 1
+            ),
+            Err(e) => debug!(
+                "Could not reach change webhook on attempt {}: {e:?}",
+                attempt + 1
+            ),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use actix_http::HttpService;
+    use actix_http_test::test_server;
+    use actix_service::map_config;
+    use actix_web::dev::AppConfig;
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Received {
+        attempts: AtomicUsize,
+        events: Mutex<Vec<FeatureChangeEvent>>,
+    }
+
+    /// Fails the first request, so every delivery needs a retry
+    async fn flaky_webhook(
+        received: web::Data<Received>,
+        event: web::Json<FeatureChangeEvent>,
+    ) -> HttpResponse {
+        if received.attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        received.events.lock().unwrap().push(event.into_inner());
+        HttpResponse::NoContent().finish()
+    }
+
+    #[actix_web::test]
+    async fn change_events_are_posted_and_retried_until_accepted() {
+        let received = web::Data::new(Received::default());
+        let server_received = received.clone();
+        let srv = test_server(move || {
+            HttpService::new(map_config(
+                App::new()
+                    .app_data(server_received.clone())
+                    .route("/hook", web::post().to(flaky_webhook)),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let webhook = ChangeWebhook::spawn_with_retry_delay(
+            Url::parse(&srv.url("/hook")).unwrap(),
+            reqwest::Client::new(),
+            Duration::from_millis(10),
+        );
+
+        webhook.notify(
+            Some("development".into()),
+            &FeatureDelta {
+                added: vec!["new.toggle".into()],
+                changed: vec!["flipped".into()],
+                removed: vec!["archived".into()],
+                segments_changed: false,
+            },
+        );
+
+        for _ in 0..100 {
+            if !received.events.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *received.events.lock().unwrap(),
+            vec![FeatureChangeEvent {
+                environment: Some("development".into()),
+                changed_features: vec!["archived".into(), "flipped".into(), "new.toggle".into()],
+                segments_changed: false,
+            }]
+        );
+        assert_eq!(received.attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    pub fn events_that_do_not_fit_in_the_queue_are_dropped_without_waiting() {
+        let (events, mut receiver) = mpsc::channel(1);
+        let webhook = ChangeWebhook { events };
+        let delta = FeatureDelta {
+            changed: vec!["flipped".into()],
+            ..Default::default()
+        };
+
+        webhook.notify(Some("development".into()), &delta);
+        webhook.notify(Some("production".into()), &delta);
+
+        assert_eq!(
+            receiver.try_recv().unwrap().environment,
+            Some("development".into())
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+}
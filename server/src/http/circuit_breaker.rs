@@ -0,0 +1,221 @@
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge, Opts};
+use tracing::{info, warn};
+
+use crate::error::EdgeError;
+use crate::types::EdgeResult;
+
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS: i64 = 30;
+
+lazy_static! {
+    pub static ref UPSTREAM_CIRCUIT_BREAKER_STATE: IntGauge = register_int_gauge!(Opts::new(
+        "upstream_circuit_breaker_state",
+        "State of the circuit breaker in front of upstream. 0 is closed, 1 is half open and 2 is open"
+    ))
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests go to upstream
+    Closed,
+    /// The cooldown has passed, and a single request goes to upstream to see if it has recovered
+    HalfOpen,
+    /// Requests fail without going to upstream until the cooldown has passed
+    Open,
+}
+
+impl BreakerState {
+    fn gauge_value(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// When the breaker opened, or when the probe went out when half open
+    opened_at: DateTime<Utc>,
+    probe_in_flight: bool,
+}
+
+/// Stops sending requests to an upstream that keeps failing, so refreshes and validations don't each wait for the timeout
+/// and upstream gets room to recover. After `failure_threshold` failures in a row requests are refused for `cooldown`,
+/// then one request is let through as a probe: if it succeeds the breaker closes, otherwise it opens for another cooldown.
+/// A threshold of 0 leaves the breaker closed
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    inner: Mutex<BreakerInner>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(
+            0,
+            Duration::seconds(DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS),
+        )
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: Utc::now(),
+                probe_in_flight: false,
+            }),
+            failure_threshold,
+            cooldown,
+        }
+    }
+
+    fn inner(&self) -> MutexGuard<'_, BreakerInner> {
+        self.inner
+            .lock()
+            .expect("Circuit breaker state was poisoned")
+    }
+
+    fn transition(inner: &mut BreakerInner, state: BreakerState) {
+        inner.state = state;
+        UPSTREAM_CIRCUIT_BREAKER_STATE.set(state.gauge_value());
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner().state
+    }
+
+    /// Whether a request may go to upstream. Every request let through has to be followed by recording how it went
+    pub fn allow_request(&self) -> EdgeResult<()> {
+        let mut inner = self.inner();
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open => {
+                let remaining = inner.opened_at + self.cooldown - Utc::now();
+                if remaining > Duration::zero() {
+                    return Err(EdgeError::UpstreamCircuitOpen(remaining.num_seconds() + 1));
+                }
+                info!("Circuit breaker cooldown passed, probing whether upstream has recovered");
+                Self::transition(&mut inner, BreakerState::HalfOpen);
+                inner.opened_at = Utc::now();
+                inner.probe_in_flight = true;
+                Ok(())
+            }
+            // A probe whose request was dropped before it got an answer never reports back, so it's replaced after a cooldown
+            BreakerState::HalfOpen
+                if inner.probe_in_flight && Utc::now() - inner.opened_at < self.cooldown =>
+            {
+                Err(EdgeError::UpstreamCircuitOpen(0))
+            }
+            BreakerState::HalfOpen => {
+                inner.opened_at = Utc::now();
+                inner.probe_in_flight = true;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner();
+        inner.consecutive_failures = 0;
+        inner.probe_in_flight = false;
+        if inner.state != BreakerState::Closed {
+            info!("Upstream answered the probe, closing the circuit breaker");
+            Self::transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner();
+        inner.consecutive_failures += 1;
+        inner.probe_in_flight = false;
+        let reopen = inner.state == BreakerState::HalfOpen;
+        if reopen || inner.consecutive_failures >= self.failure_threshold {
+            if inner.state != BreakerState::Open {
+                warn!(
+                    "Upstream failed {} times in a row, not contacting it for {} seconds",
+                    inner.consecutive_failures,
+                    self.cooldown.num_seconds()
+                );
+            }
+            inner.opened_at = Utc::now();
+            Self::transition(&mut inner, BreakerState::Open);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn opens_after_consecutive_failures_and_refuses_requests() {
+        let breaker = CircuitBreaker::new(2, Duration::seconds(60));
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(breaker.allow_request().is_ok());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(
+            breaker.allow_request(),
+            Err(EdgeError::UpstreamCircuitOpen(_))
+        ));
+    }
+
+    #[test]
+    pub fn half_opens_after_the_cooldown_with_a_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::seconds(60));
+        let cooldown_passed = || breaker.inner().opened_at = Utc::now() - Duration::seconds(61);
+        breaker.record_failure();
+        assert!(breaker.allow_request().is_err());
+        cooldown_passed();
+        assert!(breaker.allow_request().is_ok());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allow_request().is_err());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        cooldown_passed();
+        assert!(breaker.allow_request().is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow_request().is_ok());
+        assert!(breaker.allow_request().is_ok());
+    }
+
+    #[test]
+    pub fn a_probe_that_never_reports_back_is_replaced_after_the_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::seconds(60));
+        breaker.record_failure();
+        breaker.inner().opened_at = Utc::now() - Duration::seconds(61);
+        assert!(breaker.allow_request().is_ok());
+        assert!(breaker.allow_request().is_err());
+        breaker.inner().opened_at = Utc::now() - Duration::seconds(61);
+        assert!(breaker.allow_request().is_ok());
+    }
+
+    #[test]
+    pub fn never_opens_without_a_threshold() {
+        let breaker = CircuitBreaker::default();
+        for _ in 0..100 {
+            breaker.record_failure();
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow_request().is_ok());
+    }
+}
@@ -1,11 +1,22 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::{sync::Arc, time::Duration};
 
 use actix_web::http::header::EntityTag;
-use chrono::Utc;
-use dashmap::DashMap;
+use chrono::{DateTime, Utc};
+use dashmap::mapref::entry::Entry;
+use dashmap::{DashMap, DashSet};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use lazy_static::lazy_static;
+use opentelemetry::trace::FutureExt as _;
+use prometheus::{
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, GaugeVec, HistogramVec,
+    IntCounterVec, Opts,
+};
+use rand::Rng;
 use reqwest::StatusCode;
-use tracing::{debug, info, warn};
+use serde::Serialize;
+use tracing::{debug, error, info, warn};
 use unleash_types::client_features::Segment;
 use unleash_types::client_metrics::{ClientApplication, MetricsMetadata};
 use unleash_types::{
@@ -14,17 +25,136 @@ use unleash_types::{
 };
 use unleash_yggdrasil::EngineState;
 
+use crate::auth::denied_tokens::DeniedTokens;
 use crate::error::{EdgeError, FeatureError};
 use crate::filters::{filter_client_features, FeatureFilterSet};
-use crate::types::{build, EdgeResult, TokenType, TokenValidationStatus};
+use crate::types::{
+    build, ClientFeaturesDelta, ClientFeaturesDeltaRequest, ClientFeaturesDeltaResponse,
+    DeltaEvent, EdgeResult, TokenType, TokenValidationStatus,
+};
 use crate::{
     persistence::EdgePersistence,
-    tokens::{cache_key, simplify},
+    tokens::{anonymize_token, cache_key, simplify},
     types::{ClientFeaturesRequest, ClientFeaturesResponse, EdgeToken, TokenRefresh},
 };
 
+use super::broadcaster::Broadcaster;
+use super::change_webhook::ChangeWebhook;
+use super::refresh_shard::RefreshShard;
 use super::unleash_client::UnleashClient;
 
+lazy_static! {
+    pub static ref FEATURE_REFRESH_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "feature_refresh_attempts",
+            "Number of times we've tried to refresh features from upstream"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref FEATURE_REFRESH_UPDATED: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "feature_refresh_updated",
+            "Number of feature refreshes where upstream gave us updated features"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref FEATURE_REFRESH_NO_UPDATE: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "feature_refresh_no_update",
+            "Number of feature refreshes where upstream told us nothing had changed"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref FEATURE_REFRESH_FAILURES: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "feature_refresh_failures",
+            "Number of failed feature refreshes by upstream status code"
+        ),
+        &["environment", "status_code"]
+    )
+    .unwrap();
+    pub static ref FEATURE_REFRESH_COALESCED: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "feature_refresh_coalesced",
+            "Number of feature refreshes that waited for a fetch of the same token already in flight instead of asking upstream again"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref FEATURE_REFRESH_DURATION: HistogramVec = register_histogram_vec!(
+        "feature_refresh_duration",
+        "Timings for refreshing features from upstream in milliseconds",
+        &["environment"],
+        vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 5000.0]
+    )
+    .unwrap();
+    pub static ref FEATURE_CACHE_AGE: GaugeVec = register_gauge_vec!(
+        Opts::new(
+            "edge_feature_cache_age_seconds",
+            "Seconds since features for this environment were last successfully refreshed from upstream"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_AUTH_REVOCATIONS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "upstream_auth_revocations",
+            "Number of tokens we stopped refreshing because upstream answered 401 or 403"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+    pub static ref LAST_REFRESH_TIMESTAMP: GaugeVec = register_gauge_vec!(
+        Opts::new(
+            "edge_last_refresh_timestamp",
+            "Unix timestamp of the last successful refresh of features for this environment"
+        ),
+        &["environment"]
+    )
+    .unwrap();
+}
+
+/// Somewhere in `[0, jitter]`, picked anew for every Edge started
+fn startup_delay(jitter: chrono::Duration) -> Duration {
+    let jitter_ms = jitter.num_milliseconds().max(0) as u64;
+    if jitter_ms == 0 {
+        Duration::ZERO
+    } else {
+        Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms))
+    }
+}
+
+/// Tokens are labelled by environment to avoid leaking secrets and to keep label cardinality low
+fn refresh_metrics_label(token: &EdgeToken) -> String {
+    token
+        .environment
+        .clone()
+        .unwrap_or_else(|| "unknown".into())
+}
+
+fn refresh_failure_status(error: &EdgeError) -> String {
+    match error {
+        EdgeError::ClientFeaturesFetchError(
+            FeatureError::Retriable(status_code) | FeatureError::RateLimited(status_code, _),
+        ) => status_code.as_str().into(),
+        // Upstream answers both 401 and 403 with AccessDenied
+        EdgeError::ClientFeaturesFetchError(FeatureError::AccessDenied) => {
+            StatusCode::FORBIDDEN.as_str().into()
+        }
+        EdgeError::ClientFeaturesFetchError(FeatureError::NotFound) => {
+            StatusCode::NOT_FOUND.as_str().into()
+        }
+        EdgeError::ClientFeaturesFetchError(FeatureError::Timeout) => "timeout".into(),
+        EdgeError::ClientFeaturesParseError(_) => "invalid_payload".into(),
+        EdgeError::FeatureDocumentTooLarge(_) => "too_large".into(),
+        EdgeError::UpstreamCircuitOpen(_) => "circuit_open".into(),
+        _ => "unknown".into(),
+    }
+}
+
 fn frontend_token_is_covered_by_tokens(
     frontend_token: &EdgeToken,
     tokens_to_refresh: Arc<DashMap<String, TokenRefresh>>,
@@ -36,24 +166,95 @@ fn frontend_token_is_covered_by_tokens(
     })
 }
 
-fn update_client_features(
+/// What changed in the cached features of an environment after applying an update from upstream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeatureDelta {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    pub segments_changed: bool,
+}
+
+impl FeatureDelta {
+    fn from_new_features(features: &ClientFeatures) -> Self {
+        FeatureDelta {
+            added: features.features.iter().map(|f| f.name.clone()).collect(),
+            segments_changed: features.segments.as_ref().is_some_and(|s| !s.is_empty()),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.changed.is_empty()
+            && self.removed.is_empty()
+            && !self.segments_changed
+    }
+}
+
+/// What came of refreshing a token when asked to through the internal backstage
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForcedRefreshOutcome {
+    /// Upstream had new features, which are now served
+    Updated,
+    /// Upstream said the features we have are current
+    Unchanged,
+    /// Not refreshed, the token is backing off after upstream failures
+    BackingOff,
+    /// Upstream couldn't be asked or didn't answer with features
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ForcedRefresh {
+    pub environment: Option<String>,
+    pub projects: Vec<String>,
+    pub outcome: ForcedRefreshOutcome,
+}
+
+/// How the features a request got came out of the cache, as reported in the `X-Edge-Cache` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Cached, and refreshed from upstream as often as they should be
+    Hit,
+    /// Cached, but not refreshed from upstream for more than twice the refresh interval
+    Stale,
+    /// Not cached, so they were fetched from upstream while the request waited
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+}
+
+/// Applies an update from upstream to the cached features in place, only touching the features that actually changed
+fn apply_client_features_update(
     token: &EdgeToken,
-    old: &ClientFeatures,
-    update: &ClientFeatures,
-) -> ClientFeatures {
-    let mut updated_features =
-        update_projects_from_feature_update(token, &old.features, &update.features);
-    updated_features.sort();
-    let segments = merge_segments_update(old.segments.clone(), update.segments.clone());
-    ClientFeatures {
-        version: old.version.max(update.version),
-        features: updated_features,
-        segments: segments.map(|mut s| {
+    existing: &mut ClientFeatures,
+    update: ClientFeatures,
+) -> FeatureDelta {
+    let mut delta = apply_feature_update(token, &mut existing.features, update.features);
+    let segments =
+        merge_segments_update(existing.segments.clone(), update.segments).map(|mut s| {
             s.sort();
             s
-        }),
-        query: old.query.clone().or(update.query.clone()),
+        });
+    if content_differs(&segments, &existing.segments) {
+        existing.segments = segments;
+        delta.segments_changed = true;
     }
+    existing.version = existing.version.max(update.version);
+    if existing.query.is_none() {
+        existing.query = update.query;
+    }
+    delta
 }
 
 fn merge_segments_update(
@@ -70,25 +271,130 @@ fn merge_segments_update(
         (None, None) => None,
     }
 }
-pub(crate) fn update_projects_from_feature_update(
+
+/// `ClientFeature` and `Segment` only compare their names/ids, so changes are detected on their serialized content
+fn content_differs<T: Serialize>(current: &T, updated: &T) -> bool {
+    serde_json::to_value(current).ok() != serde_json::to_value(updated).ok()
+}
+
+/// Whether the feature is in one of the projects the token has access to
+fn in_token_scope(token: &EdgeToken, feature: &ClientFeature) -> bool {
+    token.projects.contains(&"*".into())
+        || token
+            .projects
+            .contains(&feature.project.clone().unwrap_or_else(|| "default".into()))
+}
+
+/// Plays the events of a delta on top of the features the token had, giving the token's features as upstream has them now.
+/// `None` if the delta doesn't start with a hydration and there are no features to play it on top of
+fn apply_delta(
+    baseline: Option<ClientFeatures>,
+    delta: ClientFeaturesDelta,
+) -> Option<ClientFeatures> {
+    let mut features = baseline;
+    for event in delta.events {
+        features = match (event, features) {
+            (
+                DeltaEvent::Hydration {
+                    features, segments, ..
+                },
+                _,
+            ) => Some(ClientFeatures {
+                version: 2,
+                features,
+                segments: Some(segments),
+                query: None,
+            }),
+            (_, None) => return None,
+            (DeltaEvent::FeatureUpdated { feature, .. }, Some(mut current)) => {
+                current.features.retain(|f| f.name != feature.name);
+                current.features.push(feature);
+                Some(current)
+            }
+            (DeltaEvent::FeatureRemoved { feature_name, .. }, Some(mut current)) => {
+                current.features.retain(|f| f.name != feature_name);
+                Some(current)
+            }
+            (DeltaEvent::SegmentUpdated { segment, .. }, Some(mut current)) => {
+                let segments = current.segments.get_or_insert_with(Vec::new);
+                segments.retain(|s| s.id != segment.id);
+                segments.push(segment);
+                Some(current)
+            }
+            (DeltaEvent::SegmentRemoved { segment_id, .. }, Some(mut current)) => {
+                if let Some(segments) = current.segments.as_mut() {
+                    segments.retain(|s| s.id != segment_id);
+                }
+                Some(current)
+            }
+        };
+    }
+    features
+}
+
+/// The update replaces every feature in the projects the token has access to, features in other projects are kept as they are.
+/// Tokens for different projects share their environment's cache, so a feature that turns up in another project than
+/// the one it was cached from replaces it, and that gets logged
+pub(crate) fn apply_feature_update(
     token: &EdgeToken,
-    original: &[ClientFeature],
-    updated: &[ClientFeature],
-) -> Vec<ClientFeature> {
-    let projects_to_update = &token.projects;
-    if projects_to_update.contains(&"*".into()) {
-        updated.into()
-    } else {
-        let mut to_keep: Vec<ClientFeature> = original
-            .iter()
-            .filter(|toggle| {
-                let p = toggle.project.clone().unwrap_or_else(|| "default".into());
-                !projects_to_update.contains(&p)
-            })
-            .cloned()
-            .collect();
-        to_keep.extend(updated.iter().cloned());
-        to_keep
+    features: &mut Vec<ClientFeature>,
+    updated: Vec<ClientFeature>,
+) -> FeatureDelta {
+    let in_scope = |feature: &ClientFeature| in_token_scope(token, feature);
+    let mut updated_by_name: HashMap<String, ClientFeature> = updated
+        .into_iter()
+        .map(|feature| (feature.name.clone(), feature))
+        .collect();
+    let mut delta = FeatureDelta::default();
+    features.retain_mut(|feature| match updated_by_name.remove(&feature.name) {
+        Some(updated_feature) => {
+            if feature.project != updated_feature.project {
+                warn!(
+                    "Feature {} was cached from project {}, but is now in project {}. Serving the latter",
+                    feature.name,
+                    feature.project.as_deref().unwrap_or("default"),
+                    updated_feature.project.as_deref().unwrap_or("default"),
+                );
+            }
+            if content_differs(feature, &updated_feature) {
+                delta.changed.push(feature.name.clone());
+                *feature = updated_feature;
+            }
+            true
+        }
+        None if in_scope(feature) => {
+            delta.removed.push(feature.name.clone());
+            false
+        }
+        None => true,
+    });
+    for (name, feature) in updated_by_name {
+        delta.added.push(name);
+        features.push(feature);
+    }
+    if !delta.is_empty() {
+        features.sort();
+    }
+    delta
+}
+
+/// A duration shared by every clone of the refresher, so a config reload can change it while refreshes are running
+#[derive(Clone, Debug)]
+pub struct ReloadableDuration(Arc<AtomicI64>);
+
+impl ReloadableDuration {
+    pub fn get(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, duration: chrono::Duration) {
+        self.0.store(duration.num_milliseconds(), Ordering::Relaxed);
+    }
+}
+
+impl From<chrono::Duration> for ReloadableDuration {
+    fn from(duration: chrono::Duration) -> Self {
+        Self(Arc::new(AtomicI64::new(duration.num_milliseconds())))
     }
 }
 
@@ -98,16 +404,63 @@ pub struct FeatureRefresher {
     pub tokens_to_refresh: Arc<DashMap<String, TokenRefresh>>,
     pub features_cache: Arc<DashMap<String, ClientFeatures>>,
     pub engine_cache: Arc<DashMap<String, EngineState>>,
-    pub refresh_interval: chrono::Duration,
+    pub refresh_interval: ReloadableDuration,
+    pub max_refresh_backoff: ReloadableDuration,
     pub persistence: Option<Arc<dyn EdgePersistence>>,
     pub strict: bool,
     pub app_name: String,
+    pub broadcaster: Broadcaster,
+    /// When we last got an answer from upstream, updated or not, keyed by the environment label of the token
+    pub last_successful_refresh: Arc<DashMap<String, DateTime<Utc>>>,
+    /// Tokens upstream stops accepting are marked invalid here, so requests using them are refused instead of re-registering them
+    pub token_cache: Arc<DashMap<String, EdgeToken>>,
+    /// The background refresh waits a random part of this before its first refresh
+    pub startup_jitter: chrono::Duration,
+    /// Tokens with a refresh running in its own task, so a slow refresh isn't started a second time
+    pub refreshes_in_flight: Arc<DashSet<String>>,
+    /// Tokens unused for longer than this are evicted, along with features no remaining token needs. Tokens are kept forever without it
+    pub token_idle_ttl: Option<chrono::Duration>,
+    /// When each token was last used, only tracked with a token idle ttl
+    pub token_last_access: Arc<DashMap<String, DateTime<Utc>>>,
+    /// Told about every update from upstream that changed features
+    pub change_webhook: Option<ChangeWebhook>,
+    /// Fetches from upstream running right now, keyed by token, so concurrent refreshes of one token share a single upstream request
+    pub fetches_in_flight: Arc<DashMap<String, Shared<BoxFuture<'static, ()>>>>,
+    /// Fetch only the changes since the last refresh from upstream's delta API instead of all features
+    pub use_delta_api: bool,
+    /// Set once upstream turns out not to have the delta API, all features are fetched from then on
+    pub delta_api_unsupported: Arc<AtomicBool>,
+    /// Features last refreshed longer ago than this are refused instead of served. Served however old without it
+    pub max_staleness: Option<chrono::Duration>,
+    /// Staleness of environments that haven't been refreshed yet is measured from here
+    pub started_at: DateTime<Utc>,
+    /// With several Edges sharing a database, environments of other shards are read from the database instead of upstream
+    pub refresh_shard: Option<RefreshShard>,
+    /// Features last read from the database for environments of other shards, shared by refreshes close together
+    pub persisted_features: Arc<tokio::sync::Mutex<Option<PersistedFeatures>>>,
+    /// The only environment features are refreshed and served for. Every environment is served without it
+    pub default_environment: Option<String>,
+    /// The environments features are refreshed and served for. Every environment is allowed when empty
+    pub allowed_environments: Vec<String>,
+    /// Environments refreshed at their own interval instead of the global one
+    pub environment_refresh_intervals: HashMap<String, chrono::Duration>,
+    /// Tokens never registered for refresh, however they reach us
+    pub denied_tokens: DeniedTokens,
+    /// The names of the features upstream has for a token and a set of tags, reused for a refresh interval
+    pub tagged_features: Arc<DashMap<(String, Vec<String>), TaggedFeatureNames>>,
 }
 
+type PersistedFeatures = (DateTime<Utc>, Arc<HashMap<String, ClientFeatures>>);
+type TaggedFeatureNames = (DateTime<Utc>, Arc<HashSet<String>>);
+
+/// How often to look for tokens other shards have seen that this shard should refresh, about as often as they're persisted
+const SHARD_ADOPTION_INTERVAL: Duration = Duration::from_secs(60);
+
 impl Default for FeatureRefresher {
     fn default() -> Self {
         Self {
-            refresh_interval: chrono::Duration::seconds(10),
+            refresh_interval: chrono::Duration::seconds(10).into(),
+            max_refresh_backoff: chrono::Duration::seconds(300).into(),
             unleash_client: Default::default(),
             tokens_to_refresh: Arc::new(DashMap::default()),
             features_cache: Default::default(),
@@ -115,6 +468,26 @@ impl Default for FeatureRefresher {
             persistence: None,
             strict: true,
             app_name: "unleash_edge".into(),
+            broadcaster: Broadcaster::default(),
+            last_successful_refresh: Default::default(),
+            token_cache: Default::default(),
+            startup_jitter: chrono::Duration::zero(),
+            refreshes_in_flight: Default::default(),
+            token_idle_ttl: None,
+            token_last_access: Default::default(),
+            change_webhook: None,
+            fetches_in_flight: Default::default(),
+            use_delta_api: false,
+            delta_api_unsupported: Default::default(),
+            max_staleness: None,
+            started_at: Utc::now(),
+            refresh_shard: None,
+            persisted_features: Default::default(),
+            default_environment: None,
+            allowed_environments: vec![],
+            environment_refresh_intervals: HashMap::new(),
+            denied_tokens: DeniedTokens::default(),
+            tagged_features: Default::default(),
         }
     }
 }
@@ -156,13 +529,138 @@ impl FeatureRefresher {
             tokens_to_refresh: Arc::new(DashMap::default()),
             features_cache: features,
             engine_cache: engines,
-            refresh_interval: features_refresh_interval,
+            refresh_interval: features_refresh_interval.into(),
+            max_refresh_backoff: (features_refresh_interval * 30).into(),
             persistence,
             strict,
             app_name: app_name.into(),
+            broadcaster: Broadcaster::default(),
+            last_successful_refresh: Default::default(),
+            token_cache: Default::default(),
+            startup_jitter: chrono::Duration::zero(),
+            refreshes_in_flight: Default::default(),
+            token_idle_ttl: None,
+            token_last_access: Default::default(),
+            change_webhook: None,
+            fetches_in_flight: Default::default(),
+            use_delta_api: false,
+            delta_api_unsupported: Default::default(),
+            max_staleness: None,
+            started_at: Utc::now(),
+            refresh_shard: None,
+            persisted_features: Default::default(),
+            default_environment: None,
+            allowed_environments: vec![],
+            environment_refresh_intervals: HashMap::new(),
+            denied_tokens: DeniedTokens::default(),
+            tagged_features: Default::default(),
+        }
+    }
+
+    pub fn with_startup_jitter(self, startup_jitter: chrono::Duration) -> Self {
+        Self {
+            startup_jitter,
+            ..self
+        }
+    }
+
+    pub fn with_token_idle_ttl(self, token_idle_ttl: Option<chrono::Duration>) -> Self {
+        Self {
+            token_idle_ttl,
+            ..self
+        }
+    }
+
+    pub fn with_delta_api(self, use_delta_api: bool) -> Self {
+        Self {
+            use_delta_api,
+            ..self
+        }
+    }
+
+    pub fn with_max_staleness(self, max_staleness: Option<chrono::Duration>) -> Self {
+        Self {
+            max_staleness,
+            ..self
+        }
+    }
+
+    pub fn with_change_webhook(self, change_webhook: Option<ChangeWebhook>) -> Self {
+        Self {
+            change_webhook,
+            ..self
+        }
+    }
+
+    pub fn with_token_cache(self, token_cache: Arc<DashMap<String, EdgeToken>>) -> Self {
+        Self {
+            token_cache,
+            ..self
+        }
+    }
+
+    pub fn with_max_refresh_backoff(self, max_refresh_backoff: chrono::Duration) -> Self {
+        Self {
+            max_refresh_backoff: max_refresh_backoff.into(),
+            ..self
+        }
+    }
+
+    pub fn with_refresh_shard(self, refresh_shard: Option<RefreshShard>) -> Self {
+        Self {
+            refresh_shard,
+            ..self
+        }
+    }
+
+    pub fn with_default_environment(self, default_environment: Option<String>) -> Self {
+        Self {
+            default_environment,
+            ..self
+        }
+    }
+
+    pub fn with_allowed_environments(self, allowed_environments: Vec<String>) -> Self {
+        Self {
+            allowed_environments,
+            ..self
+        }
+    }
+
+    pub fn with_environment_refresh_intervals(
+        self,
+        environment_refresh_intervals: HashMap<String, chrono::Duration>,
+    ) -> Self {
+        Self {
+            environment_refresh_intervals,
+            ..self
+        }
+    }
+
+    pub fn with_denied_tokens(self, denied_tokens: DeniedTokens) -> Self {
+        Self {
+            denied_tokens,
+            ..self
         }
     }
 
+    /// The interval set on the refresh itself wins over the one configured for its environment, which wins over the global one
+    pub fn refresh_interval_for(&self, refresh: &TokenRefresh) -> chrono::Duration {
+        refresh
+            .refresh_interval_seconds
+            .map(|seconds| chrono::Duration::seconds(seconds as i64))
+            .or_else(|| self.environment_refresh_interval(&refresh.token))
+            .unwrap_or_else(|| self.refresh_interval.get())
+    }
+
+    fn environment_refresh_interval(&self, token: &EdgeToken) -> Option<chrono::Duration> {
+        token
+            .environment
+            .as_ref()
+            .and_then(|environment| self.environment_refresh_intervals.get(environment))
+            .copied()
+    }
+
     pub fn with_client(client: Arc<UnleashClient>) -> Self {
         Self {
             unleash_client: client,
@@ -171,18 +669,27 @@ impl FeatureRefresher {
     }
 
     pub(crate) fn get_tokens_due_for_refresh(&self) -> Vec<TokenRefresh> {
+        let now = Utc::now();
         self.tokens_to_refresh
             .iter()
             .map(|e| e.value().clone())
-            .filter(|token| {
-                token
-                    .next_refresh
-                    .map(|refresh| Utc::now() > refresh)
-                    .unwrap_or(true)
-            })
+            .filter(|refresh| !self.waiting_to_refresh(refresh, now))
             .collect()
     }
 
+    /// Whether the refresh isn't due yet. Refreshes are scheduled by the local clock, never by upstream's. One scheduled
+    /// further out than any interval or backoff could have put it, because the clock was set back or a replica whose clock is
+    /// ahead persisted it, counts as due, so a jumping or skewed clock can't hold refreshes off
+    fn waiting_to_refresh(&self, refresh: &TokenRefresh, now: DateTime<Utc>) -> bool {
+        let longest_wait = self
+            .refresh_interval_for(refresh)
+            .max(self.max_refresh_backoff.get())
+            * 2;
+        refresh
+            .next_refresh
+            .is_some_and(|next_refresh| next_refresh > now && next_refresh - now <= longest_wait)
+    }
+
     pub(crate) fn get_tokens_never_refreshed(&self) -> Vec<TokenRefresh> {
         self.tokens_to_refresh
             .iter()
@@ -191,6 +698,35 @@ impl FeatureRefresher {
             .collect()
     }
 
+    /// Whether the token's features are refreshed from upstream by this Edge, which they always are unless sharded
+    pub fn refreshes_from_upstream(&self, token: &EdgeToken) -> bool {
+        self.refresh_shard.is_none_or(|shard| shard.owns(token))
+    }
+
+    /// Whether the token is for the environment set with --default-environment and one of --allowed-environments.
+    /// Tokens for any environment are without them
+    pub fn serves_environment(&self, token: &EdgeToken) -> bool {
+        self.default_environment
+            .as_ref()
+            .is_none_or(|environment| token.environment.as_ref() == Some(environment))
+            && (self.allowed_environments.is_empty()
+                || token
+                    .environment
+                    .as_ref()
+                    .is_some_and(|environment| self.allowed_environments.contains(environment)))
+    }
+
+    /// The environments this Edge is limited to, if it is
+    pub fn served_environments(&self) -> Option<Vec<String>> {
+        match &self.default_environment {
+            Some(environment) => Some(vec![environment.clone()]),
+            None if !self.allowed_environments.is_empty() => {
+                Some(self.allowed_environments.clone())
+            }
+            None => None,
+        }
+    }
+
     pub(crate) fn token_is_subsumed(&self, token: &EdgeToken) -> bool {
         self.tokens_to_refresh
             .iter()
@@ -233,13 +769,28 @@ impl FeatureRefresher {
         }
     }
 
+    /// The token's features, along with whether they were cached and how fresh they were
     pub(crate) async fn features_for_filter(
         &self,
         token: EdgeToken,
         filters: &FeatureFilterSet,
-    ) -> EdgeResult<ClientFeatures> {
+    ) -> EdgeResult<(ClientFeatures, CacheStatus)> {
+        let subsumed = self.token_is_subsumed(&token);
         match self.get_features_by_filter(&token, filters) {
-            Some(features) if self.token_is_subsumed(&token) => Ok(features),
+            Some(features) if subsumed => Ok((features, self.cache_status(&token))),
+            None if subsumed => {
+                debug!(
+                    "Token is registered, but its features aren't cached yet. Fetching them now"
+                );
+                self.fetch_features_on_cache_miss(&token).await;
+                self.get_features_by_filter(&token, filters)
+                    .map(|features| (features, CacheStatus::Miss))
+                    .ok_or_else(|| {
+                        EdgeError::ClientHydrationFailed(
+                            "Failed to fetch features for a registered token".into(),
+                        )
+                    })
+            }
             _ => {
                 if self.strict {
                     debug!("Strict behavior: Token is not subsumed by any registered tokens. Returning error");
@@ -249,7 +800,9 @@ impl FeatureRefresher {
                         "Dynamic behavior: Had never seen this environment. Configuring fetcher"
                     );
                     self.register_and_hydrate_token(&token).await;
-                    self.get_features_by_filter(&token, filters).ok_or_else(|| {
+                    self.get_features_by_filter(&token, filters)
+                        .map(|features| (features, CacheStatus::Miss))
+                        .ok_or_else(|| {
                     EdgeError::ClientHydrationFailed(
                         "Failed to get features by filter after registering and hydrating token (This is very likely an error in Edge. Please report this!)"
                             .into(),
@@ -260,6 +813,34 @@ impl FeatureRefresher {
         }
     }
 
+    /// Read-through for tokens we refresh but hold no features for yet, like before the first refresh after startup jitter.
+    /// The etag is left out, so upstream doesn't answer with a 304 for features we don't have
+    /// Tokens backing off after upstream failed them are left alone until their backoff is over, so requests can't
+    /// hammer upstream while it's struggling. Concurrent misses share a fetch through refresh_single
+    async fn fetch_features_on_cache_miss(&self, token: &EdgeToken) {
+        let now = Utc::now();
+        let refreshes: Vec<TokenRefresh> = self
+            .tokens_to_refresh
+            .iter()
+            .filter(|r| r.token.environment == token.environment && r.token.subsumes(token))
+            .filter(|r| {
+                let backing_off = r.failure_count > 0 && self.waiting_to_refresh(r, now);
+                if backing_off {
+                    debug!("Not fetching features on a cache miss while the token is backing off");
+                }
+                !backing_off
+            })
+            .map(|r| TokenRefresh {
+                etag: None,
+                delta_revision_id: None,
+                ..r.value().clone()
+            })
+            .collect();
+        for refresh in refreshes {
+            self.refresh_single(refresh).await;
+        }
+    }
+
     fn get_features_by_filter(
         &self,
         token: &EdgeToken,
@@ -271,23 +852,43 @@ impl FeatureRefresher {
     }
 
     ///
-    /// Registers a token for refresh, the token will be discarded if it can be subsumed by another previously registered token
+    /// Registers a token for refresh, the token will be discarded if it can be subsumed by another previously registered token.
+    /// When sharded, tokens of other shards are still registered, so they're served, but refreshed from the shared database
     pub async fn register_token_for_refresh(&self, token: EdgeToken, etag: Option<EntityTag>) {
+        if self.denied_tokens.contains(&token.token) {
+            debug!("Not refreshing features for a denied token");
+            return;
+        }
+        if !self.serves_environment(&token) {
+            warn!(
+                "Not refreshing features for {:?}, this Edge only serves {:?}",
+                token.environment,
+                self.served_environments().unwrap_or_default()
+            );
+            return;
+        }
         if !self.tokens_to_refresh.contains_key(&token.token) {
-            self.unleash_client
-                .register_as_client(
-                    token.token.clone(),
-                    client_application_from_token_and_name(
-                        token.clone(),
-                        self.refresh_interval.num_seconds(),
-                        &self.app_name,
-                    ),
-                )
-                .await
-                .unwrap_or_default();
+            // The shard refreshing the token from upstream registers it there
+            let refresh = TokenRefresh::new(token.clone(), etag).with_refresh_interval_seconds(
+                self.environment_refresh_interval(&token)
+                    .map(|interval| interval.num_seconds() as u64),
+            );
+            if self.refreshes_from_upstream(&token) {
+                self.unleash_client
+                    .register_as_client(
+                        token.token.clone(),
+                        client_application_from_token_and_name(
+                            token.clone(),
+                            self.refresh_interval_for(&refresh).num_seconds(),
+                            &self.app_name,
+                        ),
+                    )
+                    .await
+                    .unwrap_or_default();
+            }
             let mut registered_tokens: Vec<TokenRefresh> =
                 self.tokens_to_refresh.iter().map(|t| t.clone()).collect();
-            registered_tokens.push(TokenRefresh::new(token.clone(), etag));
+            registered_tokens.push(refresh);
             let minimum = simplify(&registered_tokens);
             let mut keys = HashSet::new();
             for refreshes in minimum {
@@ -299,13 +900,195 @@ impl FeatureRefresher {
         }
     }
 
+    /// How often to look for tokens that are due a refresh. Short refresh intervals are checked for as often as they're due
+    fn refresh_check_interval(&self) -> Duration {
+        self.environment_refresh_intervals
+            .values()
+            .chain(std::iter::once(&self.refresh_interval.get()))
+            .min()
+            .copied()
+            .unwrap_or_default()
+            .to_std()
+            .unwrap_or_default()
+            .clamp(Duration::from_secs(1), Duration::from_secs(5))
+    }
+
     pub async fn start_refresh_features_background_task(&self) {
+        let startup_delay = startup_delay(self.startup_jitter);
+        if !startup_delay.is_zero() {
+            info!(
+                "Waiting {} ms before the first refresh of features",
+                startup_delay.as_millis()
+            );
+            tokio::time::sleep(startup_delay).await;
+        }
         loop {
             tokio::select! {
-                _ = tokio::time::sleep(Duration::from_secs(5)) => {
-                    self.refresh_features().await;
+                _ = tokio::time::sleep(self.refresh_check_interval()) => {
+                    self.spawn_due_refreshes();
+                    self.update_feature_cache_age();
+                }
+            }
+        }
+    }
+
+    /// Keeps track of when a token was last used, so idle tokens can be evicted. Does nothing without a token idle ttl
+    pub fn mark_token_accessed(&self, token: &str) {
+        if self.token_idle_ttl.is_some() {
+            self.token_last_access.insert(token.to_string(), Utc::now());
+        }
+    }
+
+    /// Forgets tokens that haven't been used within the token idle ttl, stops refreshing them and drops cached features no remaining token needs.
+    /// Tokens we haven't seen used yet, like the ones restored at startup, get a full ttl from the first sweep.
+    /// A refresh target stays while it covers a token that is still in use, and tokens in `keep` are never evicted
+    pub fn evict_idle_tokens(&self, keep: &[String]) -> Vec<String> {
+        let Some(idle_ttl) = self.token_idle_ttl else {
+            return vec![];
+        };
+        let now = Utc::now();
+        let known_tokens: Vec<String> = self
+            .tokens_to_refresh
+            .iter()
+            .map(|refresh| refresh.key().clone())
+            .chain(self.token_cache.iter().map(|token| token.key().clone()))
+            .collect();
+        for token in known_tokens {
+            self.token_last_access.entry(token).or_insert(now);
+        }
+
+        let cutoff = now - idle_ttl;
+        let (idle, active): (Vec<_>, Vec<_>) = self
+            .token_last_access
+            .iter()
+            .map(|access| (access.key().clone(), *access.value()))
+            .partition(|(token, last_access)| *last_access < cutoff && !keep.contains(token));
+        let active_tokens: Vec<EdgeToken> = active
+            .into_iter()
+            .filter_map(|(token, _)| {
+                self.token_cache
+                    .get(&token)
+                    .map(|cached| cached.value().clone())
+                    .or_else(|| EdgeToken::try_from(token).ok())
+            })
+            .collect();
+        let idle: Vec<String> = idle
+            .into_iter()
+            .map(|(token, _)| token)
+            .filter(|token| match self.tokens_to_refresh.get(token) {
+                Some(refresh) => !active_tokens.iter().any(|active| {
+                    refresh
+                        .token
+                        .same_environment_and_broader_or_equal_project_access(active)
+                }),
+                None => true,
+            })
+            .collect();
+
+        let mut freed_cache_keys = HashSet::new();
+        for token in &idle {
+            self.token_last_access.remove(token);
+            self.token_cache.remove(token);
+            if let Some((_, refresh)) = self.tokens_to_refresh.remove(token) {
+                freed_cache_keys.insert(cache_key(&refresh.token));
+            }
+        }
+        for key in freed_cache_keys {
+            let still_needed = self
+                .tokens_to_refresh
+                .iter()
+                .any(|refresh| cache_key(&refresh.token) == key)
+                || self.token_cache.iter().any(|token| {
+                    token.status == TokenValidationStatus::Validated
+                        && cache_key(token.value()) == key
+                });
+            if !still_needed {
+                debug!("Dropping cached features for {key}, no remaining token uses them");
+                self.features_cache.remove(&key);
+                self.engine_cache.remove(&key);
+            }
+        }
+        idle
+    }
+
+    pub async fn schedule_eviction_of_idle_tokens(&self, startup_tokens: Vec<String>) {
+        let Some(idle_ttl) = self.token_idle_ttl else {
+            return std::future::pending().await;
+        };
+        let sweep_interval = idle_ttl
+            .to_std()
+            .unwrap_or_default()
+            .clamp(Duration::from_secs(1), Duration::from_secs(60));
+        loop {
+            tokio::time::sleep(sweep_interval).await;
+            let evicted = self.evict_idle_tokens(&startup_tokens);
+            if !evicted.is_empty() {
+                info!(
+                    "Evicted {} tokens that were unused for more than {} seconds",
+                    evicted.len(),
+                    idle_ttl.num_seconds()
+                );
+            }
+        }
+    }
+
+    /// Picks up tokens other shards have seen that belong to this one, so their environments are refreshed from upstream
+    /// even if no request with them ever reached this Edge. Does nothing unless sharded
+    pub async fn schedule_adoption_of_shard_refresh_targets(&self) {
+        let (Some(shard), Some(persistence)) = (self.refresh_shard, self.persistence.clone())
+        else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(SHARD_ADOPTION_INTERVAL).await;
+            match persistence.load_refresh_targets().await {
+                Ok(refresh_targets) => {
+                    let adopted = self.adopt_refresh_targets(shard, refresh_targets).await;
+                    if adopted > 0 {
+                        info!("Adopted {adopted} tokens seen by other shards for refreshing");
+                    }
                 }
+                Err(e) => warn!("Could not load the refresh targets of other shards: {e:?}"),
+            }
+        }
+    }
+
+    async fn adopt_refresh_targets(
+        &self,
+        shard: RefreshShard,
+        refresh_targets: Vec<TokenRefresh>,
+    ) -> usize {
+        let mut adopted = 0;
+        for refresh in refresh_targets {
+            let invalid = self
+                .token_cache
+                .get(&refresh.token.token)
+                .is_some_and(|known| known.status == TokenValidationStatus::Invalid);
+            if shard.owns(&refresh.token) && !invalid && !self.token_is_subsumed(&refresh.token) {
+                self.register_token_for_refresh(refresh.token, None).await;
+                adopted += 1;
+            }
+        }
+        adopted
+    }
+
+    /// Refreshes every token that is due in a task of its own, so a token whose upstream is slow to answer doesn't hold up the others.
+    /// Tokens that still have a refresh running are skipped until it finishes
+    pub fn spawn_due_refreshes(&self) {
+        for refresh in self.get_tokens_due_for_refresh() {
+            let token = refresh.token.token.clone();
+            if !self.refreshes_in_flight.insert(token.clone()) {
+                debug!(
+                    "Previous refresh of a token for {:?} is still running, skipping it this time",
+                    refresh.token.environment
+                );
+                continue;
             }
+            let refresher = self.clone();
+            tokio::spawn(async move {
+                refresher.refresh_single(refresh).await;
+                refresher.refreshes_in_flight.remove(&token);
+            });
         }
     }
 
@@ -322,66 +1105,293 @@ impl FeatureRefresher {
         }
     }
 
+    /// Concurrent refreshes of the same token, like a burst of requests with a token we haven't seen before,
+    /// wait for the fetch already in flight instead of each asking upstream
     pub async fn refresh_single(&self, refresh: TokenRefresh) {
-        let features_result = self
-            .unleash_client
+        let fetch = match self.fetches_in_flight.entry(refresh.token.token.clone()) {
+            Entry::Occupied(in_flight) => {
+                FEATURE_REFRESH_COALESCED
+                    .with_label_values(&[&refresh_metrics_label(&refresh.token)])
+                    .inc();
+                in_flight.get().clone()
+            }
+            Entry::Vacant(slot) => {
+                let refresher = self.clone();
+                let key = slot.key().clone();
+                // Spawned, so the fetch finishes even if the request that started it goes away. It keeps that request's
+                // trace context, so the upstream call shows up in the same trace
+                let handle = tokio::spawn(
+                    async move {
+                        refresher.fetch_and_apply_features(refresh).await;
+                        refresher.fetches_in_flight.remove(&key);
+                    }
+                    .with_current_context(),
+                );
+                let fetch = async move {
+                    if let Err(e) = handle.await {
+                        warn!("Feature refresh task failed: {e:?}");
+                    }
+                }
+                .boxed()
+                .shared();
+                slot.insert(fetch.clone());
+                fetch
+            }
+        };
+        fetch.await
+    }
+
+    async fn fetch_all_features(
+        &self,
+        refresh: &TokenRefresh,
+    ) -> EdgeResult<ClientFeaturesResponse> {
+        self.unleash_client
             .get_client_features(ClientFeaturesRequest {
                 api_key: refresh.token.token.clone(),
-                etag: refresh.etag,
+                etag: refresh.etag.clone(),
+                tags: vec![],
             })
-            .await;
+            .await
+    }
 
-        match features_result {
-            Ok(feature_response) => match feature_response {
-                ClientFeaturesResponse::NoUpdate(tag) => {
-                    debug!("No update needed. Will update last check time with {tag}");
-                    self.update_last_check(&refresh.token.clone());
+    /// Turns the changes since the token's last revision into the token's features as upstream has them now.
+    /// All features are fetched instead if upstream doesn't have the delta API or sends changes we have no features to apply to
+    async fn fetch_features_delta(
+        &self,
+        refresh: &TokenRefresh,
+    ) -> EdgeResult<ClientFeaturesResponse> {
+        let baseline = refresh.delta_revision_id.and_then(|revision_id| {
+            self.features_cache
+                .get(&cache_key(&refresh.token))
+                .map(|cached| {
+                    let mut features = cached.clone();
+                    features
+                        .features
+                        .retain(|feature| in_token_scope(&refresh.token, feature));
+                    (revision_id, features)
+                })
+        });
+        let revision_id = baseline.as_ref().map(|(revision_id, _)| *revision_id);
+        let response = self
+            .unleash_client
+            .get_client_features_delta(ClientFeaturesDeltaRequest {
+                api_key: refresh.token.token.clone(),
+                revision_id,
+            })
+            .await?;
+        match response {
+            ClientFeaturesDeltaResponse::NoUpdate => Ok(ClientFeaturesResponse::NoUpdate(
+                EntityTag::new_strong(revision_id.unwrap_or_default().to_string()),
+            )),
+            ClientFeaturesDeltaResponse::Updated(delta) => {
+                let new_revision_id = delta.revision_id().or(revision_id);
+                let applied = apply_delta(baseline.map(|(_, features)| features), delta);
+                if let Some(features) = &applied {
+                    self.unleash_client
+                        .check_feature_document_size(features, &refresh.token.token)?;
                 }
-                ClientFeaturesResponse::Updated(features, etag) => {
-                    debug!("Got updated client features. Updating features with {etag:?}");
-                    let key = cache_key(&refresh.token);
-                    self.update_last_refresh(&refresh.token, etag, features.features.len());
-                    self.features_cache
-                        .entry(key.clone())
-                        .and_modify(|existing_data| {
-                            let updated_data =
-                                update_client_features(&refresh.token, existing_data, &features);
-                            *existing_data = updated_data;
-                        })
-                        .or_insert_with(|| features.clone());
-                    self.engine_cache
-                        .entry(key.clone())
-                        .and_modify(|engine| {
-                            if let Some(f) = self.features_cache.get(&key) {
-                                let mut new_state = EngineState::default();
-                                let warnings = new_state.take_state(f.clone());
-                                if let Some(warnings) = warnings {
-                                    warn!("The following toggle failed to compile and will be defaulted to off: {warnings:?}");
-                                };
-                                *engine = new_state;
+                if let Some(mut known) = self.tokens_to_refresh.get_mut(&refresh.token.token) {
+                    known.delta_revision_id = applied.as_ref().and(new_revision_id);
+                }
+                match applied {
+                    Some(features) => Ok(ClientFeaturesResponse::Updated(features, None)),
+                    None => {
+                        warn!("Got changes from upstream without the features to apply them to, fetching all features instead");
+                        self.fetch_all_features(refresh).await
+                    }
+                }
+            }
+            ClientFeaturesDeltaResponse::Unsupported => {
+                info!("Upstream doesn't have the delta API, fetching all features from now on");
+                self.delta_api_unsupported.store(true, Ordering::Relaxed);
+                self.fetch_all_features(refresh).await
+            }
+        }
+    }
 
-                            }
-                        })
-                        .or_insert_with(|| {
-                            let mut new_state = EngineState::default();
+    async fn fetch_from_upstream(
+        &self,
+        refresh: &TokenRefresh,
+    ) -> EdgeResult<ClientFeaturesResponse> {
+        if self.use_delta_api && !self.delta_api_unsupported.load(Ordering::Relaxed) {
+            self.fetch_features_delta(refresh).await
+        } else {
+            self.fetch_all_features(refresh).await
+        }
+    }
 
-                            let warnings = new_state.take_state(features);
-                            if let Some(warnings) = warnings {
-                                warn!("The following toggle failed to compile and will be defaulted to off: {warnings:?}");
-                            };
-                            new_state
-                        });
-                }
-            },
-            Err(e) => {
-                match e {
-                    EdgeError::ClientFeaturesFetchError(fe) => {
-                        match fe {
-                            FeatureError::Retriable(status_code) => match status_code {
-                                StatusCode::INTERNAL_SERVER_ERROR
-                                | StatusCode::BAD_GATEWAY
-                                | StatusCode::SERVICE_UNAVAILABLE
-                                | StatusCode::GATEWAY_TIMEOUT => {
+    /// Reads the features from the shared database at most once per refresh check, however many tokens of other shards are due
+    async fn load_persisted_features(&self) -> EdgeResult<Arc<HashMap<String, ClientFeatures>>> {
+        let Some(persistence) = self.persistence.clone() else {
+            return Ok(Default::default());
+        };
+        let mut loaded = self.persisted_features.lock().await;
+        let reuse_for =
+            chrono::Duration::from_std(self.refresh_check_interval()).unwrap_or_default();
+        if let Some((loaded_at, features)) = loaded.as_ref() {
+            if Utc::now().signed_duration_since(*loaded_at) < reuse_for {
+                return Ok(features.clone());
+            }
+        }
+        let features = Arc::new(persistence.load_features().await?);
+        *loaded = Some((Utc::now(), features.clone()));
+        Ok(features)
+    }
+
+    /// Environments of other shards get the features their owner last persisted. Until it has persisted any, they're fetched from upstream
+    async fn fetch_persisted_features(
+        &self,
+        refresh: &TokenRefresh,
+    ) -> EdgeResult<ClientFeaturesResponse> {
+        match self
+            .load_persisted_features()
+            .await?
+            .get(&cache_key(&refresh.token))
+        {
+            Some(features) => Ok(ClientFeaturesResponse::Updated(features.clone(), None)),
+            None => {
+                debug!(
+                    "No features persisted for {:?} by its shard yet, fetching them from upstream",
+                    refresh.token.environment
+                );
+                self.fetch_from_upstream(refresh).await
+            }
+        }
+    }
+
+    /// Refreshes the tokens for `environment`, or every token without one, right away instead of when they're due. Tokens
+    /// backing off after upstream failures are left alone, and an open circuit breaker fails the fetches as usual
+    pub async fn refresh_now(&self, environment: Option<&str>) -> Vec<ForcedRefresh> {
+        let targets: Vec<TokenRefresh> = self
+            .tokens_to_refresh
+            .iter()
+            .map(|e| e.value().clone())
+            .filter(|refresh| {
+                environment.is_none() || refresh.token.environment.as_deref() == environment
+            })
+            .collect();
+        let mut refreshes = Vec::with_capacity(targets.len());
+        for target in targets {
+            let backing_off =
+                target.failure_count > 0 && self.waiting_to_refresh(&target, Utc::now());
+            let outcome = if backing_off {
+                ForcedRefreshOutcome::BackingOff
+            } else {
+                let token = target.token.token.clone();
+                let before = (target.last_refreshed, target.last_check);
+                self.refresh_single(target.clone()).await;
+                match self.tokens_to_refresh.get(&token) {
+                    Some(after) if after.last_refreshed != before.0 => {
+                        ForcedRefreshOutcome::Updated
+                    }
+                    Some(after) if after.last_check != before.1 => ForcedRefreshOutcome::Unchanged,
+                    _ => ForcedRefreshOutcome::Failed,
+                }
+            };
+            refreshes.push(ForcedRefresh {
+                environment: target.token.environment,
+                projects: target.token.projects,
+                outcome,
+            });
+        }
+        refreshes
+    }
+
+    async fn fetch_and_apply_features(&self, refresh: TokenRefresh) {
+        let environment = refresh_metrics_label(&refresh.token);
+        FEATURE_REFRESH_ATTEMPTS
+            .with_label_values(&[&environment])
+            .inc();
+        let start_time = Utc::now();
+        let features_result = if self.refreshes_from_upstream(&refresh.token) {
+            self.fetch_from_upstream(&refresh).await
+        } else {
+            self.fetch_persisted_features(&refresh).await
+        };
+        FEATURE_REFRESH_DURATION
+            .with_label_values(&[&environment])
+            .observe(
+                Utc::now()
+                    .signed_duration_since(start_time)
+                    .num_milliseconds() as f64,
+            );
+        match &features_result {
+            Ok(ClientFeaturesResponse::NoUpdate(_)) => {
+                FEATURE_REFRESH_NO_UPDATE
+                    .with_label_values(&[&environment])
+                    .inc();
+                self.record_successful_refresh(&environment);
+            }
+            Ok(ClientFeaturesResponse::Updated(_, _)) => {
+                FEATURE_REFRESH_UPDATED
+                    .with_label_values(&[&environment])
+                    .inc();
+                self.record_successful_refresh(&environment);
+            }
+            Err(e) => FEATURE_REFRESH_FAILURES
+                .with_label_values(&[&environment, &refresh_failure_status(e)])
+                .inc(),
+        }
+
+        match features_result {
+            Ok(feature_response) => match feature_response {
+                ClientFeaturesResponse::NoUpdate(tag) => {
+                    debug!("No update needed. Will update last check time with {tag}");
+                    self.update_last_check(&refresh.token.clone());
+                }
+                ClientFeaturesResponse::Updated(features, etag) => {
+                    debug!("Got updated client features. Updating features with {etag:?}");
+                    let key = cache_key(&refresh.token);
+                    self.update_last_refresh(&refresh.token, etag, features.features.len());
+                    let delta = match self.features_cache.entry(key.clone()) {
+                        Entry::Occupied(mut existing) => apply_client_features_update(
+                            &refresh.token,
+                            existing.get_mut(),
+                            features,
+                        ),
+                        Entry::Vacant(slot) => {
+                            let delta = FeatureDelta::from_new_features(&features);
+                            slot.insert(features);
+                            delta
+                        }
+                    };
+                    if delta.is_empty() && self.engine_cache.contains_key(&key) {
+                        debug!("Update from upstream did not change any features for {key}");
+                    } else {
+                        info!(
+                            environment = %key,
+                            added = delta.added.len(),
+                            changed = delta.changed.len(),
+                            removed = delta.removed.len(),
+                            segments_changed = delta.segments_changed,
+                            "Applied feature update"
+                        );
+                        if let Some(features) = self.features_cache.get(&key).map(|f| f.clone()) {
+                            let mut new_state = EngineState::default();
+                            let warnings = new_state.take_state(features);
+                            if let Some(warnings) = warnings {
+                                warn!("The following toggle failed to compile and will be defaulted to off: {warnings:?}");
+                            };
+                            self.engine_cache.insert(key.clone(), new_state);
+                        }
+                        if let Some(change_webhook) = &self.change_webhook {
+                            if !delta.is_empty() {
+                                change_webhook.notify(refresh.token.environment.clone(), &delta);
+                            }
+                        }
+                        self.broadcaster.notify(&key, delta);
+                    }
+                }
+            },
+            Err(e) => {
+                match e {
+                    EdgeError::ClientFeaturesFetchError(fe) => {
+                        match fe {
+                            FeatureError::Retriable(status_code) => match status_code {
+                                StatusCode::INTERNAL_SERVER_ERROR
+                                | StatusCode::BAD_GATEWAY
+                                | StatusCode::SERVICE_UNAVAILABLE
+                                | StatusCode::GATEWAY_TIMEOUT => {
                                     info!("Upstream is having some problems, increasing my waiting period");
                                     self.backoff(&refresh.token);
                                 }
@@ -393,42 +1403,227 @@ impl FeatureRefresher {
                                     info!("Couldn't refresh features, but will retry next go")
                                 }
                             },
+                            FeatureError::RateLimited(status_code, retry_after) => {
+                                info!(
+                                    "Upstream answered {status_code} and asked us to retry in {} seconds",
+                                    retry_after.num_seconds()
+                                );
+                                self.backoff_for_at_least(&refresh.token, retry_after);
+                            }
                             FeatureError::AccessDenied => {
-                                info!("Token used to fetch features was Forbidden, will remove from list of refresh tasks");
-                                self.tokens_to_refresh.remove(&refresh.token.token);
-                                if !self.tokens_to_refresh.iter().any(|e| {
-                                    e.value().token.environment == refresh.token.environment
-                                }) {
-                                    let cache_key = cache_key(&refresh.token);
-                                    // No tokens left that access the environment of our current refresh. Deleting client features and engine cache
-                                    self.features_cache.remove(&cache_key);
-                                    self.engine_cache.remove(&cache_key);
+                                warn!(
+                                    "Upstream no longer accepts the token {} for {environment}. Marking it invalid and no longer refreshing it until it's registered again",
+                                    anonymize_token(&refresh.token).token
+                                );
+                                UPSTREAM_AUTH_REVOCATIONS
+                                    .with_label_values(&[&environment])
+                                    .inc();
+                                if let Some(mut known) =
+                                    self.token_cache.get_mut(&refresh.token.token)
+                                {
+                                    known.status = TokenValidationStatus::Invalid;
                                 }
+                                self.stop_refreshing(&refresh.token);
                             }
                             FeatureError::NotFound => {
                                 info!("Had a bad URL when trying to fetch features. Increasing waiting period for the token before trying again");
                                 self.backoff(&refresh.token);
                             }
+                            FeatureError::Timeout => {
+                                warn!("Upstream did not answer in time when fetching features for {environment}. Increasing waiting period for the token before trying again");
+                                self.backoff(&refresh.token);
+                            }
                         }
                     }
                     EdgeError::ClientCacheError => {
                         info!("Couldn't refresh features, but will retry next go")
                     }
+                    EdgeError::ClientFeaturesParseError(reason) => {
+                        warn!("Upstream sent features for {environment} that couldn't be used: {reason}. Keeping the features we have and increasing waiting period for the token before trying again");
+                        self.backoff(&refresh.token);
+                    }
+                    EdgeError::FeatureDocumentTooLarge(limit) => {
+                        error!("Upstream sent features for {environment} larger than the {limit} bytes allowed by --max-feature-document-bytes. Keeping the features we have and increasing waiting period for the token before trying again");
+                        self.backoff(&refresh.token);
+                    }
                     _ => info!("Couldn't refresh features: {e:?}. Will retry next pass"),
                 }
             }
         }
     }
+    /// Features restored from persistence are as old as when they were persisted, so --max-stale-seconds and the cache age
+    /// count from then rather than from when Edge started. A replica whose clock is ahead may have persisted them "in the
+    /// future", which would keep them fresh until our clock caught up, so they're never taken to be newer than now
+    pub fn record_restored_features(&self, environment: &str, persisted_at: DateTime<Utc>) {
+        let persisted_at = persisted_at.min(Utc::now());
+        self.last_successful_refresh
+            .insert(environment.to_string(), persisted_at);
+        LAST_REFRESH_TIMESTAMP
+            .with_label_values(&[environment])
+            .set(persisted_at.timestamp() as f64);
+        self.update_feature_cache_age();
+    }
+
+    fn record_successful_refresh(&self, environment: &str) {
+        let now = Utc::now();
+        self.last_successful_refresh
+            .insert(environment.to_string(), now);
+        LAST_REFRESH_TIMESTAMP
+            .with_label_values(&[environment])
+            .set(now.timestamp() as f64);
+        FEATURE_CACHE_AGE.with_label_values(&[environment]).set(0.0);
+    }
+
+    /// The names of the token's features carrying any of the tags. The features upstream hands out to clients don't say
+    /// what they're tagged with, so upstream is asked with the tags instead. If it can't answer, the last answer is used.
+    /// Without one the features are refused, rather than served without the tags narrowing them
+    pub(crate) async fn tagged_feature_names(
+        &self,
+        token: &EdgeToken,
+        tags: &[String],
+    ) -> EdgeResult<Arc<HashSet<String>>> {
+        let key = (token.token.clone(), tags.to_vec());
+        let refresh_interval = self.refresh_interval.get();
+        let now = Utc::now();
+        let last_answer = self.tagged_features.get(&key).map(|tagged| tagged.clone());
+        if let Some((asked_at, names)) = &last_answer {
+            if now.signed_duration_since(*asked_at) < refresh_interval {
+                return Ok(names.clone());
+            }
+        }
+        let response = self
+            .unleash_client
+            .get_client_features(ClientFeaturesRequest {
+                api_key: token.token.clone(),
+                etag: None,
+                tags: tags.to_vec(),
+            })
+            .await;
+        let names: Arc<HashSet<String>> = match (response, last_answer) {
+            (Ok(ClientFeaturesResponse::Updated(features, _)), _) => Arc::new(
+                features
+                    .features
+                    .into_iter()
+                    .map(|feature| feature.name)
+                    .collect(),
+            ),
+            // Upstream only answers 304 to an ETag, and none is sent
+            (Ok(ClientFeaturesResponse::NoUpdate(_)), _) => Default::default(),
+            (Err(e), Some((_, names))) => {
+                warn!("Could not ask upstream for features tagged {tags:?}, using its last answer: {e:?}");
+                return Ok(names);
+            }
+            (Err(e), None) => {
+                warn!("Could not ask upstream for features tagged {tags:?}: {e:?}");
+                return Err(EdgeError::TaggedFeaturesUnavailable(tags.to_vec()));
+            }
+        };
+        self.tagged_features
+            .retain(|_, (asked_at, _)| now.signed_duration_since(*asked_at) < refresh_interval);
+        self.tagged_features.insert(key, (now, names.clone()));
+        Ok(names)
+    }
+
+    /// Cached features count as stale once their environment hasn't been refreshed for more than twice its refresh
+    /// interval, which means at least one refresh didn't make it
+    pub fn cache_status(&self, token: &EdgeToken) -> CacheStatus {
+        let last_refresh = self
+            .last_successful_refresh
+            .get(&refresh_metrics_label(token))
+            .map(|refreshed| *refreshed)
+            .unwrap_or(self.started_at);
+        let refresh_interval = self
+            .environment_refresh_interval(token)
+            .unwrap_or_else(|| self.refresh_interval.get());
+        if Utc::now().signed_duration_since(last_refresh) > refresh_interval * 2 {
+            CacheStatus::Stale
+        } else {
+            CacheStatus::Hit
+        }
+    }
+
+    /// Refuses features for the token's environment once they're older than the max staleness
+    pub fn check_staleness(&self, token: &EdgeToken) -> EdgeResult<()> {
+        let Some(max_staleness) = self.max_staleness else {
+            return Ok(());
+        };
+        let environment = refresh_metrics_label(token);
+        let last_refresh = self
+            .last_successful_refresh
+            .get(&environment)
+            .map(|refreshed| *refreshed)
+            .unwrap_or(self.started_at);
+        let age = Utc::now().signed_duration_since(last_refresh);
+        if age > max_staleness {
+            Err(EdgeError::FeaturesTooStale(environment, age.num_seconds()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stops refreshing features for the token. Features of an environment no remaining token refreshes are dropped
+    pub fn stop_refreshing(&self, token: &EdgeToken) {
+        let Some((_, refresh)) = self.tokens_to_refresh.remove(&token.token) else {
+            return;
+        };
+        if !self
+            .tokens_to_refresh
+            .iter()
+            .any(|e| e.value().token.environment == refresh.token.environment)
+        {
+            let cache_key = cache_key(&refresh.token);
+            // No tokens left that access the environment of the token. Deleting client features and engine cache
+            self.features_cache.remove(&cache_key);
+            self.engine_cache.remove(&cache_key);
+            self.forget_refresh_age(&refresh_metrics_label(&refresh.token));
+        }
+    }
+
+    fn forget_refresh_age(&self, environment: &str) {
+        self.last_successful_refresh.remove(environment);
+        let _ = FEATURE_CACHE_AGE.remove_label_values(&[environment]);
+        let _ = LAST_REFRESH_TIMESTAMP.remove_label_values(&[environment]);
+    }
+
+    /// Keeps the cache age growing while upstream doesn't answer, so it can be alerted on
+    pub fn update_feature_cache_age(&self) {
+        let now = Utc::now();
+        for entry in self.last_successful_refresh.iter() {
+            let age = now.signed_duration_since(*entry.value()).num_milliseconds() as f64 / 1000.0;
+            FEATURE_CACHE_AGE
+                .with_label_values(&[entry.key()])
+                .set(age.max(0.0));
+        }
+    }
+
     pub fn backoff(&self, token: &EdgeToken) {
         self.tokens_to_refresh
             .alter(&token.token, |_k, old_refresh| {
-                old_refresh.backoff(&self.refresh_interval)
+                old_refresh.backoff(
+                    &self.refresh_interval_for(&old_refresh),
+                    &self.max_refresh_backoff.get(),
+                )
+            });
+    }
+    /// Backs off as usual, but no sooner than upstream asked us to retry. Upstream asking for longer than the max backoff
+    /// gets the max backoff, so a misconfigured upstream can't stop refreshes for long
+    pub fn backoff_for_at_least(&self, token: &EdgeToken, retry_after: chrono::Duration) {
+        let max_backoff = self.max_refresh_backoff.get();
+        self.tokens_to_refresh
+            .alter(&token.token, |_k, old_refresh| {
+                let backed_off =
+                    old_refresh.backoff(&self.refresh_interval_for(&old_refresh), &max_backoff);
+                let retry_at = Utc::now() + retry_after.min(max_backoff);
+                TokenRefresh {
+                    next_refresh: backed_off.next_refresh.max(Some(retry_at)),
+                    ..backed_off
+                }
             });
     }
     pub fn update_last_check(&self, token: &EdgeToken) {
         self.tokens_to_refresh
             .alter(&token.token, |_k, old_refresh| {
-                old_refresh.successful_check(&self.refresh_interval)
+                old_refresh.successful_check(&self.refresh_interval_for(&old_refresh))
             });
     }
 
@@ -440,7 +1635,11 @@ impl FeatureRefresher {
     ) {
         self.tokens_to_refresh
             .alter(&token.token, |_k, old_refresh| {
-                old_refresh.successful_refresh(&self.refresh_interval, etag, feature_count)
+                old_refresh.successful_refresh(
+                    &self.refresh_interval_for(&old_refresh),
+                    etag,
+                    feature_count,
+                )
             });
     }
 }
@@ -459,21 +1658,31 @@ mod tests {
     use chrono::{Duration, Utc};
     use dashmap::DashMap;
     use reqwest::Url;
+    use tracing_test::traced_test;
     use unleash_types::client_features::{ClientFeature, ClientFeatures};
     use unleash_yggdrasil::EngineState;
 
+    use crate::auth::denied_tokens::DeniedTokens;
     use crate::filters::{project_filter, FeatureFilterSet};
-    use crate::http::unleash_client::new_reqwest_client;
+    use crate::http::unleash_client::{new_reqwest_client, UpstreamConnectionPool};
     use crate::tests::features_from_disk;
     use crate::tokens::cache_key;
     use crate::types::TokenValidationStatus::Validated;
     use crate::types::{TokenType, TokenValidationStatus};
     use crate::{
+        http::refresh_shard::RefreshShard,
         http::unleash_client::UnleashClient,
+        persistence::EdgePersistence,
         types::{EdgeToken, TokenRefresh},
     };
 
-    use super::{frontend_token_is_covered_by_tokens, FeatureRefresher};
+    use super::{
+        apply_client_features_update, apply_delta, apply_feature_update,
+        frontend_token_is_covered_by_tokens, startup_delay, CacheStatus, FeatureDelta,
+        FeatureRefresher, FEATURE_CACHE_AGE, FEATURE_REFRESH_ATTEMPTS, FEATURE_REFRESH_DURATION,
+        FEATURE_REFRESH_NO_UPDATE, FEATURE_REFRESH_UPDATED, LAST_REFRESH_TIMESTAMP,
+        UPSTREAM_AUTH_REVOCATIONS,
+    };
 
     impl PartialEq for TokenRefresh {
         fn eq(&self, other: &Self) -> bool {
@@ -493,6 +1702,8 @@ mod tests {
             Duration::seconds(5),
             Duration::seconds(5),
             "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
         )
         .expect("Failed to create client");
 
@@ -503,6 +1714,16 @@ mod tests {
         )
     }
 
+    fn update_projects_from_feature_update(
+        token: &EdgeToken,
+        original: &[ClientFeature],
+        updated: &[ClientFeature],
+    ) -> Vec<ClientFeature> {
+        let mut features = original.to_vec();
+        apply_feature_update(token, &mut features, updated.to_vec());
+        features
+    }
+
     #[tokio::test]
     pub async fn registering_token_for_refresh_works() {
         let unleash_client = create_test_client();
@@ -514,7 +1735,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let token =
@@ -526,6 +1747,77 @@ mod tests {
         assert_eq!(feature_refresher.tokens_to_refresh.len(), 1);
     }
 
+    #[tokio::test]
+    pub async fn denied_tokens_are_never_registered_for_refresh() {
+        let denied =
+            EdgeToken::try_from("*:development.abcdefghijklmnopqrstuvwxyz".to_string()).unwrap();
+        let allowed =
+            EdgeToken::try_from("*:production.abcdefghijklmnopqrstuvwxyz".to_string()).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(create_test_client()))
+            .with_denied_tokens(DeniedTokens::new([denied.token.clone()]));
+        feature_refresher
+            .register_token_for_refresh(denied.clone(), None)
+            .await;
+        feature_refresher.register_and_hydrate_token(&denied).await;
+        feature_refresher
+            .register_token_for_refresh(allowed.clone(), None)
+            .await;
+
+        assert!(!feature_refresher
+            .tokens_to_refresh
+            .contains_key(&denied.token));
+        assert!(feature_refresher
+            .tokens_to_refresh
+            .contains_key(&allowed.token));
+    }
+
+    #[tokio::test]
+    pub async fn environments_with_their_own_refresh_interval_are_refreshed_at_it() {
+        let feature_refresher = FeatureRefresher {
+            unleash_client: Arc::new(create_test_client()),
+            refresh_interval: Duration::seconds(60).into(),
+            environment_refresh_intervals: std::collections::HashMap::from([(
+                "production".to_string(),
+                Duration::seconds(2),
+            )]),
+            ..Default::default()
+        };
+        let production =
+            EdgeToken::try_from("*:production.abcdefghijklmnopqrstuvwxyz".to_string()).unwrap();
+        let development =
+            EdgeToken::try_from("*:development.abcdefghijklmnopqrstuvwxyz".to_string()).unwrap();
+        for token in [&production, &development] {
+            feature_refresher
+                .register_token_for_refresh(token.clone(), None)
+                .await;
+            feature_refresher.update_last_check(token);
+        }
+        let interval = |token: &EdgeToken| {
+            let refresh = feature_refresher
+                .tokens_to_refresh
+                .get(&token.token)
+                .unwrap();
+            refresh.next_refresh.unwrap() - refresh.last_check.unwrap()
+        };
+
+        assert_eq!(interval(&production), Duration::seconds(2));
+        assert_eq!(interval(&development), Duration::seconds(60));
+        assert_eq!(
+            feature_refresher.refresh_check_interval(),
+            std::time::Duration::from_secs(2)
+        );
+        let overridden = feature_refresher
+            .tokens_to_refresh
+            .get(&development.token)
+            .unwrap()
+            .clone()
+            .with_refresh_interval_seconds(Some(5));
+        assert_eq!(
+            feature_refresher.refresh_interval_for(&overridden),
+            Duration::seconds(5)
+        );
+    }
+
     #[tokio::test]
     pub async fn registering_multiple_tokens_with_same_environment_reduces_tokens_to_valid_minimal_set(
     ) {
@@ -538,7 +1830,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let token1 =
@@ -565,7 +1857,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let project_a_token =
@@ -590,6 +1882,31 @@ mod tests {
         assert_eq!(feature_refresher.tokens_to_refresh.len(), 3);
     }
 
+    #[tokio::test]
+    pub async fn tokens_for_the_same_environment_and_projects_are_refreshed_once_by_the_lowest() {
+        let lower =
+            EdgeToken::try_from("projecta:development.abcdefghijklmnopqrstuvwxyz".to_string())
+                .unwrap();
+        let higher =
+            EdgeToken::try_from("projecta:development.zyxwvutsrqponmlkjihgfedcba".to_string())
+                .unwrap();
+        for ordering in [
+            [lower.clone(), higher.clone()],
+            [higher.clone(), lower.clone()],
+        ] {
+            let feature_refresher = FeatureRefresher::with_client(Arc::new(create_test_client()));
+            for token in ordering {
+                feature_refresher
+                    .register_token_for_refresh(token, None)
+                    .await;
+            }
+            assert_eq!(feature_refresher.tokens_to_refresh.len(), 1);
+            assert!(feature_refresher
+                .tokens_to_refresh
+                .contains_key(&lower.token));
+        }
+    }
+
     #[tokio::test]
     pub async fn registering_wildcard_project_token_only_keeps_the_wildcard() {
         let unleash_client = create_test_client();
@@ -600,7 +1917,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let project_a_token =
@@ -644,7 +1961,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let project_a_token =
@@ -693,7 +2010,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let star_token =
@@ -726,7 +2043,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let project_a_token =
@@ -754,7 +2071,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: duration,
+            refresh_interval: duration.into(),
             ..Default::default()
         };
         let no_etag_due_for_refresh_token =
@@ -768,6 +2085,8 @@ mod tests {
             last_check: None,
             failure_count: 0,
             last_feature_count: None,
+            delta_revision_id: None,
+            refresh_interval_seconds: None,
         };
         let etag_and_last_refreshed_token =
             EdgeToken::try_from("projectb:development.etag_and_last_refreshed_token".to_string())
@@ -780,6 +2099,8 @@ mod tests {
             last_check: Some(Utc::now()),
             failure_count: 0,
             last_feature_count: None,
+            delta_revision_id: None,
+            refresh_interval_seconds: None,
         };
         let etag_but_old_token =
             EdgeToken::try_from("projectb:development.etag_but_old_token".to_string()).unwrap();
@@ -793,6 +2114,8 @@ mod tests {
             last_check: Some(ten_seconds_ago),
             failure_count: 0,
             last_feature_count: None,
+            delta_revision_id: None,
+            refresh_interval_seconds: None,
         };
         feature_refresher.tokens_to_refresh.insert(
             etag_but_last_refreshed_ten_seconds_ago.token.token.clone(),
@@ -815,6 +2138,48 @@ mod tests {
         assert!(tokens_to_refresh.contains(&no_etag_so_is_due_for_refresh));
     }
 
+    #[test]
+    pub fn a_skewed_clock_neither_holds_off_refreshes_nor_keeps_stale_features_fresh() {
+        let feature_refresher = FeatureRefresher {
+            max_refresh_backoff: Duration::seconds(300).into(),
+            max_staleness: Some(Duration::seconds(60)),
+            ..Default::default()
+        };
+        let refresh_at = |token: &str, next_refresh| TokenRefresh {
+            next_refresh: Some(next_refresh),
+            failure_count: 1,
+            ..TokenRefresh::new(
+                EdgeToken::try_from(format!("*:development.{token}")).unwrap(),
+                None,
+            )
+        };
+        let backing_off = refresh_at("backingoff", Utc::now() + Duration::seconds(240));
+        let set_back_clock = refresh_at("setbackclock", Utc::now() + Duration::days(1));
+        for refresh in [&backing_off, &set_back_clock] {
+            feature_refresher
+                .tokens_to_refresh
+                .insert(refresh.token.token.clone(), refresh.clone());
+        }
+        assert_eq!(
+            feature_refresher.get_tokens_due_for_refresh(),
+            vec![set_back_clock.clone()]
+        );
+
+        let token = set_back_clock.token;
+        feature_refresher
+            .record_restored_features("development", Utc::now() - Duration::minutes(5));
+        assert!(feature_refresher.check_staleness(&token).is_err());
+        feature_refresher.record_restored_features("development", Utc::now() + Duration::hours(1));
+        assert!(feature_refresher.check_staleness(&token).is_ok());
+        assert!(
+            *feature_refresher
+                .last_successful_refresh
+                .get("development")
+                .unwrap()
+                <= Utc::now()
+        );
+    }
+
     async fn client_api_test_server(
         upstream_token_cache: Arc<DashMap<String, EdgeToken>>,
         upstream_features_cache: Arc<DashMap<String, ClientFeatures>>,
@@ -826,13 +2191,408 @@ mod tests {
                     .app_data(web::Data::from(upstream_features_cache.clone()))
                     .app_data(web::Data::from(upstream_engine_cache.clone()))
                     .app_data(web::Data::from(upstream_token_cache.clone()))
-                    .service(web::scope("/api").configure(crate::client_api::configure_client_api)),
+                    .service(web::scope("/api").configure(|cfg| {
+                        crate::client_api::configure_client_api(
+                            cfg,
+                            crate::client_api::DEFAULT_MAX_METRICS_BODY_BYTES,
+                        )
+                    })),
                 |_| AppConfig::default(),
             ))
             .tcp()
         })
         .await
     }
+    #[tokio::test]
+    pub async fn timed_out_feature_fetches_are_counted_and_backed_off() {
+        let server = test_server(move || {
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(|| async {
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        actix_web::HttpResponse::Ok().finish()
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let http_client = new_reqwest_client(
+            "unleash_edge".into(),
+            false,
+            None,
+            None,
+            Duration::seconds(1),
+            Duration::milliseconds(100),
+            "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
+        )
+        .unwrap();
+        let unleash_client = UnleashClient::from_url(
+            Url::parse(&server.url("/")).unwrap(),
+            "Authorization".into(),
+            http_client,
+        );
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let token = EdgeToken::validated_client_token("*:upstream-hangs.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+
+        let started = std::time::Instant::now();
+        feature_refresher.refresh_features().await;
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        let refresh = feature_refresher
+            .tokens_to_refresh
+            .get(&token.token)
+            .unwrap()
+            .clone();
+        assert_eq!(refresh.failure_count, 1);
+        assert!(refresh.next_refresh.is_some());
+        assert_eq!(
+            super::FEATURE_REFRESH_FAILURES
+                .with_label_values(&["upstream-hangs", "timeout"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    pub async fn refreshes_wait_as_long_as_upstream_asks_when_rate_limited() {
+        let server = test_server(move || {
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(|| async {
+                        actix_web::HttpResponse::TooManyRequests()
+                            .insert_header(("Retry-After", "120"))
+                            .finish()
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client))
+            .with_max_refresh_backoff(Duration::seconds(300));
+        let token = EdgeToken::validated_client_token("*:rate-limited.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+
+        let before = Utc::now();
+        feature_refresher.refresh_features().await;
+
+        let refresh = feature_refresher
+            .tokens_to_refresh
+            .get(&token.token)
+            .unwrap()
+            .clone();
+        assert_eq!(refresh.failure_count, 1);
+        assert!(refresh.next_refresh.unwrap() >= before + Duration::seconds(120));
+        assert!(refresh.next_refresh.unwrap() <= Utc::now() + Duration::seconds(300));
+        assert_eq!(
+            super::FEATURE_REFRESH_FAILURES
+                .with_label_values(&["rate-limited", "429"])
+                .get(),
+            1
+        );
+    }
+
+    fn features_with_segment_requiring_user_id(user_id: &str) -> String {
+        serde_json::json!({
+            "version": 2,
+            "features": (["segmented", "also-segmented"].map(|name| serde_json::json!({
+                "name": name,
+                "project": "default",
+                "enabled": true,
+                "strategies": [{ "name": "default", "segments": [1], "constraints": [] }]
+            }))),
+            "segments": [{
+                "id": 1,
+                "constraints": [{
+                    "contextName": "userId",
+                    "operator": "IN",
+                    "values": [user_id],
+                    "caseInsensitive": false,
+                    "inverted": false
+                }]
+            }]
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    pub async fn segments_are_cached_and_evaluated_and_follow_upstream_updates() {
+        let payloads = Arc::new(vec![
+            features_with_segment_requiring_user_id("7"),
+            features_with_segment_requiring_user_id("8"),
+        ]);
+        let served = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = test_server(move || {
+            let payloads = payloads.clone();
+            let served = served.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move || {
+                        let payload = payloads
+                            [served.fetch_add(1, std::sync::atomic::Ordering::SeqCst)]
+                        .clone();
+                        async move {
+                            actix_web::HttpResponse::Ok()
+                                .content_type("application/json")
+                                .body(payload)
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let token = EdgeToken::validated_client_token("*:segments.secret123");
+        let enabled_for = |user_id: &str| {
+            let context = unleash_yggdrasil::Context {
+                user_id: Some(user_id.into()),
+                ..Default::default()
+            };
+            let engine = feature_refresher
+                .engine_cache
+                .get(&cache_key(&token))
+                .unwrap();
+            let enabled = engine.is_enabled("segmented", &context, &None);
+            assert_eq!(
+                engine.is_enabled("also-segmented", &context, &None),
+                enabled,
+                "features sharing a segment disagree for userId {user_id}"
+            );
+            enabled
+        };
+
+        feature_refresher
+            .refresh_single(TokenRefresh::new(token.clone(), None))
+            .await;
+        let cached = feature_refresher
+            .features_cache
+            .get(&cache_key(&token))
+            .unwrap()
+            .clone();
+        assert_eq!(cached.segments.unwrap().len(), 1);
+        assert!(enabled_for("7"));
+        assert!(!enabled_for("8"));
+
+        feature_refresher
+            .refresh_single(TokenRefresh::new(token.clone(), None))
+            .await;
+        assert!(!enabled_for("7"));
+        assert!(enabled_for("8"));
+    }
+
+    #[tokio::test]
+    pub async fn malformed_feature_payloads_keep_the_features_we_have() {
+        let features = features_from_disk("../examples/features.json");
+        let mut duplicated = features.clone();
+        duplicated.features.push(duplicated.features[0].clone());
+        let payloads = Arc::new(vec![
+            serde_json::to_string(&features).unwrap(),
+            r#"{"version":2,"features":[{"name":"trunc"#.to_string(),
+            r#"{"version":2}"#.to_string(),
+            r#"{"version":0,"features":[]}"#.to_string(),
+            serde_json::to_string(&duplicated).unwrap(),
+        ]);
+        let served = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = test_server(move || {
+            let payloads = payloads.clone();
+            let served = served.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move || {
+                        let payload = payloads
+                            [served.fetch_add(1, std::sync::atomic::Ordering::SeqCst)]
+                        .clone();
+                        async move {
+                            actix_web::HttpResponse::Ok()
+                                .content_type("application/json")
+                                .body(payload)
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let token = EdgeToken::validated_client_token("*:malformed-payloads.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+
+        for _ in 0..5 {
+            feature_refresher
+                .refresh_single(TokenRefresh::new(token.clone(), None))
+                .await;
+            assert_eq!(
+                feature_refresher
+                    .features_cache
+                    .get(&cache_key(&token))
+                    .unwrap()
+                    .features
+                    .len(),
+                features.features.len()
+            );
+        }
+        assert_eq!(
+            super::FEATURE_REFRESH_FAILURES
+                .with_label_values(&["malformed-payloads", "invalid_payload"])
+                .get(),
+            4
+        );
+        assert_eq!(
+            feature_refresher
+                .tokens_to_refresh
+                .get(&token.token)
+                .unwrap()
+                .failure_count,
+            4
+        );
+    }
+
+    #[tokio::test]
+    pub async fn feature_documents_over_the_size_limit_keep_the_features_we_have() {
+        let features = features_from_disk("../examples/features.json");
+        let mut oversized = features.clone();
+        oversized.features[0].description = Some("x".repeat(64 * 1024));
+        let payloads = Arc::new(vec![
+            serde_json::to_string(&features).unwrap(),
+            serde_json::to_string(&oversized).unwrap(),
+        ]);
+        let limit = payloads[0].len() + 1024;
+        let served = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = test_server(move || {
+            let payloads = payloads.clone();
+            let served = served.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move || {
+                        let payload = payloads
+                            [served.fetch_add(1, std::sync::atomic::Ordering::SeqCst)]
+                        .clone();
+                        async move {
+                            actix_web::HttpResponse::Ok()
+                                .content_type("application/json")
+                                .body(payload)
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None)
+            .unwrap()
+            .with_max_feature_document_bytes(Some(limit));
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let token = EdgeToken::validated_client_token("*:oversized-documents.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+
+        for _ in 0..2 {
+            feature_refresher
+                .refresh_single(TokenRefresh::new(token.clone(), None))
+                .await;
+            assert_eq!(
+                feature_refresher
+                    .features_cache
+                    .get(&cache_key(&token))
+                    .unwrap()
+                    .features[0]
+                    .description,
+                features.features[0].description
+            );
+        }
+        assert_eq!(
+            crate::http::unleash_client::OVERSIZED_FEATURE_DOCUMENTS
+                .with_label_values(&["oversized-documents"])
+                .get(),
+            1
+        );
+        assert_eq!(
+            super::FEATURE_REFRESH_FAILURES
+                .with_label_values(&["oversized-documents", "too_large"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    pub async fn a_hanging_refresh_does_not_hold_up_refreshes_for_other_environments() {
+        let server = test_server(move || {
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(|req: actix_web::HttpRequest| async move {
+                        let token = req
+                            .headers()
+                            .get("Authorization")
+                            .unwrap()
+                            .to_str()
+                            .unwrap();
+                        if token.contains("hangs") {
+                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        }
+                        actix_web::HttpResponse::Ok()
+                            .json(features_from_disk("../examples/features.json"))
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let hanging = EdgeToken::validated_client_token("*:hangs.secret123");
+        let answering = EdgeToken::validated_client_token("*:answers.secret123");
+        for token in [&hanging, &answering] {
+            feature_refresher
+                .tokens_to_refresh
+                .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        }
+
+        feature_refresher.spawn_due_refreshes();
+        let started = std::time::Instant::now();
+        while feature_refresher
+            .refreshes_in_flight
+            .contains(&answering.token)
+        {
+            assert!(started.elapsed() < std::time::Duration::from_secs(2));
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert!(feature_refresher.features_cache.contains_key("answers"));
+        assert!(!feature_refresher.features_cache.contains_key("hangs"));
+        assert!(feature_refresher
+            .refreshes_in_flight
+            .contains(&hanging.token));
+        feature_refresher.spawn_due_refreshes();
+        assert_eq!(feature_refresher.refreshes_in_flight.len(), 1);
+    }
+
     #[tokio::test]
     pub async fn getting_403_when_refreshing_features_will_remove_token() {
         let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
@@ -852,7 +2612,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: Duration::seconds(60),
+            refresh_interval: Duration::seconds(60).into(),
             ..Default::default()
         };
         let mut token = EdgeToken::try_from("*:development.secret123".to_string()).unwrap();
@@ -898,7 +2658,7 @@ mod tests {
             unleash_client: Arc::new(unleash_client),
             features_cache,
             engine_cache,
-            refresh_interval: Duration::milliseconds(1),
+            refresh_interval: Duration::milliseconds(1).into(),
             ..Default::default()
         };
         feature_refresher
@@ -925,6 +2685,132 @@ mod tests {
         assert!(warnings.is_none());
     }
 
+    #[tokio::test]
+    pub async fn feature_cache_age_grows_until_the_next_successful_refresh() {
+        let mut token = EdgeToken::try_from("*:cacheage.secret123".to_string()).unwrap();
+        token.status = Validated;
+        token.token_type = Some(TokenType::Client);
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        upstream_features_cache.insert(
+            cache_key(&token),
+            features_from_disk("../examples/features.json"),
+        );
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            Arc::new(DashMap::default()),
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        feature_refresher
+            .register_token_for_refresh(token, None)
+            .await;
+
+        let label = ["cacheage"];
+        let before = Utc::now().timestamp() as f64;
+        feature_refresher.hydrate_new_tokens().await;
+        assert!(LAST_REFRESH_TIMESTAMP.with_label_values(&label).get() >= before);
+        assert_eq!(FEATURE_CACHE_AGE.with_label_values(&label).get(), 0.0);
+
+        feature_refresher
+            .last_successful_refresh
+            .insert("cacheage".into(), Utc::now() - Duration::seconds(90));
+        feature_refresher.update_feature_cache_age();
+        let age = FEATURE_CACHE_AGE.with_label_values(&label).get();
+        assert!((90.0..95.0).contains(&age));
+    }
+
+    #[tokio::test]
+    pub async fn refreshing_features_records_metrics_by_environment() {
+        let mut token = EdgeToken::try_from("*:refreshmetrics.secret123".to_string()).unwrap();
+        token.status = Validated;
+        token.token_type = Some(TokenType::Client);
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let example_features = features_from_disk("../examples/features.json");
+        let mut engine_state = EngineState::default();
+        engine_state.take_state(example_features.clone());
+        upstream_features_cache.insert(cache_key(&token), example_features);
+        upstream_engine_cache.insert(cache_key(&token), engine_state);
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
+        feature_refresher
+            .register_token_for_refresh(token, None)
+            .await;
+
+        feature_refresher.refresh_features().await;
+        feature_refresher.refresh_features().await;
+
+        let label = ["refreshmetrics"];
+        assert_eq!(FEATURE_REFRESH_ATTEMPTS.with_label_values(&label).get(), 2);
+        assert_eq!(
+            FEATURE_REFRESH_UPDATED.with_label_values(&label).get()
+                + FEATURE_REFRESH_NO_UPDATE.with_label_values(&label).get(),
+            2
+        );
+        assert_eq!(
+            FEATURE_REFRESH_DURATION
+                .with_label_values(&label)
+                .get_sample_count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    pub async fn updated_features_are_broadcast_to_subscribers() {
+        let mut token = EdgeToken::try_from("*:broadcast.secret123".to_string()).unwrap();
+        token.status = Validated;
+        token.token_type = Some(TokenType::Client);
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let example_features = features_from_disk("../examples/features.json");
+        let mut engine_state = EngineState::default();
+        engine_state.take_state(example_features.clone());
+        upstream_features_cache.insert(cache_key(&token), example_features);
+        upstream_engine_cache.insert(cache_key(&token), engine_state);
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
+        let mut subscription = feature_refresher.broadcaster.subscribe().unwrap();
+        feature_refresher
+            .register_token_for_refresh(token, None)
+            .await;
+
+        feature_refresher.refresh_features().await;
+
+        let update = subscription.recv().await.unwrap();
+        assert_eq!(update.cache_key, "broadcast");
+        assert_eq!(
+            update.delta.added.len(),
+            features_from_disk("../examples/features.json")
+                .features
+                .len()
+        );
+    }
+
     #[tokio::test]
     pub async fn when_we_have_a_cache_and_token_gets_removed_caches_are_emptied() {
         let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
@@ -950,7 +2836,7 @@ mod tests {
         .await;
         let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
         let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
-        feature_refresher.refresh_interval = Duration::seconds(0);
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
         feature_refresher
             .register_token_for_refresh(valid_token.clone(), None)
             .await;
@@ -966,6 +2852,128 @@ mod tests {
         assert!(warnings.is_none());
     }
 
+    #[test]
+    pub fn startup_delay_stays_within_the_configured_jitter() {
+        assert!(startup_delay(Duration::zero()).is_zero());
+        assert!(startup_delay(Duration::seconds(-5)).is_zero());
+        for _ in 0..100 {
+            assert!(startup_delay(Duration::seconds(2)) <= std::time::Duration::from_secs(2));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn tokens_rejected_by_upstream_are_marked_invalid_and_no_longer_refreshed() {
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut valid_token =
+            EdgeToken::try_from("*:upstream-revoked.secret123".to_string()).unwrap();
+        valid_token.token_type = Some(TokenType::Client);
+        valid_token.status = Validated;
+        upstream_token_cache.insert(valid_token.token.clone(), valid_token.clone());
+        let server = client_api_test_server(
+            upstream_token_cache.clone(),
+            Arc::new(DashMap::default()),
+            Arc::new(DashMap::default()),
+        )
+        .await;
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        token_cache.insert(valid_token.token.clone(), valid_token.clone());
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client))
+            .with_token_cache(token_cache.clone());
+        feature_refresher
+            .register_token_for_refresh(valid_token.clone(), None)
+            .await;
+        upstream_token_cache.remove(&valid_token.token);
+
+        feature_refresher.refresh_features().await;
+
+        assert!(feature_refresher.tokens_to_refresh.is_empty());
+        assert_eq!(
+            token_cache.get(&valid_token.token).unwrap().status,
+            TokenValidationStatus::Invalid
+        );
+        assert_eq!(
+            UPSTREAM_AUTH_REVOCATIONS
+                .with_label_values(&["upstream-revoked"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    pub async fn tokens_for_different_environments_do_not_share_a_cache_entry() {
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut development_token =
+            EdgeToken::try_from("*:development.secret123".to_string()).unwrap();
+        development_token.token_type = Some(TokenType::Client);
+        development_token.status = Validated;
+        let mut production_token =
+            EdgeToken::try_from("*:production.secret123".to_string()).unwrap();
+        production_token.token_type = Some(TokenType::Client);
+        production_token.status = Validated;
+        let development_features = features_from_disk("../examples/features.json");
+        let production_features = ClientFeatures {
+            version: 2,
+            features: vec![ClientFeature {
+                name: "only.in.production".into(),
+                project: Some("default".into()),
+                enabled: true,
+                ..ClientFeature::default()
+            }],
+            segments: None,
+            query: None,
+        };
+        for (token, features) in [
+            (&development_token, &development_features),
+            (&production_token, &production_features),
+        ] {
+            upstream_token_cache.insert(token.token.clone(), token.clone());
+            let mut engine_state = EngineState::default();
+            engine_state.take_state(features.clone());
+            upstream_features_cache.insert(cache_key(token), features.clone());
+            upstream_engine_cache.insert(cache_key(token), engine_state);
+        }
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        feature_refresher
+            .register_token_for_refresh(development_token.clone(), None)
+            .await;
+        feature_refresher
+            .register_token_for_refresh(production_token.clone(), None)
+            .await;
+        feature_refresher.refresh_features().await;
+
+        assert_ne!(cache_key(&development_token), cache_key(&production_token));
+        assert_eq!(feature_refresher.features_cache.len(), 2);
+        let (production, _) = feature_refresher
+            .features_for_filter(production_token.clone(), &FeatureFilterSet::default())
+            .await
+            .unwrap();
+        assert_eq!(production.features.len(), 1);
+        assert_eq!(production.features[0].name, "only.in.production");
+        let (development, _) = feature_refresher
+            .features_for_filter(development_token.clone(), &FeatureFilterSet::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            development.features.len(),
+            development_features.features.len()
+        );
+        assert!(development
+            .features
+            .iter()
+            .all(|f| f.name != "only.in.production"));
+    }
+
     #[tokio::test]
     pub async fn removing_one_of_multiple_keys_from_same_environment_does_not_remove_feature_and_engine_caches(
     ) {
@@ -996,7 +3004,7 @@ mod tests {
         .await;
         let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
         let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
-        feature_refresher.refresh_interval = Duration::seconds(0);
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
         feature_refresher
             .register_token_for_refresh(dx_token.clone(), None)
             .await;
@@ -1047,8 +3055,8 @@ mod tests {
         let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
         let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
         feature_refresher.strict = false;
-        feature_refresher.refresh_interval = Duration::seconds(0);
-        let dx_features = feature_refresher
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
+        let (dx_features, _) = feature_refresher
             .features_for_filter(
                 dx_token.clone(),
                 &FeatureFilterSet::from(project_filter(&dx_token)),
@@ -1060,7 +3068,7 @@ mod tests {
             .iter()
             .all(|f| f.project == Some("dx".into())));
         assert_eq!(dx_features.features.len(), 16);
-        let eg_features = feature_refresher
+        let (eg_features, _) = feature_refresher
             .features_for_filter(
                 eg_token.clone(),
                 &FeatureFilterSet::from(project_filter(&eg_token)),
@@ -1075,6 +3083,107 @@ mod tests {
         assert!(warnings.is_none());
     }
 
+    #[tokio::test]
+    pub async fn registered_tokens_without_cached_features_fetch_them_on_first_request() {
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        let example_features = features_from_disk("../examples/features.json");
+        let mut engine_state = EngineState::default();
+        engine_state.take_state(example_features.clone());
+        upstream_features_cache.insert(cache_key(&token), example_features.clone());
+        upstream_engine_cache.insert(cache_key(&token), engine_state);
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        assert!(feature_refresher.strict);
+        feature_refresher
+            .register_token_for_refresh(token.clone(), None)
+            .await;
+        assert!(feature_refresher.features_cache.is_empty());
+
+        let (features, cache_status) = feature_refresher
+            .features_for_filter(token.clone(), &FeatureFilterSet::default())
+            .await
+            .expect("Features should be fetched on the cache miss");
+        assert_eq!(cache_status, CacheStatus::Miss);
+        assert_eq!(features.features.len(), example_features.features.len());
+        assert!(feature_refresher
+            .features_cache
+            .contains_key(&cache_key(&token)));
+
+        let (_, cache_status) = feature_refresher
+            .features_for_filter(token.clone(), &FeatureFilterSet::default())
+            .await
+            .unwrap();
+        assert_eq!(cache_status, CacheStatus::Hit);
+        feature_refresher.last_successful_refresh.insert(
+            "development".into(),
+            Utc::now() - feature_refresher.refresh_interval.get() * 3,
+        );
+        let (_, cache_status) = feature_refresher
+            .features_for_filter(token.clone(), &FeatureFilterSet::default())
+            .await
+            .unwrap();
+        assert_eq!(cache_status, CacheStatus::Stale);
+    }
+
+    #[tokio::test]
+    pub async fn cache_misses_do_not_fetch_for_tokens_backing_off() {
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        upstream_features_cache.insert(
+            cache_key(&token),
+            features_from_disk("../examples/features.json"),
+        );
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
+        let backing_off = TokenRefresh {
+            failure_count: 3,
+            next_refresh: Some(Utc::now() + Duration::seconds(60)),
+            ..TokenRefresh::new(token.clone(), None)
+        };
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), backing_off.clone());
+
+        assert!(feature_refresher
+            .features_for_filter(token.clone(), &FeatureFilterSet::default())
+            .await
+            .is_err());
+        assert!(feature_refresher.features_cache.is_empty());
+
+        feature_refresher.tokens_to_refresh.insert(
+            token.token.clone(),
+            TokenRefresh {
+                next_refresh: None,
+                ..backing_off
+            },
+        );
+        assert!(feature_refresher
+            .features_for_filter(token.clone(), &FeatureFilterSet::default())
+            .await
+            .is_ok());
+    }
+
     #[tokio::test]
     pub async fn should_get_data_for_multi_project_token_even_if_we_have_data_for_one_of_the_projects_when_dynamic(
     ) {
@@ -1110,8 +3219,8 @@ mod tests {
         let unleash_client = UnleashClient::new(server.url("/").as_str(), None).unwrap();
         let mut feature_refresher = FeatureRefresher::with_client(Arc::new(unleash_client));
         feature_refresher.strict = false;
-        feature_refresher.refresh_interval = Duration::seconds(0);
-        let dx_features = feature_refresher
+        feature_refresher.refresh_interval = Duration::seconds(0).into();
+        let (dx_features, _) = feature_refresher
             .features_for_filter(
                 dx_token.clone(),
                 &FeatureFilterSet::from(project_filter(&dx_token)),
@@ -1119,7 +3228,7 @@ mod tests {
             .await
             .expect("No dx features found");
         assert_eq!(dx_features.features.len(), 16);
-        let unleash_cloud_features = feature_refresher
+        let (unleash_cloud_features, _) = feature_refresher
             .features_for_filter(
                 multitoken.clone(),
                 &FeatureFilterSet::from(project_filter(&multitoken)),
@@ -1142,7 +3251,7 @@ mod tests {
                 .count(),
             7
         );
-        let eg_features = feature_refresher
+        let (eg_features, _) = feature_refresher
             .features_for_filter(
                 eg_token.clone(),
                 &FeatureFilterSet::from(project_filter(&eg_token)),
@@ -1183,6 +3292,8 @@ mod tests {
             last_check: None,
             failure_count: 0,
             last_feature_count: None,
+            delta_revision_id: None,
+            refresh_interval_seconds: None,
         };
 
         current_tokens.insert(wildcard_token.token, token_refresh);
@@ -1221,7 +3332,7 @@ mod tests {
         let feature_refresher = FeatureRefresher {
             unleash_client: Arc::new(unleash_client),
             features_cache: features_cache.clone(),
-            refresh_interval: Duration::seconds(0),
+            refresh_interval: Duration::seconds(0).into(),
             ..Default::default()
         };
 
@@ -1259,7 +3370,7 @@ mod tests {
         token.status = TokenValidationStatus::Validated;
         token.projects = vec![String::from("dx")];
 
-        let updated = super::update_projects_from_feature_update(&token, &features, &dx_data);
+        let updated = update_projects_from_feature_update(&token, &features, &dx_data);
         assert_ne!(
             features
                 .iter()
@@ -1305,7 +3416,7 @@ mod tests {
             projects: vec![String::from("dx"), String::from("eg")],
             status: TokenValidationStatus::Validated,
         };
-        let update = super::update_projects_from_feature_update(&edge_token, &features, &dx_data);
+        let update = update_projects_from_feature_update(&edge_token, &features, &dx_data);
         assert_eq!(features.len() - update.len(), 2); // We've removed two elements
     }
 
@@ -1325,9 +3436,54 @@ mod tests {
             .filter(|f| f.project == Some("eg".into()))
             .cloned()
             .collect();
-        let update = super::update_projects_from_feature_update(&edge_token, &features, &eg_data);
+        let update = update_projects_from_feature_update(&edge_token, &features, &eg_data);
         assert!(!update.iter().any(|p| p.project == Some(String::from("dx"))));
     }
+
+    #[test]
+    #[traced_test]
+    pub fn features_from_several_project_tokens_are_merged_and_name_conflicts_are_logged() {
+        let feature = |name: &str, project: &str| ClientFeature {
+            name: name.into(),
+            project: Some(project.into()),
+            enabled: true,
+            ..ClientFeature::default()
+        };
+        let token_for = |project: &str| EdgeToken {
+            token: format!("{project}:development.secret"),
+            token_type: Some(TokenType::Client),
+            environment: Some("development".into()),
+            projects: vec![project.into()],
+            status: TokenValidationStatus::Validated,
+        };
+        let mut features = vec![];
+        apply_feature_update(
+            &token_for("dx"),
+            &mut features,
+            vec![feature("dx-only", "dx"), feature("shared-name", "dx")],
+        );
+        apply_feature_update(
+            &token_for("eg"),
+            &mut features,
+            vec![feature("eg-only", "eg")],
+        );
+        let names: Vec<&str> = features.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["dx-only", "eg-only", "shared-name"]);
+        assert!(!logs_contain("was cached from project"));
+
+        apply_feature_update(
+            &token_for("eg"),
+            &mut features,
+            vec![feature("eg-only", "eg"), feature("shared-name", "eg")],
+        );
+        let shared = features.iter().find(|f| f.name == "shared-name").unwrap();
+        assert_eq!(shared.project, Some("eg".into()));
+        assert_eq!(features.len(), 3);
+        assert!(logs_contain(
+            "Feature shared-name was cached from project dx, but is now in project eg"
+        ));
+    }
+
     #[test]
     pub fn if_token_does_not_have_access_to_project_no_update_happens_to_project() {
         let features = features_from_disk("../examples/hostedexample.json").features;
@@ -1343,7 +3499,7 @@ mod tests {
             .filter(|f| f.project == Some("eg".into()))
             .cloned()
             .collect();
-        let update = super::update_projects_from_feature_update(&edge_token, &features, &eg_data);
+        let update = update_projects_from_feature_update(&edge_token, &features, &eg_data);
         assert_eq!(
             update
                 .iter()
@@ -1398,7 +3554,7 @@ mod tests {
             .filter(|t| t.project == Some("default".into()))
             .cloned()
             .collect();
-        let updated = super::update_projects_from_feature_update(&edge_token, &features, &update);
+        let updated = update_projects_from_feature_update(&edge_token, &features, &update);
         assert_eq!(updated.len(), 1);
         assert!(updated.iter().all(|f| f.project == Some("default".into())))
     }
@@ -1445,7 +3601,7 @@ mod tests {
             projects: vec![String::from("someother")],
             status: TokenValidationStatus::Validated,
         };
-        let updated = super::update_projects_from_feature_update(
+        let updated = update_projects_from_feature_update(
             &unrelated_token_to_existing_features,
             &features,
             &empty_features,
@@ -1494,11 +3650,505 @@ mod tests {
             projects: vec![String::from("testproject"), String::from("someother")],
             status: TokenValidationStatus::Validated,
         };
-        let updated = super::update_projects_from_feature_update(
+        let updated = update_projects_from_feature_update(
             &token_with_access_to_both_empty_and_full_project,
             &features,
             &empty_features,
         );
         assert_eq!(updated.len(), 0);
     }
+
+    #[test]
+    pub fn applying_an_update_reports_added_changed_and_removed_features() {
+        let mut cached = features_from_disk("../examples/hostedexample.json");
+        let mut update = cached.clone();
+        let removed = update.features.remove(0);
+        update.features[0].enabled = !update.features[0].enabled;
+        let changed = update.features[0].name.clone();
+        update.features.push(ClientFeature {
+            name: "a.brand.new.toggle".into(),
+            ..update.features[0].clone()
+        });
+        let token = EdgeToken::from_str("*:development.somesecret").unwrap();
+
+        let delta = apply_client_features_update(&token, &mut cached, update.clone());
+
+        assert_eq!(
+            delta,
+            FeatureDelta {
+                added: vec!["a.brand.new.toggle".into()],
+                changed: vec![changed],
+                removed: vec![removed.name],
+                segments_changed: false,
+            }
+        );
+        let mut expected = update;
+        expected.features.sort();
+        assert_eq!(
+            serde_json::to_value(&cached.features).unwrap(),
+            serde_json::to_value(&expected.features).unwrap()
+        );
+    }
+
+    #[test]
+    pub fn applying_an_identical_update_leaves_the_cache_untouched() {
+        let mut cached = features_from_disk("../examples/hostedexample.json");
+        let update = cached.clone();
+        let token = EdgeToken::from_str("*:development.somesecret").unwrap();
+
+        let delta = apply_client_features_update(&token, &mut cached, update.clone());
+
+        assert!(delta.is_empty());
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::to_value(&update).unwrap()
+        );
+    }
+
+    fn refresher_evicting_after(idle_ttl: Duration) -> FeatureRefresher {
+        FeatureRefresher {
+            token_idle_ttl: Some(idle_ttl),
+            ..Default::default()
+        }
+    }
+
+    fn register_with_features(feature_refresher: &FeatureRefresher, token: &EdgeToken) {
+        feature_refresher
+            .token_cache
+            .insert(token.token.clone(), token.clone());
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        feature_refresher.features_cache.insert(
+            cache_key(token),
+            features_from_disk("../examples/features.json"),
+        );
+        feature_refresher
+            .engine_cache
+            .insert(cache_key(token), EngineState::default());
+    }
+
+    #[test]
+    pub fn idle_tokens_are_evicted_along_with_features_no_other_token_uses() {
+        let feature_refresher = refresher_evicting_after(Duration::minutes(5));
+        let idle = EdgeToken::validated_client_token("*:development.idle");
+        let in_use = EdgeToken::validated_client_token("*:production.in_use");
+        register_with_features(&feature_refresher, &idle);
+        register_with_features(&feature_refresher, &in_use);
+        feature_refresher
+            .token_last_access
+            .insert(idle.token.clone(), Utc::now() - Duration::minutes(10));
+        feature_refresher.mark_token_accessed(&in_use.token);
+
+        let evicted = feature_refresher.evict_idle_tokens(&[]);
+
+        assert_eq!(evicted, vec![idle.token.clone()]);
+        assert!(!feature_refresher.token_cache.contains_key(&idle.token));
+        assert!(!feature_refresher
+            .tokens_to_refresh
+            .contains_key(&idle.token));
+        assert!(!feature_refresher.features_cache.contains_key("development"));
+        assert!(!feature_refresher.engine_cache.contains_key("development"));
+        assert!(feature_refresher
+            .tokens_to_refresh
+            .contains_key(&in_use.token));
+        assert!(feature_refresher.features_cache.contains_key("production"));
+    }
+
+    #[test]
+    pub fn tokens_still_covering_a_token_in_use_startup_tokens_and_unseen_tokens_are_kept() {
+        let feature_refresher = refresher_evicting_after(Duration::minutes(5));
+        let broad = EdgeToken::validated_client_token("*:development.broad");
+        let mut narrow = EdgeToken::validated_client_token("[]:development.narrow");
+        narrow.projects = vec!["default".into()];
+        let startup = EdgeToken::validated_client_token("*:production.startup");
+        let unseen = EdgeToken::validated_client_token("*:staging.unseen");
+        register_with_features(&feature_refresher, &broad);
+        register_with_features(&feature_refresher, &startup);
+        register_with_features(&feature_refresher, &unseen);
+        feature_refresher
+            .token_cache
+            .insert(narrow.token.clone(), narrow.clone());
+        let long_ago = Utc::now() - Duration::minutes(10);
+        feature_refresher
+            .token_last_access
+            .insert(broad.token.clone(), long_ago);
+        feature_refresher
+            .token_last_access
+            .insert(startup.token.clone(), long_ago);
+        feature_refresher.mark_token_accessed(&narrow.token);
+
+        let evicted = feature_refresher.evict_idle_tokens(std::slice::from_ref(&startup.token));
+
+        assert!(evicted.is_empty());
+        assert_eq!(feature_refresher.tokens_to_refresh.len(), 3);
+        assert!(feature_refresher.features_cache.contains_key("development"));
+    }
+
+    #[test]
+    pub fn token_use_is_only_tracked_when_evicting_idle_tokens() {
+        let feature_refresher = FeatureRefresher::default();
+        feature_refresher.mark_token_accessed("*:development.abcdef");
+        assert!(feature_refresher.token_last_access.is_empty());
+        assert!(feature_refresher.evict_idle_tokens(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn concurrent_refreshes_of_one_token_share_a_single_upstream_fetch() {
+        let upstream_hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server_hits = upstream_hits.clone();
+        let server = test_server(move || {
+            let hits = server_hits.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move || {
+                        let hits = hits.clone();
+                        async move {
+                            hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                            actix_web::HttpResponse::Ok()
+                                .json(features_from_disk("../examples/features.json"))
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ));
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+
+        futures::future::join_all(
+            (0..10)
+                .map(|_| feature_refresher.refresh_single(TokenRefresh::new(token.clone(), None))),
+        )
+        .await;
+
+        assert_eq!(upstream_hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(feature_refresher.features_cache.contains_key("development"));
+        assert!(feature_refresher.fetches_in_flight.is_empty());
+    }
+
+    fn sharded_refresher(owns_development: bool) -> FeatureRefresher {
+        let shard = (0..2)
+            .map(|index| RefreshShard::new(index, 2).unwrap())
+            .find(|shard| shard.owns_key("development") == owns_development)
+            .unwrap();
+        let mut storage_path = std::env::temp_dir();
+        storage_path.push(ulid::Ulid::new().to_string());
+        let persistence: Arc<dyn EdgePersistence> =
+            Arc::new(crate::persistence::file::FilePersister::new(&storage_path));
+        FeatureRefresher {
+            persistence: Some(persistence),
+            ..FeatureRefresher::with_client(Arc::new(
+                UnleashClient::new("http://localhost:1", None).unwrap(),
+            ))
+        }
+        .with_refresh_shard(Some(shard))
+    }
+
+    #[tokio::test]
+    pub async fn environments_of_other_shards_are_read_from_persistence_instead_of_upstream() {
+        let feature_refresher = sharded_refresher(false);
+        let features: ClientFeatures = features_from_disk("../examples/features.json");
+        feature_refresher
+            .persistence
+            .clone()
+            .unwrap()
+            .save_features(vec![("development".into(), features.clone())])
+            .await
+            .unwrap();
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+
+        feature_refresher
+            .refresh_single(TokenRefresh::new(token, None))
+            .await;
+
+        assert_eq!(
+            feature_refresher
+                .features_cache
+                .get("development")
+                .unwrap()
+                .features
+                .len(),
+            features.features.len()
+        );
+        assert!(feature_refresher.engine_cache.contains_key("development"));
+    }
+
+    #[tokio::test]
+    pub async fn only_refresh_targets_of_our_own_shard_are_adopted() {
+        let owning = sharded_refresher(true);
+        let other = sharded_refresher(false);
+        let refresh_targets = vec![TokenRefresh::new(
+            EdgeToken::validated_client_token("*:development.secret123"),
+            None,
+        )];
+
+        let adopted = owning
+            .adopt_refresh_targets(owning.refresh_shard.unwrap(), refresh_targets.clone())
+            .await;
+        let not_adopted = other
+            .adopt_refresh_targets(other.refresh_shard.unwrap(), refresh_targets)
+            .await;
+
+        assert_eq!(adopted, 1);
+        assert!(owning
+            .tokens_to_refresh
+            .contains_key("*:development.secret123"));
+        assert_eq!(not_adopted, 0);
+        assert!(other.tokens_to_refresh.is_empty());
+    }
+
+    fn feature_in(name: &str, project: &str) -> ClientFeature {
+        ClientFeature {
+            name: name.into(),
+            project: Some(project.into()),
+            ..ClientFeature::default()
+        }
+    }
+
+    fn feature_names(features: &ClientFeatures) -> Vec<String> {
+        let mut names: Vec<String> = features.features.iter().map(|f| f.name.clone()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    pub fn delta_events_are_played_on_top_of_the_baseline_in_order() {
+        let baseline = ClientFeatures {
+            version: 2,
+            features: vec![
+                feature_in("kept", "default"),
+                feature_in("removed", "default"),
+            ],
+            segments: None,
+            query: None,
+        };
+        let delta: crate::types::ClientFeaturesDelta = serde_json::from_value(serde_json::json!({
+            "events": [
+                { "eventId": 5, "type": "feature-updated", "feature": { "name": "added", "project": "default", "enabled": true } },
+                { "eventId": 6, "type": "feature-removed", "featureName": "removed", "project": "default" },
+                { "eventId": 7, "type": "segment-updated", "segment": { "id": 1, "constraints": [] } },
+                { "eventId": 8, "type": "feature-updated", "feature": { "name": "added", "project": "default", "enabled": false } }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(delta.revision_id(), Some(8));
+
+        let applied = apply_delta(Some(baseline), delta).unwrap();
+
+        assert_eq!(feature_names(&applied), vec!["added", "kept"]);
+        assert!(
+            !applied
+                .features
+                .iter()
+                .find(|f| f.name == "added")
+                .unwrap()
+                .enabled
+        );
+        assert_eq!(applied.segments.unwrap().len(), 1);
+    }
+
+    #[test]
+    pub fn delta_without_hydration_needs_a_baseline() {
+        let changes: crate::types::ClientFeaturesDelta = serde_json::from_value(serde_json::json!({
+            "events": [
+                { "eventId": 2, "type": "feature-removed", "featureName": "gone", "project": "default" }
+            ]
+        }))
+        .unwrap();
+        assert!(apply_delta(None, changes).is_none());
+
+        let hydration: crate::types::ClientFeaturesDelta = serde_json::from_value(serde_json::json!({
+            "events": [
+                { "eventId": 1, "type": "hydration", "features": [{ "name": "fresh", "project": "default", "enabled": true }], "segments": [] }
+            ]
+        }))
+        .unwrap();
+        assert_eq!(
+            feature_names(&apply_delta(None, hydration).unwrap()),
+            vec!["fresh"]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn delta_api_hydrates_then_applies_changes_since_the_last_revision() {
+        let revisions_asked_for = Arc::new(std::sync::Mutex::new(vec![]));
+        let server_revisions = revisions_asked_for.clone();
+        let server = test_server(move || {
+            let revisions = server_revisions.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/delta",
+                    web::get().to(move |req: actix_web::HttpRequest| {
+                        let revision = req
+                            .headers()
+                            .get("If-None-Match")
+                            .map(|v| v.to_str().unwrap().to_string());
+                        revisions.lock().unwrap().push(revision.clone());
+                        async move {
+                            let events = match revision.as_deref() {
+                                None => serde_json::json!([
+                                    { "eventId": 1, "type": "hydration", "segments": [], "features": [
+                                        { "name": "first", "project": "default", "enabled": true },
+                                        { "name": "second", "project": "default", "enabled": true }
+                                    ] }
+                                ]),
+                                Some("\"1\"") => serde_json::json!([
+                                    { "eventId": 2, "type": "feature-updated", "feature": { "name": "third", "project": "default", "enabled": true } },
+                                    { "eventId": 3, "type": "feature-removed", "featureName": "first", "project": "default" }
+                                ]),
+                                Some(_) => return actix_web::HttpResponse::NotModified().finish(),
+                            };
+                            actix_web::HttpResponse::Ok().json(serde_json::json!({ "events": events }))
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ))
+        .with_delta_api(true);
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        let current_refresh = || {
+            feature_refresher
+                .tokens_to_refresh
+                .get(&token.token)
+                .unwrap()
+                .clone()
+        };
+
+        for _ in 0..3 {
+            feature_refresher.refresh_single(current_refresh()).await;
+        }
+
+        assert_eq!(
+            *revisions_asked_for.lock().unwrap(),
+            vec![None, Some("\"1\"".to_string()), Some("\"3\"".to_string())]
+        );
+        assert_eq!(current_refresh().delta_revision_id, Some(3));
+        assert_eq!(
+            feature_names(&feature_refresher.features_cache.get("development").unwrap()),
+            vec!["second", "third"]
+        );
+        assert!(feature_refresher.engine_cache.contains_key("development"));
+    }
+
+    #[tokio::test]
+    pub async fn features_grown_past_the_size_limit_by_a_delta_are_not_used() {
+        let description = "x".repeat(1200);
+        let server = test_server(move || {
+            let description = description.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/delta",
+                    web::get().to(move |req: actix_web::HttpRequest| {
+                        let revision = req
+                            .headers()
+                            .get("If-None-Match")
+                            .map(|v| v.to_str().unwrap().to_string());
+                        let description = description.clone();
+                        async move {
+                            let events = match revision.as_deref() {
+                                None => serde_json::json!([
+                                    { "eventId": 1, "type": "hydration", "segments": [], "features": [
+                                        { "name": "first", "project": "default", "enabled": true, "description": description }
+                                    ] }
+                                ]),
+                                _ => serde_json::json!([
+                                    { "eventId": 2, "type": "feature-updated", "feature": { "name": "second", "project": "default", "enabled": true, "description": description } }
+                                ]),
+                            };
+                            actix_web::HttpResponse::Ok().json(serde_json::json!({ "events": events }))
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None)
+                .unwrap()
+                .with_max_feature_document_bytes(Some(2000)),
+        ))
+        .with_delta_api(true);
+        let token = EdgeToken::validated_client_token("*:grown-by-delta.secret123");
+        feature_refresher
+            .tokens_to_refresh
+            .insert(token.token.clone(), TokenRefresh::new(token.clone(), None));
+        let current_refresh = || {
+            feature_refresher
+                .tokens_to_refresh
+                .get(&token.token)
+                .unwrap()
+                .clone()
+        };
+
+        feature_refresher.refresh_single(current_refresh()).await;
+        feature_refresher.refresh_single(current_refresh()).await;
+
+        assert_eq!(current_refresh().delta_revision_id, Some(1));
+        assert_eq!(
+            feature_names(
+                &feature_refresher
+                    .features_cache
+                    .get("grown-by-delta")
+                    .unwrap()
+            ),
+            vec!["first"]
+        );
+        assert_eq!(
+            crate::http::unleash_client::OVERSIZED_FEATURE_DOCUMENTS
+                .with_label_values(&["grown-by-delta"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    pub async fn falls_back_to_fetching_all_features_when_upstream_has_no_delta_api() {
+        let upstream_features_cache: Arc<DashMap<String, ClientFeatures>> =
+            Arc::new(DashMap::default());
+        let upstream_token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let upstream_engine_cache: Arc<DashMap<String, EngineState>> = Arc::new(DashMap::default());
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        upstream_token_cache.insert(token.token.clone(), token.clone());
+        upstream_features_cache.insert(
+            cache_key(&token),
+            features_from_disk("../examples/features.json"),
+        );
+        let server = client_api_test_server(
+            upstream_token_cache,
+            upstream_features_cache,
+            upstream_engine_cache,
+        )
+        .await;
+        let feature_refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ))
+        .with_delta_api(true);
+
+        feature_refresher
+            .refresh_single(TokenRefresh::new(token.clone(), None))
+            .await;
+
+        assert!(feature_refresher
+            .delta_api_unsupported
+            .load(std::sync::atomic::Ordering::Relaxed));
+        assert!(feature_refresher.features_cache.contains_key("development"));
+    }
 }
@@ -1,4 +1,10 @@
 #[cfg(not(tarpaulin_include))]
 pub mod background_send_metrics;
+pub mod broadcaster;
+pub mod change_webhook;
+pub mod circuit_breaker;
 pub mod feature_refresher;
+pub mod refresh_shard;
 pub mod unleash_client;
+pub mod upstream_failover;
+pub mod upstream_health;
@@ -0,0 +1,97 @@
+use crate::error::EdgeError;
+use crate::tokens::cache_key;
+use crate::types::{EdgeResult, EdgeToken};
+
+/// The part of the refresh work one of several Edges sharing a database does. Environments are spread over the shards with
+/// jump consistent hashing, so every environment has exactly one owner and changing the shard count moves as few of them as possible
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RefreshShard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl RefreshShard {
+    pub fn new(index: u32, count: u32) -> EdgeResult<Self> {
+        if count == 0 || index >= count {
+            Err(EdgeError::InvalidRefreshShard(index, count))
+        } else {
+            Ok(Self { index, count })
+        }
+    }
+
+    /// Whether this shard refreshes the features cached under `key` from upstream
+    pub fn owns_key(&self, key: &str) -> bool {
+        jump_consistent_hash(fnv1a(key.as_bytes()), self.count) == self.index
+    }
+
+    /// Tokens are sharded by the features they share a cache entry for, so one environment isn't refreshed by several shards
+    pub fn owns(&self, token: &EdgeToken) -> bool {
+        self.owns_key(&cache_key(token))
+    }
+}
+
+/// Stable across processes and releases, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Lamping and Veach, "A Fast, Minimal Memory, Consistent Hash Algorithm"
+fn jump_consistent_hash(mut key: u64, buckets: u32) -> u32 {
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < buckets as i64 {
+        bucket = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    bucket as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn every_environment_has_exactly_one_owner() {
+        let shards: Vec<RefreshShard> = (0..3).map(|i| RefreshShard::new(i, 3).unwrap()).collect();
+        for environment in 0..100 {
+            let token = EdgeToken::from_str(&format!("*:env-{environment}.secret")).unwrap();
+            assert_eq!(shards.iter().filter(|shard| shard.owns(&token)).count(), 1);
+        }
+        assert!(
+            shards
+                .iter()
+                .all(|shard| (0..100)
+                    .any(|environment| shard.owns_key(&format!("env-{environment}"))))
+        );
+    }
+
+    #[test]
+    fn tokens_for_the_same_environment_share_a_shard() {
+        let shard = RefreshShard::new(1, 4).unwrap();
+        let project_a = EdgeToken::from_str("projecta:production.secret").unwrap();
+        let project_b = EdgeToken::from_str("projectb:production.other").unwrap();
+        assert_eq!(shard.owns(&project_a), shard.owns(&project_b));
+    }
+
+    #[test]
+    fn adding_a_shard_only_moves_environments_to_the_new_shard() {
+        for environment in 0..200 {
+            let key = format!("env-{environment}");
+            let before = jump_consistent_hash(fnv1a(key.as_bytes()), 4);
+            let after = jump_consistent_hash(fnv1a(key.as_bytes()), 5);
+            assert!(after == before || after == 4);
+        }
+    }
+
+    #[test]
+    fn shard_index_needs_to_be_below_the_shard_count() {
+        assert!(RefreshShard::new(2, 2).is_err());
+        assert!(RefreshShard::new(0, 0).is_err());
+        assert!(RefreshShard::new(1, 2).is_ok());
+    }
+}
@@ -1,16 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 use actix_web::http::header::EntityTag;
 use chrono::Duration;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use lazy_static::lazy_static;
-use prometheus::{register_histogram_vec, register_int_gauge_vec, HistogramVec, IntGaugeVec, Opts};
-use reqwest::header::{HeaderMap, HeaderName};
+use opentelemetry::propagation::Injector;
+use opentelemetry::{global, Context};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec, Opts,
+};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::{header, Client};
-use reqwest::{ClientBuilder, Identity, RequestBuilder, StatusCode, Url};
+use reqwest::{ClientBuilder, Identity, RequestBuilder, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use tracing::{info, trace, warn};
 use unleash_types::client_features::ClientFeatures;
@@ -20,16 +27,23 @@ use crate::cli::ClientIdentity;
 use crate::error::EdgeError::EdgeMetricsRequestError;
 use crate::error::{CertificateError, FeatureError};
 use crate::metrics::client_metrics::MetricsBatch;
-use crate::tls::build_upstream_certificate;
+use crate::middleware::version_headers::UPSTREAM_VERSION_HEADER;
+use crate::tls::{build_upstream_certificate, client_key_as_pkcs8_pem};
 use crate::types::{
+    ClientFeaturesDelta, ClientFeaturesDeltaRequest, ClientFeaturesDeltaResponse,
     ClientFeaturesResponse, EdgeResult, EdgeToken, TokenValidationStatus, ValidateTokensRequest,
 };
 use crate::urls::UnleashUrls;
 use crate::{error::EdgeError, types::ClientFeaturesRequest};
 
+use super::circuit_breaker::CircuitBreaker;
+use super::upstream_failover::UpstreamFailover;
+
 const UNLEASH_APPNAME_HEADER: &str = "UNLEASH-APPNAME";
 const UNLEASH_INSTANCE_ID_HEADER: &str = "UNLEASH-INSTANCEID";
 const UNLEASH_CLIENT_SPEC_HEADER: &str = "Unleash-Client-Spec";
+const UNLEASH_VERSION_HEADER: &str = "X-Unleash-Version";
+pub const DEFAULT_POOL_IDLE_TIMEOUT_SECONDS: u64 = 30;
 
 lazy_static! {
     pub static ref CLIENT_REGISTER_FAILURES: IntGaugeVec = register_int_gauge_vec!(
@@ -47,6 +61,21 @@ lazy_static! {
         vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 5000.0]
     )
     .unwrap();
+    pub static ref UPSTREAM_PAYLOAD_BYTES: HistogramVec = register_histogram_vec!(
+        "edge_upstream_payload_bytes",
+        "Size in bytes of the feature payloads fetched from upstream, after decompression",
+        &["environment"],
+        prometheus::exponential_buckets(1024.0, 4.0, 8).unwrap()
+    )
+    .unwrap();
+    pub static ref OVERSIZED_FEATURE_DOCUMENTS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "edge_oversized_feature_documents_total",
+            "Feature documents from upstream that were dropped for being larger than --max-feature-document-bytes"
+        ),
+        &["environment"]
+    )
+    .unwrap();
     pub static ref CLIENT_FEATURE_FETCH_FAILURES: IntGaugeVec = register_int_gauge_vec!(
         Opts::new(
             "client_feature_fetch_failures",
@@ -73,12 +102,85 @@ lazy_static! {
     .unwrap();
 }
 
+/// Lets the trace context of the request we're handling ride along to upstream, so a request through a chain of
+/// Edges to Unleash ends up in a single trace
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_str(key), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct UnleashClient {
-    pub urls: UnleashUrls,
-    backing_client: Client,
+    pub upstreams: Arc<UpstreamFailover>,
+    backing_client: Arc<RwLock<Client>>,
     custom_headers: HashMap<String, String>,
     token_header: String,
+    upstream_version: Arc<RwLock<Option<String>>>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    max_feature_document_bytes: Option<usize>,
+}
+
+/// A 429 or 503 carrying a Retry-After tells us when upstream wants to hear from us again, other failures are retried
+/// on our own backoff
+fn retriable(response: &Response) -> FeatureError {
+    let status = response.status();
+    let retry_after = match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now())),
+        _ => None,
+    };
+    match retry_after {
+        Some(retry_after) => FeatureError::RateLimited(status, retry_after),
+        None => FeatureError::Retriable(status),
+    }
+}
+
+/// Retry-After is either a number of seconds or an HTTP date. A date that has already passed means right away
+pub(crate) fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<i64>() {
+        return (seconds >= 0).then(|| Duration::seconds(seconds));
+    }
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| (date.with_timezone(&Utc) - now).max(Duration::zero()))
+}
+
+/// Bodies are measured once reqwest has decompressed them, as that's what Edge has to parse and keep in memory.
+/// Compare with the bytes on the wire to see what compression saves
+fn observe_payload_size(api_key: &str, size: usize) {
+    UPSTREAM_PAYLOAD_BYTES
+        .with_label_values(&[&payload_environment(api_key)])
+        .observe(size as f64);
+}
+
+/// Counts what's written to it, so a document can be measured without holding a serialized copy of it
+struct ByteCount(usize);
+
+impl std::io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn payload_environment(api_key: &str) -> String {
+    EdgeToken::from_str(api_key)
+        .ok()
+        .and_then(|token| token.environment)
+        .unwrap_or_else(|| "unknown".into())
 }
 
 fn load_pkcs12(id: &ClientIdentity) -> EdgeResult<Identity> {
@@ -103,6 +205,7 @@ fn load_pkcs8(id: &ClientIdentity) -> EdgeResult<Identity> {
     let key = fs::read(id.pkcs8_client_key_file.clone().unwrap()).map_err(|e| {
         EdgeError::ClientCertificateError(CertificateError::Pem8ClientKeyNotFound(format!("{e:?}")))
     })?;
+    let key = client_key_as_pkcs8_pem(&key)?;
     Identity::from_pkcs8_pem(&cert, &key).map_err(|e| {
         EdgeError::ClientCertificateError(CertificateError::Pem8IdentityGeneration(format!(
             "{e:?}"
@@ -110,6 +213,19 @@ fn load_pkcs8(id: &ClientIdentity) -> EdgeResult<Identity> {
     })
 }
 
+/// Make sure the certificate and key actually belong together at startup, rather than failing on our first request upstream
+fn validate_identity(identity: Identity) -> EdgeResult<Identity> {
+    ClientBuilder::new()
+        .identity(identity.clone())
+        .build()
+        .map(|_| identity)
+        .map_err(|e| {
+            EdgeError::ClientCertificateError(CertificateError::ClientIdentityValidation(format!(
+                "{e:?}"
+            )))
+        })
+}
+
 fn build_identity(tls: Option<ClientIdentity>) -> EdgeResult<ClientBuilder> {
     tls.map_or_else(
         || Ok(ClientBuilder::new()),
@@ -124,11 +240,58 @@ fn build_identity(tls: Option<ClientIdentity>) -> EdgeResult<ClientBuilder> {
                     CertificateError::NoCertificateFiles,
                 ))
             };
-            req_identity.map(|id| ClientBuilder::new().identity(id))
+            req_identity
+                .and_then(validate_identity)
+                .map(|id| ClientBuilder::new().identity(id))
         },
     )
 }
 
+/// Catches documents that parse, but that no Unleash would send, so a broken response doesn't replace the features we have
+fn validate_client_features(features: &ClientFeatures) -> Result<(), String> {
+    if features.version == 0 {
+        return Err("Features response has no version".into());
+    }
+    let mut names = HashSet::with_capacity(features.features.len());
+    for feature in &features.features {
+        if feature.name.is_empty() {
+            return Err("Features response has a feature without a name".into());
+        }
+        if !names.insert(feature.name.as_str()) {
+            return Err(format!(
+                "Features response has the feature {} more than once",
+                feature.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// How the connections to upstream are kept around between requests. Connections idle for longer than `idle_timeout`
+/// are closed, so Edge doesn't keep sending requests down connections to upstream pods that have gone away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpstreamConnectionPool {
+    pub idle_timeout: Option<std::time::Duration>,
+    pub max_idle_per_host: usize,
+}
+
+impl Default for UpstreamConnectionPool {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(std::time::Duration::from_secs(
+                DEFAULT_POOL_IDLE_TIMEOUT_SECONDS,
+            )),
+            max_idle_per_host: usize::MAX,
+        }
+    }
+}
+
+/// What upstream sees Edge as, next to the app name and instance id it's configured with
+pub fn user_agent() -> String {
+    format!("unleash-edge/{}", crate::types::build::PKG_VERSION)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn new_reqwest_client(
     instance_id: String,
     skip_ssl_verification: bool,
@@ -137,6 +300,8 @@ pub fn new_reqwest_client(
     connect_timeout: Duration,
     socket_timeout: Duration,
     app_name: String,
+    compression: bool,
+    connection_pool: UpstreamConnectionPool,
 ) -> EdgeResult<Client> {
     build_identity(client_identity)
         .and_then(|builder| {
@@ -162,11 +327,15 @@ pub fn new_reqwest_client(
             );
 
             client
-                .user_agent(format!("unleash-edge-{}", crate::types::build::PKG_VERSION))
+                .user_agent(user_agent())
                 .default_headers(header_map)
                 .danger_accept_invalid_certs(skip_ssl_verification)
                 .timeout(socket_timeout.to_std().unwrap())
                 .connect_timeout(connect_timeout.to_std().unwrap())
+                .gzip(compression)
+                .brotli(compression)
+                .pool_idle_timeout(connection_pool.idle_timeout)
+                .pool_max_idle_per_host(connection_pool.max_idle_per_host)
                 .build()
                 .map_err(|e| EdgeError::ClientBuildError(format!("{e:?}")))
         })
@@ -184,10 +353,15 @@ impl UnleashClient {
         backing_client: Client,
     ) -> Self {
         Self {
-            urls: UnleashUrls::from_base_url(server_url),
-            backing_client,
+            upstreams: Arc::new(UpstreamFailover::new(vec![UnleashUrls::from_base_url(
+                server_url,
+            )])),
+            backing_client: Arc::new(RwLock::new(backing_client)),
             custom_headers: Default::default(),
             token_header,
+            upstream_version: Default::default(),
+            circuit_breaker: Default::default(),
+            max_feature_document_bytes: None,
         }
     }
 
@@ -197,19 +371,28 @@ impl UnleashClient {
 
         let instance_id = instance_id_opt.unwrap_or_else(|| Ulid::new().to_string());
         Ok(Self {
-            urls: UnleashUrls::from_str(server_url)?,
-            backing_client: new_reqwest_client(
-                instance_id,
-                false,
-                None,
-                None,
-                Duration::seconds(5),
-                Duration::seconds(5),
-                "test-client".into(),
-            )
-            .unwrap(),
+            upstreams: Arc::new(UpstreamFailover::new(vec![UnleashUrls::from_str(
+                server_url,
+            )?])),
+            backing_client: Arc::new(RwLock::new(
+                new_reqwest_client(
+                    instance_id,
+                    false,
+                    None,
+                    None,
+                    Duration::seconds(5),
+                    Duration::seconds(5),
+                    "test-client".into(),
+                    true,
+                    UpstreamConnectionPool::default(),
+                )
+                .unwrap(),
+            )),
             custom_headers: Default::default(),
             token_header: "Authorization".to_string(),
+            upstream_version: Default::default(),
+            circuit_breaker: Default::default(),
+            max_feature_document_bytes: None,
         })
     }
 
@@ -218,27 +401,41 @@ impl UnleashClient {
         use ulid::Ulid;
 
         Ok(Self {
-            urls: UnleashUrls::from_str(server_url)?,
-            backing_client: new_reqwest_client(
-                Ulid::new().to_string(),
-                true,
-                None,
-                None,
-                Duration::seconds(5),
-                Duration::seconds(5),
-                "test-client".into(),
-            )
-            .unwrap(),
+            upstreams: Arc::new(UpstreamFailover::new(vec![UnleashUrls::from_str(
+                server_url,
+            )?])),
+            backing_client: Arc::new(RwLock::new(
+                new_reqwest_client(
+                    Ulid::new().to_string(),
+                    true,
+                    None,
+                    None,
+                    Duration::seconds(5),
+                    Duration::seconds(5),
+                    "test-client".into(),
+                    true,
+                    UpstreamConnectionPool::default(),
+                )
+                .unwrap(),
+            )),
             custom_headers: Default::default(),
             token_header: "Authorization".to_string(),
+            upstream_version: Default::default(),
+            circuit_breaker: Default::default(),
+            max_feature_document_bytes: None,
         })
     }
 
-    fn client_features_req(&self, req: ClientFeaturesRequest) -> RequestBuilder {
+    fn client_features_req(
+        &self,
+        urls: &UnleashUrls,
+        req: ClientFeaturesRequest,
+    ) -> RequestBuilder {
         let client_req = self
-            .backing_client
-            .get(self.urls.client_features_url.to_string())
-            .headers(self.header_map(Some(req.api_key)));
+            .http_client()
+            .get(urls.client_features_url.to_string())
+            .headers(self.header_map(Some(req.api_key)))
+            .query(&req.tags.iter().map(|tag| ("tag", tag)).collect::<Vec<_>>());
         if let Some(tag) = req.etag {
             client_req.header(header::IF_NONE_MATCH, tag.to_string())
         } else {
@@ -246,6 +443,25 @@ impl UnleashClient {
         }
     }
 
+    fn client_features_delta_req(
+        &self,
+        urls: &UnleashUrls,
+        req: ClientFeaturesDeltaRequest,
+    ) -> RequestBuilder {
+        let client_req = self
+            .http_client()
+            .get(urls.client_features_delta_url.to_string())
+            .headers(self.header_map(Some(req.api_key)));
+        if let Some(revision_id) = req.revision_id {
+            client_req.header(
+                header::IF_NONE_MATCH,
+                EntityTag::new_strong(revision_id.to_string()).to_string(),
+            )
+        } else {
+            client_req
+        }
+    }
+
     fn header_map(&self, api_key: Option<String>) -> HeaderMap {
         let mut header_map = HeaderMap::new();
         let token_header: HeaderName = HeaderName::from_str(self.token_header.as_str()).unwrap();
@@ -256,9 +472,68 @@ impl UnleashClient {
             let key = HeaderName::from_str(header_name.as_str()).unwrap();
             header_map.insert(key, header_value.parse().unwrap());
         }
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&Context::current(), &mut HeaderInjector(&mut header_map))
+        });
         header_map
     }
 
+    /// Clones of the client are cheap, they share the connection pool of the one we hold on to
+    fn http_client(&self) -> Client {
+        self.backing_client.read().unwrap().clone()
+    }
+
+    /// Swaps in a client with a fresh connection pool. Requests already under way finish on the connections they have
+    pub fn replace_http_client(&self, client: Client) {
+        *self.backing_client.write().unwrap() = client;
+    }
+
+    /// Resolves the upstream hosts every `interval`, and replaces the HTTP client with one from `build_client` when the
+    /// addresses they resolve to change. New requests then connect to where upstream is now, instead of reusing
+    /// connections to pods that have been rotated away
+    pub async fn refresh_on_dns_change(
+        self: Arc<Self>,
+        build_client: impl Fn() -> EdgeResult<Client>,
+        interval: std::time::Duration,
+    ) {
+        let mut known_addresses = None;
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let addresses = self.upstreams.resolve().await;
+            if addresses.is_empty() {
+                warn!("Could not resolve upstream, keeping the connections we have");
+                continue;
+            }
+            self.reconnect_if_moved(&mut known_addresses, addresses, &build_client);
+        }
+    }
+
+    /// Replaces the HTTP client when upstream resolves to other addresses than last time. Whether it was replaced
+    fn reconnect_if_moved(
+        &self,
+        known_addresses: &mut Option<BTreeSet<SocketAddr>>,
+        addresses: BTreeSet<SocketAddr>,
+        build_client: &impl Fn() -> EdgeResult<Client>,
+    ) -> bool {
+        let mut reconnected = false;
+        if known_addresses
+            .as_ref()
+            .is_some_and(|known| *known != addresses)
+        {
+            match build_client() {
+                Ok(client) => {
+                    info!("Upstream now resolves to {addresses:?}. Reconnecting to upstream");
+                    self.replace_http_client(client);
+                    reconnected = true;
+                }
+                Err(e) => warn!("Could not build a new client for upstream: {e}"),
+            }
+        }
+        *known_addresses = Some(addresses);
+        reconnected
+    }
+
     pub fn with_custom_client_headers(self, custom_headers: Vec<(String, String)>) -> Self {
         Self {
             custom_headers: custom_headers.iter().cloned().collect(),
@@ -266,17 +541,141 @@ impl UnleashClient {
         }
     }
 
+    pub fn with_upstream_failover(self, upstreams: UpstreamFailover) -> Self {
+        Self {
+            upstreams: Arc::new(upstreams),
+            ..self
+        }
+    }
+
+    pub fn with_circuit_breaker(self, circuit_breaker: CircuitBreaker) -> Self {
+        Self {
+            circuit_breaker: Arc::new(circuit_breaker),
+            ..self
+        }
+    }
+
+    pub fn with_max_feature_document_bytes(
+        self,
+        max_feature_document_bytes: Option<usize>,
+    ) -> Self {
+        Self {
+            max_feature_document_bytes,
+            ..self
+        }
+    }
+
+    /// Reads a features body chunk by chunk, so a document over --max-feature-document-bytes is given up on as soon as
+    /// it grows past the limit instead of being held in memory in full
+    async fn read_features_body(
+        &self,
+        mut response: Response,
+        api_key: &str,
+    ) -> EdgeResult<Vec<u8>> {
+        let unreadable = |e: reqwest::Error| EdgeError::ClientFeaturesParseError(e.to_string());
+        let Some(limit) = self.max_feature_document_bytes else {
+            return response
+                .bytes()
+                .await
+                .map(|body| body.to_vec())
+                .map_err(unreadable);
+        };
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(unreadable)? {
+            if body.len() + chunk.len() > limit {
+                OVERSIZED_FEATURE_DOCUMENTS
+                    .with_label_values(&[&payload_environment(api_key)])
+                    .inc();
+                return Err(EdgeError::FeatureDocumentTooLarge(limit));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// A delta can be small while the document it's applied to grows past --max-feature-document-bytes, so features
+    /// put together from deltas are measured as well, the way upstream would have sent them in full
+    pub(crate) fn check_feature_document_size(
+        &self,
+        features: &ClientFeatures,
+        api_key: &str,
+    ) -> EdgeResult<()> {
+        let Some(limit) = self.max_feature_document_bytes else {
+            return Ok(());
+        };
+        let mut size = ByteCount(0);
+        serde_json::to_writer(&mut size, features)?;
+        if size.0 > limit {
+            OVERSIZED_FEATURE_DOCUMENTS
+                .with_label_values(&[&payload_environment(api_key)])
+                .inc();
+            return Err(EdgeError::FeatureDocumentTooLarge(limit));
+        }
+        Ok(())
+    }
+
+    /// The Unleash version the upstream last reported. An upstream Edge passes on the version of the Unleash behind it
+    pub fn upstream_version(&self) -> Option<String> {
+        self.upstream_version.read().unwrap().clone()
+    }
+
+    pub fn record_upstream_version(&self, version: Option<&str>) {
+        if let Some(version) = version {
+            *self.upstream_version.write().unwrap() = Some(version.to_string());
+        }
+    }
+
+    /// Sends the request and reports to the failover whether the upstream could be reached at all, and to the circuit
+    /// breaker whether it answered properly. Requests are refused without being sent while the circuit breaker is open
+    async fn send(
+        &self,
+        upstream: usize,
+        request: RequestBuilder,
+    ) -> EdgeResult<reqwest::Result<Response>> {
+        self.circuit_breaker.allow_request()?;
+        let result = request.send().await;
+        match &result {
+            Ok(response)
+                if response.status().is_server_error()
+                    || response.status() == StatusCode::TOO_MANY_REQUESTS =>
+            {
+                self.circuit_breaker.record_failure()
+            }
+            Ok(_) => self.circuit_breaker.record_success(),
+            Err(_) => self.circuit_breaker.record_failure(),
+        }
+        match &result {
+            Ok(response) => {
+                self.upstreams.record_success(upstream);
+                self.record_upstream_version(
+                    response
+                        .headers()
+                        .get(UNLEASH_VERSION_HEADER)
+                        .or_else(|| response.headers().get(UPSTREAM_VERSION_HEADER.as_str()))
+                        .and_then(|version| version.to_str().ok()),
+                );
+            }
+            Err(e) if e.is_connect() || e.is_timeout() => {
+                self.upstreams.record_unreachable(upstream)
+            }
+            Err(_) => {}
+        }
+        Ok(result)
+    }
+
     pub async fn register_as_client(
         &self,
         api_key: String,
         application: ClientApplication,
     ) -> EdgeResult<()> {
-        self.backing_client
-            .post(self.urls.client_register_app_url.to_string())
+        let (upstream, urls) = self.upstreams.current();
+        let request = self
+            .http_client()
+            .post(urls.client_register_app_url.to_string())
             .headers(self.header_map(Some(api_key)))
-            .json(&application)
-            .send()
-            .await
+            .json(&application);
+        self.send(upstream, request)
+            .await?
             .map_err(|e| {
                 warn!("Failed to register client: {e:?}");
                 EdgeError::ClientRegisterError
@@ -299,14 +698,17 @@ impl UnleashClient {
         request: ClientFeaturesRequest,
     ) -> EdgeResult<ClientFeaturesResponse> {
         let start_time = Utc::now();
+        let (upstream, urls) = self.upstreams.current();
         let response = self
-            .client_features_req(request.clone())
-            .send()
-            .await
+            .send(upstream, self.client_features_req(&urls, request.clone()))
+            .await?
             .map_err(|e| {
                 warn!("Failed to fetch. Due to [{e:?}] - Will retry");
                 match e.status() {
                     Some(s) => EdgeError::ClientFeaturesFetchError(FeatureError::Retriable(s)),
+                    None if e.is_timeout() => {
+                        EdgeError::ClientFeaturesFetchError(FeatureError::Timeout)
+                    }
                     None => EdgeError::ClientFeaturesFetchError(FeatureError::NotFound),
                 }
             })?;
@@ -328,9 +730,21 @@ impl UnleashClient {
                 .get("ETag")
                 .or_else(|| response.headers().get("etag"))
                 .and_then(|etag| EntityTag::from_str(etag.to_str().unwrap()).ok());
-            let features = response.json::<ClientFeatures>().await.map_err(|e| {
-                warn!("Could not parse features response to internal representation");
-                EdgeError::ClientFeaturesParseError(e.to_string())
+            let features = match self.read_features_body(response, &request.api_key).await {
+                Ok(body) => {
+                    observe_payload_size(&request.api_key, body.len());
+                    serde_json::from_slice::<ClientFeatures>(&body).map_err(|e| e.to_string())
+                }
+                Err(EdgeError::ClientFeaturesParseError(e)) => Err(e),
+                Err(too_large) => return Err(too_large),
+            }
+            .and_then(|features| validate_client_features(&features).map(|_| features))
+            .map_err(|e| {
+                warn!("Could not parse features response to internal representation: {e}");
+                CLIENT_FEATURE_FETCH_FAILURES
+                    .with_label_values(&["invalid_payload"])
+                    .inc();
+                EdgeError::ClientFeaturesParseError(e)
             })?;
             Ok(ClientFeaturesResponse::Updated(features, etag))
         } else if response.status() == StatusCode::FORBIDDEN {
@@ -346,7 +760,7 @@ impl UnleashClient {
                 .inc();
             warn!(
                 "Failed to get features. Url: [{}]. Status code: [401]",
-                self.urls.client_features_url.to_string()
+                urls.client_features_url.to_string()
             );
             Err(EdgeError::ClientFeaturesFetchError(
                 FeatureError::AccessDenied,
@@ -357,7 +771,7 @@ impl UnleashClient {
                 .inc();
             warn!(
                 "Failed to get features. Url: [{}]. Status code: [{}]",
-                self.urls.client_features_url.to_string(),
+                urls.client_features_url.to_string(),
                 response.status().as_str()
             );
             Err(EdgeError::ClientFeaturesFetchError(FeatureError::NotFound))
@@ -365,21 +779,95 @@ impl UnleashClient {
             CLIENT_FEATURE_FETCH_FAILURES
                 .with_label_values(&[response.status().as_str()])
                 .inc();
-            Err(EdgeError::ClientFeaturesFetchError(
-                FeatureError::Retriable(response.status()),
-            ))
+            Err(EdgeError::ClientFeaturesFetchError(retriable(&response)))
+        }
+    }
+
+    /// Checks that the current upstream answers at all, for when there's no token to ask it anything with
+    pub async fn upstream_health(&self) -> EdgeResult<()> {
+        let (upstream, urls) = self.upstreams.current();
+        let request = self
+            .http_client()
+            .get(urls.health_url.to_string())
+            .headers(self.header_map(None));
+        let response = self
+            .send(upstream, request)
+            .await?
+            .map_err(|e| EdgeError::UpstreamUnreachable(format!("{e:?}")))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(EdgeError::UpstreamUnreachable(format!(
+                "Upstream health check answered {}",
+                response.status()
+            )))
+        }
+    }
+
+    /// Gets the changes since the revision from `/api/client/delta`
+    pub async fn get_client_features_delta(
+        &self,
+        request: ClientFeaturesDeltaRequest,
+    ) -> EdgeResult<ClientFeaturesDeltaResponse> {
+        let (upstream, urls) = self.upstreams.current();
+        let api_key = request.api_key.clone();
+        let response = self
+            .send(upstream, self.client_features_delta_req(&urls, request))
+            .await?
+            .map_err(|e| {
+                warn!("Failed to fetch delta. Due to [{e:?}] - Will retry");
+                match e.status() {
+                    Some(s) => EdgeError::ClientFeaturesFetchError(FeatureError::Retriable(s)),
+                    None if e.is_timeout() => {
+                        EdgeError::ClientFeaturesFetchError(FeatureError::Timeout)
+                    }
+                    None => EdgeError::ClientFeaturesFetchError(FeatureError::NotFound),
+                }
+            })?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED => Ok(ClientFeaturesDeltaResponse::NoUpdate),
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => Ok(ClientFeaturesDeltaResponse::Unsupported),
+            status if status.is_success() => {
+                let delta = match self.read_features_body(response, &api_key).await {
+                    Ok(body) => {
+                        observe_payload_size(&api_key, body.len());
+                        serde_json::from_slice::<ClientFeaturesDelta>(&body)
+                            .map_err(|e| e.to_string())
+                    }
+                    Err(EdgeError::ClientFeaturesParseError(e)) => Err(e),
+                    Err(too_large) => return Err(too_large),
+                }
+                .map_err(|e| {
+                    warn!("Could not parse delta response to internal representation");
+                    EdgeError::ClientFeaturesParseError(e)
+                })?;
+                Ok(ClientFeaturesDeltaResponse::Updated(delta))
+            }
+            status => {
+                CLIENT_FEATURE_FETCH_FAILURES
+                    .with_label_values(&[status.as_str()])
+                    .inc();
+                Err(EdgeError::ClientFeaturesFetchError(match status {
+                    StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => FeatureError::AccessDenied,
+                    _ => retriable(&response),
+                }))
+            }
         }
     }
 
     pub async fn send_batch_metrics(&self, request: MetricsBatch) -> EdgeResult<()> {
         trace!("Sending metrics to old /edge/metrics endpoint");
-        let result = self
-            .backing_client
-            .post(self.urls.edge_metrics_url.to_string())
+        let (upstream, urls) = self.upstreams.current();
+        let edge_metrics_request = self
+            .http_client()
+            .post(urls.edge_metrics_url.to_string())
             .headers(self.header_map(None))
-            .json(&request)
-            .send()
-            .await
+            .json(&request);
+        let result = self
+            .send(upstream, edge_metrics_request)
+            .await?
             .map_err(|e| {
                 info!("Failed to send batch metrics: {e:?}");
                 EdgeError::EdgeMetricsError
@@ -403,13 +891,15 @@ impl UnleashClient {
         token: &str,
     ) -> EdgeResult<()> {
         trace!("Sending metrics to bulk endpoint");
-        let result = self
-            .backing_client
-            .post(self.urls.client_bulk_metrics_url.to_string())
+        let (upstream, urls) = self.upstreams.current();
+        let bulk_metrics_request = self
+            .http_client()
+            .post(urls.client_bulk_metrics_url.to_string())
             .headers(self.header_map(Some(token.to_string())))
-            .json(&request)
-            .send()
-            .await
+            .json(&request);
+        let result = self
+            .send(upstream, bulk_metrics_request)
+            .await?
             .map_err(|e| {
                 info!("Failed to send metrics to /api/client/metrics/bulk endpoint {e:?}");
                 EdgeError::EdgeMetricsError
@@ -431,13 +921,15 @@ impl UnleashClient {
         &self,
         request: ValidateTokensRequest,
     ) -> EdgeResult<Vec<EdgeToken>> {
-        let result = self
-            .backing_client
-            .post(self.urls.edge_validate_url.to_string())
+        let (upstream, urls) = self.upstreams.current();
+        let validation_request = self
+            .http_client()
+            .post(urls.edge_validate_url.to_string())
             .headers(self.header_map(None))
-            .json(&request)
-            .send()
-            .await
+            .json(&request);
+        let result = self
+            .send(upstream, validation_request)
+            .await?
             .map_err(|e| {
                 info!("Failed to validate tokens: [{e:?}]");
                 EdgeError::EdgeTokenError
@@ -470,7 +962,7 @@ impl UnleashClient {
                     .inc();
                 warn!(
                     "Failed to validate tokens. Requested url: [{}]. Got status: {:?}",
-                    self.urls.edge_validate_url.to_string(),
+                    urls.edge_validate_url.to_string(),
                     s
                 );
                 Err(EdgeError::TokenValidationError(
@@ -492,31 +984,40 @@ mod tests {
     use actix_service::map_config;
     use actix_web::{
         dev::{AppConfig, ServiceRequest, ServiceResponse},
-        http::header::EntityTag,
-        web, App, HttpResponse,
+        http::header::{self, EntityTag},
+        middleware::Compress,
+        web, App, HttpRequest, HttpResponse,
     };
-    use chrono::Duration;
+    use chrono::{DateTime, Duration, Utc};
+    use reqwest::{StatusCode, Url};
     use unleash_types::client_features::{ClientFeature, ClientFeatures};
 
     use crate::cli::ClientIdentity;
-    use crate::http::unleash_client::new_reqwest_client;
+    use crate::http::circuit_breaker::CircuitBreaker;
+    use crate::http::unleash_client::{
+        new_reqwest_client, UpstreamConnectionPool, UPSTREAM_PAYLOAD_BYTES,
+    };
+    use crate::http::upstream_failover::UpstreamFailover;
+    use crate::urls::UnleashUrls;
     use crate::{
-        cli::TlsOptions,
+        cli::{TlsOptions, TlsVersion},
+        error::{CertificateError, EdgeError, FeatureError},
         middleware::as_async_middleware::as_async_middleware,
         tls,
         types::{
-            ClientFeaturesRequest, ClientFeaturesResponse, EdgeToken, TokenValidationStatus,
-            ValidateTokensRequest,
+            ClientFeaturesDeltaRequest, ClientFeaturesRequest, ClientFeaturesResponse, EdgeToken,
+            TokenValidationStatus, ValidateTokensRequest,
         },
     };
 
-    use super::{EdgeTokens, UnleashClient};
+    use super::{parse_retry_after, EdgeTokens, UnleashClient};
 
     impl ClientFeaturesRequest {
         pub(crate) fn new(api_key: String, etag: Option<String>) -> Self {
             Self {
                 api_key,
                 etag: etag.map(EntityTag::new_weak),
+                tags: vec![],
             }
         }
     }
@@ -582,6 +1083,9 @@ mod tests {
                 tls_enable: true,
                 tls_server_key: Some("../examples/server.key".into()),
                 tls_server_port: 443,
+                tls_min_version: TlsVersion::Tls12,
+                tls_cipher_suites: vec![],
+                tls_reload_interval_seconds: None,
             };
             let server_config = tls::config(tls_options).unwrap();
             let tls_acceptor_config =
@@ -643,10 +1147,31 @@ mod tests {
         .await
     }
 
+    async fn require_identification_middleware(
+        req: ServiceRequest,
+        srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+    ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+        let identified = header(header::USER_AGENT.as_str()) == Some(super::user_agent())
+            && header("UNLEASH-APPNAME").as_deref() == Some("edge-eu-west")
+            && header("UNLEASH-INSTANCEID").as_deref() == Some("edge-eu-west-1");
+        let res = if identified {
+            srv.call(req).await?.map_into_left_body()
+        } else {
+            req.into_response(HttpResponse::Forbidden().finish())
+                .map_into_right_body()
+        };
+        Ok(res)
+    }
+
     fn expected_etag(features: ClientFeatures) -> String {
         let hash = features.xx3_hash().unwrap();
         let len = serde_json::to_string(&features)
-            .map(|string| string.as_bytes().len())
+            .map(|string| string.len())
             .unwrap();
         format!("{len:x}-{hash}")
     }
@@ -671,6 +1196,281 @@ mod tests {
         }
     }
 
+    #[actix_web::test]
+    async fn requests_identify_edge_to_upstream() {
+        let srv = test_server(move || {
+            HttpService::new(map_config(
+                App::new()
+                    .wrap(as_async_middleware(require_identification_middleware))
+                    .service(
+                        web::resource("/api/client/features")
+                            .route(web::get().to(return_client_features)),
+                    )
+                    .service(
+                        web::resource("/edge/validate")
+                            .route(web::post().to(return_validate_tokens)),
+                    ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let backing_client = new_reqwest_client(
+            "edge-eu-west-1".into(),
+            false,
+            None,
+            None,
+            Duration::seconds(5),
+            Duration::seconds(5),
+            "edge-eu-west".into(),
+            true,
+            UpstreamConnectionPool::default(),
+        )
+        .unwrap();
+        let client = UnleashClient::from_url(
+            Url::parse(&srv.url("/")).unwrap(),
+            "Authorization".into(),
+            backing_client,
+        );
+        assert!(client
+            .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+            .await
+            .is_ok());
+        assert!(client
+            .validate_tokens(ValidateTokensRequest {
+                tokens: vec![TEST_TOKEN.to_string()],
+            })
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn payload_sizes_are_observed_per_environment() {
+        let srv = test_features_server().await;
+        let client = UnleashClient::new(srv.url("/").as_str(), None).unwrap();
+        client
+            .get_client_features(ClientFeaturesRequest::new(
+                "*:payload-sizes.secret123".to_string(),
+                None,
+            ))
+            .await
+            .unwrap();
+        let histogram = UPSTREAM_PAYLOAD_BYTES.with_label_values(&["payload-sizes"]);
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(
+            histogram.get_sample_sum(),
+            serde_json::to_vec(&two_client_features()).unwrap().len() as f64
+        );
+    }
+
+    #[actix_web::test]
+    async fn client_fails_over_to_the_next_upstream_when_the_current_is_unreachable() {
+        let srv = test_features_server().await;
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let client = UnleashClient::new(&unreachable, None)
+            .unwrap()
+            .with_upstream_failover(
+                UpstreamFailover::new(vec![
+                    UnleashUrls::from_str(&unreachable).unwrap(),
+                    UnleashUrls::from_str(srv.url("/").as_str()).unwrap(),
+                ])
+                .with_failover_threshold(1),
+            );
+
+        let first_attempt = client
+            .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+            .await;
+        assert!(first_attempt.is_err());
+        let after_failover = client
+            .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+            .await;
+        assert!(matches!(
+            after_failover,
+            Ok(ClientFeaturesResponse::Updated(_, _))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn features_are_the_same_with_and_without_compression() {
+        use std::sync::{Arc, Mutex};
+
+        let accepted_encodings = Arc::new(Mutex::new(vec![]));
+        let recorded = accepted_encodings.clone();
+        let srv = test_server(move || {
+            let recorded = recorded.clone();
+            HttpService::new(map_config(
+                App::new().wrap(Compress::default()).route(
+                    "/api/client/features",
+                    web::get().to(move |req: HttpRequest| {
+                        recorded.lock().unwrap().push(
+                            req.headers()
+                                .get(header::ACCEPT_ENCODING)
+                                .map(|encoding| encoding.to_str().unwrap().to_string()),
+                        );
+                        return_client_features()
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+
+        let mut fetched = vec![];
+        for compression in [true, false] {
+            let http_client = new_reqwest_client(
+                "test_compression".into(),
+                false,
+                None,
+                None,
+                Duration::seconds(5),
+                Duration::seconds(5),
+                "test-client".into(),
+                compression,
+                UpstreamConnectionPool::default(),
+            )
+            .unwrap();
+            let client = UnleashClient::from_url(
+                Url::parse(&srv.url("/")).unwrap(),
+                "Authorization".into(),
+                http_client,
+            );
+            match client
+                .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+                .await
+            {
+                Ok(ClientFeaturesResponse::Updated(features, _)) => fetched.push(features),
+                other => panic!("Expected features, got {other:?}"),
+            }
+        }
+        assert_eq!(fetched[0], two_client_features());
+        assert_eq!(fetched[0], fetched[1]);
+        let accepted_encodings = accepted_encodings.lock().unwrap();
+        assert!(accepted_encodings[0]
+            .as_deref()
+            .is_some_and(|encoding| encoding.contains("gzip") && encoding.contains("br")));
+        assert_eq!(accepted_encodings[1], None);
+    }
+
+    #[actix_web::test]
+    async fn requests_short_circuit_while_the_circuit_breaker_is_open() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+        let srv = test_server(move || {
+            let served = served.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move || {
+                        served.fetch_add(1, Ordering::SeqCst);
+                        async { HttpResponse::ServiceUnavailable().finish() }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let client = UnleashClient::new(srv.url("/").as_str(), None)
+            .unwrap()
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::seconds(60)));
+
+        for _ in 0..2 {
+            let result = client
+                .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+                .await;
+            assert!(matches!(
+                result,
+                Err(EdgeError::ClientFeaturesFetchError(_))
+            ));
+        }
+        let short_circuited = client
+            .get_client_features(ClientFeaturesRequest::new("somekey".to_string(), None))
+            .await;
+        assert!(matches!(
+            short_circuited,
+            Err(EdgeError::UpstreamCircuitOpen(_))
+        ));
+        let validation = client
+            .validate_tokens(ValidateTokensRequest {
+                tokens: vec!["*:development.secret123".into()],
+            })
+            .await;
+        assert!(matches!(validation, Err(EdgeError::UpstreamCircuitOpen(_))));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_as_an_http_date() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::seconds(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::seconds(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::zero())
+        );
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[actix_web::test]
+    async fn rate_limited_feature_fetches_say_when_upstream_wants_us_back() {
+        let srv = test_server(move || {
+            HttpService::new(map_config(
+                App::new()
+                    .route(
+                        "/api/client/features",
+                        web::get().to(|| async {
+                            HttpResponse::TooManyRequests()
+                                .insert_header((header::RETRY_AFTER, "120"))
+                                .finish()
+                        }),
+                    )
+                    .route(
+                        "/api/client/delta",
+                        web::get().to(|| async { HttpResponse::TooManyRequests().finish() }),
+                    ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let client = UnleashClient::new(srv.url("/").as_str(), None).unwrap();
+        let result = client
+            .get_client_features(ClientFeaturesRequest::new(TEST_TOKEN.to_string(), None))
+            .await;
+        assert!(matches!(
+            result,
+            Err(EdgeError::ClientFeaturesFetchError(FeatureError::RateLimited(
+                StatusCode::TOO_MANY_REQUESTS,
+                retry_after
+            ))) if retry_after == Duration::seconds(120)
+        ));
+        let without_retry_after = client
+            .get_client_features_delta(ClientFeaturesDeltaRequest {
+                api_key: TEST_TOKEN.to_string(),
+                revision_id: None,
+            })
+            .await;
+        assert!(matches!(
+            without_retry_after,
+            Err(EdgeError::ClientFeaturesFetchError(
+                FeatureError::Retriable(StatusCode::TOO_MANY_REQUESTS)
+            ))
+        ));
+    }
+
     #[actix_web::test]
     async fn client_handles_304() {
         let srv = test_features_server().await;
@@ -738,6 +1538,7 @@ mod tests {
             .get_client_features(ClientFeaturesRequest {
                 api_key: "notneeded".into(),
                 etag: None,
+                tags: vec![],
             })
             .await;
         assert!(res.is_err());
@@ -745,6 +1546,7 @@ mod tests {
             .get_client_features(ClientFeaturesRequest {
                 api_key: "notneeded".into(),
                 etag: None,
+                tags: vec![],
             })
             .await;
         assert!(authed_res.is_ok());
@@ -799,6 +1601,8 @@ mod tests {
             Duration::seconds(5),
             Duration::seconds(5),
             "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
         );
         assert!(client.is_ok());
     }
@@ -821,6 +1625,8 @@ mod tests {
             Duration::seconds(5),
             Duration::seconds(5),
             "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
         );
         assert!(client.is_err());
     }
@@ -843,7 +1649,161 @@ mod tests {
             Duration::seconds(5),
             Duration::seconds(5),
             "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
         );
         assert!(client.is_ok());
     }
+
+    #[test]
+    pub fn can_instantiate_client_with_pkcs_1_rsa_key() {
+        let key = "./testdata/pkcs1/snakeoil.key";
+        let cert = "./testdata/pkcs12/snakeoil.pem";
+        let identity = ClientIdentity {
+            pkcs8_client_certificate_file: Some(cert.into()),
+            pkcs8_client_key_file: Some(key.into()),
+            pkcs12_identity_file: None,
+            pkcs12_passphrase: None,
+        };
+        let client = new_reqwest_client(
+            "test_pkcs1".into(),
+            false,
+            Some(identity),
+            None,
+            Duration::seconds(5),
+            Duration::seconds(5),
+            "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
+        );
+        assert!(client.is_ok());
+    }
+
+    #[test]
+    pub fn should_throw_error_if_client_key_does_not_match_certificate() {
+        let key = "./testdata/client_certs/client.key.pem";
+        let cert = "./testdata/pkcs12/snakeoil.pem";
+        let identity = ClientIdentity {
+            pkcs8_client_certificate_file: Some(cert.into()),
+            pkcs8_client_key_file: Some(key.into()),
+            pkcs12_identity_file: None,
+            pkcs12_passphrase: None,
+        };
+        let client = new_reqwest_client(
+            "test_mismatch".into(),
+            false,
+            Some(identity),
+            None,
+            Duration::seconds(5),
+            Duration::seconds(5),
+            "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
+        );
+        assert!(matches!(
+            client,
+            Err(EdgeError::ClientCertificateError(
+                CertificateError::ClientIdentityValidation(_)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn upstream_requests_carry_the_trace_context_of_the_request_being_handled() {
+        use std::sync::{Arc, Mutex};
+
+        use actix_service::Service;
+        use opentelemetry::trace::{
+            FutureExt, SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+        use opentelemetry::Context;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let traceparents = Arc::new(Mutex::new(vec![]));
+        let recorded = traceparents.clone();
+        let srv = test_server(move || {
+            let recorded = recorded.clone();
+            HttpService::new(map_config(
+                App::new()
+                    .wrap_fn(move |req, srv| {
+                        recorded.lock().unwrap().push((
+                            req.path().to_string(),
+                            req.headers()
+                                .get("traceparent")
+                                .map(|v| v.to_str().unwrap().to_string()),
+                        ));
+                        srv.call(req)
+                    })
+                    .service(
+                        web::resource("/api/client/features")
+                            .route(web::get().to(return_client_features)),
+                    )
+                    .service(
+                        web::resource("/edge/validate")
+                            .route(web::post().to(return_validate_tokens)),
+                    ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let client = UnleashClient::new(srv.url("/").as_str(), None).unwrap();
+        let incoming = Context::new().with_remote_span_context(SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        ));
+
+        client
+            .validate_tokens(ValidateTokensRequest {
+                tokens: vec![TEST_TOKEN.to_string()],
+            })
+            .with_context(incoming.clone())
+            .await
+            .unwrap();
+        client
+            .get_client_features(ClientFeaturesRequest::new(TEST_TOKEN.to_string(), None))
+            .with_context(incoming)
+            .await
+            .unwrap();
+        client
+            .get_client_features(ClientFeaturesRequest::new(TEST_TOKEN.to_string(), None))
+            .await
+            .unwrap();
+
+        let expected = Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string());
+        assert_eq!(
+            *traceparents.lock().unwrap(),
+            vec![
+                ("/edge/validate".to_string(), expected.clone()),
+                ("/api/client/features".to_string(), expected),
+                ("/api/client/features".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn reconnects_only_when_upstream_resolves_somewhere_else() {
+        let client = UnleashClient::new("http://localhost:4242", None).unwrap();
+        let builds = std::cell::Cell::new(0);
+        let build_client = || {
+            builds.set(builds.get() + 1);
+            Ok(reqwest::Client::new())
+        };
+        let first: std::collections::BTreeSet<std::net::SocketAddr> =
+            ["127.0.0.1:4242".parse().unwrap()].into();
+        let moved: std::collections::BTreeSet<std::net::SocketAddr> =
+            ["127.0.0.2:4242".parse().unwrap()].into();
+        let mut known_addresses = None;
+
+        assert!(!client.reconnect_if_moved(&mut known_addresses, first.clone(), &build_client));
+        assert!(!client.reconnect_if_moved(&mut known_addresses, first, &build_client));
+        assert_eq!(builds.get(), 0);
+        assert!(client.reconnect_if_moved(&mut known_addresses, moved.clone(), &build_client));
+        assert_eq!(builds.get(), 1);
+        assert_eq!(known_addresses, Some(moved));
+    }
 }
@@ -0,0 +1,199 @@
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Duration, Utc};
+use tracing::{info, warn};
+
+use crate::urls::UnleashUrls;
+
+pub const DEFAULT_FAILOVER_THRESHOLD: u32 = 3;
+pub const DEFAULT_PRIMARY_COOLDOWN_SECONDS: i64 = 300;
+
+#[derive(Debug, Default)]
+struct FailoverState {
+    current: usize,
+    consecutive_failures: u32,
+    failed_over_at: Option<DateTime<Utc>>,
+}
+
+/// Decides which of the configured upstreams requests are sent to. The first upstream is the primary.
+/// After `failover_threshold` consecutive attempts where the current upstream was unreachable we move on to the next one,
+/// and once `primary_cooldown` has passed since failing over we try the primary again.
+#[derive(Debug)]
+pub struct UpstreamFailover {
+    upstreams: Vec<UnleashUrls>,
+    state: Mutex<FailoverState>,
+    failover_threshold: u32,
+    primary_cooldown: Duration,
+}
+
+impl Default for UpstreamFailover {
+    fn default() -> Self {
+        UpstreamFailover::new(vec![UnleashUrls::default()])
+    }
+}
+
+impl UpstreamFailover {
+    pub fn new(upstreams: Vec<UnleashUrls>) -> Self {
+        assert!(
+            !upstreams.is_empty(),
+            "At least one upstream is needed to fail over between"
+        );
+        Self {
+            upstreams,
+            state: Mutex::new(FailoverState::default()),
+            failover_threshold: DEFAULT_FAILOVER_THRESHOLD,
+            primary_cooldown: Duration::seconds(DEFAULT_PRIMARY_COOLDOWN_SECONDS),
+        }
+    }
+
+    pub fn with_failover_threshold(self, failover_threshold: u32) -> Self {
+        Self {
+            failover_threshold: failover_threshold.max(1),
+            ..self
+        }
+    }
+
+    pub fn with_primary_cooldown(self, primary_cooldown: Duration) -> Self {
+        Self {
+            primary_cooldown,
+            ..self
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, FailoverState> {
+        self.state
+            .lock()
+            .expect("Upstream failover state was poisoned")
+    }
+
+    /// The upstream to send the next request to, along with its index for reporting the outcome of the request
+    pub fn current(&self) -> (usize, UnleashUrls) {
+        let mut state = self.state();
+        if let Some(failed_over_at) = state.failed_over_at {
+            if state.current != 0 && Utc::now() - failed_over_at >= self.primary_cooldown {
+                info!(
+                    "Cooldown passed, switching back to primary upstream {}",
+                    self.upstreams[0].base_url
+                );
+                *state = FailoverState::default();
+            }
+        }
+        (state.current, self.upstreams[state.current].clone())
+    }
+
+    /// Every address the upstream hosts resolve to right now. Hosts that don't resolve are left out, so one failover
+    /// upstream being gone doesn't hide where the others moved
+    pub async fn resolve(&self) -> BTreeSet<SocketAddr> {
+        let mut addresses = BTreeSet::new();
+        for upstream in &self.upstreams {
+            let url = &upstream.base_url;
+            let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+                continue;
+            };
+            match tokio::net::lookup_host((host, port)).await {
+                Ok(resolved) => addresses.extend(resolved),
+                Err(e) => warn!("Could not resolve upstream {host}: {e}"),
+            }
+        }
+        addresses
+    }
+
+    pub fn record_success(&self, upstream: usize) {
+        let mut state = self.state();
+        if state.current == upstream {
+            state.consecutive_failures = 0;
+        }
+    }
+
+    /// Failures reported for an upstream we've already moved away from are ignored, so concurrent requests only switch once
+    pub fn record_unreachable(&self, upstream: usize) {
+        let mut state = self.state();
+        if state.current != upstream || self.upstreams.len() < 2 {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failover_threshold {
+            let next = (upstream + 1) % self.upstreams.len();
+            warn!(
+                "Upstream {} was unreachable {} times in a row, switching to {}",
+                self.upstreams[upstream].base_url,
+                state.consecutive_failures,
+                self.upstreams[next].base_url
+            );
+            state.current = next;
+            state.consecutive_failures = 0;
+            state.failed_over_at = Some(Utc::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn primary_and_standby() -> UpstreamFailover {
+        UpstreamFailover::new(vec![
+            UnleashUrls::from_str("http://primary:4242").unwrap(),
+            UnleashUrls::from_str("http://standby:4242").unwrap(),
+        ])
+    }
+
+    #[test]
+    pub fn fails_over_after_consecutive_unreachable_attempts() {
+        let failover = primary_and_standby().with_failover_threshold(2);
+        failover.record_unreachable(0);
+        failover.record_success(0);
+        failover.record_unreachable(0);
+        assert_eq!(failover.current().0, 0);
+        failover.record_unreachable(0);
+        let (upstream, urls) = failover.current();
+        assert_eq!(upstream, 1);
+        assert_eq!(urls.base_url.host_str(), Some("standby"));
+        failover.record_unreachable(0);
+        assert_eq!(failover.current().0, 1);
+    }
+
+    #[test]
+    pub fn returns_to_primary_after_cooldown() {
+        let failover = primary_and_standby()
+            .with_failover_threshold(1)
+            .with_primary_cooldown(Duration::zero());
+        failover.record_unreachable(0);
+        assert_eq!(failover.current().0, 0);
+
+        let failover = primary_and_standby()
+            .with_failover_threshold(1)
+            .with_primary_cooldown(Duration::minutes(5));
+        failover.record_unreachable(0);
+        assert_eq!(failover.current().0, 1);
+    }
+
+    #[test]
+    pub fn a_single_upstream_never_fails_over() {
+        let failover = UpstreamFailover::default().with_failover_threshold(1);
+        failover.record_unreachable(0);
+        failover.record_unreachable(0);
+        assert_eq!(failover.current().0, 0);
+    }
+
+    #[tokio::test]
+    pub async fn resolves_every_upstream_with_its_port() {
+        let failover = UpstreamFailover::new(vec![
+            UnleashUrls::from_str("http://127.0.0.1:4242").unwrap(),
+            UnleashUrls::from_str("http://upstream.invalid").unwrap(),
+            UnleashUrls::from_str("https://127.0.0.2").unwrap(),
+        ]);
+        let addresses = failover.resolve().await;
+        assert_eq!(
+            addresses.into_iter().collect::<Vec<SocketAddr>>(),
+            vec![
+                "127.0.0.1:4242".parse().unwrap(),
+                "127.0.0.2:443".parse().unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::Mutex;
+
+use crate::error::EdgeError;
+use crate::types::{ClientFeaturesRequest, EdgeResult, TokenRefresh};
+
+use super::feature_refresher::FeatureRefresher;
+use super::unleash_client::UnleashClient;
+
+#[derive(Clone)]
+struct LastCheck {
+    checked_at: Instant,
+    result: Result<(), String>,
+}
+
+/// Checks that upstream is reachable and accepts the tokens we refresh with. The answer is remembered for a while,
+/// and checks wait for the one already running, so frequent health probes don't hammer upstream
+#[derive(Clone)]
+pub struct UpstreamHealthCheck {
+    unleash_client: Arc<UnleashClient>,
+    tokens_to_refresh: Arc<DashMap<String, TokenRefresh>>,
+    cache_for: Duration,
+    last_check: Arc<Mutex<Option<LastCheck>>>,
+}
+
+impl UpstreamHealthCheck {
+    pub fn new(refresher: &FeatureRefresher, cache_for: Duration) -> Self {
+        UpstreamHealthCheck {
+            unleash_client: refresher.unleash_client.clone(),
+            tokens_to_refresh: refresher.tokens_to_refresh.clone(),
+            cache_for,
+            last_check: Default::default(),
+        }
+    }
+
+    pub async fn check(&self) -> EdgeResult<()> {
+        let mut last_check = self.last_check.lock().await;
+        let result = match last_check.as_ref() {
+            Some(last) if last.checked_at.elapsed() < self.cache_for => last.result.clone(),
+            _ => {
+                let result = self.ping().await;
+                *last_check = Some(LastCheck {
+                    checked_at: Instant::now(),
+                    result: result.clone(),
+                });
+                result
+            }
+        };
+        result.map_err(EdgeError::UpstreamUnreachable)
+    }
+
+    /// Fetches features for one of our tokens, with its etag so upstream usually only has to say nothing changed.
+    /// Without any tokens yet, upstream's own health endpoint is all we can ask
+    async fn ping(&self) -> Result<(), String> {
+        let refresh = self
+            .tokens_to_refresh
+            .iter()
+            .next()
+            .map(|refresh| refresh.value().clone());
+        let result = match refresh {
+            Some(refresh) => self
+                .unleash_client
+                .get_client_features(ClientFeaturesRequest {
+                    api_key: refresh.token.token,
+                    etag: refresh.etag,
+                    tags: vec![],
+                })
+                .await
+                .map(|_| ()),
+            None => self.unleash_client.upstream_health().await,
+        };
+        result.map_err(|e| match e {
+            EdgeError::UpstreamUnreachable(msg) => msg,
+            e => e.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_http::HttpService;
+    use actix_http_test::test_server;
+    use actix_service::map_config;
+    use actix_web::dev::AppConfig;
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+    use crate::types::EdgeToken;
+
+    #[tokio::test]
+    pub async fn upstream_is_pinged_with_a_known_token_and_the_answer_is_cached() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server_hits = hits.clone();
+        let server = test_server(move || {
+            let hits = server_hits.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/api/client/features",
+                    web::get().to(move |req: actix_web::HttpRequest| {
+                        let hits = hits.clone();
+                        async move {
+                            hits.fetch_add(1, Ordering::SeqCst);
+                            match req.headers().get("Authorization") {
+                                Some(token) if token == "*:development.secret123" => {
+                                    HttpResponse::NotModified().finish()
+                                }
+                                _ => HttpResponse::Forbidden().finish(),
+                            }
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new(server.url("/").as_str(), None).unwrap(),
+        ));
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        refresher.tokens_to_refresh.insert(
+            token.token.clone(),
+            TokenRefresh::new(
+                token,
+                Some(actix_web::http::header::EntityTag::new_weak("abc".into())),
+            ),
+        );
+        let health = UpstreamHealthCheck::new(&refresher, Duration::from_secs(60));
+
+        assert!(health.check().await.is_ok());
+        assert!(health.check().await.is_ok());
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let uncached = UpstreamHealthCheck::new(&refresher, Duration::ZERO);
+        refresher.tokens_to_refresh.clear();
+        let revoked = EdgeToken::validated_client_token("*:development.revoked");
+        refresher
+            .tokens_to_refresh
+            .insert(revoked.token.clone(), TokenRefresh::new(revoked, None));
+        assert!(matches!(
+            uncached.check().await,
+            Err(EdgeError::UpstreamUnreachable(_))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn unreachable_upstream_is_unhealthy_without_any_tokens() {
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new("http://127.0.0.1:1", None).unwrap(),
+        ));
+        let health = UpstreamHealthCheck::new(&refresher, Duration::from_secs(5));
+        assert!(matches!(
+            health.check().await,
+            Err(EdgeError::UpstreamUnreachable(_))
+        ));
+    }
+}
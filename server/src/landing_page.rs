@@ -0,0 +1,202 @@
+use actix_web::http::header::{self, ContentType};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use crate::types::EDGE_VERSION;
+
+const FAVICON: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 32 32"><rect width="32" height="32" rx="6" fill="#1a4049"/><path d="M9 8h4v10a3 3 0 0 0 6 0V8h4v10a7 7 0 0 1-14 0z" fill="#fff"/></svg>"##;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Endpoint {
+    pub name: &'static str,
+    pub path: String,
+}
+
+/// What `/` answers with, so operators probing Edge see what it is and where to go instead of a 404
+#[derive(Debug, Clone, Serialize)]
+pub struct LandingPage {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl LandingPage {
+    pub fn new(base_path: &str, disable_openapi: bool) -> Self {
+        let mut endpoints = vec![
+            ("api", "/api"),
+            ("edge", "/edge"),
+            ("internal-backstage", "/internal-backstage"),
+        ];
+        if !disable_openapi {
+            endpoints.push(("swagger", "/swagger-ui/"));
+        }
+        LandingPage {
+            name: "Unleash Edge",
+            version: EDGE_VERSION,
+            endpoints: endpoints
+                .into_iter()
+                .map(|(name, path)| Endpoint {
+                    name,
+                    path: format!("{base_path}{path}"),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_html(&self) -> String {
+        let endpoints: String = self
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                format!(
+                    "<li><a href=\"{path}\">{name}</a> <code>{path}</code></li>",
+                    path = endpoint.path,
+                    name = endpoint.name
+                )
+            })
+            .collect();
+        format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{name}</title></head><body><h1>{name} {version}</h1><ul>{endpoints}</ul></body></html>",
+            name = self.name,
+            version = self.version
+        )
+    }
+}
+
+/// Browsers get the page as HTML, anything else as JSON
+async fn root(req: HttpRequest, page: web::Data<LandingPage>) -> HttpResponse {
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(page.to_html())
+    } else {
+        HttpResponse::Ok().json(page.get_ref())
+    }
+}
+
+async fn favicon() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .insert_header((header::CACHE_CONTROL, "public, max-age=86400"))
+        .body(FAVICON)
+}
+
+/// Needs to be mounted inside the base path scope. The root answers both with and without a trailing slash, since the base path scope sees either
+pub fn configure_landing_page(
+    cfg: &mut web::ServiceConfig,
+    base_path: &str,
+    disable_openapi: bool,
+) {
+    cfg.service(
+        web::resource(["", "/"])
+            .app_data(web::Data::new(LandingPage::new(base_path, disable_openapi)))
+            .route(web::get().to(root)),
+    )
+    .service(web::resource(["/favicon.ico", "/favicon.svg"]).route(web::get().to(favicon)));
+}
+
+/// Browsers ask for `/favicon.ico` no matter the base path, so with one set the favicon is served at the root too.
+/// Needs to be mounted on the app, before the base path scope
+pub fn configure_root_favicon(cfg: &mut web::ServiceConfig, base_path: &str) {
+    if !base_path.is_empty() {
+        cfg.service(web::resource("/favicon.ico").route(web::get().to(favicon)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, init_service, TestRequest};
+    use actix_web::{web, App};
+    use serde_json::Value;
+
+    use super::{configure_landing_page, configure_root_favicon};
+    use crate::types::EDGE_VERSION;
+
+    #[actix_web::test]
+    async fn root_lists_the_endpoints_under_the_base_path() {
+        let app = init_service(
+            App::new().service(
+                web::scope("/feature-edge")
+                    .configure(|cfg| configure_landing_page(cfg, "/feature-edge", false)),
+            ),
+        )
+        .await;
+
+        let page: Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/feature-edge").to_request())
+                .await;
+        assert_eq!(page["version"], EDGE_VERSION);
+        let paths: Vec<&str> = page["endpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|endpoint| endpoint["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/feature-edge/api",
+                "/feature-edge/edge",
+                "/feature-edge/internal-backstage",
+                "/feature-edge/swagger-ui/"
+            ]
+        );
+
+        let html = call_and_read_body(
+            &app,
+            TestRequest::get()
+                .uri("/feature-edge/")
+                .insert_header((header::ACCEPT, "text/html,application/xhtml+xml"))
+                .to_request(),
+        )
+        .await;
+        let html = String::from_utf8_lossy(&html);
+        assert!(html.contains("href=\"/feature-edge/internal-backstage\""));
+        assert!(html.contains(EDGE_VERSION));
+    }
+
+    #[actix_web::test]
+    async fn swagger_is_left_out_when_openapi_is_disabled_and_the_favicon_is_served() {
+        let app = init_service(
+            App::new()
+                .service(web::scope("").configure(|cfg| configure_landing_page(cfg, "", true))),
+        )
+        .await;
+
+        let page: Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(page["endpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|endpoint| endpoint["name"] != "swagger"));
+
+        let favicon =
+            call_and_read_body(&app, TestRequest::get().uri("/favicon.ico").to_request()).await;
+        assert!(String::from_utf8_lossy(&favicon).starts_with("<svg"));
+    }
+
+    #[actix_web::test]
+    async fn favicon_is_served_at_the_root_when_there_is_a_base_path() {
+        let app = init_service(
+            App::new()
+                .configure(|cfg| configure_root_favicon(cfg, "/feature-edge"))
+                .service(
+                    web::scope("/feature-edge")
+                        .configure(|cfg| configure_landing_page(cfg, "/feature-edge", false)),
+                ),
+        )
+        .await;
+
+        for uri in ["/favicon.ico", "/feature-edge/favicon.ico"] {
+            let favicon = call_and_read_body(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert!(String::from_utf8_lossy(&favicon).starts_with("<svg"));
+        }
+    }
+}
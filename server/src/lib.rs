@@ -1,17 +1,21 @@
 pub mod auth;
+pub mod background_tasks;
 #[cfg(not(tarpaulin_include))]
 pub mod builder;
 #[cfg(not(tarpaulin_include))]
 pub mod cli;
 pub mod client_api;
+pub mod config_reload;
 pub mod edge_api;
 #[cfg(not(tarpaulin_include))]
 pub mod error;
+pub mod feature_overrides;
 pub mod filters;
 pub mod frontend_api;
 pub mod health_checker;
 pub mod http;
 pub mod internal_backstage;
+pub mod landing_page;
 pub mod metrics;
 pub mod middleware;
 pub mod offline;
@@ -26,6 +30,8 @@ pub mod ready_checker;
 pub mod tls;
 pub mod tokens;
 pub mod types;
+#[cfg(unix)]
+pub mod unix_socket;
 pub mod urls;
 #[cfg(test)]
 mod tests {
@@ -61,9 +67,8 @@ mod tests {
         upstream_engine_cache: Arc<DashMap<String, EngineState>>,
     ) -> TestServer {
         let token_validator = Arc::new(TokenValidator {
-            unleash_client: Arc::new(Default::default()),
             token_cache: upstream_token_cache.clone(),
-            persistence: None,
+            ..Default::default()
         });
 
         test_server(move || {
@@ -85,7 +90,12 @@ mod tests {
                     .app_data(web::Data::new(connect_via))
                     .service(
                         web::scope("/api")
-                            .configure(crate::client_api::configure_client_api)
+                            .configure(|cfg| {
+                                crate::client_api::configure_client_api(
+                                    cfg,
+                                    crate::client_api::DEFAULT_MAX_METRICS_BODY_BYTES,
+                                )
+                            })
                             .configure(|cfg| {
                                 crate::frontend_api::configure_frontend_api(cfg, false)
                             }),
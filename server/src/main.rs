@@ -1,33 +1,66 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use actix_cors::Cors;
 use actix_middleware_etag::Etag;
-use actix_web::middleware::Logger;
+use actix_web::http::KeepAlive;
+use actix_web::middleware::{Compress, Condition, Logger};
 use actix_web::{web, App, HttpServer};
-use clap::Parser;
 use dashmap::DashMap;
 use futures::future::join_all;
 use unleash_types::client_features::ClientFeatures;
 use unleash_types::client_metrics::ConnectVia;
-use utoipa::OpenApi;
-use utoipa_swagger_ui::SwaggerUi;
 
+use unleash_edge::auth::denied_tokens::DeniedTokens;
+use unleash_edge::background_tasks::supervise;
 use unleash_edge::builder::build_caches_and_refreshers;
-use unleash_edge::cli::{CliArgs, EdgeMode};
+use unleash_edge::cli::{ArgumentSource, CliArgs, EdgeMode, LogFormat};
+use unleash_edge::config_reload;
+use unleash_edge::feature_overrides::FeatureOverrides;
+use unleash_edge::http::feature_refresher::FeatureRefresher;
+use unleash_edge::http::refresh_shard::RefreshShard;
+use unleash_edge::http::upstream_health::UpstreamHealthCheck;
+use unleash_edge::metrics::cache_sizes::{CacheSizes, CACHE_SIZE_SAMPLE_INTERVAL};
 use unleash_edge::metrics::client_metrics::MetricsCache;
+use unleash_edge::middleware::access_log::{access_log, log_slow_requests, SlowRequestThreshold};
+use unleash_edge::middleware::as_async_middleware::as_async_middleware;
+use unleash_edge::middleware::backstage_access::restrict_backstage_access;
+use unleash_edge::middleware::cache_control::cache_for_refresh_interval;
+use unleash_edge::middleware::cache_status::add_cache_status_header;
+use unleash_edge::middleware::cors::cors_middleware;
+use unleash_edge::middleware::error_body::error_bodies;
+use unleash_edge::middleware::in_flight_requests::{count_in_flight_requests, in_flight_requests};
+use unleash_edge::middleware::rate_limit::RateLimiter;
+use unleash_edge::middleware::request_id::request_id;
+use unleash_edge::middleware::request_timeout::{enforce_request_timeout, RequestTimeout};
 use unleash_edge::middleware::request_tracing::RequestTracing;
+use unleash_edge::middleware::version_headers::add_version_headers;
 use unleash_edge::offline::offline_hotload;
-use unleash_edge::persistence::{persist_data, EdgePersistence};
-use unleash_edge::types::{EdgeToken, TokenValidationStatus};
-use unleash_edge::{cli, client_api, frontend_api, health_checker, openapi, ready_checker};
+use unleash_edge::persistence::{
+    check_persistence_integrity, features_to_persist, persist_data, reload_from_persistence,
+    EdgePersistence,
+};
+use unleash_edge::types::{EdgeToken, TokenRefresh, TokenValidationStatus};
+#[cfg(unix)]
+use unleash_edge::unix_socket;
+use unleash_edge::{
+    cli, client_api, frontend_api, health_checker, landing_page, openapi, ready_checker,
+};
 use unleash_edge::{edge_api, prom_metrics};
 use unleash_edge::{internal_backstage, tls};
 
+/// actix' default access log format with the request id at the end
+const PLAIN_ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{x-request-id}i"#;
+
 #[cfg(not(tarpaulin_include))]
 #[actix_web::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args = CliArgs::parse();
+    let (args, argument_sources) = CliArgs::parse_with_sources();
+    let args = args.with_upstream_token_from_file()?;
+    let denied_tokens = DeniedTokens::from_args(&args)?;
+    let args = args.without_denied_tokens(&denied_tokens);
     let disable_all_endpoint = args.disable_all_endpoint;
+    let disable_openapi = args.disable_openapi;
     if args.markdown_help {
         clap_markdown::print_help_markdown::<CliArgs>();
         return Ok(());
@@ -41,13 +74,71 @@ async fn main() -> Result<(), anyhow::Error> {
         return ready_checker::check_ready(args).await.map_err(|e| e.into());
     }
     let schedule_args = args.clone();
+    let running_args = Arc::new(RwLock::new(args.clone()));
     let mode_arg = args.clone().mode;
     let http_args = args.clone().http;
     let token_header = args.clone().token_header;
     let request_timeout = args.edge_request_timeout;
+    let shutdown_timeout = args.shutdown_timeout_seconds;
+    let drain = Arc::new(internal_backstage::Drain::new(Duration::from_secs(
+        args.drain_grace_period_seconds,
+    )));
+    let structured_access_log = args.log_format == LogFormat::Json;
     let trust_proxy = args.clone().trust_proxy;
+    let cors_options = args.clone().cors;
+    let internal_backstage_args = args.clone().internal_backstage;
+    let rate_limiter = RateLimiter::from(&args.rate_limit);
     let base_path = http_args.base_path.clone();
-    let (metrics_handler, request_metrics) = prom_metrics::instantiate(None, &args.log_format);
+    let openapi_server_url = http_args.openapi_server_url();
+    let max_metrics_body_bytes = http_args.max_metrics_body_bytes;
+    let request_timeout_ms = http_args.request_timeout_ms;
+    let slow_request_threshold_ms = http_args.slow_request_threshold_ms;
+    let max_stream_subscribers = args.max_stream_subscribers;
+    let (metrics_handler, request_metrics) =
+        prom_metrics::instantiate(None, &args.log_format, args.log_level.as_deref());
+    prom_metrics::instantiate_otlp_tracing(&args.otlp)?;
+    argument_sources.log();
+    let server_certificates = if http_args.tls.tls_enable {
+        Some(Arc::new(tls::ServerCertificates::load(&http_args.tls)?))
+    } else {
+        None
+    };
+    if let (Some(certificates), Some(seconds)) = (
+        &server_certificates,
+        http_args.tls.tls_reload_interval_seconds,
+    ) {
+        tokio::spawn(
+            certificates
+                .clone()
+                .reload_when_changed(Duration::from_secs(seconds)),
+        );
+    }
+    if argument_sources.get("instance-id") == Some(ArgumentSource::Default) {
+        tracing::info!("Generated instance id {}", args.instance_id);
+    } else {
+        tracing::info!("Using instance id {}", args.instance_id);
+    }
+    if !denied_tokens.is_empty() {
+        tracing::info!("Denying {} tokens", denied_tokens.len());
+    }
+    let read_replica = matches!(args.mode, cli::EdgeMode::ReadReplica(_));
+    let disable_metrics = args.disable_metrics || read_replica;
+    let metrics_cache = if read_replica {
+        tracing::info!(
+            "Read replicas don't talk to upstream. Metrics and registrations from SDKs are dropped"
+        );
+        Arc::new(MetricsCache::disabled())
+    } else if disable_metrics {
+        tracing::info!("Metrics are disabled. Metrics and registrations from SDKs are dropped");
+        Arc::new(MetricsCache::disabled())
+    } else {
+        Arc::new(
+            MetricsCache::default()
+                .with_max_entries(args.max_buffered_metrics, args.metrics_overflow_policy)
+                .with_aggregation_window(args.metrics_aggregation_window_seconds),
+        )
+    };
+    let metrics_cache_clone = metrics_cache.clone();
     let connect_via = ConnectVia {
         app_name: args.clone().app_name,
         instance_id: args.clone().instance_id,
@@ -57,7 +148,29 @@ async fn main() -> Result<(), anyhow::Error> {
         token_validator,
         feature_refresher,
         persistence,
-    ) = build_caches_and_refreshers(args).await.unwrap();
+    ) = build_caches_and_refreshers(args, metrics_cache.clone(), &denied_tokens)
+        .await
+        .unwrap();
+
+    let feature_overrides = FeatureOverrides::new(persistence.clone()).restore().await;
+
+    #[cfg(unix)]
+    tokio::spawn(reload_config_on_sighup(
+        running_args.clone(),
+        feature_refresher.clone(),
+        server_certificates.clone(),
+    ));
+
+    tokio::spawn(
+        CacheSizes {
+            token_cache: token_cache.clone(),
+            features_cache: features_cache.clone(),
+            refresh_targets: feature_refresher
+                .as_ref()
+                .map(|refresher| refresher.tokens_to_refresh.clone()),
+        }
+        .sample_periodically(CACHE_SIZE_SAMPLE_INTERVAL),
+    );
 
     let token_validator_schedule = token_validator.clone();
     let lazy_feature_cache = features_cache.clone();
@@ -65,20 +178,24 @@ async fn main() -> Result<(), anyhow::Error> {
     let lazy_engine_cache = engine_cache.clone();
     let lazy_feature_refresher = feature_refresher.clone();
 
-    let metrics_cache = Arc::new(MetricsCache::default());
-    let metrics_cache_clone = metrics_cache.clone();
-
-    let openapi = openapi::ApiDoc::openapi();
     let refresher_for_app_data = feature_refresher.clone();
+    let upstream_health = feature_refresher.as_ref().map(|refresher| {
+        UpstreamHealthCheck::new(
+            refresher,
+            Duration::from_secs(internal_backstage_args.upstream_health_cache_seconds),
+        )
+    });
+    let broadcaster = feature_refresher
+        .as_ref()
+        .map(|refresher| refresher.broadcaster.clone())
+        .unwrap_or_default()
+        .with_max_subscribers(max_stream_subscribers);
+    let drain_for_app_data = drain.clone();
     let server = HttpServer::new(move || {
-        let qs_config =
-            serde_qs::actix::QsQueryConfig::default().qs_config(serde_qs::Config::new(5, false));
-
-        let cors_middleware = Cors::default()
-            .allow_any_origin()
-            .send_wildcard()
-            .allow_any_header()
-            .allow_any_method();
+        let qs_config = serde_qs::actix::QsQueryConfig::default()
+            .qs_config(frontend_api::context_query_config());
+
+        let cors_middleware = cors_middleware(&cors_options);
         let mut app = App::new()
             .app_data(qs_config)
             .app_data(web::Data::new(token_header.clone()))
@@ -88,7 +205,15 @@ async fn main() -> Result<(), anyhow::Error> {
             .app_data(web::Data::from(metrics_cache.clone()))
             .app_data(web::Data::from(token_cache.clone()))
             .app_data(web::Data::from(features_cache.clone()))
-            .app_data(web::Data::from(engine_cache.clone()));
+            .app_data(web::Data::from(engine_cache.clone()))
+            .app_data(web::Data::new(broadcaster.clone()))
+            .app_data(web::Data::new(internal_backstage_args.clone()))
+            .app_data(web::Data::from(drain_for_app_data.clone()))
+            .app_data(web::Data::new(feature_overrides.clone()))
+            .app_data(web::Data::new(denied_tokens.clone()))
+            .app_data(web::Data::new(http_args.empty_cache_behavior))
+            .app_data(web::Data::from(running_args.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()));
         app = match token_validator.clone() {
             Some(v) => app.app_data(web::Data::from(v)),
             None => app,
@@ -97,95 +222,232 @@ async fn main() -> Result<(), anyhow::Error> {
             Some(refresher) => app.app_data(web::Data::from(refresher)),
             None => app,
         };
+        app = match request_timeout_ms {
+            Some(timeout_ms) => app.app_data(web::Data::new(RequestTimeout(
+                Duration::from_millis(timeout_ms),
+            ))),
+            None => app,
+        };
+        app = match slow_request_threshold_ms {
+            Some(threshold_ms) => app.app_data(web::Data::new(SlowRequestThreshold(
+                Duration::from_millis(threshold_ms),
+            ))),
+            None => app,
+        };
+        app = match upstream_health.clone() {
+            Some(upstream_health) => app.app_data(web::Data::new(upstream_health)),
+            None => app,
+        };
+        app = app.configure(|cfg| landing_page::configure_root_favicon(cfg, &base_path));
         app.service(
             web::scope(&base_path)
                 .wrap(Etag)
-                .wrap(actix_web::middleware::Compress::default())
+                .wrap(Condition::new(
+                    !http_args.disable_compression,
+                    Compress::default(),
+                ))
                 .wrap(actix_web::middleware::NormalizePath::default())
                 .wrap(cors_middleware)
                 .wrap(RequestTracing::new())
                 .wrap(request_metrics.clone())
-                .wrap(Logger::default())
-                .service(web::scope("/internal-backstage").configure(|service_cfg| {
-                    internal_backstage::configure_internal_backstage(
-                        service_cfg,
-                        metrics_handler.clone(),
-                    )
-                }))
+                .wrap(Condition::new(
+                    !structured_access_log,
+                    Logger::new(PLAIN_ACCESS_LOG_FORMAT),
+                ))
+                .wrap(Condition::new(
+                    structured_access_log,
+                    as_async_middleware(access_log),
+                ))
+                .wrap(Condition::new(
+                    slow_request_threshold_ms.is_some(),
+                    as_async_middleware(log_slow_requests),
+                ))
+                .wrap(as_async_middleware(request_id))
+                .wrap(as_async_middleware(count_in_flight_requests))
+                .wrap(Condition::new(
+                    !http_args.disable_version_headers,
+                    as_async_middleware(add_version_headers),
+                ))
+                .service(
+                    web::scope("/internal-backstage")
+                        .wrap(as_async_middleware(restrict_backstage_access))
+                        .configure(|service_cfg| {
+                            internal_backstage::configure_internal_backstage(
+                                service_cfg,
+                                metrics_handler.clone(),
+                            )
+                        }),
+                )
                 .service(
                     web::scope("/api")
-                        .configure(client_api::configure_client_api)
+                        .wrap(error_bodies())
+                        .wrap(Condition::new(
+                            request_timeout_ms.is_some(),
+                            as_async_middleware(enforce_request_timeout),
+                        ))
+                        .wrap(Condition::new(
+                            !http_args.disable_cache_control,
+                            as_async_middleware(cache_for_refresh_interval),
+                        ))
+                        .wrap(Condition::new(
+                            http_args.cache_status_header,
+                            as_async_middleware(add_cache_status_header),
+                        ))
+                        .configure(|cfg| {
+                            client_api::configure_client_api(cfg, max_metrics_body_bytes)
+                        })
                         .configure(|cfg| {
                             frontend_api::configure_frontend_api(cfg, disable_all_endpoint)
                         }),
                 )
-                .service(web::scope("/edge").configure(edge_api::configure_edge_api))
                 .service(
-                    SwaggerUi::new("/swagger-ui/{_:.*}")
-                        .url("/api-doc/openapi.json", openapi.clone()),
-                ),
+                    web::scope("/edge")
+                        .wrap(error_bodies())
+                        .configure(edge_api::configure_edge_api),
+                )
+                .configure(|cfg| {
+                    openapi::configure_swagger_ui(cfg, &openapi_server_url, disable_openapi)
+                })
+                .configure(|cfg| {
+                    landing_page::configure_landing_page(cfg, &base_path, disable_openapi)
+                }),
         )
     });
-    let server = if http_args.tls.tls_enable {
-        let config = tls::config(http_args.clone().tls)
-            .expect("Was expecting to succeed in configuring TLS");
-        server
-            .bind_rustls_0_23(http_args.https_server_tuple(), config)?
-            .bind(http_args.http_server_tuple())
-    } else {
-        server.bind(http_args.http_server_tuple())
-    };
-    let server = server?
+    let server = server
+        .max_connections(http_args.max_connections)
+        .max_connection_rate(http_args.max_connection_rate)
+        .backlog(http_args.backlog)
+        .keep_alive(match http_args.client_keep_alive_seconds {
+            0 => KeepAlive::Disabled,
+            seconds => KeepAlive::Timeout(Duration::from_secs(seconds)),
+        });
+    let mut server = server;
+    if http_args.listens_on_tcp() {
+        if let Some(certificates) = server_certificates {
+            let config = tls::config_with_certificates(http_args.clone().tls, certificates)?;
+            server = server.bind_rustls_0_23(http_args.https_server_tuple(), config)?;
+        }
+        server = if http_args.enable_h2c {
+            server.bind_auto_h2c(http_args.http_server_tuple())?
+        } else {
+            server.bind(http_args.http_server_tuple())?
+        };
+    }
+    if let Some(socket) = &http_args.unix_socket {
+        #[cfg(unix)]
+        {
+            unix_socket::remove_stale_socket(socket)?;
+            server = server.bind_uds(socket)?;
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Listening on a unix socket ({}) is not supported on this platform",
+            socket.display()
+        ));
+    }
+    let server = server
         .workers(http_args.workers)
-        .shutdown_timeout(5)
-        .client_request_timeout(std::time::Duration::from_secs(request_timeout));
+        .shutdown_timeout(shutdown_timeout)
+        .disable_signals()
+        .client_request_timeout(std::time::Duration::from_secs(request_timeout))
+        .run();
+    let server_handle = server.handle();
+    tokio::spawn(async move {
+        let cause = tokio::select! {
+            signal = shutdown_signal() => format!("Received {signal}"),
+            _ = drain.finished() => "Drain grace period is over".to_string(),
+        };
+        tracing::info!(
+            "{cause} with {} requests in flight. Waiting up to {shutdown_timeout} seconds for them to finish",
+            in_flight_requests()
+        );
+        server_handle.stop(true).await;
+    });
+    let persistence_timeout = std::time::Duration::from_secs(shutdown_timeout);
 
     match schedule_args.mode {
         cli::EdgeMode::Edge(edge) => {
             let refresher = feature_refresher.clone().unwrap();
             let validator = token_validator_schedule.clone().unwrap();
             tokio::select! {
-                _ = server.run() => {
-                    tracing::info!("Actix is shutting down. Persisting data");
-                    clean_shutdown(persistence.clone(), lazy_feature_cache.clone(), lazy_token_cache.clone()).await;
+                _ = server => {
+                    if !disable_metrics {
+                        tracing::info!("Actix is shutting down. Sending metrics upstream");
+                        if unleash_edge::http::background_send_metrics::flush_metrics(metrics_cache_clone.clone(), refresher.clone(), chrono::Duration::seconds(edge.metrics_max_age_seconds.try_into().unwrap()), persistence_timeout).await {
+                            tracing::info!("Sent the remaining metrics upstream");
+                        }
+                    }
+                    tracing::info!("Persisting data");
+                    clean_shutdown(persistence.clone(), lazy_feature_cache.clone(), lazy_token_cache.clone(), Some(refresher.tokens_to_refresh.clone()), refresher.refresh_shard, metrics_cache_clone.clone(), persistence_timeout).await;
                     tracing::info!("Actix was shutdown properly");
                 },
-                _ = refresher.start_refresh_features_background_task() => {
+                _ = supervise("feature_refresher", || refresher.start_refresh_features_background_task()) => {
                     tracing::info!("Feature refresher unexpectedly shut down");
                 }
-                _ = unleash_edge::http::background_send_metrics::send_metrics_task(metrics_cache_clone.clone(), refresher.clone(), edge.metrics_interval_seconds.try_into().unwrap()) => {
+                _ = async {
+                    if disable_metrics {
+                        std::future::pending().await
+                    } else {
+                        supervise("metrics_poster", || unleash_edge::http::background_send_metrics::send_metrics_task(metrics_cache_clone.clone(), refresher.clone(), edge.metrics_interval_seconds.try_into().unwrap(), edge.metrics_send_retries, chrono::Duration::seconds(edge.metrics_max_age_seconds.try_into().unwrap()))).await
+                    }
+                } => {
                     tracing::info!("Metrics poster unexpectedly shut down");
                 }
-                _ = persist_data(persistence.clone(), lazy_token_cache.clone(), lazy_feature_cache.clone()) => {
+                _ = supervise("persister", || persist_data(persistence.clone(), lazy_token_cache.clone(), lazy_feature_cache.clone(), refresher.tokens_to_refresh.clone(), refresher.refresh_shard)) => {
                     tracing::info!("Persister was unexpectedly shut down");
                 }
-                _ = validator.schedule_validation_of_known_tokens(edge.token_revalidation_interval_seconds) => {
+                _ = supervise("persistence_integrity_check", || check_persistence_integrity(persistence.clone(), lazy_token_cache.clone(), lazy_feature_cache.clone(), refresher.refresh_shard, edge.persistence_integrity_check_interval_seconds.map(Duration::from_secs), edge.persistence_integrity_repair)) => {
+                    tracing::info!("Persistence integrity check was unexpectedly shut down");
+                }
+                _ = supervise("token_validation", || validator.schedule_validation_of_known_tokens(edge.token_revalidation_interval_seconds)) => {
                     tracing::info!("Token validator validation of known tokens was unexpectedly shut down");
                 }
-                _ = validator.schedule_revalidation_of_startup_tokens(edge.tokens, lazy_feature_refresher) => {
+                _ = supervise("startup_token_revalidation", || validator.schedule_revalidation_of_startup_tokens(edge.tokens.clone(), lazy_feature_refresher.clone())) => {
                     tracing::info!("Token validator validation of startup tokens was unexpectedly shut down");
                 }
+                _ = supervise("idle_token_eviction", || refresher.schedule_eviction_of_idle_tokens(edge.tokens.clone())) => {
+                    tracing::info!("Eviction of idle tokens was unexpectedly shut down");
+                }
+                _ = supervise("shard_adoption", || refresher.schedule_adoption_of_shard_refresh_targets()) => {
+                    tracing::info!("Adoption of refresh targets from other shards was unexpectedly shut down");
+                }
             }
         }
-        cli::EdgeMode::Offline(offline_args) if offline_args.reload_interval > 0 => {
+        cli::EdgeMode::ReadReplica(read_replica_args) => {
             tokio::select! {
-                _ = offline_hotload::start_hotload_loop(lazy_feature_cache, lazy_engine_cache, offline_args) => {
+                _ = supervise("persistence_reload", || reload_from_persistence(persistence.clone().unwrap(), lazy_token_cache.clone(), lazy_feature_cache.clone(), lazy_engine_cache.clone(), Duration::from_secs(read_replica_args.persistence_reload_interval_seconds))) => {
+                    tracing::info!("Reloading from persistence unexpectedly shut down");
+                },
+                _ = server => {
+                    tracing::info!("Actix is shutting down. Read replicas don't persist anything");
+                },
+            }
+        }
+        cli::EdgeMode::Offline(offline_args) => {
+            tokio::select! {
+                _ = supervise("offline_hotload", || offline_hotload::start_hotload_loop(lazy_token_cache.clone(), lazy_feature_cache.clone(), lazy_engine_cache.clone(), offline_args.clone())) => {
                     tracing::info!("Hotloader unexpectedly shut down.");
                 },
-                _ = server.run() => {
+                _ = server => {
                     tracing::info!("Actix is shutting down. No pending tasks.");
                 },
             }
         }
         _ => tokio::select! {
-            _ = server.run() => {
+            _ = server => {
                 tracing::info!("Actix is shutting down. Persisting data");
-                clean_shutdown(persistence, lazy_feature_cache.clone(), lazy_token_cache.clone()).await;
+                clean_shutdown(persistence, lazy_feature_cache.clone(), lazy_token_cache.clone(), None, None, metrics_cache_clone.clone(), persistence_timeout).await;
                 tracing::info!("Actix was shutdown properly");
 
             }
         },
     };
+    #[cfg(unix)]
+    if let Some(socket) = &http_args.unix_socket {
+        unix_socket::remove_socket(socket);
+    }
+    // Flushing blocks, and the exporter's connection is driven by this runtime, so flush from a blocking thread
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
 
     Ok(())
 }
@@ -195,6 +457,10 @@ async fn clean_shutdown(
     persistence: Option<Arc<dyn EdgePersistence>>,
     feature_cache: Arc<DashMap<String, ClientFeatures>>,
     token_cache: Arc<DashMap<String, EdgeToken>>,
+    refresh_targets: Option<Arc<DashMap<String, TokenRefresh>>>,
+    refresh_shard: Option<RefreshShard>,
+    metrics_cache: Arc<MetricsCache>,
+    persistence_timeout: Duration,
 ) {
     let tokens: Vec<EdgeToken> = token_cache
         .iter()
@@ -202,17 +468,26 @@ async fn clean_shutdown(
         .map(|entry| entry.value().clone())
         .collect();
 
-    let features: Vec<(String, ClientFeatures)> = feature_cache
-        .iter()
-        .map(|entry| (entry.key().clone(), entry.value().clone()))
-        .collect();
+    let features = features_to_persist(&feature_cache, refresh_shard);
+
+    let refresh_targets: Vec<TokenRefresh> = refresh_targets
+        .map(|targets| targets.iter().map(|entry| entry.value().clone()).collect())
+        .unwrap_or_default();
 
     if let Some(persistence) = persistence {
-        let res = join_all(vec![
+        let saves = join_all(vec![
             persistence.save_tokens(tokens),
             persistence.save_features(features),
-        ])
-        .await;
+            persistence.save_refresh_targets(refresh_targets),
+            persistence.save_metrics(metrics_cache.snapshot()),
+        ]);
+        let Ok(res) = tokio::time::timeout(persistence_timeout, saves).await else {
+            tracing::error!(
+                "Persisting data did not finish within {} seconds, shutting down without it",
+                persistence_timeout.as_secs()
+            );
+            return;
+        };
         if res.iter().all(|save| save.is_ok()) {
             tracing::info!("Successfully persisted data");
         } else {
@@ -222,3 +497,58 @@ async fn clean_shutdown(
         }
     }
 }
+
+/// Re-reads the command line, environment and config file on SIGHUP and applies what can change at runtime, along
+/// with the TLS server certificate
+#[cfg(all(unix, not(tarpaulin_include)))]
+async fn reload_config_on_sighup(
+    running: Arc<RwLock<CliArgs>>,
+    refresher: Option<Arc<FeatureRefresher>>,
+    server_certificates: Option<Arc<tls::ServerCertificates>>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading configuration");
+        match CliArgs::try_parse_with_config_file(std::env::args_os()) {
+            Ok(reloaded) => config_reload::reload(
+                &mut running.write().unwrap(),
+                &reloaded,
+                refresher.as_deref(),
+            ),
+            Err(e) => {
+                tracing::error!("Could not reload configuration, keeping the current one: {e}")
+            }
+        }
+        if let Some(certificates) = &server_certificates {
+            match certificates.reload() {
+                Ok(()) => tracing::info!("Reloaded the TLS server certificate"),
+                Err(e) => tracing::error!(
+                    "Could not reload the TLS server certificate, keeping the current one: {e}"
+                ),
+            }
+        }
+    }
+}
+
+/// Edge handles signals itself instead of leaving it to actix, so we can report what was in flight before draining.
+/// SIGINT, SIGTERM and SIGQUIT all stop the server the same way, after which the mode's shutdown persists data
+#[cfg(not(tarpaulin_include))]
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+        let mut quit = signal(SignalKind::quit()).expect("Failed to listen for SIGQUIT");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+            _ = quit.recv() => "SIGQUIT",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
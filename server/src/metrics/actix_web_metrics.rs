@@ -4,7 +4,9 @@ use actix_web::dev::ServiceRequest;
 use actix_web::http::{header, Method, StatusCode, Version};
 use futures::{future, FutureExt};
 use futures_core::future::LocalBoxFuture;
-use opentelemetry::metrics::{Histogram, Meter, MeterProvider, MetricsError, Unit, UpDownCounter};
+use opentelemetry::metrics::{
+    Counter, Histogram, Meter, MeterProvider, MetricsError, Unit, UpDownCounter,
+};
 use opentelemetry::{global, Key, KeyValue, Value};
 use opentelemetry_semantic_conventions::trace::{
     CLIENT_ADDRESS, HTTP_REQUEST_METHOD, HTTP_RESPONSE_STATUS_CODE, HTTP_ROUTE,
@@ -20,6 +22,8 @@ const HTTP_SERVER_ACTIVE_REQUESTS: &str = "http.server.active_requests";
 const HTTP_SERVER_DURATION: &str = "http.server.duration";
 const HTTP_SERVER_REQUEST_SIZE: &str = "http.server.request.size";
 const HTTP_SERVER_RESPONSE_SIZE: &str = "http.server.response.size";
+const HTTP_SERVER_RESPONSES: &str = "http.server.responses";
+const HTTP_RESPONSE_STATUS_CLASS: &str = "http.response.status_class";
 
 #[inline]
 pub(super) fn http_method_str(method: &Method) -> Value {
@@ -58,6 +62,17 @@ pub(super) fn http_scheme(scheme: &str) -> Value {
     }
 }
 
+#[inline]
+pub(super) fn http_status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
 pub(crate) fn trace_attributes_from_request(
     req: &ServiceRequest,
     http_route: &str,
@@ -178,6 +193,7 @@ struct Metrics {
     http_server_duration: Histogram<f64>,
     http_server_request_size: Histogram<u64>,
     http_server_response_size: Histogram<u64>,
+    http_server_responses: Counter<u64>,
 }
 
 impl Metrics {
@@ -206,11 +222,17 @@ impl Metrics {
             .with_unit(Unit::new("By"))
             .init();
 
+        let http_server_responses = meter
+            .u64_counter(HTTP_SERVER_RESPONSES)
+            .with_description("HTTP responses per route and status class")
+            .init();
+
         Metrics {
             http_server_active_requests,
             http_server_duration,
             http_server_request_size,
             http_server_response_size,
+            http_server_responses,
         }
     }
 }
@@ -348,18 +370,30 @@ where
             .record(content_length, &attributes);
 
         let request_metrics = self.metrics.clone();
+        let http_route = http_target.into_owned();
         Box::pin(self.service.call(req).map(move |res| {
             request_metrics
                 .http_server_active_requests
                 .add(-1, &attributes);
 
-            let status_code = match &res {
+            let status = match &res {
                 Ok(res) => res.status(),
                 Err(e) => e.as_response_error().status_code(),
-            }
-            .as_u16() as i64;
+            };
+
+            // Only the route template and status class, so the number of series stays bounded
+            request_metrics.http_server_responses.add(
+                1,
+                &[
+                    KeyValue::new(HTTP_ROUTE, http_route),
+                    KeyValue::new(HTTP_RESPONSE_STATUS_CLASS, http_status_class(status)),
+                ],
+            );
 
-            attributes.push(KeyValue::new(HTTP_RESPONSE_STATUS_CODE, status_code));
+            attributes.push(KeyValue::new(
+                HTTP_RESPONSE_STATUS_CODE,
+                status.as_u16() as i64,
+            ));
 
             let response_size = res
                 .as_ref()
@@ -519,4 +553,44 @@ mod tests {
             "Metric value for status code 500 did not match expected"
         );
     }
+
+    #[tokio::test]
+    async fn counts_responses_per_route_and_status_class() {
+        let registry = Registry::new();
+        let (_, request_metrics) =
+            prom_metrics::test_instantiate_without_tracing_and_logging(Some(registry.clone()));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(request_metrics.clone())
+                .service(web::resource("/test_ok/{id}").to(test_ok_endpoint))
+                .service(web::resource("/test_client_error").to(test_client_error_endpoint)),
+        )
+        .await;
+        for uri in ["/test_ok/1", "/test_ok/2", "/test_client_error"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let metrics_output = String::from_utf8(buffer).unwrap();
+        let responses = |route: &str, status_class: &str| {
+            metrics_output
+                .lines()
+                .filter(|line| {
+                    line.starts_with("http_server_responses_total")
+                        && line.contains(&format!("http_route=\"{route}\""))
+                        && line.contains(&format!("http_response_status_class=\"{status_class}\""))
+                })
+                .flat_map(|line| line.split_whitespace().last())
+                .flat_map(|value| value.parse::<f64>().ok())
+                .next()
+        };
+
+        assert_eq!(responses("/test_ok/{id}", "2xx"), Some(2.0));
+        assert_eq!(responses("/test_client_error", "4xx"), Some(1.0));
+        assert_eq!(responses("/test_client_error", "2xx"), None);
+    }
 }
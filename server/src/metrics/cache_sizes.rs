@@ -0,0 +1,94 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge, Opts};
+use unleash_types::client_features::ClientFeatures;
+
+use crate::types::{EdgeToken, TokenRefresh};
+
+pub const CACHE_SIZE_SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+lazy_static! {
+    pub static ref TOKENS_TOTAL: IntGauge = register_int_gauge!(Opts::new(
+        "edge_tokens_total",
+        "Number of tokens Edge keeps track of, valid or not"
+    ))
+    .unwrap();
+    pub static ref FEATURE_ENVIRONMENTS_TOTAL: IntGauge = register_int_gauge!(Opts::new(
+        "edge_feature_environments_total",
+        "Number of environments Edge has cached features for"
+    ))
+    .unwrap();
+    pub static ref REFRESH_TARGETS_TOTAL: IntGauge = register_int_gauge!(Opts::new(
+        "edge_refresh_targets_total",
+        "Number of tokens Edge refreshes features from upstream with"
+    ))
+    .unwrap();
+}
+
+/// The caches Edge keeps in memory, to be measured for the gauges. Only edge mode refreshes, so only it has refresh targets
+#[derive(Clone)]
+pub struct CacheSizes {
+    pub token_cache: Arc<DashMap<String, EdgeToken>>,
+    pub features_cache: Arc<DashMap<String, ClientFeatures>>,
+    pub refresh_targets: Option<Arc<DashMap<String, TokenRefresh>>>,
+}
+
+impl CacheSizes {
+    pub fn sample(&self) {
+        TOKENS_TOTAL.set(self.token_cache.len() as i64);
+        FEATURE_ENVIRONMENTS_TOTAL.set(self.features_cache.len() as i64);
+        REFRESH_TARGETS_TOTAL.set(
+            self.refresh_targets
+                .as_ref()
+                .map(|targets| targets.len())
+                .unwrap_or_default() as i64,
+        );
+    }
+
+    /// Counting is cheap, but the caches change all the time, so sampling them on an interval is enough to follow their growth
+    pub async fn sample_periodically(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.sample();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    pub fn gauges_follow_the_cache_sizes() {
+        let token = EdgeToken::from_str("*:development.secret123").unwrap();
+        let sizes = CacheSizes {
+            token_cache: Arc::new(DashMap::default()),
+            features_cache: Arc::new(DashMap::default()),
+            refresh_targets: Some(Arc::new(DashMap::default())),
+        };
+        sizes.token_cache.insert(token.token.clone(), token.clone());
+        sizes
+            .token_cache
+            .insert("*:production.secret123".into(), token.clone());
+        sizes.features_cache.insert(
+            "development".into(),
+            ClientFeatures {
+                version: 2,
+                features: vec![],
+                segments: None,
+                query: None,
+            },
+        );
+        sizes.sample();
+
+        assert_eq!(TOKENS_TOTAL.get(), 2);
+        assert_eq!(FEATURE_ENVIRONMENTS_TOTAL.get(), 1);
+        assert_eq!(REFRESH_TARGETS_TOTAL.get(), 0);
+    }
+}
@@ -1,14 +1,19 @@
+use crate::cli::MetricsOverflowPolicy;
+use crate::error::EdgeError;
 use crate::types::{BatchMetricsRequestBody, EdgeToken};
+use actix_web::dev::Payload;
 use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
 use iter_tools::Itertools;
 use lazy_static::lazy_static;
-use prometheus::{register_histogram, Histogram};
+use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    hash::{Hash, Hasher},
+    collections::{BTreeMap, HashMap, HashSet},
+    future::{ready, Ready},
+    sync::Mutex,
 };
 use tracing::{debug, instrument};
 use unleash_types::client_metrics::{
@@ -17,6 +22,10 @@ use unleash_types::client_metrics::{
 
 pub const UPSTREAM_MAX_BODY_SIZE: usize = 100 * 1024;
 pub const BATCH_BODY_SIZE: usize = 95 * 1024;
+pub const CONNECT_VIA_HEADER: &str = "X-Unleash-Connect-Via";
+pub const DEFAULT_MAX_BUFFERED_METRICS: usize = 100_000;
+/// Upstream buckets metrics by the hour, so that's how long the windows metrics are added up in are unless configured
+pub const DEFAULT_METRICS_AGGREGATION_WINDOW_SECONDS: u64 = 3600;
 
 lazy_static! {
     pub static ref METRICS_SIZE_HISTOGRAM: Histogram = register_histogram!(
@@ -25,6 +34,65 @@ lazy_static! {
         vec![1000.0, 10000.0, 20000.0, 50000.0, 75000.0, 100000.0, 250000.0, 500000.0, 1000000.0]
     )
     .unwrap();
+    pub static ref METRICS_DROPPED_AT_CAPACITY: IntCounter = register_int_counter!(
+        "metrics_dropped_at_capacity",
+        "Number of metric entries dropped because Edge already buffered --max-buffered-metrics entries"
+    )
+    .unwrap();
+}
+
+/// The hops a request went through before it reached us, from the [`CONNECT_VIA_HEADER`]. The header holds comma
+/// separated `app_name;instance_id` pairs, starting with the hop closest to the SDK
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IncomingConnectVia(pub Vec<ConnectVia>);
+
+impl IncomingConnectVia {
+    pub fn parse(header: &str) -> Self {
+        Self(
+            header
+                .split(',')
+                .filter_map(|hop| {
+                    let (app_name, instance_id) = hop.trim().split_once(';')?;
+                    let (app_name, instance_id) = (app_name.trim(), instance_id.trim());
+                    (!app_name.is_empty() && !instance_id.is_empty()).then(|| ConnectVia {
+                        app_name: app_name.into(),
+                        instance_id: instance_id.into(),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Applications that already carry a chain keep it, as that's what the hops before us built. The header only
+    /// fills in for those that come without one
+    pub fn seed(&self, application: ClientApplication) -> ClientApplication {
+        let has_chain = application
+            .connect_via
+            .as_ref()
+            .is_some_and(|chain| !chain.is_empty());
+        if has_chain || self.0.is_empty() {
+            application
+        } else {
+            ClientApplication {
+                connect_via: Some(self.0.clone()),
+                ..application
+            }
+        }
+    }
+}
+
+impl FromRequest for IncomingConnectVia {
+    type Error = EdgeError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .headers()
+            .get(CONNECT_VIA_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .map(IncomingConnectVia::parse)
+            .unwrap_or_default()))
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -53,7 +121,21 @@ impl From<ClientMetricsEnv> for MetricsKey {
     }
 }
 
-#[derive(Debug, Clone, Eq, Deserialize, Serialize)]
+impl From<&ClientMetricsEnv> for MetricsKey {
+    fn from(value: &ClientMetricsEnv) -> Self {
+        Self {
+            app_name: value.app_name.clone(),
+            feature_name: value.feature_name.clone(),
+            timestamp: value.timestamp,
+            environment: value.environment.clone(),
+        }
+    }
+}
+
+/// Metrics are kept apart per app, so apps reporting the same toggle stay separate entries upstream. The upstream format has no
+/// instance on metrics, instances of an app are told apart by their application registrations instead. The cache keys
+/// metrics by the start of the aggregation window they fall in, see [`MetricsCache::with_aggregation_window`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct MetricsKey {
     pub app_name: String,
     pub feature_name: String,
@@ -61,31 +143,6 @@ pub struct MetricsKey {
     pub timestamp: DateTime<Utc>,
 }
 
-impl Hash for MetricsKey {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.app_name.hash(state);
-        self.feature_name.hash(state);
-        self.environment.hash(state);
-        to_time_key(&self.timestamp).hash(state);
-    }
-}
-
-fn to_time_key(timestamp: &DateTime<Utc>) -> String {
-    format!("{}", timestamp.format("%Y-%m-%d %H"))
-}
-
-impl PartialEq for MetricsKey {
-    fn eq(&self, other: &Self) -> bool {
-        let other_hour_bin = to_time_key(&other.timestamp);
-        let self_hour_bin = to_time_key(&self.timestamp);
-
-        self.app_name == other.app_name
-            && self.feature_name == other.feature_name
-            && self.environment == other.environment
-            && self_hour_bin == other_hour_bin
-    }
-}
-
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct MetricsBatch {
     pub applications: Vec<ClientApplication>,
@@ -96,12 +153,17 @@ pub struct MetricsBatch {
 pub struct MetricsCache {
     pub(crate) applications: DashMap<ApplicationKey, ClientApplication>,
     pub(crate) metrics: DashMap<MetricsKey, ClientMetricsEnv>,
+    disabled: bool,
+    max_entries: Option<usize>,
+    overflow_policy: MetricsOverflowPolicy,
+    /// The buffered metric keys by the window they're in, so the oldest can be dropped without going through every
+    /// entry. Only kept when buffering is capped
+    buffered_windows: Mutex<BTreeMap<DateTime<Utc>, HashSet<MetricsKey>>>,
+    aggregation_window_seconds: Option<u64>,
 }
 
 pub(crate) fn size_of_batch(batch: &MetricsBatch) -> usize {
-    serde_json::to_string(batch)
-        .map(|s| s.as_bytes().len())
-        .unwrap_or(0)
+    serde_json::to_string(batch).map(|s| s.len()).unwrap_or(0)
 }
 
 pub(crate) fn register_client_application(
@@ -118,29 +180,24 @@ pub(crate) fn register_client_application(
         environment: edge_token.environment,
         ..updated_with_connection_info
     };
-    metrics_cache.applications.insert(
-        ApplicationKey {
-            app_name: to_write.app_name.clone(),
-            instance_id: to_write
-                .instance_id
-                .clone()
-                .unwrap_or_else(|| ulid::Ulid::new().to_string()),
-        },
-        to_write,
-    );
+    metrics_cache.register_application(to_write);
 }
 
+/// Metrics are attributed to the environment of the token they were posted with, whether or not the SDK included one,
+/// so everything buffered carries the environment upstream expects
 pub(crate) fn register_client_metrics(
     edge_token: EdgeToken,
     metrics: ClientMetrics,
     metrics_cache: Data<MetricsCache>,
 ) {
+    let environment = edge_token
+        .environment
+        .or(metrics.environment)
+        .unwrap_or_else(|| "development".into());
     let metrics = unleash_types::client_metrics::from_bucket_app_name_and_env(
         metrics.bucket,
         metrics.app_name,
-        edge_token
-            .environment
-            .unwrap_or_else(|| "development".into()),
+        environment,
         metrics.metadata.clone(),
     );
 
@@ -148,7 +205,8 @@ pub(crate) fn register_client_metrics(
 }
 
 /***
-   Will filter out metrics that do not belong to the environment that edge_token has access to
+   Will filter out metrics that do not belong to the environment that edge_token has access to. Metrics posted without
+   an environment are attributed to the token's environment, like single client metrics are
 */
 pub(crate) fn register_bulk_metrics(
     metrics_cache: &MetricsCache,
@@ -156,19 +214,19 @@ pub(crate) fn register_bulk_metrics(
     edge_token: &EdgeToken,
     metrics: BatchMetricsRequestBody,
 ) {
+    let environment = edge_token.environment.as_ref();
     let updated: BatchMetricsRequestBody = BatchMetricsRequestBody {
-        applications: metrics.applications.clone(),
+        applications: metrics.applications,
         metrics: metrics
             .metrics
-            .iter()
-            .filter(|m| {
-                edge_token
-                    .environment
-                    .clone()
-                    .map(|e| e == m.environment)
-                    .unwrap_or(false)
+            .into_iter()
+            .filter_map(|mut m| {
+                let environment = environment?;
+                if m.environment.is_empty() {
+                    m.environment.clone_from(environment);
+                }
+                (&m.environment == environment).then_some(m)
             })
-            .cloned()
             .collect(),
     };
     metrics_cache.sink_bulk_metrics(updated, connect_via);
@@ -220,30 +278,45 @@ pub(crate) fn cut_into_sendable_batches(batch: MetricsBatch) -> Vec<MetricsBatch
 }
 
 impl MetricsCache {
+    /// Registrations go upstream with the environment they were made in, even before the application has posted any
+    /// metrics, so it shows up in Unleash as soon as it connects. Registrations without an environment, such as those
+    /// forwarded by an older Edge, go along with every environment
     pub fn get_metrics_by_environment(&self) -> HashMap<String, MetricsBatch> {
-        let mut batches_by_environment = HashMap::new();
-
-        let applications = self
+        let (applications, unscoped_applications): (Vec<_>, Vec<_>) = self
             .applications
             .iter()
             .map(|e| e.value().clone())
-            .collect::<Vec<ClientApplication>>();
-        let data = self
+            .partition(|application| application.environment.is_some());
+        let mut applications_by_environment: HashMap<String, Vec<ClientApplication>> = applications
+            .into_iter()
+            .into_group_map_by(|application| application.environment.clone().unwrap_or_default());
+        let mut metrics_by_environment: HashMap<String, Vec<ClientMetricsEnv>> = self
             .metrics
             .iter()
             .map(|e| e.value().clone())
-            .collect::<Vec<ClientMetricsEnv>>();
-        let map: HashMap<String, Vec<ClientMetricsEnv>> = data
-            .into_iter()
             .into_group_map_by(|metric| metric.environment.clone());
-        for (environment, metrics) in map {
-            let batch = MetricsBatch {
-                applications: applications.clone(),
-                metrics,
-            };
-            batches_by_environment.insert(environment, batch);
-        }
-        batches_by_environment
+        let environments = applications_by_environment
+            .keys()
+            .chain(metrics_by_environment.keys())
+            .cloned()
+            .unique()
+            .collect::<Vec<String>>();
+        environments
+            .into_iter()
+            .map(|environment| {
+                let mut applications = applications_by_environment
+                    .remove(&environment)
+                    .unwrap_or_default();
+                applications.extend(unscoped_applications.iter().cloned());
+                let batch = MetricsBatch {
+                    applications,
+                    metrics: metrics_by_environment
+                        .remove(&environment)
+                        .unwrap_or_default(),
+                };
+                (environment, batch)
+            })
+            .collect()
     }
 
     pub fn get_appropriately_sized_env_batches(&self, batch: &MetricsBatch) -> Vec<MetricsBatch> {
@@ -251,7 +324,9 @@ impl MetricsCache {
             self.applications.remove(&ApplicationKey::from(app.clone()));
         }
         for metric in batch.metrics.clone() {
-            self.metrics.remove(&MetricsKey::from(metric.clone()));
+            let key = MetricsKey::from(metric.clone());
+            self.untrack(&key);
+            self.metrics.remove(&key);
         }
         METRICS_SIZE_HISTOGRAM.observe(size_of_batch(batch) as f64);
         if sendable(batch) {
@@ -285,7 +360,9 @@ impl MetricsCache {
             self.applications.remove(&ApplicationKey::from(app.clone()));
         }
         for metric in batch.metrics.clone() {
-            self.metrics.remove(&MetricsKey::from(metric.clone()));
+            let key = MetricsKey::from(metric.clone());
+            self.untrack(&key);
+            self.metrics.remove(&key);
         }
         METRICS_SIZE_HISTOGRAM.observe(size_of_batch(&batch) as f64);
         if sendable(&batch) {
@@ -300,6 +377,23 @@ impl MetricsCache {
         }
     }
 
+    /// Everything we haven't posted upstream yet, without removing it from the cache
+    pub fn snapshot(&self) -> MetricsBatch {
+        MetricsBatch {
+            applications: self
+                .applications
+                .iter()
+                .map(|e| e.value().clone())
+                .collect(),
+            metrics: self
+                .metrics
+                .iter()
+                .map(|e| e.value().clone())
+                .filter(|m| m.yes > 0 || m.no > 0)
+                .collect(),
+        }
+    }
+
     pub fn reinsert_batch(&self, batch: MetricsBatch) {
         for application in batch.applications {
             self.register_application(application);
@@ -319,23 +413,168 @@ impl MetricsCache {
     pub fn reset_metrics(&self) {
         self.applications.clear();
         self.metrics.clear();
+        self.buffered_windows.lock().unwrap().clear();
+    }
+
+    /// A cache that drops every application and metric it's given, for deployments where usage data mustn't leave
+    /// the cluster. There's then never anything to post upstream
+    pub fn disabled() -> Self {
+        Self {
+            disabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Buffer at most `max_entries` metric entries, dropping the ones `overflow_policy` picks to stay under it. Metrics
+    /// for entries that are already buffered are still added to them, since that doesn't take up more room
+    pub fn with_max_entries(
+        self,
+        max_entries: usize,
+        overflow_policy: MetricsOverflowPolicy,
+    ) -> Self {
+        Self {
+            max_entries: Some(max_entries),
+            overflow_policy,
+            ..self
+        }
+    }
+
+    /// Add up metrics in windows of this many seconds, counted from the Unix epoch, instead of by the hour. Metrics are
+    /// posted with the start of their window as their timestamp, however often they're posted
+    pub fn with_aggregation_window(self, aggregation_window_seconds: u64) -> Self {
+        Self {
+            aggregation_window_seconds: Some(aggregation_window_seconds),
+            ..self
+        }
+    }
+
+    fn window_start(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let window = self
+            .aggregation_window_seconds
+            .unwrap_or(DEFAULT_METRICS_AGGREGATION_WINDOW_SECONDS)
+            .max(1) as i64;
+        let seconds = timestamp.timestamp();
+        DateTime::from_timestamp(seconds - seconds.rem_euclid(window), 0).unwrap_or(timestamp)
+    }
+
+    fn key_of(&self, metric: &ClientMetricsEnv) -> MetricsKey {
+        MetricsKey {
+            timestamp: self.window_start(metric.timestamp),
+            ..MetricsKey::from(metric)
+        }
+    }
+
+    /// Makes room for the metrics that aren't buffered yet, or leaves out the ones that don't fit, depending on the
+    /// overflow policy. Returns the metrics that should be sunk
+    fn fit_to_capacity<'a>(&self, metrics: &'a [ClientMetricsEnv]) -> Vec<&'a ClientMetricsEnv> {
+        let Some(max_entries) = self.max_entries else {
+            return metrics.iter().collect();
+        };
+        let mut new_keys = metrics
+            .iter()
+            .map(|metric| self.key_of(metric))
+            .filter(|key| !self.metrics.contains_key(key))
+            .unique()
+            .collect::<Vec<MetricsKey>>();
+        let overflow = (self.metrics.len() + new_keys.len()).saturating_sub(max_entries);
+        if overflow == 0 {
+            return metrics.iter().collect();
+        }
+        let mut evicted = 0;
+        let left_out: HashSet<MetricsKey> = match self.overflow_policy {
+            MetricsOverflowPolicy::DropOldest => {
+                // Keys posted while we were looking are gone from the cache already, so we go on until enough are dropped
+                while evicted < overflow {
+                    let oldest = self.take_oldest(overflow - evicted);
+                    if oldest.is_empty() {
+                        break;
+                    }
+                    for key in oldest {
+                        if self.metrics.remove(&key).is_some() {
+                            evicted += 1;
+                        }
+                    }
+                }
+                // More new metrics than there's room for at all, so the oldest of them go too
+                new_keys.sort_by_key(|key| key.timestamp);
+                new_keys.into_iter().take(overflow - evicted).collect()
+            }
+            MetricsOverflowPolicy::DropNewest => {
+                let kept = new_keys.len().saturating_sub(overflow);
+                new_keys.into_iter().skip(kept).collect()
+            }
+        };
+        METRICS_DROPPED_AT_CAPACITY.inc_by((evicted + left_out.len()) as u64);
+        metrics
+            .iter()
+            .filter(|metric| !left_out.contains(&self.key_of(metric)))
+            .collect()
+    }
+
+    fn track(&self, key: &MetricsKey) {
+        if self.max_entries.is_some() {
+            self.buffered_windows
+                .lock()
+                .unwrap()
+                .entry(key.timestamp)
+                .or_default()
+                .insert(key.clone());
+        }
+    }
+
+    fn untrack(&self, key: &MetricsKey) {
+        if self.max_entries.is_some() {
+            let mut windows = self.buffered_windows.lock().unwrap();
+            if let Some(window) = windows.get_mut(&key.timestamp) {
+                window.remove(key);
+                if window.is_empty() {
+                    windows.remove(&key.timestamp);
+                }
+            }
+        }
+    }
+
+    /// Takes up to `count` keys out of the earliest windows
+    fn take_oldest(&self, count: usize) -> Vec<MetricsKey> {
+        let mut windows = self.buffered_windows.lock().unwrap();
+        let mut oldest = Vec::with_capacity(count);
+        while oldest.len() < count {
+            let Some(mut window) = windows.first_entry() else {
+                break;
+            };
+            let wanted = count - oldest.len();
+            if window.get().len() <= wanted {
+                oldest.extend(window.remove());
+            } else {
+                let taken: Vec<MetricsKey> = window.get().iter().take(wanted).cloned().collect();
+                for key in &taken {
+                    window.get_mut().remove(key);
+                }
+                oldest.extend(taken);
+            }
+        }
+        oldest
     }
 
     pub fn register_application(&self, application: ClientApplication) {
+        if self.disabled {
+            return;
+        }
         self.applications
             .insert(ApplicationKey::from(application.clone()), application);
     }
 
     pub fn sink_metrics(&self, metrics: &[ClientMetricsEnv]) {
+        if self.disabled {
+            return;
+        }
         debug!("Sinking {} metrics", metrics.len());
-        for metric in metrics.iter() {
+        for metric in self.fit_to_capacity(metrics) {
+            let key = self.key_of(metric);
+            let window_start = key.timestamp;
+            self.track(&key);
             self.metrics
-                .entry(MetricsKey {
-                    app_name: metric.app_name.clone(),
-                    feature_name: metric.feature_name.clone(),
-                    timestamp: metric.timestamp,
-                    environment: metric.environment.clone(),
-                })
+                .entry(key)
                 .and_modify(|feature_stats| {
                     feature_stats.yes += metric.yes;
                     feature_stats.no += metric.no;
@@ -349,7 +588,10 @@ impl MetricsCache {
                             .or_insert(*added_count);
                     });
                 })
-                .or_insert_with(|| metric.clone());
+                .or_insert_with(|| ClientMetricsEnv {
+                    timestamp: window_start,
+                    ..metric.clone()
+                });
         }
     }
 }
@@ -573,6 +815,253 @@ mod test {
         assert!(cache.metrics.is_empty());
     }
 
+    fn metrics_from(app_name: &str, instance_id: &str) -> ClientMetrics {
+        serde_json::from_value(serde_json::json!({
+            "appName": app_name,
+            "instanceId": instance_id,
+            "bucket": {
+                "start": "2024-07-01T12:00:00Z",
+                "stop": "2024-07-01T12:01:00Z",
+                "toggles": { "shared-toggle": { "yes": 1, "no": 2, "variants": {} } }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn application(app_name: &str, instance_id: Option<&str>) -> ClientApplication {
+        ClientApplication {
+            app_name: app_name.into(),
+            connect_via: None,
+            environment: None,
+            instance_id: instance_id.map(String::from),
+            interval: 15,
+            started: Default::default(),
+            strategies: vec![],
+            metadata: MetricsMetadata {
+                platform_name: None,
+                platform_version: None,
+                sdk_version: None,
+                yggdrasil_version: None,
+            },
+        }
+    }
+
+    fn metric_in_hour(feature_name: &str, hours_ago: i64) -> ClientMetricsEnv {
+        ClientMetricsEnv {
+            feature_name: feature_name.into(),
+            app_name: "some-app".into(),
+            environment: "development".into(),
+            timestamp: Utc::now() - chrono::Duration::hours(hours_ago),
+            yes: 1,
+            no: 0,
+            variants: Default::default(),
+            metadata: application("some-app", None).metadata,
+        }
+    }
+
+    fn buffered_features(cache: &MetricsCache) -> Vec<String> {
+        cache
+            .metrics
+            .iter()
+            .map(|entry| entry.value().feature_name.clone())
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn metrics_are_added_up_in_their_aggregation_window_and_posted_with_its_start() {
+        let at = |time: &str| {
+            DateTime::parse_from_rfc3339(&format!("2024-03-12T{time}Z"))
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let metric_at = |time: &str| ClientMetricsEnv {
+            timestamp: at(time),
+            ..metric_in_hour("some-feature", 0)
+        };
+        let cache = MetricsCache::default().with_aggregation_window(60);
+        cache.sink_metrics(&[metric_at("12:00:10"), metric_at("12:00:50")]);
+        cache.sink_metrics(&[metric_at("12:01:05")]);
+
+        let posted: Vec<(DateTime<Utc>, u32)> = cache
+            .get_appropriately_sized_batches()
+            .into_iter()
+            .flat_map(|batch| batch.metrics)
+            .map(|metric| (metric.timestamp, metric.yes))
+            .sorted()
+            .collect();
+        assert_eq!(posted, vec![(at("12:00:00"), 2), (at("12:01:00"), 1)]);
+        assert!(cache.metrics.is_empty());
+
+        let hourly = MetricsCache::default();
+        hourly.sink_metrics(&[metric_at("12:00:10"), metric_at("12:59:59")]);
+        hourly.sink_metrics(&[metric_at("13:00:00")]);
+        assert_eq!(hourly.metrics.len(), 2);
+    }
+
+    #[test]
+    fn a_full_cache_drops_the_oldest_metrics_to_make_room() {
+        let cache = MetricsCache::default().with_max_entries(2, MetricsOverflowPolicy::DropOldest);
+        let dropped_before = METRICS_DROPPED_AT_CAPACITY.get();
+        cache.sink_metrics(&[metric_in_hour("oldest", 3), metric_in_hour("older", 2)]);
+        cache.sink_metrics(&[metric_in_hour("newest", 0)]);
+        cache.sink_metrics(&[metric_in_hour("older", 2)]);
+
+        assert_eq!(buffered_features(&cache), vec!["newest", "older"]);
+        assert_eq!(
+            cache
+                .metrics
+                .iter()
+                .find(|entry| entry.value().feature_name == "older")
+                .unwrap()
+                .yes,
+            2
+        );
+        assert!(METRICS_DROPPED_AT_CAPACITY.get() > dropped_before);
+    }
+
+    #[test]
+    fn posted_metrics_are_not_counted_towards_the_cap() {
+        let cache = MetricsCache::default().with_max_entries(2, MetricsOverflowPolicy::DropOldest);
+        cache.sink_metrics(&[metric_in_hour("oldest", 3), metric_in_hour("older", 2)]);
+        cache.get_appropriately_sized_batches();
+        cache.sink_metrics(&[metric_in_hour("old", 1), metric_in_hour("newest", 0)]);
+        cache.sink_metrics(&[metric_in_hour("newest", 0)]);
+
+        assert_eq!(buffered_features(&cache), vec!["newest", "old"]);
+        let windows = cache.buffered_windows.lock().unwrap();
+        assert_eq!(windows.values().map(HashSet::len).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn a_full_cache_can_drop_new_metrics_instead() {
+        let cache = MetricsCache::default().with_max_entries(2, MetricsOverflowPolicy::DropNewest);
+        cache.sink_metrics(&[
+            metric_in_hour("oldest", 3),
+            metric_in_hour("older", 2),
+            metric_in_hour("old", 1),
+        ]);
+        cache.sink_metrics(&[metric_in_hour("newest", 0), metric_in_hour("oldest", 3)]);
+
+        assert_eq!(buffered_features(&cache), vec!["older", "oldest"]);
+    }
+
+    #[test]
+    fn registrations_are_posted_with_their_environment_before_any_metrics() {
+        let cache = MetricsCache::default();
+        cache.register_application(ClientApplication {
+            environment: Some("production".into()),
+            ..application("registered-only", Some("instance-1"))
+        });
+        cache.register_application(ClientApplication {
+            environment: Some("development".into()),
+            ..application("with-metrics", Some("instance-2"))
+        });
+        cache.register_application(application("forwarded", Some("instance-3")));
+        cache.sink_metrics(&[ClientMetricsEnv {
+            feature_name: "some-feature".into(),
+            app_name: "with-metrics".into(),
+            environment: "development".into(),
+            timestamp: Utc::now(),
+            yes: 1,
+            no: 0,
+            variants: Default::default(),
+            metadata: application("with-metrics", None).metadata,
+        }]);
+
+        let batches = cache.get_metrics_by_environment();
+        let app_names = |environment: &str| {
+            batches[environment]
+                .applications
+                .iter()
+                .map(|app| app.app_name.clone())
+                .sorted()
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            app_names("production"),
+            vec!["forwarded", "registered-only"]
+        );
+        assert!(batches["production"].metrics.is_empty());
+        assert_eq!(app_names("development"), vec!["forwarded", "with-metrics"]);
+        assert_eq!(batches["development"].metrics.len(), 1);
+    }
+
+    #[test]
+    fn apps_reporting_the_same_toggle_are_posted_as_separate_entries() {
+        let cache = Data::new(MetricsCache::default());
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        let edge = ConnectVia {
+            app_name: "edge".into(),
+            instance_id: "edge-1".into(),
+        };
+        for (app_name, instance_id) in [
+            ("checkout", "checkout-1"),
+            ("checkout", "checkout-2"),
+            ("search", "search-1"),
+        ] {
+            register_client_metrics(
+                token.clone(),
+                metrics_from(app_name, instance_id),
+                cache.clone(),
+            );
+            register_client_application(
+                token.clone(),
+                &edge,
+                application(app_name, Some(instance_id)),
+                cache.clone(),
+            );
+        }
+
+        let batch = cache.get_appropriately_sized_batches().pop().unwrap();
+        let mut metrics: Vec<(String, u32, u32)> = batch
+            .metrics
+            .iter()
+            .map(|metric| (metric.app_name.clone(), metric.yes, metric.no))
+            .collect();
+        metrics.sort();
+        assert_eq!(
+            metrics,
+            vec![("checkout".into(), 2, 4), ("search".into(), 1, 2)]
+        );
+        let mut instances: Vec<(String, Option<String>)> = batch
+            .applications
+            .iter()
+            .map(|app| (app.app_name.clone(), app.instance_id.clone()))
+            .collect();
+        instances.sort();
+        assert_eq!(
+            instances,
+            vec![
+                ("checkout".into(), Some("checkout-1".into())),
+                ("checkout".into(), Some("checkout-2".into())),
+                ("search".into(), Some("search-1".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn applications_without_an_instance_id_are_cleared_once_posted() {
+        let cache = Data::new(MetricsCache::default());
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        let edge = ConnectVia {
+            app_name: "edge".into(),
+            instance_id: "edge-1".into(),
+        };
+        for _ in 0..3 {
+            register_client_application(
+                token.clone(),
+                &edge,
+                application("no-instance", None),
+                cache.clone(),
+            );
+        }
+        assert_eq!(cache.applications.len(), 1);
+        cache.get_appropriately_sized_batches();
+        assert!(cache.applications.is_empty());
+    }
+
     #[test]
     fn adding_another_connection_link_works() {
         let client_application = ClientApplication {
@@ -722,6 +1211,29 @@ mod test {
         assert!(metrics_batch.first().unwrap().metrics.is_empty());
     }
 
+    #[test]
+    pub fn the_connect_via_header_only_fills_in_for_applications_without_a_chain() {
+        let incoming =
+            IncomingConnectVia::parse(" edge-a;instance-a, malformed, ;empty , edge-b;instance-b");
+        let edge_a = ConnectVia {
+            app_name: "edge-a".into(),
+            instance_id: "instance-a".into(),
+        };
+        let edge_b = ConnectVia {
+            app_name: "edge-b".into(),
+            instance_id: "instance-b".into(),
+        };
+        assert_eq!(incoming, IncomingConnectVia(vec![edge_a.clone(), edge_b]));
+
+        let without_chain = ClientApplication::new("app", 15);
+        assert_eq!(
+            incoming.seed(without_chain).connect_via,
+            incoming.0.clone().into()
+        );
+        let with_chain = ClientApplication::new("app", 15).connect_via("edge-a", "instance-a");
+        assert_eq!(incoming.seed(with_chain).connect_via, Some(vec![edge_a]));
+    }
+
     #[test]
     pub fn register_bulk_metrics_filters_metrics_based_on_environment_in_token() {
         let metrics_cache = MetricsCache::default();
@@ -778,6 +1290,50 @@ mod test {
         assert_eq!(metrics_cache.metrics.len(), 1);
     }
 
+    #[test]
+    pub fn bulk_metrics_without_an_environment_are_attributed_to_the_token_environment() {
+        let metrics_cache = MetricsCache::default();
+        let connect_via = ConnectViaBuilder::default()
+            .app_name("edge_bulk_metrics".into())
+            .instance_id("sometest".into())
+            .build()
+            .unwrap();
+        let mut edge_token_with_development =
+            EdgeToken::from_str("*:development.randomstring").unwrap();
+        edge_token_with_development.status = TokenValidationStatus::Validated;
+        edge_token_with_development.token_type = Some(TokenType::Client);
+        let metrics = BatchMetricsRequestBody {
+            applications: vec![],
+            metrics: vec![ClientMetricsEnv {
+                feature_name: "feature_one".into(),
+                app_name: "my_app".into(),
+                environment: "".into(),
+                timestamp: Utc::now(),
+                yes: 50,
+                no: 10,
+                variants: Default::default(),
+                metadata: MetricsMetadata {
+                    platform_name: None,
+                    platform_version: None,
+                    sdk_version: None,
+                    yggdrasil_version: None,
+                },
+            }],
+        };
+        register_bulk_metrics(
+            &metrics_cache,
+            &connect_via,
+            &edge_token_with_development,
+            metrics,
+        );
+        let buffered = metrics_cache.get_metrics_by_environment();
+        assert_eq!(buffered["development"].metrics.len(), 1);
+        assert_eq!(
+            buffered["development"].metrics[0].environment,
+            "development"
+        );
+    }
+
     #[test]
     pub fn metrics_will_be_gathered_per_environment() {
         let metrics = vec![
@@ -5,6 +5,7 @@ use tracing::trace;
 #[cfg(not(tarpaulin_include))]
 pub mod actix_web_metrics;
 
+pub mod cache_sizes;
 pub mod client_metrics;
 pub mod route_formatter;
 
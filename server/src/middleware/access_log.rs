@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::web::Data;
+use actix_web::HttpMessage;
+use tracing::{info, warn};
+
+use crate::middleware::request_id::RequestId;
+use crate::types::EdgeToken;
+
+/// Structured counterpart to actix' Logger, used when logging as JSON so every field ends up as its own key
+pub async fn access_log(
+    token: Option<EdgeToken>,
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let peer = req.connection_info().peer_addr().map(str::to_string);
+    let environment = token.and_then(|t| t.environment);
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let res = srv.call(req).await?;
+    info!(
+        method,
+        path,
+        request_id,
+        status = res.status().as_u16(),
+        environment,
+        peer,
+        duration_ms = started.elapsed().as_millis() as u64,
+        "request handled"
+    );
+    Ok(res)
+}
+
+/// Requests taking longer than this get logged at warn level by [`log_slow_requests`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlowRequestThreshold(pub Duration);
+
+/// Warns about requests that took longer than the [`SlowRequestThreshold`] in app data, whatever the access log level,
+/// so the odd pathological request stands out without logging every request
+pub async fn log_slow_requests(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(threshold) = req
+        .app_data::<Data<SlowRequestThreshold>>()
+        .map(|threshold| threshold.0)
+    else {
+        return srv.call(req).await;
+    };
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+    let res = srv.call(req).await;
+    let duration = started.elapsed();
+    if duration > threshold {
+        let status = match &res {
+            Ok(res) => res.status(),
+            Err(error) => error.as_response_error().status_code(),
+        };
+        warn!(
+            method,
+            path,
+            request_id,
+            status = status.as_u16(),
+            duration_ms = duration.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "slow request"
+        );
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+    use crate::middleware::request_id::request_id;
+
+    #[actix_web::test]
+    #[traced_test]
+    async fn access_log_records_request_fields() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(access_log))
+                .wrap(as_async_middleware(request_id))
+                .route("/api/client/features", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/api/client/features")
+            .insert_header((
+                "Authorization",
+                "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+            ))
+            .insert_header(("X-Request-Id", "support-case-1234"))
+            .to_request();
+        test::call_service(&app, req).await;
+        assert!(logs_contain("method=\"GET\""));
+        assert!(logs_contain("path=\"/api/client/features\""));
+        assert!(logs_contain("status=200"));
+        assert!(logs_contain("environment=\"development\""));
+        assert!(logs_contain("request_id=\"support-case-1234\""));
+    }
+
+    #[actix_web::test]
+    #[traced_test]
+    async fn only_requests_over_the_threshold_are_logged_as_slow() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(SlowRequestThreshold(Duration::from_millis(50))))
+                .wrap(as_async_middleware(log_slow_requests))
+                .route("/fast", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        HttpResponse::ServiceUnavailable().finish()
+                    }),
+                ),
+        )
+        .await;
+        test::call_service(&app, test::TestRequest::get().uri("/fast").to_request()).await;
+        assert!(!logs_contain("slow request"));
+        test::call_service(&app, test::TestRequest::get().uri("/slow").to_request()).await;
+        assert!(logs_contain("slow request"));
+        assert!(logs_contain("path=\"/slow\""));
+        assert!(logs_contain("status=503"));
+        assert!(!logs_contain("path=\"/fast\""));
+    }
+}
@@ -0,0 +1,242 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    web::Data,
+    HttpResponse,
+};
+use tracing::debug;
+
+use crate::auth::keys_match;
+use crate::cli::InternalBackstageArgs;
+use crate::middleware::enrich_with_client_ip::client_ip;
+
+/// Refuses requests from outside `--backstage-allow-cidr`. Requests where we can't tell the client ip, like over a unix socket, are refused when an allowlist is set
+pub async fn restrict_backstage_access(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let allowed = match req.app_data::<Data<InternalBackstageArgs>>() {
+        Some(args) if !args.backstage_allow_cidr.is_empty() => client_ip(&req).is_some_and(|ip| {
+            args.backstage_allow_cidr
+                .iter()
+                .any(|allowed| allowed.contains(&ip))
+        }),
+        _ => true,
+    };
+    if allowed {
+        Ok(srv.call(req).await?.map_into_left_body())
+    } else {
+        debug!(
+            "Refused access to {} from outside the allowlist",
+            req.path()
+        );
+        Ok(req
+            .into_response(HttpResponse::Forbidden().finish())
+            .map_into_right_body())
+    }
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    let (scheme, token) = req
+        .headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+/// Refuses to serve metrics to scrapers that don't present `--metrics-auth-token` as a bearer token, when one is set
+pub async fn require_metrics_auth_token(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authorized = match req
+        .app_data::<Data<InternalBackstageArgs>>()
+        .and_then(|args| args.metrics_auth_token.as_deref())
+    {
+        Some(expected) => bearer_token(&req)
+            .is_some_and(|token| keys_match(token.as_bytes(), expected.as_bytes())),
+        None => true,
+    };
+    if authorized {
+        Ok(srv.call(req).await?.map_into_left_body())
+    } else {
+        debug!("Refused to serve metrics without the metrics auth token");
+        Ok(req
+            .into_response(
+                HttpResponse::Unauthorized()
+                    .insert_header((WWW_AUTHENTICATE, "Bearer"))
+                    .finish(),
+            )
+            .map_into_right_body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App};
+
+    use super::*;
+    use crate::cli::{ip_or_cidr, TrustProxy};
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    fn allowlist(cidrs: &[&str]) -> InternalBackstageArgs {
+        InternalBackstageArgs {
+            backstage_allow_cidr: cidrs.iter().map(|cidr| ip_or_cidr(cidr).unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn from_peer(peer: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/internal-backstage/health")
+            .peer_addr(SocketAddr::new(IpAddr::from_str(peer).unwrap(), 1337))
+    }
+
+    macro_rules! backstage {
+        ($args:expr, $trust_proxy:expr) => {
+            init_service(
+                App::new()
+                    .app_data(Data::new($args))
+                    .app_data(Data::new($trust_proxy))
+                    .service(
+                        web::scope("/internal-backstage")
+                            .wrap(as_async_middleware(restrict_backstage_access))
+                            .route("/health", web::get().to(HttpResponse::Ok)),
+                    ),
+            )
+            .await
+        };
+    }
+
+    fn no_trust() -> TrustProxy {
+        TrustProxy {
+            trust_proxy: false,
+            proxy_trusted_servers: vec![],
+        }
+    }
+
+    #[actix_web::test]
+    async fn allows_everyone_without_an_allowlist() {
+        let app = backstage!(InternalBackstageArgs::default(), no_trust());
+        let res = call_service(&app, from_peer("203.0.113.7").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn only_allows_peers_inside_the_allowlist() {
+        let app = backstage!(
+            allowlist(&["10.0.0.0/8", "2001:db8::/32", "192.168.1.5"]),
+            no_trust()
+        );
+        for (peer, expected) in [
+            ("10.1.2.3", StatusCode::OK),
+            ("192.168.1.5", StatusCode::OK),
+            ("2001:db8::1", StatusCode::OK),
+            ("::ffff:10.1.2.3", StatusCode::OK),
+            ("192.168.1.6", StatusCode::FORBIDDEN),
+            ("203.0.113.7", StatusCode::FORBIDDEN),
+        ] {
+            let res = call_service(&app, from_peer(peer).to_request()).await;
+            assert_eq!(res.status(), expected, "{peer}");
+        }
+        let without_peer = TestRequest::get()
+            .uri("/internal-backstage/health")
+            .to_request();
+        assert_eq!(
+            call_service(&app, without_peer).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn forwarded_for_is_only_used_from_trusted_proxies() {
+        let app = backstage!(
+            allowlist(&["10.0.0.0/8"]),
+            TrustProxy {
+                trust_proxy: true,
+                proxy_trusted_servers: vec![ip_or_cidr("172.16.0.1").unwrap()],
+            }
+        );
+        let via_trusted_proxy = from_peer("172.16.0.1")
+            .insert_header(("X-Forwarded-For", "10.1.2.3"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, via_trusted_proxy).await.status(),
+            StatusCode::OK
+        );
+        let spoofed = from_peer("203.0.113.7")
+            .insert_header(("X-Forwarded-For", "10.1.2.3"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, spoofed).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let app = backstage!(allowlist(&["10.0.0.0/8"]), no_trust());
+        let untrusted = from_peer("172.16.0.1")
+            .insert_header(("X-Forwarded-For", "10.1.2.3"))
+            .to_request();
+        assert_eq!(
+            call_service(&app, untrusted).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn metrics_require_the_auth_token_as_a_bearer_token_when_configured() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(InternalBackstageArgs {
+                    metrics_auth_token: Some("scrape-secret".into()),
+                    ..Default::default()
+                }))
+                .service(
+                    web::resource("/internal-backstage/metrics")
+                        .wrap(as_async_middleware(require_metrics_auth_token))
+                        .route(web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+        for (authorization, expected) in [
+            (Some("Bearer scrape-secret"), StatusCode::OK),
+            (Some("bearer scrape-secret"), StatusCode::OK),
+            (Some("Bearer wrong-secret"), StatusCode::UNAUTHORIZED),
+            (Some("scrape-secret"), StatusCode::UNAUTHORIZED),
+            (None, StatusCode::UNAUTHORIZED),
+        ] {
+            let mut req = TestRequest::get().uri("/internal-backstage/metrics");
+            if let Some(authorization) = authorization {
+                req = req.insert_header((AUTHORIZATION, authorization));
+            }
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), expected, "{authorization:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_open_without_an_auth_token() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(InternalBackstageArgs::default()))
+                .service(
+                    web::resource("/internal-backstage/metrics")
+                        .wrap(as_async_middleware(require_metrics_auth_token))
+                        .route(web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+        let req = TestRequest::get().uri("/internal-backstage/metrics");
+        let res = call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,106 @@
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, CacheControl, CacheDirective, TryIntoHeaderPair};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Data;
+
+use crate::http::feature_refresher::FeatureRefresher;
+
+/// Lets SDKs and browsers reuse features for as long as Edge goes between refreshing them from upstream, so they don't
+/// poll faster than the features can change. Features depend on the token, so responses are only cached by the client itself.
+/// A 304 from the Etag middleware loses the header, but clients keep the max-age of the response they already have
+pub async fn cache_for_refresh_interval(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let max_age = req
+        .app_data::<Data<FeatureRefresher>>()
+        .filter(|_| req.method() == Method::GET)
+        .map(|refresher| refresher.refresh_interval.get().num_seconds().max(0) as u32);
+    let mut res = srv.call(req).await?;
+    let streaming = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "text/event-stream");
+    if let Some(max_age) = max_age {
+        if res.status() == StatusCode::OK
+            && !streaming
+            && !res.headers().contains_key(header::CACHE_CONTROL)
+        {
+            if let Ok((name, value)) = CacheControl(vec![
+                CacheDirective::Private,
+                CacheDirective::MaxAge(max_age),
+            ])
+            .try_into_pair()
+            {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::http::unleash_client::UnleashClient;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[actix_web::test]
+    async fn features_are_cached_for_the_refresh_interval() {
+        let refresher = FeatureRefresher::with_client(Arc::new(
+            UnleashClient::new("http://localhost:4242", None).unwrap(),
+        ));
+        refresher
+            .refresh_interval
+            .set(chrono::Duration::seconds(15));
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(refresher))
+                .wrap(as_async_middleware(cache_for_refresh_interval))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() }))
+                .route(
+                    "/",
+                    web::post().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route(
+                    "/stream",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .content_type("text/event-stream")
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "private, max-age=15"
+        );
+        for req in [
+            test::TestRequest::post().to_request(),
+            test::TestRequest::get().uri("/stream").to_request(),
+        ] {
+            let res = test::call_service(&app, req).await;
+            assert!(!res.headers().contains_key(header::CACHE_CONTROL));
+        }
+    }
+
+    #[actix_web::test]
+    async fn nothing_is_cached_without_a_feature_refresher() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(cache_for_refresh_interval))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert!(!res.headers().contains_key(header::CACHE_CONTROL));
+    }
+}
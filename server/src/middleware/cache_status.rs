@@ -0,0 +1,71 @@
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+
+use crate::http::feature_refresher::CacheStatus;
+
+pub const EDGE_CACHE_HEADER: HeaderName = HeaderName::from_static("x-edge-cache");
+
+/// Tells clients whether their features came from a fresh cache (`HIT`), one that's fallen behind upstream (`STALE`),
+/// or had to be fetched while they waited (`MISS`). Handlers that serve features leave the status in the request extensions
+pub async fn add_cache_status_header(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = srv.call(req).await?;
+    let cache_status = res.request().extensions().get::<CacheStatus>().copied();
+    if let Some(cache_status) = cache_status {
+        res.headers_mut().insert(
+            EDGE_CACHE_HEADER,
+            HeaderValue::from_static(cache_status.as_str()),
+        );
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[actix_web::test]
+    async fn responses_say_how_their_features_came_out_of_the_cache() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(add_cache_status_header))
+                .route(
+                    "/api/client/features",
+                    web::get().to(|req: HttpRequest| async move {
+                        req.extensions_mut().insert(CacheStatus::Stale);
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route(
+                    "/api/client/metrics",
+                    web::post().to(HttpResponse::Accepted),
+                ),
+        )
+        .await;
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/client/features")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.headers().get(EDGE_CACHE_HEADER).unwrap(), "STALE");
+
+        let res = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/api/client/metrics")
+                .to_request(),
+        )
+        .await;
+        assert!(res.headers().get(EDGE_CACHE_HEADER).is_none());
+    }
+}
@@ -66,7 +66,7 @@ mod tests {
 
     use crate::auth::token_validator::TokenValidator;
     use crate::http::feature_refresher::FeatureRefresher;
-    use crate::http::unleash_client::{new_reqwest_client, UnleashClient};
+    use crate::http::unleash_client::{new_reqwest_client, UnleashClient, UpstreamConnectionPool};
     use crate::tests::upstream_server;
     use crate::types::{EdgeToken, TokenType, TokenValidationStatus};
 
@@ -76,16 +76,15 @@ mod tests {
         local_features_cache: Arc<DashMap<String, ClientFeatures>>,
         local_engine_cache: Arc<DashMap<String, EngineState>>,
     ) -> TestServer {
-        let token_validator = Arc::new(TokenValidator {
-            unleash_client: unleash_client.clone(),
-            token_cache: local_token_cache.clone(),
-            persistence: None,
-        });
+        let token_validator = Arc::new(TokenValidator::new(
+            unleash_client.clone(),
+            local_token_cache.clone(),
+        ));
         let feature_refresher = Arc::new(FeatureRefresher {
             unleash_client: unleash_client.clone(),
             features_cache: local_features_cache.clone(),
             engine_cache: local_engine_cache.clone(),
-            refresh_interval: Duration::seconds(5),
+            refresh_interval: Duration::seconds(5).into(),
             ..Default::default()
         });
         test_server(move || {
@@ -102,7 +101,12 @@ mod tests {
                     .app_data(web::Data::from(feature_refresher.clone()))
                     .service(
                         web::scope("/api")
-                            .configure(crate::client_api::configure_client_api)
+                            .configure(|cfg| {
+                                crate::client_api::configure_client_api(
+                                    cfg,
+                                    crate::client_api::DEFAULT_MAX_METRICS_BODY_BYTES,
+                                )
+                            })
                             .configure(|cfg| {
                                 crate::frontend_api::configure_frontend_api(cfg, false)
                             }),
@@ -141,6 +145,8 @@ mod tests {
             Duration::seconds(5),
             Duration::seconds(5),
             "test-client".into(),
+            true,
+            UpstreamConnectionPool::default(),
         )
         .expect("Failed to create client");
 
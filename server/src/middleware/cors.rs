@@ -0,0 +1,107 @@
+use actix_cors::Cors;
+
+use crate::cli::CorsOptions;
+
+/// Builds the CORS middleware from the configured allowlists. Anything not configured falls back to being permissive
+pub fn cors_middleware(cors_options: &CorsOptions) -> Cors {
+    let mut cors = Cors::default().allow_any_header();
+    cors = if cors_options.cors_origins.is_empty() {
+        cors.allow_any_origin().send_wildcard()
+    } else {
+        cors_options
+            .cors_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin))
+    };
+    cors = if cors_options.cors_methods.is_empty() {
+        cors.allow_any_method()
+    } else {
+        cors.allowed_methods(cors_options.cors_methods.clone())
+    };
+    cors.max_age(cors_options.cors_max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    };
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, web, App, HttpResponse};
+
+    use crate::cli::CorsOptions;
+
+    use super::cors_middleware;
+
+    fn cors_options(origins: Vec<&str>, methods: Vec<Method>) -> CorsOptions {
+        CorsOptions {
+            cors_origins: origins.into_iter().map(String::from).collect(),
+            cors_methods: methods,
+            cors_max_age: Some(600),
+        }
+    }
+
+    #[actix_web::test]
+    async fn allows_any_origin_when_no_origins_are_configured() {
+        let app = test::init_service(
+            App::new()
+                .wrap(cors_middleware(&cors_options(vec![], vec![])))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/")
+            .insert_header((ORIGIN, "https://anything.example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "*"
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_allows_configured_origins_and_methods() {
+        let app = test::init_service(
+            App::new()
+                .wrap(cors_middleware(&cors_options(
+                    vec!["https://app.example.com", "https://other.example.com"],
+                    vec![Method::GET],
+                )))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let allowed = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .insert_header((ORIGIN, "https://other.example.com"))
+            .insert_header((ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+            .to_request();
+        let resp = test::call_service(&app, allowed).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://other.example.com"
+        );
+        assert_eq!(resp.headers().get(ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        let wrong_method = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .insert_header((ORIGIN, "https://app.example.com"))
+            .insert_header((ACCESS_CONTROL_REQUEST_METHOD, "DELETE"))
+            .to_request();
+        let resp = test::call_service(&app, wrong_method).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let wrong_origin = test::TestRequest::get()
+            .uri("/")
+            .insert_header((ORIGIN, "https://app.example.com.evil.com"))
+            .to_request();
+        let resp = test::try_call_service(&app, wrong_origin).await;
+        assert!(resp
+            .map(|r| r.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none())
+            .unwrap_or(true));
+    }
+}
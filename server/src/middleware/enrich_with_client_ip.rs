@@ -3,36 +3,80 @@ use crate::types::ClientIp;
 use actix_http::body::MessageBody;
 use actix_http::HttpMessage;
 use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::X_FORWARDED_FOR;
 use actix_web::web::Data;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use tracing::{debug, trace};
 
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    IpAddr::from_str(hop)
+        .or_else(|_| SocketAddr::from_str(hop).map(|addr| addr.ip()))
+        .ok()
+}
+
+/// The ip of the client making the request. That's the peer address, unless trust proxy is enabled and the peer is one of
+/// `--proxy-trusted-servers` (or any peer if none are set). Then it's the rightmost X-Forwarded-For hop that isn't a trusted
+/// server itself, since everything left of it may have been made up by the client. Without X-Forwarded-For, the Forwarded
+/// header is used instead
+pub fn client_ip(req: &ServiceRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let Some(trust) = req
+        .app_data::<Data<TrustProxy>>()
+        .filter(|trust| trust.trust_proxy)
+    else {
+        trace!("Trust proxy is not enabled. Will use peer ip");
+        return peer;
+    };
+    let trusted = |ip: &IpAddr| {
+        trust.proxy_trusted_servers.is_empty()
+            || trust
+                .proxy_trusted_servers
+                .iter()
+                .any(|server| server.contains(ip))
+    };
+    let peer_trusted = match peer {
+        Some(peer) => trusted(&peer),
+        None => trust.proxy_trusted_servers.is_empty(),
+    };
+    if !peer_trusted {
+        trace!("Peer is not a trusted proxy. Will use peer ip");
+        return peer;
+    }
+    let hops: Vec<&str> = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    if hops.is_empty() {
+        return req
+            .connection_info()
+            .realip_remote_addr()
+            .and_then(parse_hop)
+            .or(peer);
+    }
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = Some(ip);
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
 pub async fn enrich_with_client_ip(
     req: ServiceRequest,
     srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
 ) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
     debug!("Calling enricher for ip");
-    let trust_proxy_config: Option<&Data<TrustProxy>> = req.app_data::<Data<TrustProxy>>();
-    let ip = match trust_proxy_config {
-        Some(config) => {
-            if config.trust_proxy {
-                trace!("Trust proxy was configured and enabled");
-                req.connection_info().realip_remote_addr().and_then(|r| {
-                    trace!("{r}");
-                    IpAddr::from_str(r).ok()
-                })
-            } else {
-                trace!("Trust proxy was configured and disabled");
-                req.peer_addr().map(|s| s.ip())
-            }
-        }
-        None => {
-            trace!("Trust proxy was not configured. Will use peer ip");
-            req.peer_addr().map(|s| s.ip())
-        }
-    };
-    if let Some(ip) = ip {
+    if let Some(ip) = client_ip(&req) {
         trace!("Found client ip to be: {}", ip);
         req.extensions_mut().insert(ClientIp { ip });
     }
@@ -41,7 +85,7 @@ pub async fn enrich_with_client_ip(
 
 #[cfg(test)]
 mod tests {
-    use crate::cli::TrustProxy;
+    use crate::cli::{ip_or_cidr, TrustProxy};
     use crate::middleware::as_async_middleware::as_async_middleware;
     use crate::middleware::enrich_with_client_ip::enrich_with_client_ip;
     use crate::types::{ClientIp, EdgeJsonResult};
@@ -124,4 +168,42 @@ mod tests {
         let ip: String = test::call_and_read_body_json(&app, req).await;
         assert_eq!(ip, "192.168.0.1");
     }
+
+    #[tokio::test]
+    pub async fn the_rightmost_untrusted_hop_is_the_client_behind_trusted_proxies() {
+        let trust_proxy = TrustProxy {
+            trust_proxy: true,
+            proxy_trusted_servers: vec![
+                ip_or_cidr("10.0.0.0/8").unwrap(),
+                ip_or_cidr("172.16.0.1").unwrap(),
+            ],
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(trust_proxy))
+                .wrap(as_async_middleware(enrich_with_client_ip))
+                .service(hello_ip),
+        )
+        .await;
+
+        let peer = |ip: &str| SocketAddr::new(IpAddr::from_str(ip).unwrap(), 1337);
+        for (peer_ip, forwarded_for, expected) in [
+            (
+                "172.16.0.1",
+                "6.6.6.6, 203.0.113.7, 10.1.2.3",
+                "203.0.113.7",
+            ),
+            ("172.16.0.1", "10.2.3.4, 10.1.2.3", "10.2.3.4"),
+            ("172.16.0.1", "not-an-ip, 10.1.2.3", "10.1.2.3"),
+            ("203.0.113.9", "6.6.6.6", "203.0.113.9"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri("/")
+                .peer_addr(peer(peer_ip))
+                .insert_header(("X-Forwarded-For", forwarded_for))
+                .to_request();
+            let ip: String = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(ip, expected, "{forwarded_for} via {peer_ip}");
+        }
+    }
 }
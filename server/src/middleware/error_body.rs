@@ -0,0 +1,99 @@
+use actix_web::{
+    body::{BodySize, BoxBody, EitherBody, MessageBody},
+    dev::ServiceResponse,
+    http::header::{self, HeaderValue},
+    middleware::{ErrorHandlerResponse, ErrorHandlers},
+};
+
+use crate::error::ErrorResponse;
+
+/// Gives error responses that were sent without a body, like 404s for routes that don't exist or 405s for the wrong
+/// method, the same JSON body as the rest of our errors. Responses that already have a body are left alone
+pub fn error_bodies<B: MessageBody + 'static>() -> ErrorHandlers<B> {
+    ErrorHandlers::new().default_handler(add_missing_error_body)
+}
+
+fn add_missing_error_body<B: MessageBody + 'static>(
+    res: ServiceResponse<B>,
+) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    if !matches!(
+        res.response().body().size(),
+        BodySize::None | BodySize::Sized(0)
+    ) {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    }
+    let status = res.status();
+    let body = serde_json::to_vec(&ErrorResponse {
+        error: status.canonical_reason().unwrap_or("Error").into(),
+        status: status.as_u16(),
+    })?;
+    Ok(ErrorHandlerResponse::Response(res.map_body(|head, _| {
+        head.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        EitherBody::right(BoxBody::new(body))
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn empty_error_responses_get_a_json_body() {
+        let app = test::init_service(
+            App::new().service(
+                web::scope("/api")
+                    .wrap(error_bodies())
+                    .route(
+                        "/forbidden",
+                        web::get().to(|| async { HttpResponse::Forbidden().finish() }),
+                    )
+                    .route(
+                        "/teapot",
+                        web::get()
+                            .to(|| async { HttpResponse::ImATeapot().body("already explained") }),
+                    ),
+            ),
+        )
+        .await;
+
+        let forbidden = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/forbidden").to_request(),
+        )
+        .await;
+        assert_eq!(forbidden.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            forbidden.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body: ErrorResponse = test::read_body_json(forbidden).await;
+        assert_eq!(
+            body,
+            ErrorResponse {
+                error: "Forbidden".into(),
+                status: 403
+            }
+        );
+
+        let missing = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/missing").to_request(),
+        )
+        .await;
+        let body: ErrorResponse = test::read_body_json(missing).await;
+        assert_eq!(body.status, 404);
+
+        let teapot = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/api/teapot").to_request(),
+        )
+        .await;
+        assert_eq!(test::read_body(teapot).await, "already explained");
+    }
+}
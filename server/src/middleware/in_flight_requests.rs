@@ -0,0 +1,66 @@
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use lazy_static::lazy_static;
+use prometheus::{register_int_gauge, IntGauge, Opts};
+
+lazy_static! {
+    pub static ref IN_FLIGHT_REQUESTS: IntGauge = register_int_gauge!(Opts::new(
+        "http_requests_in_flight",
+        "Number of requests currently being handled"
+    ))
+    .unwrap();
+}
+
+/// Decrements on drop, so requests whose future gets cancelled are not counted forever
+struct InFlightGuard;
+
+impl InFlightGuard {
+    fn new() -> Self {
+        IN_FLIGHT_REQUESTS.inc();
+        InFlightGuard
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_REQUESTS.dec();
+    }
+}
+
+pub fn in_flight_requests() -> i64 {
+    IN_FLIGHT_REQUESTS.get()
+}
+
+pub async fn count_in_flight_requests(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let _guard = InFlightGuard::new();
+    srv.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[actix_web::test]
+    async fn requests_are_counted_while_being_handled() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(count_in_flight_requests))
+                .route(
+                    "/",
+                    web::get()
+                        .to(|| async { HttpResponse::Ok().body(in_flight_requests().to_string()) }),
+                ),
+        )
+        .await;
+        let before = in_flight_requests();
+        let body = test::call_and_read_body(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(body, (before + 1).to_string());
+        assert_eq!(in_flight_requests(), before);
+    }
+}
@@ -1,5 +1,15 @@
+pub mod access_log;
+
 pub mod as_async_middleware;
 
+pub mod backstage_access;
+
+pub mod cache_control;
+
+pub mod cache_status;
+
+pub mod cors;
+
 #[cfg(not(tarpaulin_include))]
 pub mod request_tracing;
 
@@ -8,3 +18,15 @@ pub mod validate_token;
 pub mod client_token_from_frontend_token;
 
 pub mod enrich_with_client_ip;
+
+pub mod error_body;
+
+pub mod in_flight_requests;
+
+pub mod rate_limit;
+
+pub mod request_id;
+
+pub mod request_timeout;
+
+pub mod version_headers;
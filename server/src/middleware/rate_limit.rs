@@ -0,0 +1,247 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    web::Data,
+    HttpResponse,
+};
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec, Opts};
+use tracing::debug;
+
+use crate::cli::RateLimitArgs;
+use crate::middleware::validate_token::ApiScope;
+use crate::types::EdgeToken;
+
+lazy_static! {
+    pub static ref RATE_LIMITED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "rate_limited_requests",
+            "Number of requests refused because the token exceeded its rate limit"
+        ),
+        &["api"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    fn new(per_second: Option<u32>, burst: Option<u32>) -> Option<Self> {
+        per_second.filter(|rate| *rate > 0).map(|rate| RateLimit {
+            per_second: rate as f64,
+            burst: burst.unwrap_or(rate).max(1) as f64,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            available: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    /// Takes one request out of the bucket, or says how long until the next one is available
+    fn try_acquire(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.available = (self.available + elapsed * limit.per_second).min(limit.burst);
+        self.last_refill = now;
+        if self.available >= 1.0 {
+            self.available -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.available) / limit.per_second,
+            ))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LimitedApi {
+    Client,
+    Frontend,
+}
+
+impl LimitedApi {
+    fn from_path(path: &str) -> Option<Self> {
+        ApiScope::from_path(path).map(|scope| match scope {
+            ApiScope::Client => LimitedApi::Client,
+            ApiScope::Frontend => LimitedApi::Frontend,
+        })
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            LimitedApi::Client => "client",
+            LimitedApi::Frontend => "frontend",
+        }
+    }
+}
+
+/// Token buckets per token, kept separately for the client and the frontend API
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    client_limit: Option<RateLimit>,
+    frontend_limit: Option<RateLimit>,
+    client_buckets: Arc<DashMap<String, TokenBucket>>,
+    frontend_buckets: Arc<DashMap<String, TokenBucket>>,
+}
+
+impl From<&RateLimitArgs> for RateLimiter {
+    fn from(args: &RateLimitArgs) -> Self {
+        RateLimiter {
+            client_limit: RateLimit::new(
+                args.client_rate_limit_per_second,
+                args.client_rate_limit_burst,
+            ),
+            frontend_limit: RateLimit::new(
+                args.frontend_rate_limit_per_second,
+                args.frontend_rate_limit_burst,
+            ),
+            ..Default::default()
+        }
+    }
+}
+
+impl RateLimiter {
+    fn check(&self, api: LimitedApi, token: &str, now: Instant) -> Result<(), Duration> {
+        let (limit, buckets) = match api {
+            LimitedApi::Client => (self.client_limit, &self.client_buckets),
+            LimitedApi::Frontend => (self.frontend_limit, &self.frontend_buckets),
+        };
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        buckets
+            .entry(token.to_string())
+            .or_insert_with(|| TokenBucket::full(&limit, now))
+            .try_acquire(&limit, now)
+    }
+}
+
+/// Needs to run after [`crate::middleware::validate_token::validate_token`], so only known tokens get a bucket
+pub async fn rate_limit(
+    token: EdgeToken,
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limited = match (
+        req.app_data::<Data<RateLimiter>>(),
+        LimitedApi::from_path(req.path()),
+    ) {
+        (Some(limiter), Some(api)) => limiter
+            .check(api, &token.token, Instant::now())
+            .err()
+            .map(|retry_after| (api, retry_after)),
+        _ => None,
+    };
+    match limited {
+        Some((api, retry_after)) => {
+            debug!("Rate limited request to {}", req.path());
+            RATE_LIMITED_REQUESTS
+                .with_label_values(&[api.label()])
+                .inc();
+            let retry_after_seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            Ok(req
+                .into_response(
+                    HttpResponse::TooManyRequests()
+                        .insert_header((header::RETRY_AFTER, retry_after_seconds.to_string()))
+                        .finish(),
+                )
+                .map_into_right_body())
+        }
+        None => Ok(srv.call(req).await?.map_into_left_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[test]
+    pub fn bucket_allows_a_burst_then_refills_at_the_configured_rate() {
+        let limit = RateLimit::new(Some(2), Some(3)).unwrap();
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, start);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(&limit, start).is_ok());
+        }
+        let retry_after = bucket.try_acquire(&limit, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+        assert!(bucket
+            .try_acquire(&limit, start + Duration::from_millis(500))
+            .is_ok());
+        assert!(bucket
+            .try_acquire(&limit, start + Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    pub fn client_and_frontend_tokens_have_separate_limits() {
+        let limiter = RateLimiter::from(&RateLimitArgs {
+            client_rate_limit_per_second: Some(1),
+            frontend_rate_limit_per_second: None,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(limiter.check(LimitedApi::Client, "client", now).is_ok());
+        assert!(limiter.check(LimitedApi::Client, "client", now).is_err());
+        assert!(limiter.check(LimitedApi::Client, "other", now).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.check(LimitedApi::Frontend, "frontend", now).is_ok());
+        }
+    }
+
+    #[actix_web::test]
+    async fn exceeding_the_limit_returns_429_with_retry_after() {
+        let limiter = RateLimiter::from(&RateLimitArgs {
+            client_rate_limit_per_second: Some(1),
+            ..Default::default()
+        });
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(limiter))
+                .wrap(as_async_middleware(rate_limit))
+                .route(
+                    "/api/client/features",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/api/client/features")
+                .insert_header(("Authorization", "*:development.secret123"))
+                .to_request()
+        };
+        let allowed = call_service(&app, request()).await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        let limited = call_service(&app, request()).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers().get(header::RETRY_AFTER).unwrap(), "1");
+    }
+}
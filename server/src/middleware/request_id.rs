@@ -0,0 +1,103 @@
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpMessage;
+use tracing::{info_span, Instrument};
+use ulid::Ulid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer ids than this from clients are replaced, so they can't bloat every log line
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The id of the request being handled, available from the request extensions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+}
+
+/// Takes the request id the client sent in X-Request-Id, or makes one up, so a request can be followed through the
+/// logs. The id is logged with everything that happens while handling the request and echoed back in the response
+pub async fn request_id(
+    mut req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = incoming_request_id(&req).unwrap_or_else(|| Ulid::new().to_string());
+    let header_value = HeaderValue::from_str(&id).expect("Request ids are valid header values");
+    // Also set on the request, so the plain text access log can print it
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value.clone());
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let mut res = srv
+        .call(req)
+        .instrument(info_span!("request", request_id = %id))
+        .await?;
+    res.headers_mut().insert(REQUEST_ID_HEADER, header_value);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    async fn echo_request_id(req: HttpRequest) -> HttpResponse {
+        let id = req.extensions().get::<RequestId>().cloned().unwrap();
+        HttpResponse::Ok().body(id.0)
+    }
+
+    #[actix_web::test]
+    async fn incoming_request_id_is_kept_and_echoed_back() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(request_id))
+                .route("/", web::get().to(echo_request_id)),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .insert_header(("X-Request-Id", "support-case-1234"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(REQUEST_ID_HEADER).unwrap(),
+            "support-case-1234"
+        );
+        assert_eq!(test::read_body(res).await, "support-case-1234");
+    }
+
+    #[actix_web::test]
+    async fn request_id_is_generated_when_missing_or_unusable() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(request_id))
+                .route("/", web::get().to(echo_request_id)),
+        )
+        .await;
+        for req in [
+            test::TestRequest::get().to_request(),
+            test::TestRequest::get()
+                .insert_header(("X-Request-Id", "x".repeat(MAX_REQUEST_ID_LENGTH + 1)))
+                .to_request(),
+        ] {
+            let res = test::call_service(&app, req).await;
+            let echoed = res
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert!(Ulid::from_string(&echoed).is_ok());
+            assert_eq!(test::read_body(res).await, echoed);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    web::Data,
+};
+use tracing::warn;
+
+use crate::error::EdgeError;
+
+/// How long a request may take before Edge gives up on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestTimeout(pub Duration);
+
+/// Drops the handler future once the request has taken longer than the [`RequestTimeout`] in app data and fails it with
+/// 504 Gateway Timeout instead, so a slow handler doesn't hold on to a worker. Only covers producing the response,
+/// so streaming bodies such as server-sent events keep going after the headers are sent
+pub async fn enforce_request_timeout(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let Some(timeout) = req
+        .app_data::<Data<RequestTimeout>>()
+        .map(|timeout| timeout.0)
+    else {
+        return srv.call(req).await;
+    };
+    // The request can't be cloned while it's being handled, so there's nothing to build a response from once it times out.
+    // Failing with the error turns it into the 504
+    let request_line = format!("{} {}", req.method(), req.path());
+    match tokio::time::timeout(timeout, srv.call(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            let error = EdgeError::RequestTimedOut(timeout.as_millis() as u64);
+            warn!("{request_line}: {error}");
+            Err(error.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[actix_web::test]
+    async fn slow_requests_are_answered_with_gateway_timeout() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(RequestTimeout(Duration::from_millis(50))))
+                .wrap(as_async_middleware(enforce_request_timeout))
+                .route(
+                    "/fast",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                )
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+
+        let fast =
+            test::call_service(&app, test::TestRequest::get().uri("/fast").to_request()).await;
+        assert_eq!(fast.status(), StatusCode::OK);
+        let slow =
+            test::try_call_service(&app, test::TestRequest::get().uri("/slow").to_request()).await;
+        assert_eq!(
+            slow.err().unwrap().error_response().status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
@@ -1,13 +1,152 @@
+use std::sync::Arc;
+
+use crate::auth::denied_tokens::DeniedTokens;
 use crate::auth::token_validator::TokenValidator;
+use crate::cli::EdgeMode;
+use crate::error::{json_error_response, EdgeError};
+use crate::http::feature_refresher::FeatureRefresher;
+use crate::middleware::enrich_with_client_ip::client_ip;
+use crate::middleware::request_id::RequestId;
+use crate::offline::offline_hotload;
+use crate::tokens::anonymize_token;
 use crate::types::{EdgeToken, TokenType, TokenValidationStatus};
 use actix_web::{
     body::MessageBody,
     dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
     web::Data,
-    HttpResponse,
+    HttpMessage, ResponseError,
 };
 use dashmap::DashMap;
-use tracing::trace;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec, Opts};
+use tracing::{info, trace};
+use unleash_types::client_features::ClientFeatures;
+use unleash_yggdrasil::EngineState;
+
+/// The APIs a token type gives access to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ApiScope {
+    Client,
+    Frontend,
+}
+
+impl ApiScope {
+    /// Finds the scope from the first pair of whole path segments that is `/api/client`, `/api/frontend` or `/api/proxy`,
+    /// so `/api/client/x/api/frontend` is a client path and `/api/frontendx` isn't a frontend path
+    pub(crate) fn from_path(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        segments.windows(2).find_map(|pair| match pair {
+            ["api", "client"] => Some(ApiScope::Client),
+            ["api", "frontend" | "proxy"] => Some(ApiScope::Frontend),
+            _ => None,
+        })
+    }
+}
+
+/// Target of the audit log, so it can be routed separately from the rest of the logs, e.g. with RUST_LOG=unleash_edge::audit=info
+pub const AUDIT_TARGET: &str = "unleash_edge::audit";
+
+lazy_static! {
+    pub static ref TOKEN_VALIDATION_RESULTS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "token_validation_results",
+            "Number of requests by how their token was validated"
+        ),
+        &["result", "token_type"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ValidationResult {
+    Validated,
+    Unknown,
+    Invalid,
+    /// Valid, but not for the API or environment it was used for
+    ForbiddenWrongScope,
+}
+
+impl ValidationResult {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ValidationResult::Validated => "validated",
+            ValidationResult::Unknown => "unknown",
+            ValidationResult::Invalid => "invalid",
+            ValidationResult::ForbiddenWrongScope => "forbidden_wrong_scope",
+        }
+    }
+
+    fn outcome(&self) -> &'static str {
+        match self {
+            ValidationResult::Validated => "allowed",
+            ValidationResult::Unknown => "unauthorized",
+            ValidationResult::Invalid | ValidationResult::ForbiddenWrongScope => "forbidden",
+        }
+    }
+}
+
+fn token_type_label(token: &EdgeToken) -> &'static str {
+    match token.token_type {
+        Some(TokenType::Client) => "client",
+        Some(TokenType::Frontend) => "frontend",
+        Some(TokenType::Admin) => "admin",
+        Some(TokenType::Invalid) | None => "unknown",
+    }
+}
+
+/// Records which token was let through to what, with only a short prefix of the secret, and counts the result per token type
+fn audit(req: &ServiceRequest, token: &EdgeToken, result: ValidationResult) {
+    let token_type = token_type_label(token);
+    TOKEN_VALIDATION_RESULTS
+        .with_label_values(&[result.as_str(), token_type])
+        .inc();
+    let scope = ApiScope::from_path(req.path()).map(|scope| format!("{scope:?}").to_lowercase());
+    info!(
+        target: AUDIT_TARGET,
+        token = anonymize_token(token).token,
+        token_type = token.token_type.as_ref().map(|_| token_type),
+        environment = token.environment,
+        path = req.path(),
+        scope,
+        client_ip = client_ip(req).map(|ip| ip.to_string()),
+        request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+        outcome = result.outcome(),
+        result = result.as_str(),
+        "token validation decision"
+    );
+}
+
+async fn accepts_any_offline_token(
+    req: &ServiceRequest,
+    token: &EdgeToken,
+    token_cache: Arc<DashMap<String, EdgeToken>>,
+) -> bool {
+    let (Some(EdgeMode::Offline(offline_args)), Some(features_cache), Some(engine_cache)) = (
+        req.app_data::<Data<EdgeMode>>().map(|mode| mode.get_ref()),
+        req.app_data::<Data<DashMap<String, ClientFeatures>>>(),
+        req.app_data::<Data<DashMap<String, EngineState>>>(),
+    ) else {
+        return false;
+    };
+    offline_hotload::accept_any_offline_token(
+        offline_args,
+        token,
+        token_cache,
+        features_cache.clone().into_inner(),
+        engine_cache.clone().into_inner(),
+    )
+    .await
+}
+
+/// Tokens for other environments than the one set with --default-environment or those in --allowed-environments are
+/// refused, with an error saying which ones are served
+fn environment_not_served(req: &ServiceRequest, token: &EdgeToken) -> Option<EdgeError> {
+    req.app_data::<Data<FeatureRefresher>>()
+        .filter(|refresher| !refresher.serves_environment(token))
+        .and_then(|refresher| refresher.served_environments())
+        .map(EdgeError::EnvironmentNotServed)
+}
 
 pub async fn validate_token(
     token: EdgeToken,
@@ -21,49 +160,130 @@ pub async fn validate_token(
         .unwrap()
         .clone()
         .into_inner();
+    if req
+        .app_data::<Data<DeniedTokens>>()
+        .is_some_and(|denied| denied.contains(&token.token))
+    {
+        trace!("Refused denied token");
+        audit(&req, &token, ValidationResult::Invalid);
+        return Ok(req
+            .into_response(json_error_response(
+                StatusCode::FORBIDDEN,
+                "The token has been denied",
+            ))
+            .map_into_right_body());
+    }
     match maybe_validator {
+        Some(validator) if validator.is_revoked(&token.token) => {
+            trace!("Refused revoked token");
+            audit(&req, &token, ValidationResult::Invalid);
+            Ok(req
+                .into_response(json_error_response(
+                    StatusCode::FORBIDDEN,
+                    "The token has been revoked",
+                ))
+                .map_into_right_body())
+        }
         Some(validator) => {
-            let known_token = validator.register_token(token.token.clone()).await?;
-            let res = match known_token.status {
+            let known_token = if validator.strict_token_validation {
+                token_cache
+                    .get(&token.token)
+                    .map(|known| known.value().clone())
+                    .unwrap_or(token)
+            } else {
+                validator.register_token(token.token.clone()).await?
+            };
+            if known_token.status == TokenValidationStatus::Validated {
+                if let Some(refresher) = req.app_data::<Data<FeatureRefresher>>() {
+                    refresher.mark_token_accessed(&known_token.token);
+                }
+            }
+            if let Some(not_served) = environment_not_served(&req, &known_token)
+                .filter(|_| known_token.status == TokenValidationStatus::Validated)
+            {
+                trace!(
+                    "Refused token for another environment {:?}",
+                    known_token.environment
+                );
+                audit(&req, &known_token, ValidationResult::ForbiddenWrongScope);
+                return Ok(req
+                    .into_response(not_served.error_response())
+                    .map_into_right_body());
+            }
+            let scope = ApiScope::from_path(req.path());
+            let result = match known_token.status {
                 TokenValidationStatus::Validated => match known_token.token_type {
-                    Some(TokenType::Frontend) => {
+                    Some(TokenType::Frontend) if scope == Some(ApiScope::Frontend) => {
                         trace!("Got FE token validated {:?}", known_token);
-                        if req.path().contains("/api/frontend") || req.path().contains("/api/proxy")
-                        {
-                            trace!("Was allowed to access");
-                            srv.call(req).await?.map_into_left_body()
-                        } else {
-                            req.into_response(HttpResponse::Forbidden().finish())
-                                .map_into_right_body()
-                        }
+                        ValidationResult::Validated
                     }
-                    Some(TokenType::Client) => {
+                    Some(TokenType::Client) if scope == Some(ApiScope::Client) => {
                         trace!("Got Client token validated {:?}", known_token);
-                        if req.path().contains("/api/client") {
-                            srv.call(req).await?.map_into_left_body()
-                        } else {
-                            req.into_response(HttpResponse::Forbidden().finish())
-                                .map_into_right_body()
-                        }
+                        ValidationResult::Validated
                     }
-                    _ => req
-                        .into_response(HttpResponse::Forbidden().finish())
-                        .map_into_right_body(),
+                    _ => ValidationResult::ForbiddenWrongScope,
                 },
-                TokenValidationStatus::Unknown => req
-                    .into_response(HttpResponse::Unauthorized().finish())
+                TokenValidationStatus::Unknown => ValidationResult::Unknown,
+                TokenValidationStatus::Invalid => ValidationResult::Invalid,
+            };
+            audit(&req, &known_token, result);
+            let res = match result {
+                ValidationResult::Validated => srv.call(req).await?.map_into_left_body(),
+                ValidationResult::Unknown => req
+                    .into_response(json_error_response(
+                        StatusCode::UNAUTHORIZED,
+                        "The token could not be validated",
+                    ))
                     .map_into_right_body(),
-                TokenValidationStatus::Invalid => req
-                    .into_response(HttpResponse::Forbidden().finish())
+                ValidationResult::Invalid => req
+                    .into_response(json_error_response(
+                        StatusCode::FORBIDDEN,
+                        "The token is not valid",
+                    ))
+                    .map_into_right_body(),
+                ValidationResult::ForbiddenWrongScope => req
+                    .into_response(json_error_response(
+                        StatusCode::FORBIDDEN,
+                        "The token can't be used with this API",
+                    ))
                     .map_into_right_body(),
             };
             Ok(res)
         }
         None => {
-            let res = match token_cache.get(&token.token) {
-                Some(_) => srv.call(req).await?.map_into_left_body(),
-                None => req
-                    .into_response(HttpResponse::Forbidden().finish())
+            let cached = token_cache
+                .get(&token.token)
+                .map(|known| known.value().clone());
+            let result = match cached {
+                // Read replicas serve the tokens edge nodes validated, which know what they were validated for. Offline
+                // tokens don't, and go everywhere
+                Some(known) => match (known.token_type, ApiScope::from_path(req.path())) {
+                    (None, _)
+                    | (Some(TokenType::Client), Some(ApiScope::Client))
+                    | (Some(TokenType::Frontend), Some(ApiScope::Frontend)) => {
+                        ValidationResult::Validated
+                    }
+                    _ => ValidationResult::ForbiddenWrongScope,
+                },
+                None if accepts_any_offline_token(&req, &token, token_cache).await => {
+                    ValidationResult::Validated
+                }
+                None => ValidationResult::Invalid,
+            };
+            audit(&req, &token, result);
+            let res = match result {
+                ValidationResult::Validated => srv.call(req).await?.map_into_left_body(),
+                ValidationResult::ForbiddenWrongScope => req
+                    .into_response(json_error_response(
+                        StatusCode::FORBIDDEN,
+                        "The token can't be used with this API",
+                    ))
+                    .map_into_right_body(),
+                _ => req
+                    .into_response(json_error_response(
+                        StatusCode::FORBIDDEN,
+                        "The token is not known to Edge",
+                    ))
                     .map_into_right_body(),
             };
 
@@ -71,3 +291,419 @@ pub async fn validate_token(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use actix_http::HttpService;
+    use actix_http_test::test_server;
+    use actix_service::map_config;
+    use actix_web::dev::AppConfig;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::http::unleash_client::UnleashClient;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[test]
+    pub fn scope_is_matched_on_whole_path_segments() {
+        for (path, scope) in [
+            ("/api/frontend", Some(ApiScope::Frontend)),
+            ("/api/frontend/all", Some(ApiScope::Frontend)),
+            ("/api/proxy", Some(ApiScope::Frontend)),
+            ("/api/proxy/client/metrics", Some(ApiScope::Frontend)),
+            ("/edge/api/frontend/all", Some(ApiScope::Frontend)),
+            ("/api/client/features", Some(ApiScope::Client)),
+            ("/api/client/x/api/frontend", Some(ApiScope::Client)),
+            ("/api/frontendx", None),
+            ("/apiclient/features", None),
+            ("/internal-backstage/api/tokens", None),
+        ] {
+            assert_eq!(ApiScope::from_path(path), scope, "{path}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn frontend_tokens_are_refused_on_client_paths_mentioning_the_frontend_api() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let mut frontend_token =
+            EdgeToken::try_from("*:development.secret123".to_string()).unwrap();
+        frontend_token.status = TokenValidationStatus::Validated;
+        frontend_token.token_type = Some(TokenType::Frontend);
+        token_cache.insert(frontend_token.token.clone(), frontend_token.clone());
+        let validator = TokenValidator::new(
+            Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap()),
+            token_cache.clone(),
+        );
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::new(validator))
+                .wrap(as_async_middleware(validate_token))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (uri, status) in [
+            ("/api/frontend", StatusCode::OK),
+            ("/api/frontend/all", StatusCode::OK),
+            ("/api/proxy", StatusCode::OK),
+            ("/api/client/features", StatusCode::FORBIDDEN),
+            ("/api/client/x?redir=/api/frontend", StatusCode::FORBIDDEN),
+            ("/api/client/x/api/frontend", StatusCode::FORBIDDEN),
+            ("/api/frontendx", StatusCode::FORBIDDEN),
+        ] {
+            let request = TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", frontend_token.token.clone()))
+                .to_request();
+            assert_eq!(call_service(&app, request).await.status(), status, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    #[traced_test]
+    async fn decisions_are_audited_without_the_token_secret() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let client_token = EdgeToken::validated_client_token(
+            "*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7",
+        );
+        token_cache.insert(client_token.token.clone(), client_token.clone());
+        let validator = TokenValidator {
+            token_cache: token_cache.clone(),
+            unleash_client: Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap()),
+            strict_token_validation: true,
+            ..Default::default()
+        };
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::new(validator))
+                .wrap(as_async_middleware(validate_token))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let counted = |result: &str| {
+            TOKEN_VALIDATION_RESULTS
+                .with_label_values(&[result, "client"])
+                .get()
+        };
+        let validated_before = counted("validated");
+        let wrong_scope_before = counted("forbidden_wrong_scope");
+
+        for (uri, token) in [
+            ("/api/client/features", client_token.token.as_str()),
+            ("/api/frontend", client_token.token.as_str()),
+            ("/api/client/features", "*:development.unknown123"),
+        ] {
+            let request = TestRequest::get()
+                .uri(uri)
+                .insert_header(("Authorization", token))
+                .peer_addr("198.51.100.4:4242".parse().unwrap())
+                .to_request();
+            call_service(&app, request).await;
+        }
+        assert!(logs_contain("token validation decision"));
+        assert!(logs_contain("client_ip=\"198.51.100.4\""));
+        assert!(logs_contain("outcome=\"allowed\""));
+        assert!(logs_contain("path=\"/api/frontend\" scope=\"frontend\""));
+        assert!(logs_contain(
+            "outcome=\"forbidden\" result=\"forbidden_wrong_scope\""
+        ));
+        assert!(logs_contain("outcome=\"unauthorized\""));
+        assert!(logs_contain("token_type=\"client\""));
+        assert!(logs_contain("token=\"*:development.03fa5f****\""));
+        assert!(!logs_contain(
+            "03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7"
+        ));
+        assert!(!logs_contain("unknown123"));
+        assert!(counted("validated") > validated_before);
+        assert!(counted("forbidden_wrong_scope") > wrong_scope_before);
+    }
+
+    #[actix_web::test]
+    async fn tokens_for_other_environments_than_the_default_environment_are_refused() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let production = EdgeToken::validated_client_token("*:production.secret123");
+        let development = EdgeToken::validated_client_token("*:development.secret123");
+        token_cache.insert(production.token.clone(), production.clone());
+        token_cache.insert(development.token.clone(), development.clone());
+        let unleash_client = Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap());
+        let validator = TokenValidator {
+            token_cache: token_cache.clone(),
+            unleash_client: unleash_client.clone(),
+            strict_token_validation: true,
+            ..Default::default()
+        };
+        let refresher = FeatureRefresher::with_client(unleash_client)
+            .with_default_environment(Some("production".into()));
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::new(validator))
+                .app_data(Data::new(refresher))
+                .wrap(as_async_middleware(validate_token))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let served = TestRequest::get()
+            .uri("/api/client/features")
+            .insert_header(("Authorization", production.token.clone()))
+            .to_request();
+        assert_eq!(call_service(&app, served).await.status(), StatusCode::OK);
+
+        let refused = TestRequest::get()
+            .uri("/api/client/features")
+            .insert_header(("Authorization", development.token.clone()))
+            .to_request();
+        let res = call_service(&app, refused).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(
+            body["error"],
+            "This Edge only serves the production environment"
+        );
+    }
+
+    #[actix_web::test]
+    async fn tokens_for_environments_outside_the_allowed_environments_are_refused() {
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        let tokens = ["production", "staging", "development"]
+            .map(|env| EdgeToken::validated_client_token(&format!("*:{env}.secret123")));
+        for token in &tokens {
+            token_cache.insert(token.token.clone(), token.clone());
+        }
+        let unleash_client = Arc::new(UnleashClient::new("http://localhost:4242", None).unwrap());
+        let validator = TokenValidator {
+            token_cache: token_cache.clone(),
+            unleash_client: unleash_client.clone(),
+            strict_token_validation: true,
+            ..Default::default()
+        };
+        let refresher = FeatureRefresher::with_client(unleash_client)
+            .with_allowed_environments(vec!["production".into(), "staging".into()]);
+        refresher
+            .register_token_for_refresh(tokens[2].clone(), None)
+            .await;
+        assert!(refresher.tokens_to_refresh.is_empty());
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::new(validator))
+                .app_data(Data::new(refresher))
+                .wrap(as_async_middleware(validate_token))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (token, expected) in
+            tokens
+                .iter()
+                .zip([StatusCode::OK, StatusCode::OK, StatusCode::FORBIDDEN])
+        {
+            let req = TestRequest::get()
+                .uri("/api/client/features")
+                .insert_header(("Authorization", token.token.clone()))
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.status(), expected, "{}", token.token);
+            if expected == StatusCode::FORBIDDEN {
+                let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+                assert_eq!(
+                    body["error"],
+                    "This Edge only serves the production, staging environments"
+                );
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn denied_tokens_are_refused_in_every_scope_without_asking_upstream() {
+        let client = EdgeToken::validated_client_token("*:development.client123");
+        let frontend = EdgeToken {
+            token_type: Some(TokenType::Frontend),
+            ..EdgeToken::validated_client_token("*:development.frontend123")
+        };
+        let validations = Arc::new(AtomicUsize::new(0));
+        let upstream_validations = validations.clone();
+        let accepted = serde_json::json!({ "tokens": [client.clone(), frontend.clone()] });
+        let upstream = test_server(move || {
+            let validations = upstream_validations.clone();
+            let accepted = accepted.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/edge/validate",
+                    web::post().to(move || {
+                        validations.fetch_add(1, Ordering::SeqCst);
+                        let accepted = accepted.clone();
+                        async move { HttpResponse::Ok().json(accepted) }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+        let denied_tokens = DeniedTokens::new([client.token.clone(), frontend.token.clone()]);
+
+        for with_validator in [true, false] {
+            let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+            if !with_validator {
+                token_cache.insert(client.token.clone(), client.clone());
+                token_cache.insert(frontend.token.clone(), frontend.clone());
+            }
+            let validator = TokenValidator::new(
+                Arc::new(UnleashClient::new(upstream.url("/").as_str(), None).unwrap()),
+                token_cache.clone(),
+            );
+            let mut app = App::new()
+                .app_data(Data::from(token_cache))
+                .app_data(Data::new(denied_tokens.clone()));
+            if with_validator {
+                app = app.app_data(Data::new(validator));
+            }
+            let app = init_service(
+                app.wrap(as_async_middleware(validate_token))
+                    .default_service(web::to(HttpResponse::Ok)),
+            )
+            .await;
+
+            for token in [&client, &frontend] {
+                for path in ["/api/client/features", "/api/frontend", "/api/proxy/all"] {
+                    let request = TestRequest::get()
+                        .uri(path)
+                        .insert_header(("Authorization", token.token.as_str()))
+                        .to_request();
+                    assert_eq!(
+                        call_service(&app, request).await.status(),
+                        StatusCode::FORBIDDEN,
+                        "{} on {path}",
+                        token.token
+                    );
+                }
+            }
+        }
+        assert_eq!(validations.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_web::test]
+    async fn strict_token_validation_refuses_tokens_not_already_validated_without_asking_upstream()
+    {
+        let unknown = "*:development.unknown123";
+        let validations = Arc::new(AtomicUsize::new(0));
+        let upstream_validations = validations.clone();
+        let upstream = test_server(move || {
+            let validations = upstream_validations.clone();
+            HttpService::new(map_config(
+                App::new().route(
+                    "/edge/validate",
+                    web::post().to(move || {
+                        validations.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            HttpResponse::Ok().json(serde_json::json!({
+                                "tokens": [EdgeToken::validated_client_token(unknown)]
+                            }))
+                        }
+                    }),
+                ),
+                |_| AppConfig::default(),
+            ))
+            .tcp()
+        })
+        .await;
+
+        for (strict, unknown_status, expected_validations) in [
+            (false, StatusCode::OK, 1),
+            (true, StatusCode::UNAUTHORIZED, 0),
+        ] {
+            validations.store(0, Ordering::SeqCst);
+            let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+            let validated = EdgeToken::validated_client_token("*:development.validated123");
+            let revoked = EdgeToken::validated_client_token("*:development.revoked123");
+            token_cache.insert(validated.token.clone(), validated.clone());
+            token_cache.insert(revoked.token.clone(), revoked.clone());
+            let validator = TokenValidator {
+                token_cache: token_cache.clone(),
+                unleash_client: Arc::new(
+                    UnleashClient::new(upstream.url("/").as_str(), None).unwrap(),
+                ),
+                strict_token_validation: strict,
+                ..Default::default()
+            };
+            validator.revoked_tokens.insert(revoked.token.clone());
+            let app = init_service(
+                App::new()
+                    .app_data(Data::from(token_cache))
+                    .app_data(Data::new(validator))
+                    .wrap(as_async_middleware(validate_token))
+                    .default_service(web::to(HttpResponse::Ok)),
+            )
+            .await;
+
+            for (token, status) in [
+                (validated.token.as_str(), StatusCode::OK),
+                (unknown, unknown_status),
+                (revoked.token.as_str(), StatusCode::FORBIDDEN),
+            ] {
+                let request = TestRequest::get()
+                    .uri("/api/client/features")
+                    .insert_header(("Authorization", token))
+                    .to_request();
+                assert_eq!(
+                    call_service(&app, request).await.status(),
+                    status,
+                    "{token} with strict token validation {strict}"
+                );
+            }
+            assert_eq!(validations.load(Ordering::SeqCst), expected_validations);
+        }
+    }
+
+    #[actix_web::test]
+    async fn read_replicas_only_let_persisted_tokens_through_to_the_apis_of_their_type() {
+        let client = EdgeToken::validated_client_token("*:development.client123");
+        let frontend = EdgeToken {
+            token_type: Some(TokenType::Frontend),
+            ..EdgeToken::validated_client_token("*:development.frontend123")
+        };
+        let untyped = EdgeToken::from_str("*:development.offline123").unwrap();
+        let token_cache: Arc<DashMap<String, EdgeToken>> = Arc::new(DashMap::default());
+        for token in [&client, &frontend, &untyped] {
+            token_cache.insert(token.token.clone(), token.clone());
+        }
+        let app = init_service(
+            App::new()
+                .app_data(Data::from(token_cache))
+                .wrap(as_async_middleware(validate_token))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (token, path, status) in [
+            (&client, "/api/client/features", StatusCode::OK),
+            (&client, "/api/frontend", StatusCode::FORBIDDEN),
+            (&frontend, "/api/client/features", StatusCode::FORBIDDEN),
+            (&frontend, "/api/frontend", StatusCode::OK),
+            (&frontend, "/api/proxy/all", StatusCode::OK),
+            (&untyped, "/api/client/features", StatusCode::OK),
+            (&untyped, "/api/frontend", StatusCode::OK),
+        ] {
+            let request = TestRequest::get()
+                .uri(path)
+                .insert_header(("Authorization", token.token.as_str()))
+                .to_request();
+            assert_eq!(
+                call_service(&app, request).await.status(),
+                status,
+                "{} on {path}",
+                token.token
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use actix_http::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::web::Data;
+
+use crate::http::feature_refresher::FeatureRefresher;
+
+pub const EDGE_VERSION_HEADER: HeaderName = HeaderName::from_static("x-edge-version");
+pub const UPSTREAM_VERSION_HEADER: HeaderName =
+    HeaderName::from_static("x-unleash-upstream-version");
+
+/// Adds the version of this Edge and of the upstream it last heard from, so it's easy to spot which Edge nodes in a
+/// fleet are out of step
+pub async fn add_version_headers(
+    req: ServiceRequest,
+    srv: crate::middleware::as_async_middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let upstream_version = req
+        .app_data::<Data<FeatureRefresher>>()
+        .and_then(|refresher| refresher.unleash_client.upstream_version())
+        .and_then(|version| HeaderValue::from_str(&version).ok());
+    let mut res = srv.call(req).await?;
+    let headers = res.headers_mut();
+    headers.insert(
+        EDGE_VERSION_HEADER,
+        HeaderValue::from_static(env!("CARGO_PKG_VERSION")),
+    );
+    if let Some(version) = upstream_version {
+        headers.insert(UPSTREAM_VERSION_HEADER, version);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    use super::*;
+    use crate::http::unleash_client::UnleashClient;
+    use crate::middleware::as_async_middleware::as_async_middleware;
+
+    #[actix_web::test]
+    async fn responses_carry_the_edge_and_last_seen_upstream_version() {
+        let client = UnleashClient::new("http://localhost:4242", None).unwrap();
+        client.record_upstream_version(Some("6.3.0"));
+        let refresher = FeatureRefresher::with_client(Arc::new(client));
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(refresher))
+                .wrap(as_async_middleware(add_version_headers))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert_eq!(
+            res.headers().get(EDGE_VERSION_HEADER).unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(res.headers().get(UPSTREAM_VERSION_HEADER).unwrap(), "6.3.0");
+    }
+
+    #[actix_web::test]
+    async fn upstream_version_is_left_out_until_upstream_has_told_us() {
+        let app = test::init_service(
+            App::new()
+                .wrap(as_async_middleware(add_version_headers))
+                .route("/", web::get().to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+        let res = test::call_service(&app, test::TestRequest::get().to_request()).await;
+        assert!(res.headers().contains_key(EDGE_VERSION_HEADER));
+        assert!(!res.headers().contains_key(UPSTREAM_VERSION_HEADER));
+    }
+}
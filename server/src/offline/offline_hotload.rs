@@ -3,56 +3,155 @@ use std::{
     fs::File,
     io::{BufReader, Read},
     path::Path,
-    str::FromStr,
     sync::Arc,
     time::Duration,
 };
 
 use dashmap::DashMap;
 use serde::Deserialize;
-use tracing::warn;
+use tracing::{info, warn};
 use unleash_types::client_features::{
     ClientFeature, ClientFeatures, Strategy, Variant, WeightType,
 };
 use unleash_yggdrasil::EngineState;
 
-use crate::{cli::OfflineArgs, error::EdgeError, types::EdgeToken};
+use crate::{cli::OfflineArgs, error::EdgeError, tokens::cache_key, types::EdgeToken};
 
+/// Offline mode without tokens accepts any token, so made up tokens could otherwise grow the caches without bound
+pub(crate) const MAX_ACCEPTED_OFFLINE_TOKENS: usize = 1000;
+
+/// Reloads the bootstrap file every `reload_interval` seconds, if set, and whenever Edge receives a SIGHUP
 pub async fn start_hotload_loop(
+    token_cache: Arc<DashMap<String, EdgeToken>>,
     features_cache: Arc<DashMap<std::string::String, ClientFeatures>>,
     engine_cache: Arc<DashMap<std::string::String, EngineState>>,
     offline_args: OfflineArgs,
 ) {
-    let known_tokens = offline_args.tokens;
-    let bootstrap_path = offline_args.bootstrap_file;
+    let mut hangup = Hangup::new();
 
     loop {
         tokio::select! {
-            _ = tokio::time::sleep(Duration::from_secs(offline_args.reload_interval)) => {
-                let bootstrap = bootstrap_path.as_ref().map(|bootstrap_path|load_bootstrap(bootstrap_path));
-                match bootstrap {
-                    Some(Ok(bootstrap)) => {
-                        let edge_tokens: Vec<EdgeToken> = known_tokens
-                        .iter()
-                        .map(|token| EdgeToken::from_str(token).unwrap_or_else(|_| EdgeToken::offline_token(token)))
-                        .collect();
-
-                        for edge_token in edge_tokens {
-                            load_offline_engine_cache(&edge_token, features_cache.clone(), engine_cache.clone(), bootstrap.clone());
-                        }
-                    },
-                    Some(Err(e)) => {
-                        tracing::error!("Error loading bootstrap file: {:?}", e);
-                    }
-                    None => {
-                        tracing::debug!("No bootstrap file provided");
-                    }
-                };
+            _ = tokio::time::sleep(Duration::from_secs(offline_args.reload_interval)), if offline_args.reload_interval > 0 => {}
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading bootstrap file");
+            }
+        }
+        match offline_args.bootstrap_file.as_ref() {
+            Some(bootstrap_path) => {
+                reload_bootstrap(
+                    bootstrap_path,
+                    token_cache.clone(),
+                    features_cache.clone(),
+                    engine_cache.clone(),
+                )
+                .await
+            }
+            None => {
+                tracing::debug!("No bootstrap file provided");
+            }
+        };
+    }
+}
+
+/// Loads the bootstrap file again for every token Edge knows about. If the file can't be loaded, the features we have are kept
+pub(crate) async fn reload_bootstrap(
+    bootstrap_path: &Path,
+    token_cache: Arc<DashMap<String, EdgeToken>>,
+    features_cache: Arc<DashMap<String, ClientFeatures>>,
+    engine_cache: Arc<DashMap<String, EngineState>>,
+) {
+    match read_bootstrap(bootstrap_path).await {
+        Ok(bootstrap) => {
+            let edge_tokens: Vec<EdgeToken> = token_cache
+                .iter()
+                .map(|entry| entry.value().clone())
+                .collect();
+            for edge_token in edge_tokens {
+                load_offline_engine_cache(
+                    &edge_token,
+                    features_cache.clone(),
+                    engine_cache.clone(),
+                    bootstrap.clone(),
+                );
+            }
+        }
+        Err(e) => {
+            tracing::error!("Error loading bootstrap file: {:?}", e);
+        }
+    }
+}
+
+/// When offline mode is started without tokens, any token is accepted. The first time we see a token for an environment we
+/// load the bootstrap file for it. Once [`MAX_ACCEPTED_OFFLINE_TOKENS`] tokens are known, new ones are refused
+pub(crate) async fn accept_any_offline_token(
+    offline_args: &OfflineArgs,
+    edge_token: &EdgeToken,
+    token_cache: Arc<DashMap<String, EdgeToken>>,
+    features_cache: Arc<DashMap<String, ClientFeatures>>,
+    engine_cache: Arc<DashMap<String, EngineState>>,
+) -> bool {
+    if !offline_args.tokens.is_empty() {
+        return false;
+    }
+    let Some(bootstrap_path) = offline_args.bootstrap_file.as_ref() else {
+        return false;
+    };
+    if token_cache.len() >= MAX_ACCEPTED_OFFLINE_TOKENS {
+        warn!("Already accepting {MAX_ACCEPTED_OFFLINE_TOKENS} tokens in offline mode, refusing new ones");
+        return false;
+    }
+    if !features_cache.contains_key(&cache_key(edge_token)) {
+        match read_bootstrap(bootstrap_path).await {
+            Ok(bootstrap) => {
+                load_offline_engine_cache(edge_token, features_cache, engine_cache, bootstrap)
+            }
+            Err(e) => {
+                tracing::error!("Error loading bootstrap file: {:?}", e);
+                return false;
+            }
+        }
+    }
+    token_cache.insert(edge_token.token.clone(), edge_token.clone());
+    true
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        let signal =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).map_err(|e| {
+                warn!("Could not listen for SIGHUP, the bootstrap file will only be reloaded on the reload interval: {e:?}");
+            });
+        Hangup(signal.ok())
+    }
+
+    async fn recv(&mut self) {
+        match self.0.as_mut() {
+            Some(signal) => {
+                signal.recv().await;
             }
+            None => std::future::pending().await,
         }
     }
 }
 
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Hangup
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
 pub(crate) fn load_offline_engine_cache(
     edge_token: &EdgeToken,
     features_cache: Arc<DashMap<String, ClientFeatures>>,
@@ -133,6 +232,18 @@ pub(crate) fn load_bootstrap(bootstrap_path: &Path) -> Result<ClientFeatures, Ed
     })
 }
 
+/// Like [`load_bootstrap`], without blocking the runtime, for reloads while serving
+async fn read_bootstrap(bootstrap_path: &Path) -> Result<ClientFeatures, EdgeError> {
+    let content = tokio::fs::read_to_string(bootstrap_path)
+        .await
+        .map_err(|_| EdgeError::NoFeaturesFile)?;
+
+    parse_bootstrap(content).map_err(|e| {
+        let path = format!("{}", bootstrap_path.to_path_buf().display());
+        EdgeError::InvalidBackupFile(path, e.to_string())
+    })
+}
+
 fn parse_bootstrap(content: String) -> Result<ClientFeatures, serde_json::Error> {
     let client_features: Result<ClientFeatures, serde_json::Error> =
         serde_json::from_str::<HashMap<String, SimpleFeature>>(&content)
@@ -144,7 +255,17 @@ fn parse_bootstrap(content: String) -> Result<ClientFeatures, serde_json::Error>
 
 #[cfg(test)]
 mod tests {
-    use super::parse_bootstrap;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use dashmap::DashMap;
+
+    use super::{
+        accept_any_offline_token, parse_bootstrap, reload_bootstrap, MAX_ACCEPTED_OFFLINE_TOKENS,
+    };
+    use crate::cli::OfflineArgs;
+    use crate::tokens::cache_key;
+    use crate::types::EdgeToken;
 
     #[test]
     fn loads_simple_bootstrap_format() {
@@ -219,4 +340,106 @@ mod tests {
           }"#;
         parse_bootstrap(simple_bootstrap.to_string()).unwrap();
     }
+
+    #[tokio::test]
+    async fn reloading_picks_up_changes_to_the_bootstrap_file_for_known_tokens() {
+        let path = std::env::temp_dir().join("reloading_picks_up_changes_to_the_bootstrap_file");
+        std::fs::write(&path, r#"{ "feature1": { "enabled": true } }"#).unwrap();
+        let token_cache = Arc::new(DashMap::default());
+        let features_cache = Arc::new(DashMap::default());
+        let engine_cache = Arc::new(DashMap::default());
+        let token = EdgeToken::offline_token("*:development.secret");
+        token_cache.insert(token.token.clone(), token.clone());
+
+        reload_bootstrap(
+            &path,
+            token_cache.clone(),
+            features_cache.clone(),
+            engine_cache.clone(),
+        )
+        .await;
+        assert!(features_cache.get(&cache_key(&token)).unwrap().features[0].enabled);
+
+        std::fs::write(&path, r#"{ "feature1": { "enabled": false } }"#).unwrap();
+        reload_bootstrap(
+            &path,
+            token_cache.clone(),
+            features_cache.clone(),
+            engine_cache.clone(),
+        )
+        .await;
+        assert!(!features_cache.get(&cache_key(&token)).unwrap().features[0].enabled);
+
+        std::fs::write(&path, "not json").unwrap();
+        reload_bootstrap(&path, token_cache, features_cache.clone(), engine_cache).await;
+        assert!(!features_cache.get(&cache_key(&token)).unwrap().features[0].enabled);
+    }
+
+    #[tokio::test]
+    async fn only_accepts_any_token_when_started_without_tokens() {
+        let token = EdgeToken::offline_token("some-token");
+        let args = |tokens: Vec<String>| OfflineArgs {
+            bootstrap_file: Some("../examples/features.json".into()),
+            tokens,
+            reload_interval: 0,
+        };
+        let token_cache = Arc::new(DashMap::default());
+        let features_cache = Arc::new(DashMap::default());
+        let engine_cache = Arc::new(DashMap::default());
+
+        assert!(
+            !accept_any_offline_token(
+                &args(vec!["known-token".into()]),
+                &token,
+                token_cache.clone(),
+                features_cache.clone(),
+                engine_cache.clone(),
+            )
+            .await
+        );
+        assert!(token_cache.is_empty());
+
+        assert!(
+            accept_any_offline_token(
+                &args(vec![]),
+                &token,
+                token_cache.clone(),
+                features_cache.clone(),
+                engine_cache.clone(),
+            )
+            .await
+        );
+        assert!(token_cache.contains_key(&token.token));
+        assert!(features_cache.contains_key(&cache_key(&token)));
+        assert!(engine_cache.contains_key(&cache_key(&token)));
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_new_tokens_once_the_cap_is_reached() {
+        let args = OfflineArgs {
+            bootstrap_file: Some("../examples/features.json".into()),
+            tokens: vec![],
+            reload_interval: 0,
+        };
+        let token_cache = Arc::new(DashMap::default());
+        let features_cache = Arc::new(DashMap::default());
+        let engine_cache = Arc::new(DashMap::default());
+        for i in 0..MAX_ACCEPTED_OFFLINE_TOKENS {
+            let token = EdgeToken::from_str(&format!("*:development.secret{i}")).unwrap();
+            token_cache.insert(token.token.clone(), token);
+        }
+
+        assert!(
+            !accept_any_offline_token(
+                &args,
+                &EdgeToken::offline_token("one-too-many"),
+                token_cache.clone(),
+                features_cache.clone(),
+                engine_cache,
+            )
+            .await
+        );
+        assert_eq!(token_cache.len(), MAX_ACCEPTED_OFFLINE_TOKENS);
+        assert!(features_cache.is_empty());
+    }
 }
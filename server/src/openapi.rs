@@ -1,7 +1,12 @@
+use actix_web::web;
 use utoipa::{
-    openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        Server,
+    },
     Modify, OpenApi,
 };
+use utoipa_swagger_ui::{Config, SwaggerUi};
 
 #[derive(OpenApi)]
 #[openapi(
@@ -23,6 +28,7 @@ use utoipa::{
         crate::frontend_api::post_proxy_metrics,
         crate::frontend_api::post_frontend_evaluate_single_feature,
         crate::frontend_api::get_frontend_evaluate_single_feature,
+        crate::frontend_api::stream_frontend_features,
         crate::client_api::get_features,
         crate::client_api::register,
         crate::client_api::metrics,
@@ -30,9 +36,9 @@ use utoipa::{
         crate::edge_api::validate,
     ),
     components(schemas(
-        unleash_types::frontend::FrontendResult,
-        unleash_types::frontend::EvaluatedToggle,
-        unleash_types::frontend::EvaluatedVariant,
+        crate::types::FrontendResult,
+        crate::types::EvaluatedToggle,
+        crate::types::EvaluatedVariant,
         unleash_types::client_features::Payload,
         unleash_types::client_features::ClientFeatures,
         unleash_types::client_features::Context,
@@ -51,10 +57,12 @@ use utoipa::{
         unleash_types::client_metrics::ConnectVia,
         crate::types::TokenStrings,
         crate::types::ValidatedTokens,
+        crate::types::ValidateTokensBody,
         crate::types::BatchMetricsRequestBody,
         crate::types::EdgeToken,
         crate::types::TokenValidationStatus,
-        crate::types::TokenType
+        crate::types::TokenType,
+        crate::error::ErrorResponse
     )),
     modifiers(&SecurityAddon)
 )]
@@ -71,3 +79,123 @@ impl Modify for SecurityAddon {
         )
     }
 }
+
+/// The server url is the public url when one is configured, otherwise the base path. The document lists it as the server
+/// so requests made from Swagger UI go there. Without either, they go to wherever the document was loaded from
+pub fn api_doc(server_url: &str) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    if !server_url.is_empty() {
+        openapi.servers = Some(vec![Server::new(server_url)]);
+    }
+    openapi
+}
+
+/// Needs to be mounted inside the base path scope. Swagger UI fetches the document from an absolute url, so that one includes the base path
+pub fn swagger_ui(server_url: &str) -> SwaggerUi {
+    SwaggerUi::new("/swagger-ui/{_:.*}")
+        .url("/api-doc/openapi.json", api_doc(server_url))
+        .config(Config::new([format!("{server_url}/api-doc/openapi.json")]))
+}
+
+pub fn configure_swagger_ui(cfg: &mut web::ServiceConfig, server_url: &str, disable_openapi: bool) {
+    if !disable_openapi {
+        cfg.service(swagger_ui(server_url));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{
+        call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest,
+    };
+    use actix_web::{web, App, HttpResponse};
+    use serde_json::Value;
+
+    use super::{configure_swagger_ui, swagger_ui};
+
+    #[actix_web::test]
+    async fn swagger_ui_and_api_doc_include_the_base_path() {
+        let app = init_service(
+            App::new().service(web::scope("/feature-edge").service(swagger_ui("/feature-edge"))),
+        )
+        .await;
+
+        let doc: Value = call_and_read_body_json(
+            &app,
+            TestRequest::get()
+                .uri("/feature-edge/api-doc/openapi.json")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(doc["servers"][0]["url"], "/feature-edge");
+
+        let initializer = call_and_read_body(
+            &app,
+            TestRequest::get()
+                .uri("/feature-edge/swagger-ui/swagger-initializer.js")
+                .to_request(),
+        )
+        .await;
+        assert!(
+            String::from_utf8_lossy(&initializer).contains("/feature-edge/api-doc/openapi.json")
+        );
+    }
+
+    #[actix_web::test]
+    async fn api_doc_lists_the_public_url_as_the_server() {
+        let public_url = "https://edge.example.com/feature-edge";
+        let app = init_service(
+            App::new().service(web::scope("/feature-edge").service(swagger_ui(public_url))),
+        )
+        .await;
+
+        let doc: Value = call_and_read_body_json(
+            &app,
+            TestRequest::get()
+                .uri("/feature-edge/api-doc/openapi.json")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(doc["servers"][0]["url"], public_url);
+
+        let initializer = call_and_read_body(
+            &app,
+            TestRequest::get()
+                .uri("/feature-edge/swagger-ui/swagger-initializer.js")
+                .to_request(),
+        )
+        .await;
+        assert!(String::from_utf8_lossy(&initializer)
+            .contains("https://edge.example.com/feature-edge/api-doc/openapi.json"));
+    }
+
+    #[actix_web::test]
+    async fn api_doc_has_no_servers_without_a_base_path() {
+        let app = init_service(App::new().service(web::scope("").service(swagger_ui("")))).await;
+
+        let doc: Value = call_and_read_body_json(
+            &app,
+            TestRequest::get().uri("/api-doc/openapi.json").to_request(),
+        )
+        .await;
+        assert!(doc.get("servers").is_none());
+    }
+
+    #[actix_web::test]
+    async fn disabling_openapi_serves_neither_the_document_nor_swagger_ui() {
+        let app = init_service(
+            App::new()
+                .configure(|cfg| configure_swagger_ui(cfg, "", true))
+                .route("/health", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for uri in ["/api-doc/openapi.json", "/swagger-ui/index.html"] {
+            let res = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        let res = call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -3,11 +3,14 @@ use std::path::Path;
 use std::{path::PathBuf, str::FromStr};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use unleash_types::client_features::ClientFeatures;
 
-use crate::types::EdgeToken;
+use crate::feature_overrides::FeatureOverride;
+use crate::metrics::client_metrics::MetricsBatch;
+use crate::types::{EdgeToken, TokenRefresh};
 use crate::{error::EdgeError, types::EdgeResult};
 
 use super::EdgePersistence;
@@ -47,12 +50,44 @@ impl FilePersister {
         refresh_target_path
     }
 
+    pub fn revoked_tokens_path(&self) -> PathBuf {
+        let mut revoked_tokens_path = self.storage_path.clone();
+        revoked_tokens_path.push("unleash_revoked_tokens.json");
+        revoked_tokens_path
+    }
+
+    pub fn feature_overrides_path(&self) -> PathBuf {
+        let mut feature_overrides_path = self.storage_path.clone();
+        feature_overrides_path.push("unleash_feature_overrides.json");
+        feature_overrides_path
+    }
+
+    pub fn metrics_path(&self) -> PathBuf {
+        let mut metrics_path = self.storage_path.clone();
+        metrics_path.push("unleash_metrics.json");
+        metrics_path
+    }
+
     pub fn new(storage_path: &Path) -> Self {
         let _ = std::fs::create_dir_all(storage_path);
         FilePersister {
             storage_path: storage_path.to_path_buf(),
         }
     }
+
+    /// Writes and removes a probe file, so a folder we can't persist to is caught at startup instead of on every save
+    pub fn check_writable(&self) -> EdgeResult<()> {
+        let mut probe_path = self.storage_path.clone();
+        probe_path.push(".unleash_edge_write_check");
+        std::fs::write(&probe_path, b"")
+            .and_then(|_| std::fs::remove_file(&probe_path))
+            .map_err(|e| {
+                EdgeError::PersistenceError(format!(
+                    "Could not write to backup folder {}: {e}",
+                    self.storage_path.display()
+                ))
+            })
+    }
 }
 
 #[async_trait]
@@ -133,13 +168,211 @@ impl EdgePersistence for FilePersister {
                         .to_string(),
                 )
             })?;
+        file.write_all(&serde_json::to_vec(&features).map_err(|_| {
+            EdgeError::PersistenceError("Failed to serialize features".to_string())
+        })?)
+        .await
+        .map_err(|_| {
+            EdgeError::PersistenceError("Could not serialize tokens to disc".to_string())
+        })?;
+        // The file's modification time is when the features were persisted, so the write mustn't still be in flight
+        // once we return
+        file.flush().await.map_err(|_| {
+            EdgeError::PersistenceError("Could not flush features to disc".to_string())
+        })
+    }
+
+    /// The backup file is written as a whole, so every environment in it is as old as the file
+    async fn load_features_persisted_at(&self) -> EdgeResult<HashMap<String, DateTime<Utc>>> {
+        let modified = tokio::fs::metadata(self.features_path())
+            .await
+            .and_then(|metadata| metadata.modified())
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot tell when features were backed up, reading backup file metadata failed"
+                        .to_string(),
+                )
+            })?;
+        let persisted_at = DateTime::<Utc>::from(modified);
+        Ok(self
+            .load_features()
+            .await?
+            .into_keys()
+            .map(|environment| (environment, persisted_at))
+            .collect())
+    }
+
+    async fn load_refresh_targets(&self) -> EdgeResult<Vec<TokenRefresh>> {
+        let mut file = tokio::fs::File::open(self.refresh_target_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot load refresh targets from backup, opening backup file failed"
+                        .to_string(),
+                )
+            })?;
+
+        let mut contents = vec![];
+
+        file.read_to_end(&mut contents).await.map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load refresh targets from backup, reading backup file failed".to_string(),
+            )
+        })?;
+        serde_json::from_slice(&contents).map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load refresh targets from backup, parsing backup file failed".to_string(),
+            )
+        })
+    }
+
+    async fn save_refresh_targets(&self, refresh_targets: Vec<TokenRefresh>) -> EdgeResult<()> {
+        let mut file = tokio::fs::File::create(self.refresh_target_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot write refresh targets to backup. Opening backup file for writing failed"
+                        .to_string(),
+                )
+            })?;
+        file.write_all(&serde_json::to_vec(&refresh_targets).map_err(|_| {
+            EdgeError::PersistenceError("Failed to serialize refresh targets".to_string())
+        })?)
+        .await
+        .map_err(|_| {
+            EdgeError::PersistenceError("Could not serialize refresh targets to disc".to_string())
+        })
+        .map(|_| ())
+    }
+
+    async fn load_revoked_tokens(&self) -> EdgeResult<Vec<String>> {
+        let mut file = tokio::fs::File::open(self.revoked_tokens_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot load revoked tokens from backup, opening backup file failed"
+                        .to_string(),
+                )
+            })?;
+
+        let mut contents = vec![];
+
+        file.read_to_end(&mut contents).await.map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load revoked tokens from backup, reading backup file failed".to_string(),
+            )
+        })?;
+        serde_json::from_slice(&contents).map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load revoked tokens from backup, parsing backup file failed".to_string(),
+            )
+        })
+    }
+
+    async fn save_revoked_tokens(&self, revoked_tokens: Vec<String>) -> EdgeResult<()> {
+        let mut file = tokio::fs::File::create(self.revoked_tokens_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot write revoked tokens to backup. Opening backup file for writing failed"
+                        .to_string(),
+                )
+            })?;
+        file.write_all(&serde_json::to_vec(&revoked_tokens).map_err(|_| {
+            EdgeError::PersistenceError("Failed to serialize revoked tokens".to_string())
+        })?)
+        .await
+        .map_err(|_| {
+            EdgeError::PersistenceError("Could not serialize revoked tokens to disc".to_string())
+        })
+        .map(|_| ())
+    }
+
+    async fn load_feature_overrides(&self) -> EdgeResult<Vec<FeatureOverride>> {
+        let mut file = match tokio::fs::File::open(self.feature_overrides_path()).await {
+            Ok(file) => file,
+            // Nothing has been overridden yet, like redis and postgres without the key
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(_) => {
+                return Err(EdgeError::PersistenceError(
+                    "Cannot load feature overrides from backup, opening backup file failed"
+                        .to_string(),
+                ))
+            }
+        };
+
+        let mut contents = vec![];
+
+        file.read_to_end(&mut contents).await.map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load feature overrides from backup, reading backup file failed".to_string(),
+            )
+        })?;
+        serde_json::from_slice(&contents).map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load feature overrides from backup, parsing backup file failed".to_string(),
+            )
+        })
+    }
+
+    async fn save_feature_overrides(&self, overrides: Vec<FeatureOverride>) -> EdgeResult<()> {
+        let mut file = tokio::fs::File::create(self.feature_overrides_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot write feature overrides to backup. Opening backup file for writing failed"
+                        .to_string(),
+                )
+            })?;
+        file.write_all(&serde_json::to_vec(&overrides).map_err(|_| {
+            EdgeError::PersistenceError("Failed to serialize feature overrides".to_string())
+        })?)
+        .await
+        .map_err(|_| {
+            EdgeError::PersistenceError("Could not serialize feature overrides to disc".to_string())
+        })
+        .map(|_| ())
+    }
+
+    async fn load_metrics(&self) -> EdgeResult<MetricsBatch> {
+        let mut file = tokio::fs::File::open(self.metrics_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot load metrics from backup, opening backup file failed".to_string(),
+                )
+            })?;
+
+        let mut contents = vec![];
+
+        file.read_to_end(&mut contents).await.map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load metrics from backup, reading backup file failed".to_string(),
+            )
+        })?;
+        serde_json::from_slice(&contents).map_err(|_| {
+            EdgeError::PersistenceError(
+                "Cannot load metrics from backup, parsing backup file failed".to_string(),
+            )
+        })
+    }
+
+    async fn save_metrics(&self, metrics: MetricsBatch) -> EdgeResult<()> {
+        let mut file = tokio::fs::File::create(self.metrics_path())
+            .await
+            .map_err(|_| {
+                EdgeError::PersistenceError(
+                    "Cannot write metrics to backup. Opening backup file for writing failed"
+                        .to_string(),
+                )
+            })?;
         file.write_all(
-            &serde_json::to_vec(&features).map_err(|_| {
-                EdgeError::PersistenceError("Failed to serialize features".to_string())
+            &serde_json::to_vec(&metrics).map_err(|_| {
+                EdgeError::PersistenceError("Failed to serialize metrics".to_string())
             })?,
         )
         .await
-        .map_err(|_| EdgeError::PersistenceError("Could not serialize tokens to disc".to_string()))
+        .map_err(|_| EdgeError::PersistenceError("Could not serialize metrics to disc".to_string()))
         .map(|_| ())
     }
 }
@@ -148,11 +381,16 @@ impl EdgePersistence for FilePersister {
 mod tests {
     use std::env::temp_dir;
 
+    use actix_web::http::header::EntityTag;
+    use chrono::Utc;
     use unleash_types::client_features::{ClientFeature, ClientFeatures};
+    use unleash_types::client_metrics::{ClientMetricsEnv, MetricsMetadata};
 
+    use crate::feature_overrides::FeatureOverride;
+    use crate::metrics::client_metrics::MetricsBatch;
     use crate::persistence::file::FilePersister;
     use crate::persistence::EdgePersistence;
-    use crate::types::{EdgeToken, TokenType, TokenValidationStatus};
+    use crate::types::{EdgeToken, TokenRefresh, TokenType, TokenValidationStatus};
 
     #[tokio::test]
     async fn file_persister_can_save_and_load_features() {
@@ -216,4 +454,108 @@ mod tests {
 
         assert_eq!(reloaded, tokens);
     }
+
+    #[tokio::test]
+    async fn file_persister_can_save_and_load_refresh_targets_with_etags() {
+        let persister = FilePersister::try_from(temp_dir().to_str().unwrap()).unwrap();
+        let refresh_targets = vec![TokenRefresh::new(
+            EdgeToken {
+                token: "*:development.ajsdkajnsdlsan".into(),
+                token_type: Some(TokenType::Client),
+                environment: Some("development".into()),
+                projects: vec!["*".into()],
+                status: TokenValidationStatus::Validated,
+            },
+            Some(EntityTag::new_weak("1d-2a7f".into())),
+        )];
+
+        persister
+            .save_refresh_targets(refresh_targets.clone())
+            .await
+            .unwrap();
+
+        let reloaded = persister.load_refresh_targets().await.unwrap();
+
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].token, refresh_targets[0].token);
+        assert_eq!(reloaded[0].etag, refresh_targets[0].etag);
+    }
+
+    #[tokio::test]
+    async fn file_persister_can_save_and_load_revoked_tokens() {
+        let persister = FilePersister::try_from(temp_dir().to_str().unwrap()).unwrap();
+        let revoked_tokens = vec!["*:development.leakedsecret".to_string()];
+
+        persister
+            .save_revoked_tokens(revoked_tokens.clone())
+            .await
+            .unwrap();
+
+        let reloaded = persister.load_revoked_tokens().await.unwrap();
+
+        assert_eq!(reloaded, revoked_tokens);
+    }
+
+    #[tokio::test]
+    async fn file_persister_can_save_and_load_feature_overrides() {
+        let persister = FilePersister::try_from(temp_dir().to_str().unwrap()).unwrap();
+        let overrides = vec![FeatureOverride {
+            environment: "development".into(),
+            feature: "checkout".into(),
+            enabled: true,
+            variant: Some("new-flow".into()),
+        }];
+
+        persister
+            .save_feature_overrides(overrides.clone())
+            .await
+            .unwrap();
+
+        let reloaded = persister.load_feature_overrides().await.unwrap();
+
+        assert_eq!(reloaded, overrides);
+    }
+
+    #[tokio::test]
+    async fn file_persister_without_saved_feature_overrides_loads_none() {
+        let storage_path = temp_dir().join(format!("unleash-edge-overrides-{}", ulid::Ulid::new()));
+        let persister = FilePersister::new(&storage_path);
+
+        let reloaded = persister.load_feature_overrides().await.unwrap();
+
+        assert!(reloaded.is_empty());
+        let _ = std::fs::remove_dir_all(storage_path);
+    }
+
+    #[tokio::test]
+    async fn file_persister_can_save_and_load_metrics() {
+        let persister = FilePersister::try_from(temp_dir().to_str().unwrap()).unwrap();
+        let metrics = MetricsBatch {
+            applications: vec![],
+            metrics: vec![ClientMetricsEnv {
+                app_name: "some-app".into(),
+                feature_name: "some-feature".into(),
+                environment: "development".into(),
+                timestamp: Utc::now(),
+                yes: 3,
+                no: 1,
+                variants: Default::default(),
+                metadata: MetricsMetadata {
+                    platform_name: None,
+                    platform_version: None,
+                    sdk_version: None,
+                    yggdrasil_version: None,
+                },
+            }],
+        };
+
+        persister.save_metrics(metrics.clone()).await.unwrap();
+
+        let reloaded = persister.load_metrics().await.unwrap();
+
+        assert_eq!(
+            serde_json::to_value(reloaded).unwrap(),
+            serde_json::to_value(metrics).unwrap()
+        );
+    }
 }
@@ -1,21 +1,57 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use tracing::{debug, warn};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec, Opts};
+use tracing::{debug, info, warn};
 use unleash_types::client_features::ClientFeatures;
+use unleash_yggdrasil::EngineState;
 
-use crate::types::{EdgeResult, EdgeToken, TokenValidationStatus};
+use crate::feature_overrides::FeatureOverride;
+use crate::http::refresh_shard::RefreshShard;
+use crate::metrics::client_metrics::MetricsBatch;
+use crate::types::{EdgeResult, EdgeToken, TokenRefresh, TokenValidationStatus};
 
 pub mod file;
+pub mod postgres;
 pub mod redis;
 
+lazy_static! {
+    pub static ref PERSISTENCE_MISMATCHES: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "persistence_integrity_mismatches",
+            "Tokens and environments found to differ between the caches and persistence by the integrity check"
+        ),
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// How often the caches are persisted
+pub const PERSIST_INTERVAL_SECONDS: u64 = 60;
+
 #[async_trait]
 pub trait EdgePersistence: Send + Sync {
     async fn load_tokens(&self) -> EdgeResult<Vec<EdgeToken>>;
     async fn save_tokens(&self, tokens: Vec<EdgeToken>) -> EdgeResult<()>;
     async fn load_features(&self) -> EdgeResult<HashMap<String, ClientFeatures>>;
     async fn save_features(&self, features: Vec<(String, ClientFeatures)>) -> EdgeResult<()>;
+    /// When the features of each environment were last saved. Environments we can't tell the age of are left out
+    async fn load_features_persisted_at(&self) -> EdgeResult<HashMap<String, DateTime<Utc>>>;
+    async fn load_refresh_targets(&self) -> EdgeResult<Vec<TokenRefresh>>;
+    async fn save_refresh_targets(&self, refresh_targets: Vec<TokenRefresh>) -> EdgeResult<()>;
+    async fn load_revoked_tokens(&self) -> EdgeResult<Vec<String>>;
+    async fn save_revoked_tokens(&self, revoked_tokens: Vec<String>) -> EdgeResult<()>;
+    async fn load_feature_overrides(&self) -> EdgeResult<Vec<FeatureOverride>>;
+    async fn save_feature_overrides(&self, overrides: Vec<FeatureOverride>) -> EdgeResult<()>;
+    async fn load_metrics(&self) -> EdgeResult<MetricsBatch>;
+    async fn save_metrics(&self, metrics: MetricsBatch) -> EdgeResult<()>;
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -23,14 +59,17 @@ pub async fn persist_data(
     persistence: Option<Arc<dyn EdgePersistence>>,
     token_cache: Arc<DashMap<String, EdgeToken>>,
     features_cache: Arc<DashMap<String, ClientFeatures>>,
+    refresh_targets: Arc<DashMap<String, TokenRefresh>>,
+    refresh_shard: Option<RefreshShard>,
 ) {
     loop {
         tokio::select! {
-            _ = tokio::time::sleep(Duration::from_secs(60)) => {
+            _ = tokio::time::sleep(Duration::from_secs(PERSIST_INTERVAL_SECONDS)) => {
                 if let Some(persister) = persistence.clone() {
 
                     save_known_tokens(&token_cache, &persister).await;
-                    save_features(&features_cache, &persister).await;
+                    save_features(&features_cache, refresh_shard, &persister).await;
+                    save_refresh_targets(&refresh_targets, &persister).await;
                 } else {
                     debug!("No persistence configured, skipping persistence");
                 }
@@ -39,8 +78,178 @@ pub async fn persist_data(
     }
 }
 
+/// What the integrity check found in the caches that persistence doesn't have the same
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PersistenceMismatches {
+    /// Validated tokens in the token cache that aren't persisted, and persisted tokens the cache no longer has validated
+    pub tokens: usize,
+    /// Environments whose cached features aren't persisted, are persisted differently, or are only persisted
+    pub environments: Vec<String>,
+}
+
+impl PersistenceMismatches {
+    pub fn is_empty(&self) -> bool {
+        self.tokens == 0 && self.environments.is_empty()
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+pub async fn check_persistence_integrity(
+    persistence: Option<Arc<dyn EdgePersistence>>,
+    token_cache: Arc<DashMap<String, EdgeToken>>,
+    features_cache: Arc<DashMap<String, ClientFeatures>>,
+    refresh_shard: Option<RefreshShard>,
+    interval: Option<Duration>,
+    repair: bool,
+) {
+    let Some(interval) = interval else {
+        return std::future::pending().await;
+    };
+    let Some(persistence) = persistence else {
+        warn!("No persistence configured, so there's nothing to check the caches against");
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        let mismatches =
+            compare_with_persistence(&persistence, &token_cache, &features_cache, refresh_shard)
+                .await;
+        if repair && !mismatches.is_empty() {
+            info!("Re-persisting tokens and features to repair the mismatches");
+            save_known_tokens(&token_cache, &persistence).await;
+            save_features(&features_cache, refresh_shard, &persistence).await;
+        }
+    }
+}
+
+/// Compares what the persister would save, validated tokens and the features of the environments this shard owns,
+/// against what persistence loads, both what the caches have that isn't persisted and what's persisted that the caches no
+/// longer have. Persisting happens once a minute, so changes made since are reported until the next save. Mismatches
+/// that last longer mean persistence isn't keeping up with the caches
+pub async fn compare_with_persistence(
+    persistence: &Arc<dyn EdgePersistence>,
+    token_cache: &DashMap<String, EdgeToken>,
+    features_cache: &DashMap<String, ClientFeatures>,
+    refresh_shard: Option<RefreshShard>,
+) -> PersistenceMismatches {
+    let mut mismatches = PersistenceMismatches::default();
+    match persistence.load_tokens().await {
+        Ok(tokens) => {
+            let persisted: HashSet<String> = tokens.into_iter().map(|t| t.token).collect();
+            let cached: HashSet<String> = token_cache
+                .iter()
+                .filter(|t| t.value().status == TokenValidationStatus::Validated)
+                .map(|t| t.key().clone())
+                .collect();
+            mismatches.tokens = cached.symmetric_difference(&persisted).count();
+        }
+        Err(load_error) => {
+            warn!("Could not load tokens to check against the cache: {load_error:?}")
+        }
+    }
+    match persistence.load_features().await {
+        Ok(persisted) => {
+            let cached: HashMap<String, ClientFeatures> =
+                features_to_persist(features_cache, refresh_shard)
+                    .into_iter()
+                    .collect();
+            let only_persisted = persisted.keys().filter(|key| {
+                !cached.contains_key(*key) && refresh_shard.is_none_or(|shard| shard.owns_key(key))
+            });
+            mismatches.environments = cached
+                .iter()
+                .filter(|(key, features)| persisted.get(*key) != Some(*features))
+                .map(|(key, _)| key)
+                .chain(only_persisted)
+                .cloned()
+                .collect();
+            mismatches.environments.sort();
+        }
+        Err(load_error) => {
+            warn!("Could not load features to check against the cache: {load_error:?}")
+        }
+    }
+    if mismatches.tokens > 0 {
+        warn!(
+            "{} validated tokens differ between the cache and persistence",
+            mismatches.tokens
+        );
+        PERSISTENCE_MISMATCHES
+            .with_label_values(&["tokens"])
+            .inc_by(mismatches.tokens as u64);
+    }
+    if !mismatches.environments.is_empty() {
+        warn!(
+            "Cached features differ from the persisted ones for {:?}",
+            mismatches.environments
+        );
+        PERSISTENCE_MISMATCHES
+            .with_label_values(&["features"])
+            .inc_by(mismatches.environments.len() as u64);
+    }
+    mismatches
+}
+
+/// Read replicas never talk to upstream, they serve what the edge nodes persisted and pick up their changes on an interval
+#[cfg(not(tarpaulin_include))]
+pub async fn reload_from_persistence(
+    persistence: Arc<dyn EdgePersistence>,
+    token_cache: Arc<DashMap<String, EdgeToken>>,
+    features_cache: Arc<DashMap<String, ClientFeatures>>,
+    engine_cache: Arc<DashMap<String, EngineState>>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        reload_persisted_data(&persistence, &token_cache, &features_cache, &engine_cache).await;
+    }
+}
+
+/// Tokens and environments that are no longer persisted are dropped, so a token revoked on an edge node is refused by the
+/// replicas as well. Engines are only rebuilt for environments whose features changed. Whatever can't be loaded is kept
+/// as it was
+pub async fn reload_persisted_data(
+    persistence: &Arc<dyn EdgePersistence>,
+    token_cache: &DashMap<String, EdgeToken>,
+    features_cache: &DashMap<String, ClientFeatures>,
+    engine_cache: &DashMap<String, EngineState>,
+) {
+    match persistence.load_tokens().await {
+        Ok(tokens) => {
+            let persisted: HashSet<String> = tokens.iter().map(|t| t.token.clone()).collect();
+            token_cache.retain(|token, _| persisted.contains(token));
+            for token in tokens {
+                token_cache.insert(token.token.clone(), token);
+            }
+        }
+        Err(load_error) => warn!("Could not reload tokens from persistence: {load_error:?}"),
+    }
+    match persistence.load_features().await {
+        Ok(features) => {
+            features_cache.retain(|key, _| features.contains_key(key));
+            engine_cache.retain(|key, _| features.contains_key(key));
+            for (key, features) in features {
+                if features_cache
+                    .get(&key)
+                    .is_some_and(|cached| *cached.value() == features)
+                {
+                    continue;
+                }
+                debug!("Reloaded changed features for {key:?}");
+                let mut engine_state = EngineState::default();
+                if let Some(warnings) = engine_state.take_state(features.clone()) {
+                    warn!("Failed to load reloaded features for {key:?}: {warnings:?}");
+                }
+                engine_cache.insert(key.clone(), engine_state);
+                features_cache.insert(key, features);
+            }
+        }
+        Err(load_error) => warn!("Could not reload features from persistence: {load_error:?}"),
+    }
+}
+
 async fn save_known_tokens(
-    token_cache: &Arc<DashMap<String, EdgeToken>>,
+    token_cache: &DashMap<String, EdgeToken>,
     persister: &Arc<dyn EdgePersistence>,
 ) {
     if !token_cache.is_empty() {
@@ -62,18 +271,26 @@ async fn save_known_tokens(
     }
 }
 
+/// When sharded, only the environments this shard refreshes from upstream are saved, so the database has a single writer for each
+pub fn features_to_persist(
+    features_cache: &DashMap<String, ClientFeatures>,
+    refresh_shard: Option<RefreshShard>,
+) -> Vec<(String, ClientFeatures)> {
+    features_cache
+        .iter()
+        .filter(|e| refresh_shard.is_none_or(|shard| shard.owns_key(e.key())))
+        .map(|e| (e.key().clone(), e.value().clone()))
+        .collect()
+}
+
 async fn save_features(
-    features_cache: &Arc<DashMap<String, ClientFeatures>>,
+    features_cache: &DashMap<String, ClientFeatures>,
+    refresh_shard: Option<RefreshShard>,
     persister: &Arc<dyn EdgePersistence>,
 ) {
     if !features_cache.is_empty() {
         match persister
-            .save_features(
-                features_cache
-                    .iter()
-                    .map(|e| (e.key().clone(), e.value().clone()))
-                    .collect(),
-            )
+            .save_features(features_to_persist(features_cache, refresh_shard))
             .await
         {
             Ok(()) => debug!("Persisted features"),
@@ -84,6 +301,23 @@ async fn save_features(
     }
 }
 
+async fn save_refresh_targets(
+    refresh_targets: &Arc<DashMap<String, TokenRefresh>>,
+    persister: &Arc<dyn EdgePersistence>,
+) {
+    if !refresh_targets.is_empty() {
+        match persister
+            .save_refresh_targets(refresh_targets.iter().map(|e| e.value().clone()).collect())
+            .await
+        {
+            Ok(()) => debug!("Persisted refresh targets"),
+            Err(save_error) => warn!("Could not persist refresh targets: {save_error:?}"),
+        }
+    } else {
+        debug!("No refresh targets found, skipping refresh targets persistence");
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -111,6 +345,42 @@ pub mod tests {
         async fn save_features(&self, _: Vec<(String, ClientFeatures)>) -> EdgeResult<()> {
             panic!("Not expected to be called");
         }
+
+        async fn load_features_persisted_at(&self) -> EdgeResult<HashMap<String, DateTime<Utc>>> {
+            panic!("Not expected to be called");
+        }
+
+        async fn load_refresh_targets(&self) -> EdgeResult<Vec<TokenRefresh>> {
+            panic!("Not expected to be called");
+        }
+
+        async fn save_refresh_targets(&self, _: Vec<TokenRefresh>) -> EdgeResult<()> {
+            panic!("Not expected to be called");
+        }
+
+        async fn load_revoked_tokens(&self) -> EdgeResult<Vec<String>> {
+            panic!("Not expected to be called");
+        }
+
+        async fn save_revoked_tokens(&self, _: Vec<String>) -> EdgeResult<()> {
+            panic!("Not expected to be called");
+        }
+
+        async fn load_feature_overrides(&self) -> EdgeResult<Vec<FeatureOverride>> {
+            panic!("Not expected to be called");
+        }
+
+        async fn save_feature_overrides(&self, _: Vec<FeatureOverride>) -> EdgeResult<()> {
+            panic!("Not expected to be called");
+        }
+
+        async fn load_metrics(&self) -> EdgeResult<MetricsBatch> {
+            panic!("Not expected to be called");
+        }
+
+        async fn save_metrics(&self, _: MetricsBatch) -> EdgeResult<()> {
+            panic!("Not expected to be called");
+        }
     }
 
     #[tokio::test]
@@ -118,7 +388,7 @@ pub mod tests {
         let cache: DashMap<String, ClientFeatures> = DashMap::new();
         let persister = build_mock_persistence();
 
-        save_features(&Arc::new(cache), &persister.clone()).await;
+        save_features(&Arc::new(cache), None, &persister.clone()).await;
     }
 
     #[tokio::test]
@@ -128,4 +398,117 @@ pub mod tests {
 
         save_known_tokens(&Arc::new(cache), &persister.clone()).await;
     }
+
+    #[tokio::test]
+    async fn persistence_ignores_empty_refresh_targets() {
+        let cache: DashMap<String, TokenRefresh> = DashMap::new();
+        let persister = build_mock_persistence();
+
+        save_refresh_targets(&Arc::new(cache), &persister.clone()).await;
+    }
+
+    #[tokio::test]
+    async fn reloading_follows_what_edge_nodes_persisted() {
+        let persister: Arc<dyn EdgePersistence> = Arc::new(file::FilePersister::new(
+            &std::env::temp_dir().join("reloading_follows_what_edge_nodes_persisted"),
+        ));
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        let features = crate::tests::features_from_disk("../examples/features.json");
+        persister.save_tokens(vec![token.clone()]).await.unwrap();
+        persister
+            .save_features(vec![("development".into(), features.clone())])
+            .await
+            .unwrap();
+
+        let token_cache = DashMap::new();
+        let features_cache = DashMap::new();
+        let engine_cache = DashMap::new();
+        let revoked = EdgeToken::validated_client_token("*:development.revoked123");
+        token_cache.insert(revoked.token.clone(), revoked);
+        reload_persisted_data(&persister, &token_cache, &features_cache, &engine_cache).await;
+        assert_eq!(token_cache.len(), 1);
+        assert!(token_cache.contains_key(&token.token));
+        assert_eq!(*features_cache.get("development").unwrap(), features);
+        assert!(engine_cache.contains_key("development"));
+
+        let changed = ClientFeatures {
+            features: features.features[..1].to_vec(),
+            ..features
+        };
+        persister
+            .save_features(vec![("development".into(), changed.clone())])
+            .await
+            .unwrap();
+        reload_persisted_data(&persister, &token_cache, &features_cache, &engine_cache).await;
+        assert_eq!(*features_cache.get("development").unwrap(), changed);
+
+        persister
+            .save_features(vec![("production".into(), changed.clone())])
+            .await
+            .unwrap();
+        reload_persisted_data(&persister, &token_cache, &features_cache, &engine_cache).await;
+        assert!(!features_cache.contains_key("development"));
+        assert!(!engine_cache.contains_key("development"));
+        assert!(features_cache.contains_key("production"));
+    }
+
+    #[tokio::test]
+    async fn integrity_check_finds_what_persistence_does_not_have_until_it_is_persisted_again() {
+        let persister: Arc<dyn EdgePersistence> = Arc::new(file::FilePersister::new(
+            &std::env::temp_dir().join("integrity_check_finds_what_persistence_does_not_have"),
+        ));
+        let token = EdgeToken::validated_client_token("*:development.secret123");
+        let features = crate::tests::features_from_disk("../examples/features.json");
+        let token_cache = DashMap::new();
+        let features_cache = DashMap::new();
+        token_cache.insert(token.token.clone(), token.clone());
+        features_cache.insert("development".to_string(), features.clone());
+        save_known_tokens(&token_cache, &persister).await;
+        save_features(&features_cache, None, &persister).await;
+        assert!(
+            compare_with_persistence(&persister, &token_cache, &features_cache, None)
+                .await
+                .is_empty()
+        );
+
+        let other = EdgeToken::validated_client_token("*:production.secret123");
+        token_cache.insert(other.token.clone(), other);
+        features_cache.insert(
+            "development".to_string(),
+            ClientFeatures {
+                features: features.features[..1].to_vec(),
+                ..features.clone()
+            },
+        );
+        features_cache.insert("production".to_string(), features);
+        let mismatches =
+            compare_with_persistence(&persister, &token_cache, &features_cache, None).await;
+        assert_eq!(
+            mismatches,
+            PersistenceMismatches {
+                tokens: 1,
+                environments: vec!["development".into(), "production".into()],
+            }
+        );
+
+        save_known_tokens(&token_cache, &persister).await;
+        save_features(&features_cache, None, &persister).await;
+        assert!(
+            compare_with_persistence(&persister, &token_cache, &features_cache, None)
+                .await
+                .is_empty()
+        );
+
+        token_cache.remove("*:production.secret123");
+        features_cache.remove("production");
+        let mismatches =
+            compare_with_persistence(&persister, &token_cache, &features_cache, None).await;
+        assert_eq!(
+            mismatches,
+            PersistenceMismatches {
+                tokens: 1,
+                environments: vec!["production".into()],
+            }
+        );
+    }
 }
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Manager, ManagerConfig, Pool, PoolError, RecyclingMethod};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::{NoTls, Transaction};
+use tracing::{debug, info};
+use unleash_types::client_features::ClientFeatures;
+
+use crate::feature_overrides::FeatureOverride;
+use crate::metrics::client_metrics::MetricsBatch;
+use crate::types::{EdgeToken, TokenRefresh};
+use crate::{error::EdgeError, types::EdgeResult};
+
+use super::EdgePersistence;
+
+pub const REVOKED_TOKENS_KEY: &str = "unleash-revoked-tokens";
+pub const FEATURE_OVERRIDES_KEY: &str = "unleash-feature-overrides";
+pub const METRICS_KEY: &str = "unleash-metrics";
+/// Used unless the url sets connect_timeout, so an unreachable database can't hold up startup for long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run at startup. Every table is keyed, so replicas sharing a database upsert into each other's environments instead
+/// of replacing the whole table
+const MIGRATION: &str = "
+CREATE TABLE IF NOT EXISTS edge_tokens (
+    token TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS edge_features (
+    environment TEXT PRIMARY KEY,
+    features JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS edge_refresh_targets (
+    token TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE TABLE IF NOT EXISTS edge_state (
+    key TEXT PRIMARY KEY,
+    data JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+";
+
+impl From<tokio_postgres::Error> for EdgeError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        EdgeError::PersistenceError(format!("Error talking to Postgres: {err}"))
+    }
+}
+
+impl From<PoolError> for EdgeError {
+    fn from(err: PoolError) -> Self {
+        EdgeError::PersistenceError(format!("Error connecting to Postgres: {err}"))
+    }
+}
+
+fn from_jsonb<T: DeserializeOwned>(value: Value, what: &str) -> EdgeResult<T> {
+    serde_json::from_value(value).map_err(|e| {
+        EdgeError::PersistenceError(format!("Failed to load {what} from postgres: {e}"))
+    })
+}
+
+pub struct PostgresPersister {
+    pool: Pool,
+}
+
+impl PostgresPersister {
+    /// Connects and creates the tables Edge needs if they aren't there yet
+    pub async fn new(url: &str, pool_size: usize) -> EdgeResult<PostgresPersister> {
+        let mut config = url.parse::<tokio_postgres::Config>()?;
+        if config.get_connect_timeout().is_none() {
+            config.connect_timeout(CONNECT_TIMEOUT);
+        }
+        let manager_config = ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        };
+        let manager = if config.get_ssl_mode() == SslMode::Require {
+            let connector = TlsConnector::new().map_err(|e| {
+                EdgeError::PersistenceError(format!("Failed to set up TLS for postgres: {e}"))
+            })?;
+            Manager::from_config(config, MakeTlsConnector::new(connector), manager_config)
+        } else {
+            Manager::from_config(config, NoTls, manager_config)
+        };
+        let pool = Pool::builder(manager)
+            .max_size(pool_size)
+            .build()
+            .map_err(|e| {
+                EdgeError::PersistenceError(format!("Failed to build a postgres pool: {e}"))
+            })?;
+        pool.get().await?.batch_execute(MIGRATION).await?;
+        info!("[POSTGRES Persister]: Configured with a pool of up to {pool_size} connections");
+        Ok(Self { pool })
+    }
+
+    async fn load_state<T: DeserializeOwned + Default>(&self, key: &str) -> EdgeResult<T> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt("SELECT data FROM edge_state WHERE key = $1", &[&key])
+            .await?;
+        match row {
+            Some(row) => from_jsonb(row.get("data"), key),
+            None => Ok(T::default()),
+        }
+    }
+
+    async fn save_state<T: Serialize>(&self, key: &str, value: &T) -> EdgeResult<()> {
+        let data = serde_json::to_value(value)?;
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "INSERT INTO edge_state (key, data) VALUES ($1, $2)
+                 ON CONFLICT (key) DO UPDATE SET data = EXCLUDED.data, updated_at = now()",
+                &[&key, &data],
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+async fn upsert_all(
+    transaction: &Transaction<'_>,
+    statement: &str,
+    rows: Vec<(String, Value)>,
+) -> EdgeResult<()> {
+    let statement = transaction.prepare(statement).await?;
+    for (key, data) in rows {
+        transaction.execute(&statement, &[&key, &data]).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the rows that aren't saved again in the same transaction, so what Edge dropped, like evicted tokens,
+/// doesn't come back on the next restart
+async fn replace_all(
+    transaction: &Transaction<'_>,
+    delete_absent: &str,
+    upsert: &str,
+    rows: Vec<(String, Value)>,
+) -> EdgeResult<()> {
+    let keys: Vec<&str> = rows.iter().map(|(key, _)| key.as_str()).collect();
+    transaction.execute(delete_absent, &[&keys]).await?;
+    upsert_all(transaction, upsert, rows).await
+}
+
+#[async_trait]
+impl EdgePersistence for PostgresPersister {
+    async fn load_tokens(&self) -> EdgeResult<Vec<EdgeToken>> {
+        debug!("Loading tokens from persistence");
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query("SELECT data FROM edge_tokens", &[])
+            .await?;
+        rows.into_iter()
+            .map(|row| from_jsonb(row.get("data"), "tokens"))
+            .collect()
+    }
+
+    async fn save_tokens(&self, tokens: Vec<EdgeToken>) -> EdgeResult<()> {
+        debug!("Saving {} tokens to persistence", tokens.len());
+        let rows = tokens
+            .into_iter()
+            .map(|token| Ok((token.token.clone(), serde_json::to_value(&token)?)))
+            .collect::<EdgeResult<Vec<_>>>()?;
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        replace_all(
+            &transaction,
+            "DELETE FROM edge_tokens WHERE token <> ALL($1)",
+            "INSERT INTO edge_tokens (token, data) VALUES ($1, $2)
+             ON CONFLICT (token) DO UPDATE SET data = EXCLUDED.data, updated_at = now()",
+            rows,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn load_features(&self) -> EdgeResult<HashMap<String, ClientFeatures>> {
+        debug!("Loading features from persistence");
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query("SELECT environment, features FROM edge_features", &[])
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                from_jsonb(row.get("features"), "features")
+                    .map(|features| (row.get("environment"), features))
+            })
+            .collect()
+    }
+
+    async fn save_features(&self, features: Vec<(String, ClientFeatures)>) -> EdgeResult<()> {
+        debug!("Saving {} features to persistence", features.len());
+        let rows = features
+            .into_iter()
+            .map(|(environment, features)| Ok((environment, serde_json::to_value(&features)?)))
+            .collect::<EdgeResult<Vec<_>>>()?;
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        upsert_all(
+            &transaction,
+            "INSERT INTO edge_features (environment, features) VALUES ($1, $2)
+             ON CONFLICT (environment) DO UPDATE SET features = EXCLUDED.features, updated_at = now()",
+            rows,
+        )
+        .await?;
+        transaction.commit().await?;
+        debug!("Done saving to persistence");
+        Ok(())
+    }
+
+    async fn load_features_persisted_at(&self) -> EdgeResult<HashMap<String, DateTime<Utc>>> {
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "SELECT environment, (EXTRACT(EPOCH FROM updated_at) * 1000)::BIGINT AS updated_at_millis FROM edge_features",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                DateTime::from_timestamp_millis(row.get("updated_at_millis"))
+                    .map(|persisted_at| (row.get("environment"), persisted_at))
+            })
+            .collect())
+    }
+
+    async fn load_refresh_targets(&self) -> EdgeResult<Vec<TokenRefresh>> {
+        debug!("Loading refresh targets from persistence");
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query("SELECT data FROM edge_refresh_targets", &[])
+            .await?;
+        rows.into_iter()
+            .map(|row| from_jsonb(row.get("data"), "refresh targets"))
+            .collect()
+    }
+
+    async fn save_refresh_targets(&self, refresh_targets: Vec<TokenRefresh>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} refresh targets to persistence",
+            refresh_targets.len()
+        );
+        let rows = refresh_targets
+            .into_iter()
+            .map(|refresh| Ok((refresh.token.token.clone(), serde_json::to_value(&refresh)?)))
+            .collect::<EdgeResult<Vec<_>>>()?;
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        replace_all(
+            &transaction,
+            "DELETE FROM edge_refresh_targets WHERE token <> ALL($1)",
+            "INSERT INTO edge_refresh_targets (token, data) VALUES ($1, $2)
+             ON CONFLICT (token) DO UPDATE SET data = EXCLUDED.data, updated_at = now()",
+            rows,
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn load_revoked_tokens(&self) -> EdgeResult<Vec<String>> {
+        debug!("Loading revoked tokens from persistence");
+        self.load_state(REVOKED_TOKENS_KEY).await
+    }
+
+    async fn save_revoked_tokens(&self, revoked_tokens: Vec<String>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} revoked tokens to persistence",
+            revoked_tokens.len()
+        );
+        self.save_state(REVOKED_TOKENS_KEY, &revoked_tokens).await
+    }
+
+    async fn load_feature_overrides(&self) -> EdgeResult<Vec<FeatureOverride>> {
+        debug!("Loading feature overrides from persistence");
+        self.load_state(FEATURE_OVERRIDES_KEY).await
+    }
+
+    async fn save_feature_overrides(&self, overrides: Vec<FeatureOverride>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} feature overrides to persistence",
+            overrides.len()
+        );
+        self.save_state(FEATURE_OVERRIDES_KEY, &overrides).await
+    }
+
+    async fn load_metrics(&self) -> EdgeResult<MetricsBatch> {
+        debug!("Loading metrics from persistence");
+        self.load_state(METRICS_KEY).await
+    }
+
+    async fn save_metrics(&self, metrics: MetricsBatch) -> EdgeResult<()> {
+        debug!(
+            "Saving {} metrics and {} applications to persistence",
+            metrics.metrics.len(),
+            metrics.applications.len()
+        );
+        self.save_state(METRICS_KEY, &metrics).await
+    }
+}
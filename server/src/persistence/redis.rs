@@ -3,21 +3,28 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use redis::cluster::ClusterClient;
 use redis::{AsyncCommands, Client, Commands, RedisError};
 use tokio::sync::RwLock;
 use tracing::{debug, info};
 use unleash_types::client_features::ClientFeatures;
 
+use crate::feature_overrides::FeatureOverride;
+use crate::metrics::client_metrics::MetricsBatch;
 use crate::persistence::redis::RedisClientOptions::{Cluster, Single};
-use crate::types::EdgeToken;
+use crate::types::{EdgeToken, TokenRefresh};
 use crate::{error::EdgeError, types::EdgeResult};
 
 use super::EdgePersistence;
 
 pub const FEATURES_KEY: &str = "unleash-features";
+pub const FEATURES_PERSISTED_AT_KEY: &str = "unleash-features-persisted-at";
 pub const TOKENS_KEY: &str = "unleash-tokens";
 pub const REFRESH_TARGETS_KEY: &str = "unleash-refresh-targets";
+pub const REVOKED_TOKENS_KEY: &str = "unleash-revoked-tokens";
+pub const FEATURE_OVERRIDES_KEY: &str = "unleash-feature-overrides";
+pub const METRICS_KEY: &str = "unleash-metrics";
 
 impl From<RedisError> for EdgeError {
     fn from(err: RedisError) -> Self {
@@ -33,6 +40,7 @@ enum RedisClientOptions {
 pub struct RedisPersister {
     read_timeout: Duration,
     write_timeout: Duration,
+    ttl: Option<Duration>,
     redis_client: Arc<RwLock<RedisClientOptions>>,
 }
 impl RedisPersister {
@@ -48,6 +56,7 @@ impl RedisPersister {
             redis_client: Arc::new(RwLock::new(Single(client))),
             read_timeout,
             write_timeout,
+            ttl: None,
         })
     }
     pub fn new_with_cluster(
@@ -63,8 +72,76 @@ impl RedisPersister {
             redis_client: Arc::new(RwLock::new(Cluster(client))),
             read_timeout,
             write_timeout,
+            ttl: None,
         })
     }
+
+    /// Persisted features will expire after the given duration, so stale features eventually get cleaned out of redis.
+    /// Everything else is kept until it's overwritten, revoked tokens in particular have to survive restarts
+    pub fn with_ttl(self, ttl: Option<Duration>) -> Self {
+        Self { ttl, ..self }
+    }
+
+    /// Checks that redis answers, so a persister that can't reach it is refused on startup rather than failing on every save
+    pub async fn ping(&self) -> EdgeResult<()> {
+        let mut client = self.redis_client.write().await;
+        let _pong: String = match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.read_timeout,
+                        self.read_timeout,
+                    )
+                    .await?;
+                redis::cmd("PING").query_async(&mut conn).await?
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                redis::cmd("PING").query(&mut conn)?
+            }
+        };
+        Ok(())
+    }
+
+    async fn set(&self, key: &str, value: String) -> EdgeResult<()> {
+        self.set_with_ttl(key, value, None).await
+    }
+
+    /// Like [`Self::set`], expiring the key after the configured TTL, if there is one
+    async fn set_features(&self, key: &str, value: String) -> EdgeResult<()> {
+        self.set_with_ttl(key, value, self.ttl).await
+    }
+
+    async fn set_with_ttl(
+        &self,
+        key: &str,
+        value: String,
+        ttl: Option<Duration>,
+    ) -> EdgeResult<()> {
+        let mut client = self.redis_client.write().await;
+        match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.write_timeout,
+                        self.write_timeout,
+                    )
+                    .await?;
+                match ttl {
+                    Some(ttl) => conn.set_ex::<_, _, ()>(key, value, ttl.as_secs()).await?,
+                    None => conn.set::<_, _, ()>(key, value).await?,
+                }
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                match ttl {
+                    Some(ttl) => conn.set_ex::<_, _, ()>(key, value, ttl.as_secs())?,
+                    None => conn.set::<_, _, ()>(key, value)?,
+                }
+            }
+        };
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -94,24 +171,8 @@ impl EdgePersistence for RedisPersister {
 
     async fn save_tokens(&self, tokens: Vec<EdgeToken>) -> EdgeResult<()> {
         debug!("Saving {} tokens to persistence", tokens.len());
-        let mut client = self.redis_client.write().await;
         let raw_tokens = serde_json::to_string(&tokens)?;
-        match &mut *client {
-            RedisClientOptions::Single(c) => {
-                let mut conn = c
-                    .get_multiplexed_tokio_connection_with_response_timeouts(
-                        self.write_timeout,
-                        self.write_timeout,
-                    )
-                    .await?;
-                conn.set(TOKENS_KEY, raw_tokens).await?;
-            }
-            RedisClientOptions::Cluster(c) => {
-                let mut conn = c.get_connection()?;
-                conn.set(TOKENS_KEY, raw_tokens)?
-            }
-        };
-        Ok(())
+        self.set(TOKENS_KEY, raw_tokens).await
     }
 
     async fn load_features(&self) -> EdgeResult<HashMap<String, ClientFeatures>> {
@@ -139,27 +200,190 @@ impl EdgePersistence for RedisPersister {
 
     async fn save_features(&self, features: Vec<(String, ClientFeatures)>) -> EdgeResult<()> {
         debug!("Saving {} features to persistence", features.len());
-        let mut client = self.redis_client.write().await;
+        let now = Utc::now();
+        let persisted_at: HashMap<&String, DateTime<Utc>> = features
+            .iter()
+            .map(|(environment, _)| (environment, now))
+            .collect();
+        let raw_persisted_at = serde_json::to_string(&persisted_at)?;
         let raw_features = serde_json::to_string(&features)?;
-        match &mut *client {
-            Single(client) => {
-                let mut conn = client
+        self.set_features(FEATURES_KEY, raw_features).await?;
+        self.set_features(FEATURES_PERSISTED_AT_KEY, raw_persisted_at)
+            .await?;
+        debug!("Done saving to persistence");
+        Ok(())
+    }
+
+    /// Features saved by versions of Edge that didn't keep track of when have no age
+    async fn load_features_persisted_at(&self) -> EdgeResult<HashMap<String, DateTime<Utc>>> {
+        let mut client = self.redis_client.write().await;
+        let raw_persisted_at: Option<String> = match &mut *client {
+            Single(c) => {
+                let mut conn = c
                     .get_multiplexed_tokio_connection_with_response_timeouts(
-                        self.write_timeout,
-                        self.write_timeout,
+                        self.read_timeout,
+                        self.read_timeout,
                     )
                     .await?;
-                conn.set(FEATURES_KEY, raw_features)
-                    .await
-                    .map_err(EdgeError::from)?
+                conn.get(FEATURES_PERSISTED_AT_KEY).await?
             }
-            Cluster(cluster) => {
-                let mut conn = cluster.get_connection()?;
-                conn.set(FEATURES_KEY, raw_features)
-                    .map_err(EdgeError::from)?
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                conn.get(FEATURES_PERSISTED_AT_KEY)?
             }
         };
-        debug!("Done saving to persistence");
-        Ok(())
+        raw_persisted_at
+            .map(|raw| {
+                serde_json::from_str(&raw).map_err(|_e| {
+                    EdgeError::PersistenceError(
+                        "Failed to load when features were persisted from redis".into(),
+                    )
+                })
+            })
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+
+    async fn load_refresh_targets(&self) -> EdgeResult<Vec<TokenRefresh>> {
+        debug!("Loading refresh targets from persistence");
+        let mut client = self.redis_client.write().await;
+        let raw_refresh_targets: String = match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.read_timeout,
+                        self.read_timeout,
+                    )
+                    .await?;
+                conn.get(REFRESH_TARGETS_KEY).await?
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                conn.get(REFRESH_TARGETS_KEY)?
+            }
+        };
+        serde_json::from_str::<Option<Vec<TokenRefresh>>>(&raw_refresh_targets)
+            .map_err(|_e| {
+                EdgeError::PersistenceError("Failed to load refresh targets from redis".into())
+            })
+            .map(Option::unwrap_or_default)
+    }
+
+    async fn save_refresh_targets(&self, refresh_targets: Vec<TokenRefresh>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} refresh targets to persistence",
+            refresh_targets.len()
+        );
+        let raw_refresh_targets = serde_json::to_string(&refresh_targets)?;
+        self.set(REFRESH_TARGETS_KEY, raw_refresh_targets).await
+    }
+
+    async fn load_revoked_tokens(&self) -> EdgeResult<Vec<String>> {
+        debug!("Loading revoked tokens from persistence");
+        let mut client = self.redis_client.write().await;
+        let raw_revoked_tokens: String = match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.read_timeout,
+                        self.read_timeout,
+                    )
+                    .await?;
+                conn.get(REVOKED_TOKENS_KEY).await?
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                conn.get(REVOKED_TOKENS_KEY)?
+            }
+        };
+        serde_json::from_str::<Option<Vec<String>>>(&raw_revoked_tokens)
+            .map_err(|_e| {
+                EdgeError::PersistenceError("Failed to load revoked tokens from redis".into())
+            })
+            .map(Option::unwrap_or_default)
+    }
+
+    async fn save_revoked_tokens(&self, revoked_tokens: Vec<String>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} revoked tokens to persistence",
+            revoked_tokens.len()
+        );
+        let raw_revoked_tokens = serde_json::to_string(&revoked_tokens)?;
+        self.set(REVOKED_TOKENS_KEY, raw_revoked_tokens).await
+    }
+
+    async fn load_feature_overrides(&self) -> EdgeResult<Vec<FeatureOverride>> {
+        debug!("Loading feature overrides from persistence");
+        let mut client = self.redis_client.write().await;
+        let raw_overrides: Option<String> = match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.read_timeout,
+                        self.read_timeout,
+                    )
+                    .await?;
+                conn.get(FEATURE_OVERRIDES_KEY).await?
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                conn.get(FEATURE_OVERRIDES_KEY)?
+            }
+        };
+        raw_overrides
+            .map(|raw_overrides| {
+                serde_json::from_str::<Vec<FeatureOverride>>(&raw_overrides).map_err(|_e| {
+                    EdgeError::PersistenceError(
+                        "Failed to load feature overrides from redis".into(),
+                    )
+                })
+            })
+            .unwrap_or_else(|| Ok(vec![]))
+    }
+
+    async fn save_feature_overrides(&self, overrides: Vec<FeatureOverride>) -> EdgeResult<()> {
+        debug!(
+            "Saving {} feature overrides to persistence",
+            overrides.len()
+        );
+        let raw_overrides = serde_json::to_string(&overrides)?;
+        self.set(FEATURE_OVERRIDES_KEY, raw_overrides).await
+    }
+
+    async fn load_metrics(&self) -> EdgeResult<MetricsBatch> {
+        debug!("Loading metrics from persistence");
+        let mut client = self.redis_client.write().await;
+        let raw_metrics: Option<String> = match &mut *client {
+            Single(c) => {
+                let mut conn = c
+                    .get_multiplexed_tokio_connection_with_response_timeouts(
+                        self.read_timeout,
+                        self.read_timeout,
+                    )
+                    .await?;
+                conn.get(METRICS_KEY).await?
+            }
+            Cluster(c) => {
+                let mut conn = c.get_connection()?;
+                conn.get(METRICS_KEY)?
+            }
+        };
+        raw_metrics
+            .map(|raw_metrics| {
+                serde_json::from_str::<MetricsBatch>(&raw_metrics).map_err(|_e| {
+                    EdgeError::PersistenceError("Failed to load metrics from redis".into())
+                })
+            })
+            .unwrap_or_else(|| Ok(MetricsBatch::default()))
+    }
+
+    async fn save_metrics(&self, metrics: MetricsBatch) -> EdgeResult<()> {
+        debug!(
+            "Saving {} metrics and {} applications to persistence",
+            metrics.metrics.len(),
+            metrics.applications.len()
+        );
+        let raw_metrics = serde_json::to_string(&metrics)?;
+        self.set(METRICS_KEY, raw_metrics).await
     }
 }
@@ -1,58 +1,134 @@
-use crate::cli::LogFormat;
+use crate::cli::{LogFormat, OtlpOptions};
 use opentelemetry::global;
+use opentelemetry::trace::TraceError;
+use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::Sampler;
+use opentelemetry_sdk::Resource;
 use opentelemetry_semantic_conventions::resource::SERVICE_NAME;
 #[cfg(target_os = "linux")]
 use prometheus::process_collector::ProcessCollector;
+use std::sync::OnceLock;
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{EnvFilter, Registry};
+use tracing_subscriber::{reload, EnvFilter, Registry};
 
 use crate::http::background_send_metrics;
 use crate::metrics::actix_web_metrics::{
     PrometheusMetricsHandler, RequestMetrics, RequestMetricsBuilder,
 };
 
-fn instantiate_tracing_and_logging(log_format: &LogFormat) {
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
+/// Lets the log filter be swapped out after the subscriber has been installed
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// RUST_LOG wins over --log-level, so a RUST_LOG set for debugging isn't overridden by the deployment's --log-level.
+/// Info when neither is set, or RUST_LOG can't be parsed and --log-level isn't set
+fn log_directives<'a>(rust_log: Option<&'a str>, log_level: Option<&'a str>) -> &'a str {
+    rust_log
+        .filter(|directives| EnvFilter::try_new(directives).is_ok())
+        .or(log_level)
+        .unwrap_or("info")
+}
+
+/// The directives logging is filtered with for the given --log-level
+pub fn log_directives_in_use(log_level: Option<&str>) -> String {
+    let rust_log = std::env::var(EnvFilter::DEFAULT_ENV).ok();
+    log_directives(rust_log.as_deref(), log_level).to_string()
+}
+
+fn log_filter(log_level: Option<&str>) -> EnvFilter {
+    EnvFilter::new(log_directives_in_use(log_level))
+}
+
+fn instantiate_tracing_and_logging(log_format: &LogFormat, log_level: Option<&str>) {
+    let (env_filter, handle) = reload::Layer::new(log_filter(log_level));
+    let _ = LOG_FILTER.set(handle);
     match log_format {
         LogFormat::Plain => {
             let logger = tracing_subscriber::fmt::layer();
-            let collector = Registry::default().with(logger).with(env_filter);
+            let collector = Registry::default().with(env_filter).with(logger);
             tracing::subscriber::set_global_default(collector).unwrap();
         }
         LogFormat::Json => {
             let logger = tracing_subscriber::fmt::layer().json();
-            let collector = Registry::default().with(logger).with(env_filter);
+            let collector = Registry::default().with(env_filter).with(logger);
             tracing::subscriber::set_global_default(collector).unwrap();
         }
         LogFormat::Pretty => {
             let logger = tracing_subscriber::fmt::layer().pretty();
-            let collector = Registry::default().with(logger).with(env_filter);
+            let collector = Registry::default().with(env_filter).with(logger);
             tracing::subscriber::set_global_default(collector).unwrap();
         }
     };
 }
 
+/// Replaces the filter of the installed subscriber, so a new --log-level applies without a restart
+pub fn reload_log_level(log_level: Option<&str>) -> Result<(), reload::Error> {
+    match LOG_FILTER.get() {
+        Some(handle) => handle.reload(log_filter(log_level)),
+        None => Ok(()),
+    }
+}
+
 pub fn instantiate(
     registry: Option<prometheus::Registry>,
     log_format: &LogFormat,
+    log_level: Option<&str>,
 ) -> (PrometheusMetricsHandler, RequestMetrics) {
-    instantiate_tracing_and_logging(log_format);
+    instantiate_tracing_and_logging(log_format, log_level);
     let registry = registry.unwrap_or_else(instantiate_registry);
     register_custom_metrics(&registry);
     instantiate_prometheus_metrics_handler(registry)
 }
 
-fn instantiate_prometheus_metrics_handler(
-    registry: prometheus::Registry,
-) -> (PrometheusMetricsHandler, RequestMetrics) {
-    let resource = opentelemetry_sdk::Resource::new(vec![
+fn edge_resource() -> Resource {
+    Resource::new(vec![
         opentelemetry::KeyValue::new(SERVICE_NAME, "unleash-edge"),
         opentelemetry::KeyValue::new("edge_version", crate::types::build::PKG_VERSION),
         opentelemetry::KeyValue::new("edge_githash", crate::types::build::SHORT_COMMIT),
-    ]);
+    ])
+}
+
+/// Installs an OTLP exporter as the global tracer provider, which is what [`crate::middleware::request_tracing::RequestTracing`] reports to.
+/// Actix runs on a current thread runtime, so the batch exporter gets its own thread to be able to flush on shutdown.
+/// Without an endpoint we leave the no-op provider in place.
+pub fn instantiate_otlp_tracing(options: &OtlpOptions) -> Result<(), TraceError> {
+    let Some(endpoint) = options.otlp_endpoint.clone() else {
+        return Ok(());
+    };
+    let mut metadata = tonic::metadata::MetadataMap::new();
+    for (key, value) in &options.otlp_headers {
+        let key = tonic::metadata::MetadataKey::from_bytes(key.as_bytes())
+            .map_err(|_| TraceError::from(format!("Invalid OTLP header name: {key}")))?;
+        let value = value
+            .parse()
+            .map_err(|_| TraceError::from(format!("Invalid value for OTLP header {key}")))?;
+        metadata.insert(key, value);
+    }
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint)
+        .with_metadata(metadata);
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        options.trace_sampling_ratio,
+    )));
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            opentelemetry_sdk::trace::config()
+                .with_sampler(sampler)
+                .with_resource(edge_resource()),
+        )
+        .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(())
+}
+
+fn instantiate_prometheus_metrics_handler(
+    registry: prometheus::Registry,
+) -> (PrometheusMetricsHandler, RequestMetrics) {
+    let resource = edge_resource();
     let exporter = opentelemetry_prometheus::exporter()
         .with_registry(registry.clone())
         .build()
@@ -98,6 +174,11 @@ fn register_custom_metrics(registry: &prometheus::Registry) {
             crate::metrics::client_metrics::METRICS_SIZE_HISTOGRAM.clone(),
         ))
         .unwrap();
+    registry
+        .register(Box::new(
+            crate::metrics::client_metrics::METRICS_DROPPED_AT_CAPACITY.clone(),
+        ))
+        .unwrap();
     registry
         .register(Box::new(
             background_send_metrics::METRICS_UPSTREAM_CLIENT_BULK.clone(),
@@ -113,6 +194,11 @@ fn register_custom_metrics(registry: &prometheus::Registry) {
             background_send_metrics::METRICS_INTERVAL_BETWEEN_SEND.clone(),
         ))
         .unwrap();
+    registry
+        .register(Box::new(
+            background_send_metrics::METRICS_UPSTREAM_EXPIRED.clone(),
+        ))
+        .unwrap();
     registry
         .register(Box::new(
             crate::http::unleash_client::CLIENT_FEATURE_FETCH_FAILURES.clone(),
@@ -133,11 +219,109 @@ fn register_custom_metrics(registry: &prometheus::Registry) {
             crate::http::unleash_client::CLIENT_FEATURE_FETCH.clone(),
         ))
         .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::unleash_client::UPSTREAM_PAYLOAD_BYTES.clone(),
+        ))
+        .unwrap();
     registry
         .register(Box::new(
             crate::http::unleash_client::UPSTREAM_VERSION.clone(),
         ))
         .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_ATTEMPTS.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_UPDATED.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_NO_UPDATE.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_FAILURES.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_COALESCED.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_REFRESH_DURATION.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::FEATURE_CACHE_AGE.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::LAST_REFRESH_TIMESTAMP.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::feature_refresher::UPSTREAM_AUTH_REVOCATIONS.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::change_webhook::CHANGE_WEBHOOK_DELIVERIES.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::middleware::in_flight_requests::IN_FLIGHT_REQUESTS.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::middleware::rate_limit::RATE_LIMITED_REQUESTS.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::middleware::validate_token::TOKEN_VALIDATION_RESULTS.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::auth::token_validator::TOKENS_REFUSED_AT_CAPACITY.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::http::circuit_breaker::UPSTREAM_CIRCUIT_BREAKER_STATE.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(crate::metrics::cache_sizes::TOKENS_TOTAL.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::metrics::cache_sizes::FEATURE_ENVIRONMENTS_TOTAL.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::metrics::cache_sizes::REFRESH_TARGETS_TOTAL.clone(),
+        ))
+        .unwrap();
+    registry
+        .register(Box::new(
+            crate::background_tasks::BACKGROUND_TASK_PANICS.clone(),
+        ))
+        .unwrap();
 }
 
 #[cfg(test)]
@@ -148,3 +332,26 @@ pub fn test_instantiate_without_tracing_and_logging(
     register_custom_metrics(&registry);
     instantiate_prometheus_metrics_handler(registry)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::log_directives;
+
+    #[test]
+    fn rust_log_takes_precedence_over_the_log_level() {
+        assert_eq!(log_directives(None, None), "info");
+        assert_eq!(log_directives(None, Some("warn")), "warn");
+        assert_eq!(
+            log_directives(None, Some("info,unleash_edge::http=debug")),
+            "info,unleash_edge::http=debug"
+        );
+        assert_eq!(
+            log_directives(Some("warn,unleash_edge=debug"), Some("error")),
+            "warn,unleash_edge=debug"
+        );
+        assert_eq!(
+            log_directives(Some("unleash_edge=loud"), Some("error")),
+            "error"
+        );
+    }
+}
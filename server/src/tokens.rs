@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::future::{ready, Ready};
 use std::str::FromStr;
 
@@ -14,6 +14,7 @@ use crate::error::EdgeError;
 use crate::types::EdgeResult;
 use crate::types::EdgeToken;
 use crate::types::TokenRefresh;
+use crate::types::TokenType;
 use crate::types::TokenValidationStatus;
 
 pub(crate) fn simplify(tokens: &[TokenRefresh]) -> Vec<TokenRefresh> {
@@ -33,22 +34,26 @@ pub(crate) fn simplify(tokens: &[TokenRefresh]) -> Vec<TokenRefresh> {
         .collect()
 }
 
+/// Tokens for the same environment and projects fetch the same features, so only one of them is refreshed. It's the
+/// lowest token, so which one doesn't depend on the order tokens were registered or restored in
 fn filter_unique_tokens(tokens: &[TokenRefresh]) -> Vec<TokenRefresh> {
-    let mut unique_tokens = Vec::new();
-    let mut unique_keys = HashSet::new();
-
+    let mut lowest_by_scope: HashMap<(Vec<String>, Option<String>), &str> = HashMap::new();
     for token in tokens {
-        let key = (
-            token.token.projects.clone(),
-            token.token.environment.clone(),
-        );
-        if !unique_keys.contains(&key) {
-            unique_tokens.push(token.clone());
-            unique_keys.insert(key);
-        }
+        let mut projects = token.token.projects.clone();
+        projects.sort();
+        lowest_by_scope
+            .entry((projects, token.token.environment.clone()))
+            .and_modify(|lowest| *lowest = (*lowest).min(token.token.token.as_str()))
+            .or_insert(token.token.token.as_str());
     }
-
-    unique_tokens
+    let lowest: HashSet<&str> = lowest_by_scope.into_values().collect();
+    let mut seen = HashSet::new();
+    tokens
+        .iter()
+        .filter(|token| lowest.contains(token.token.token.as_str()))
+        .filter(|token| seen.insert(token.token.token.clone()))
+        .cloned()
+        .collect()
 }
 
 pub fn anonymize_token(edge_token: &EdgeToken) -> EdgeToken {
@@ -63,22 +68,30 @@ pub fn anonymize_token(edge_token: &EdgeToken) -> EdgeToken {
                 ..edge_token.clone()
             }
         }
-        _ => edge_token.clone(),
+        _ => EdgeToken {
+            token: clean_hash(&edge_token.token),
+            ..edge_token.clone()
+        },
     }
 }
+/// Only keeps a short prefix, and nothing at all for secrets short enough that a prefix would give most of it away
 fn clean_hash(hash: &str) -> String {
-    format!(
-        "{}****{}",
-        &hash[..6].to_string(),
-        &hash[hash.len() - 6..].to_string()
-    )
+    let prefix: String = if hash.chars().count() > 12 {
+        hash.chars().take(6).collect()
+    } else {
+        String::new()
+    };
+    format!("{prefix}****")
 }
 
+/// The key a token's features and engine are cached under, used both when sinking features and when reading them back.
+/// Features are cached per environment, with every project merged in, and narrowed to the token's projects on the way out.
+/// Tokens without an environment get a key of their own, prefixed so it can't collide with an environment name
 pub(crate) fn cache_key(token: &EdgeToken) -> String {
-    token
-        .environment
-        .clone()
-        .unwrap_or_else(|| token.token.clone())
+    match &token.environment {
+        Some(environment) => environment.clone(),
+        None => format!("token:{}", token.token),
+    }
 }
 
 impl EdgeToken {
@@ -92,6 +105,19 @@ impl EdgeToken {
         }
     }
 
+    /// The type the token's own structure gives away. Admin tokens have access to every project and environment
+    /// (`*:*.<secret>`) and personal access tokens start with `user:`. Client and frontend tokens look alike, so
+    /// telling those apart needs upstream
+    pub(crate) fn embedded_token_type(&self) -> Option<TokenType> {
+        if self.token.starts_with("user:") {
+            return Some(TokenType::Admin);
+        }
+        EdgeToken::from_str(&self.token)
+            .ok()
+            .filter(|parsed| parsed.environment.as_deref() == Some("*"))
+            .map(|_| TokenType::Admin)
+    }
+
     pub(crate) fn subsumes(&self, other: &EdgeToken) -> bool {
         self.token_type == other.token_type
             && self.same_environment_and_broader_or_equal_project_access(other)
@@ -128,7 +154,7 @@ impl FromRequest for EdgeToken {
                     },
                     None => Err(EdgeError::AuthorizationDenied),
                 },
-                EdgeMode::Edge(_) => match value {
+                EdgeMode::Edge(_) | EdgeMode::ReadReplica(_) => match value {
                     Some(v) => EdgeToken::try_from(v.clone()),
                     None => Err(EdgeError::AuthorizationDenied),
                 },
@@ -223,7 +249,7 @@ mod tests {
     use ulid::Ulid;
 
     use crate::{
-        tokens::simplify,
+        tokens::{anonymize_token, cache_key, simplify},
         types::{EdgeToken, TokenRefresh, TokenType},
     };
 
@@ -341,6 +367,27 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn tokens_sharing_environment_and_projects_refresh_with_the_lowest_whatever_the_order() {
+        let tokens = vec![
+            test_token(Some("b-secret"), Some("development"), vec!["a", "b"]),
+            test_token(Some("a-secret"), Some("development"), vec!["b", "a"]),
+            test_token(Some("c-secret"), Some("production"), vec!["a", "b"]),
+        ];
+        for ordering in [tokens.clone(), tokens.into_iter().rev().collect()] {
+            let refreshes: Vec<TokenRefresh> = ordering
+                .into_iter()
+                .map(|t| TokenRefresh::new(t, None))
+                .collect();
+            let mut kept: Vec<String> = simplify(&refreshes)
+                .into_iter()
+                .map(|refresh| refresh.token.token)
+                .collect();
+            kept.sort();
+            assert_eq!(kept, vec!["a-secret", "c-secret"]);
+        }
+    }
+
     #[test]
     fn test_project_tokens() {
         let df_projects: Vec<TokenRefresh> = vec![
@@ -454,4 +501,56 @@ mod tests {
         assert_eq!(token1, token2);
         assert_eq!(token2, token3);
     }
+
+    #[test]
+    fn anonymized_tokens_only_keep_a_short_prefix_of_the_secret() {
+        let long = test_token(
+            Some("*:development.03fa5f506428fe80ed5640c351c7232e38940814d2923b08f5c05fa7"),
+            None,
+            vec![],
+        );
+        assert_eq!(anonymize_token(&long).token, "*:development.03fa5f****");
+        let short = test_token(Some("*:development.abc"), None, vec![]);
+        assert_eq!(anonymize_token(&short).token, "*:development.****");
+        let without_environment = test_token(Some("some-offline-secret-token"), None, vec![]);
+        assert_eq!(anonymize_token(&without_environment).token, "some-o****");
+    }
+
+    #[test]
+    fn cache_key_separates_environments_but_is_shared_between_projects() {
+        let development = EdgeToken::from_str("projecta:development.secret").unwrap();
+        let other_project = EdgeToken::from_str("projectb:development.secret").unwrap();
+        let production = EdgeToken::from_str("projecta:production.secret").unwrap();
+        assert_eq!(cache_key(&development), "development");
+        assert_eq!(cache_key(&development), cache_key(&other_project));
+        assert_ne!(cache_key(&development), cache_key(&production));
+    }
+
+    #[test]
+    fn cache_key_for_tokens_without_environment_does_not_collide_with_an_environment() {
+        let named_like_an_environment = EdgeToken::offline_token("development");
+        let development = EdgeToken::from_str("*:development.secret").unwrap();
+        assert_ne!(
+            cache_key(&named_like_an_environment),
+            cache_key(&development)
+        );
+    }
+
+    #[test]
+    fn only_admin_tokens_give_away_their_type() {
+        for (token, expected) in [
+            ("*:*.secret123", Some(TokenType::Admin)),
+            ("user:abcdef1234567890", Some(TokenType::Admin)),
+            ("*:development.secret123", None),
+            ("[]:production.secret123", None),
+            ("jamesbond", None),
+            ("*:missing-a-secret", None),
+        ] {
+            assert_eq!(
+                EdgeToken::no_project_or_environment(token).embedded_token_type(),
+                expected,
+                "{token}"
+            );
+        }
+    }
 }
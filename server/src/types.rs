@@ -1,4 +1,4 @@
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
 use std::net::IpAddr;
@@ -9,16 +9,19 @@ use std::{
     str::FromStr,
 };
 
-use actix_web::{http::header::EntityTag, web::Json};
+use actix_web::http::header::{Accept, EntityTag, Header};
+use actix_web::web::{Json, Query};
+use actix_web::HttpRequest;
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use dashmap::DashMap;
+use rand::Rng;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use shadow_rs::shadow;
-use unleash_types::client_features::ClientFeatures;
 use unleash_types::client_features::Context;
+use unleash_types::client_features::{ClientFeature, ClientFeatures, Payload, Segment};
 use unleash_types::client_metrics::{ClientApplication, ClientMetricsEnv};
-use unleash_yggdrasil::EngineState;
+use unleash_yggdrasil::{EngineState, ResolvedToggle};
 use utoipa::{IntoParams, ToSchema};
 
 use crate::error::EdgeError;
@@ -27,7 +30,7 @@ use crate::metrics::client_metrics::MetricsKey;
 pub type EdgeJsonResult<T> = Result<Json<T>, EdgeError>;
 pub type EdgeResult<T> = Result<T, EdgeError>;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct IncomingContext {
     #[serde(flatten)]
@@ -37,6 +40,111 @@ pub struct IncomingContext {
     pub extra_properties: HashMap<String, String>,
 }
 
+/// Read from the query string of GET requests and the body of POST requests alike. Browser SDKs posting their context
+/// wrap it in `context`, which is unwrapped. Context values that aren't strings are made into the strings a query
+/// string would have carried: numbers and booleans as written, arrays joined by commas and nested objects as JSON, so
+/// GET and POST evaluate the same.
+impl<'de> Deserialize<'de> for IncomingContext {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let mut fields = HashMap::<String, serde_json::Value>::deserialize(deserializer)?;
+        match fields.remove("context") {
+            Some(serde_json::Value::Object(wrapped)) => fields.extend(wrapped),
+            Some(other) => {
+                fields.insert("context".into(), other);
+            }
+            None => {}
+        }
+        let mut incoming = IncomingContext {
+            context: Context {
+                properties: None,
+                ..Context::default()
+            },
+            extra_properties: HashMap::new(),
+        };
+        for (name, value) in fields {
+            let context = &mut incoming.context;
+            match name.as_str() {
+                "userId" => context.user_id = context_value(value),
+                "sessionId" => context.session_id = context_value(value),
+                "environment" => context.environment = context_value(value),
+                "appName" => context.app_name = context_value(value),
+                "currentTime" => context.current_time = context_value(value),
+                "remoteAddress" => context.remote_address = context_value(value),
+                "properties" => {
+                    context.properties = match value {
+                        serde_json::Value::Object(properties) => Some(
+                            properties
+                                .into_iter()
+                                .filter_map(|(name, value)| Some((name, context_value(value)?)))
+                                .collect(),
+                        ),
+                        serde_json::Value::Null => None,
+                        _ => return Err(D::Error::custom("properties must be an object")),
+                    }
+                }
+                _ => {
+                    if let Some(value) = context_value(value) {
+                        incoming.extra_properties.insert(name, value);
+                    }
+                }
+            }
+        }
+        Ok(incoming)
+    }
+}
+
+fn context_value(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value),
+        serde_json::Value::Array(values) => Some(
+            values
+                .into_iter()
+                .filter_map(context_value)
+                .collect::<Vec<String>>()
+                .join(","),
+        ),
+        serde_json::Value::Object(values) => Some(
+            serde_json::Value::Object(
+                values
+                    .into_iter()
+                    .filter_map(|(name, value)| {
+                        Some((name, serde_json::Value::String(context_value(value)?)))
+                    })
+                    .collect(),
+            )
+            .to_string(),
+        ),
+        scalar => Some(scalar.to_string()),
+    }
+}
+
+impl IncomingContext {
+    /// Context from `other` wins over this where both have a value, properties are merged
+    pub fn merge(self, other: IncomingContext) -> IncomingContext {
+        let mut properties = self.context.properties.unwrap_or_default();
+        properties.extend(other.context.properties.unwrap_or_default());
+        let mut extra_properties = self.extra_properties;
+        extra_properties.extend(other.extra_properties);
+        IncomingContext {
+            context: Context {
+                user_id: other.context.user_id.or(self.context.user_id),
+                session_id: other.context.session_id.or(self.context.session_id),
+                environment: other.context.environment.or(self.context.environment),
+                app_name: other.context.app_name.or(self.context.app_name),
+                current_time: other.context.current_time.or(self.context.current_time),
+                remote_address: other.context.remote_address.or(self.context.remote_address),
+                properties: (!properties.is_empty()).then_some(properties),
+            },
+            extra_properties,
+        }
+    }
+}
+
 impl From<IncomingContext> for Context {
     fn from(input: IncomingContext) -> Self {
         let properties = if input.extra_properties.is_empty() {
@@ -53,6 +161,52 @@ impl From<IncomingContext> for Context {
     }
 }
 
+/// What the frontend API answers with. Like [`unleash_types::frontend::FrontendResult`], but with the variants the Unleash
+/// frontend API and proxy return, which browser SDKs rely on
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FrontendResult {
+    pub toggles: Vec<EvaluatedToggle>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[allow(non_snake_case)]
+pub struct EvaluatedToggle {
+    pub name: String,
+    pub enabled: bool,
+    pub variant: EvaluatedVariant,
+    #[serde(rename = "impression_data")]
+    pub impression_data: bool,
+    pub impressionData: bool,
+}
+
+/// The variant a feature evaluated to. Features that are disabled, or that have no variants, get the `disabled` variant,
+/// and `feature_enabled` tells those two apart
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[allow(non_snake_case)]
+pub struct EvaluatedVariant {
+    pub name: String,
+    pub enabled: bool,
+    pub payload: Option<Payload>,
+    #[serde(rename = "feature_enabled")]
+    pub feature_enabled: bool,
+    pub featureEnabled: bool,
+}
+
+impl From<&ResolvedToggle> for EvaluatedVariant {
+    fn from(resolved: &ResolvedToggle) -> Self {
+        EvaluatedVariant {
+            name: resolved.variant.name.clone(),
+            enabled: resolved.variant.enabled,
+            payload: resolved.variant.payload.clone(),
+            feature_enabled: resolved.variant.feature_enabled,
+            featureEnabled: resolved.variant.feature_enabled,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, utoipa::ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum TokenType {
@@ -71,6 +225,66 @@ pub enum ClientFeaturesResponse {
     Updated(ClientFeatures, Option<EntityTag>),
 }
 
+/// A change from upstream's delta API. A hydration carries everything the token has access to, the others one change each
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DeltaEvent {
+    #[serde(rename_all = "camelCase")]
+    Hydration {
+        event_id: u32,
+        features: Vec<ClientFeature>,
+        #[serde(default)]
+        segments: Vec<Segment>,
+    },
+    #[serde(rename_all = "camelCase")]
+    FeatureUpdated {
+        event_id: u32,
+        feature: ClientFeature,
+    },
+    #[serde(rename_all = "camelCase")]
+    FeatureRemoved {
+        event_id: u32,
+        feature_name: String,
+        project: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    SegmentUpdated { event_id: u32, segment: Segment },
+    #[serde(rename_all = "camelCase")]
+    SegmentRemoved { event_id: u32, segment_id: i32 },
+}
+
+impl DeltaEvent {
+    pub fn event_id(&self) -> u32 {
+        match self {
+            DeltaEvent::Hydration { event_id, .. }
+            | DeltaEvent::FeatureUpdated { event_id, .. }
+            | DeltaEvent::FeatureRemoved { event_id, .. }
+            | DeltaEvent::SegmentUpdated { event_id, .. }
+            | DeltaEvent::SegmentRemoved { event_id, .. } => *event_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientFeaturesDelta {
+    pub events: Vec<DeltaEvent>,
+}
+
+impl ClientFeaturesDelta {
+    /// The revision our features are at once the delta is applied, the id of the last event in it
+    pub fn revision_id(&self) -> Option<u32> {
+        self.events.iter().map(DeltaEvent::event_id).max()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ClientFeaturesDeltaResponse {
+    NoUpdate,
+    Updated(ClientFeaturesDelta),
+    /// Upstream doesn't have the delta API
+    Unsupported,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Default, Deserialize, utoipa::ToSchema)]
 pub enum TokenValidationStatus {
     Invalid,
@@ -91,6 +305,15 @@ pub enum Status {
 pub struct ClientFeaturesRequest {
     pub api_key: String,
     pub etag: Option<EntityTag>,
+    /// Asks upstream for only the features carrying any of these tags, as `type:value`
+    pub tags: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientFeaturesDeltaRequest {
+    pub api_key: String,
+    /// Without a revision upstream starts the delta with a hydration
+    pub revision_id: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -221,9 +444,30 @@ impl EdgeToken {
 pub struct TokenStrings {
     pub tokens: Vec<String>,
 }
+/// Either `{"tokens": [...]}`, which is what Unleash and other Edges send, or a bare array of token strings
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(untagged)]
+pub enum ValidateTokensBody {
+    Wrapped(TokenStrings),
+    Bare(Vec<String>),
+}
+
+impl ValidateTokensBody {
+    pub fn into_tokens(self) -> Vec<String> {
+        match self {
+            ValidateTokensBody::Wrapped(token_strings) => token_strings.tokens,
+            ValidateTokensBody::Bare(tokens) => tokens,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct ValidatedTokens {
     pub tokens: Vec<EdgeToken>,
+    /// Requested tokens that aren't valid, with their status. Kept out of `tokens`, since Edges in front of us take everything there to be valid
+    #[serde(default)]
+    pub invalid_tokens: Vec<EdgeToken>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -250,6 +494,12 @@ pub struct TokenRefresh {
     pub last_feature_count: Option<usize>,
     pub last_check: Option<DateTime<Utc>>,
     pub failure_count: u32,
+    /// The revision of the last delta applied for this token, only used with the delta API
+    #[serde(default)]
+    pub delta_revision_id: Option<u32>,
+    /// Refresh this token this often instead of at the global features refresh interval
+    #[serde(default)]
+    pub refresh_interval_seconds: Option<u64>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -277,14 +527,24 @@ impl TokenRefresh {
             next_refresh: None,
             failure_count: 0,
             last_feature_count: None,
+            delta_revision_id: None,
+            refresh_interval_seconds: None,
         }
     }
 
-    /// Something went wrong (but it was retriable. Increment our failure count and set last_checked and next_refresh
-    pub fn backoff(&self, refresh_interval: &Duration) -> Self {
+    pub fn with_refresh_interval_seconds(self, refresh_interval_seconds: Option<u64>) -> Self {
+        Self {
+            refresh_interval_seconds,
+            ..self
+        }
+    }
+
+    /// Something went wrong (but it was retriable). Increment our failure count and set last_checked and next_refresh.
+    /// The delay doubles for every consecutive failure, is capped at max_backoff and gets ±20% jitter so that multiple Edge instances don't retry in lockstep
+    pub fn backoff(&self, refresh_interval: &Duration, max_backoff: &Duration) -> Self {
         let failure_count: u32 = min(self.failure_count + 1, 10);
         let now = Utc::now();
-        let next_refresh = calculate_next_refresh(now, *refresh_interval, failure_count as u64);
+        let next_refresh = now + calculate_backoff(*refresh_interval, *max_backoff, failure_count);
         Self {
             failure_count,
             next_refresh: Some(next_refresh),
@@ -292,39 +552,27 @@ impl TokenRefresh {
             ..self.clone()
         }
     }
-    /// We successfully talked to upstream, but there was no updates. Update our next_refresh, decrement our failure count and set when we last_checked
+    /// We successfully talked to upstream, but there was no updates. Update our next_refresh, reset our failure count and set when we last_checked
     pub fn successful_check(&self, refresh_interval: &Duration) -> Self {
-        let failure_count = if self.failure_count > 0 {
-            self.failure_count - 1
-        } else {
-            0
-        };
         let now = Utc::now();
-        let next_refresh = calculate_next_refresh(now, *refresh_interval, failure_count as u64);
         Self {
-            failure_count,
-            next_refresh: Some(next_refresh),
+            failure_count: 0,
+            next_refresh: Some(now + *refresh_interval),
             last_check: Some(now),
             ..self.clone()
         }
     }
-    /// We successfully talked to upstream. There were updates. Update next_refresh, last_refreshed and last_check, and decrement our failure count
+    /// We successfully talked to upstream. There were updates. Update next_refresh, last_refreshed and last_check, and reset our failure count
     pub fn successful_refresh(
         &self,
         refresh_interval: &Duration,
         etag: Option<EntityTag>,
         feature_count: usize,
     ) -> Self {
-        let failure_count = if self.failure_count > 0 {
-            self.failure_count - 1
-        } else {
-            0
-        };
         let now = Utc::now();
-        let next_refresh = calculate_next_refresh(now, *refresh_interval, failure_count as u64);
         Self {
-            failure_count,
-            next_refresh: Some(next_refresh),
+            failure_count: 0,
+            next_refresh: Some(now + *refresh_interval),
             last_refreshed: Some(now),
             last_check: Some(now),
             last_feature_count: Some(feature_count),
@@ -334,16 +582,16 @@ impl TokenRefresh {
     }
 }
 
-fn calculate_next_refresh(
-    now: DateTime<Utc>,
+/// refresh_interval * 2^failure_count, capped at max_backoff, with ±20% jitter applied
+fn calculate_backoff(
     refresh_interval: Duration,
-    failure_count: u64,
-) -> DateTime<Utc> {
-    if failure_count == 0 {
-        now + refresh_interval
-    } else {
-        now + refresh_interval + (refresh_interval * (failure_count.try_into().unwrap_or(0)))
-    }
+    max_backoff: Duration,
+    failure_count: u32,
+) -> Duration {
+    let exponential = refresh_interval * 2_i32.pow(min(failure_count, 10));
+    let capped = min(exponential, max(max_backoff, refresh_interval));
+    let jitter = rand::thread_rng().gen_range(0.8..=1.2);
+    Duration::milliseconds((capped.num_milliseconds() as f64 * jitter) as i64)
 }
 
 impl fmt::Debug for TokenRefresh {
@@ -469,6 +717,56 @@ impl Default for BuildInfo {
 #[serde(rename_all = "camelCase")]
 pub struct FeatureFilters {
     pub name_prefix: Option<String>,
+    /// Only serve the features that are turned on, and the ones they depend on. Everything is served without it
+    pub enabled_only: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, IntoParams)]
+pub struct LongPoll {
+    /// Seconds to hold on to the request until the features change, for clients that send If-None-Match. Capped at 60
+    pub wait: Option<u64>,
+}
+
+/// Media type asking the features endpoints for the feature configuration as Unleash serves it to backend SDKs
+pub const RAW_FEATURES_MEDIA_TYPE: &str = "application/vnd.unleash.raw+json";
+/// Media type asking the features endpoints for the toggles evaluated against the context in the query string
+pub const EVALUATED_FEATURES_MEDIA_TYPE: &str = "application/vnd.unleash.evaluated+json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FeatureFormat {
+    Raw,
+    Evaluated,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, IntoParams)]
+pub struct FeatureFormatQuery {
+    /// Whether to answer with the raw feature configuration or with evaluated toggles. Takes precedence over the Accept
+    /// header, which can ask for application/vnd.unleash.raw+json or application/vnd.unleash.evaluated+json instead
+    pub format: Option<FeatureFormat>,
+}
+
+impl FeatureFormat {
+    /// The format the client asked for, from the `format` query parameter or else the most preferred of our media
+    /// types in its Accept header. None when it didn't ask for either, so the endpoint answers the way it always has
+    pub fn requested(req: &HttpRequest) -> EdgeResult<Option<FeatureFormat>> {
+        let query = Query::<FeatureFormatQuery>::from_query(req.query_string())
+            .map_err(|e| EdgeError::FeatureFormatNotAcceptable(e.to_string()))?;
+        if let Some(format) = query.format {
+            return Ok(Some(format));
+        }
+        let Ok(accept) = Accept::parse(req) else {
+            return Ok(None);
+        };
+        Ok(accept
+            .ranked()
+            .iter()
+            .find_map(|mime| match mime.essence_str() {
+                RAW_FEATURES_MEDIA_TYPE => Some(FeatureFormat::Raw),
+                EVALUATED_FEATURES_MEDIA_TYPE => Some(FeatureFormat::Evaluated),
+                _ => None,
+            }))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
@@ -500,7 +798,7 @@ mod tests {
 
     use crate::error::EdgeError::EdgeTokenParseError;
     use crate::http::unleash_client::EdgeTokens;
-    use crate::types::{EdgeResult, EdgeToken, IncomingContext};
+    use crate::types::{EdgeResult, EdgeToken, IncomingContext, TokenRefresh};
 
     fn test_str(token: &str) -> EdgeToken {
         EdgeToken::from_str(
@@ -687,4 +985,84 @@ mod tests {
         let converted: Context = incoming_context.into();
         assert_eq!(converted.properties, None);
     }
+
+    #[test]
+    fn context_reads_the_same_from_a_query_string_and_from_a_posted_body() {
+        let from_query: Context = serde_qs::from_str::<IncomingContext>(
+            "appName=web&userId=7&properties[tags][]=beta&properties[tags][]=eu&properties[seats]=12&properties[plan][tier]=pro&country=NO",
+        )
+        .unwrap()
+        .into();
+        let from_body: Context = serde_json::from_value::<IncomingContext>(serde_json::json!({
+            "context": {
+                "appName": "web",
+                "userId": 7,
+                "properties": {"tags": ["beta", "eu"], "seats": 12, "plan": {"tier": "pro"}, "missing": null},
+                "country": "NO"
+            }
+        }))
+        .unwrap()
+        .into();
+        for context in [from_query, from_body] {
+            assert_eq!(context.app_name.as_deref(), Some("web"));
+            assert_eq!(context.user_id.as_deref(), Some("7"));
+            assert_eq!(
+                context.properties,
+                Some(HashMap::from([
+                    ("tags".into(), "beta,eu".into()),
+                    ("seats".into(), "12".into()),
+                    ("plan".into(), r#"{"tier":"pro"}"#.into()),
+                    ("country".into(), "NO".into()),
+                ]))
+            );
+        }
+    }
+
+    #[test]
+    fn posted_context_wins_over_the_query_string_it_is_merged_with() {
+        let query: IncomingContext =
+            serde_qs::from_str("userId=1&sessionId=s&properties[plan]=free&country=NO").unwrap();
+        let body: IncomingContext =
+            serde_json::from_str(r#"{"userId": "2", "properties": {"plan": "pro"}}"#).unwrap();
+        let merged: Context = query.merge(body).into();
+        assert_eq!(merged.user_id.as_deref(), Some("2"));
+        assert_eq!(merged.session_id.as_deref(), Some("s"));
+        assert_eq!(
+            merged.properties,
+            Some(HashMap::from([
+                ("plan".into(), "pro".into()),
+                ("country".into(), "NO".into()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_with_jitter_and_is_capped() {
+        let interval = chrono::Duration::seconds(15);
+        let max_backoff = chrono::Duration::seconds(60);
+        let mut refresh = TokenRefresh::new(EdgeToken::default(), None);
+        let expected_base = [30, 60, 60, 60];
+        for base in expected_base {
+            refresh = refresh.backoff(&interval, &max_backoff);
+            let delay =
+                (refresh.next_refresh.unwrap() - refresh.last_check.unwrap()).num_milliseconds();
+            assert!(delay >= base * 800 && delay <= base * 1200);
+        }
+        assert_eq!(refresh.failure_count, 4);
+    }
+
+    #[test]
+    fn successful_check_resets_backoff_to_base_interval() {
+        let interval = chrono::Duration::seconds(15);
+        let max_backoff = chrono::Duration::seconds(300);
+        let refresh = TokenRefresh::new(EdgeToken::default(), None)
+            .backoff(&interval, &max_backoff)
+            .backoff(&interval, &max_backoff)
+            .successful_check(&interval);
+        assert_eq!(refresh.failure_count, 0);
+        assert_eq!(
+            refresh.next_refresh.unwrap() - refresh.last_check.unwrap(),
+            interval
+        );
+    }
 }
@@ -0,0 +1,70 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+use crate::error::EdgeError;
+use crate::types::EdgeResult;
+
+/// A socket left behind by an instance that didn't shut down cleanly would make binding fail, so we remove it.
+/// Anything at the path that isn't a socket is left alone, we'd rather fail than delete someone's file.
+pub fn remove_stale_socket(path: &Path) -> EdgeResult<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            info!("Removing stale unix socket at {}", path.display());
+            std::fs::remove_file(path)
+                .map_err(|e| EdgeError::UnixSocketError(format!("{}: {e}", path.display())))
+        }
+        Ok(_) => Err(EdgeError::UnixSocketError(format!(
+            "{} already exists and is not a socket",
+            path.display()
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(EdgeError::UnixSocketError(format!(
+            "{}: {e}",
+            path.display()
+        ))),
+    }
+}
+
+pub fn remove_socket(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove unix socket at {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[test]
+    pub fn stale_sockets_are_removed() {
+        let dir = std::env::temp_dir().join(format!("edge-uds-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("edge.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        assert!(remove_stale_socket(&path).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    pub fn regular_files_are_not_removed() {
+        let dir = std::env::temp_dir().join(format!("edge-uds-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("edge.sock");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(matches!(
+            remove_stale_socket(&path),
+            Err(EdgeError::UnixSocketError(_))
+        ));
+        assert!(path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,9 +7,11 @@ use crate::types::EdgeResult;
 #[derive(Clone, Debug)]
 pub struct UnleashUrls {
     pub base_url: Url,
+    pub health_url: Url,
     pub api_url: Url,
     pub client_api_url: Url,
     pub client_features_url: Url,
+    pub client_features_delta_url: Url,
     pub client_register_app_url: Url,
     pub client_metrics_url: Url,
     pub client_bulk_metrics_url: Url,
@@ -39,6 +41,8 @@ impl Default for UnleashUrls {
 
 impl UnleashUrls {
     pub fn from_base_url(base_url: Url) -> Self {
+        let mut health_url = base_url.clone();
+        health_url.path_segments_mut().unwrap().push("health");
         let mut api_url = base_url.clone();
         api_url.path_segments_mut().unwrap().push("api");
 
@@ -49,6 +53,11 @@ impl UnleashUrls {
             .path_segments_mut()
             .unwrap()
             .push("features");
+        let mut client_features_delta_url = client_api_url.clone();
+        client_features_delta_url
+            .path_segments_mut()
+            .unwrap()
+            .push("delta");
         let mut client_register_app_url = client_api_url.clone();
         client_register_app_url
             .path_segments_mut()
@@ -90,9 +99,11 @@ impl UnleashUrls {
             .push("bulk");
         UnleashUrls {
             base_url,
+            health_url,
             api_url,
             client_api_url,
             client_features_url,
+            client_features_delta_url,
             client_register_app_url,
             client_bulk_metrics_url,
             client_metrics_url,
@@ -1,13 +1,16 @@
 use std::{str::FromStr, time::Duration};
 
-use redis::Client;
+use redis::{AsyncCommands, Client};
 use testcontainers::runners::AsyncRunner;
 use testcontainers::ContainerAsync;
 use testcontainers_modules::redis::Redis;
 use unleash_types::client_features::{ClientFeature, ClientFeatures};
 
 use unleash_edge::{
-    persistence::{redis::RedisPersister, EdgePersistence},
+    persistence::{
+        redis::{RedisPersister, FEATURES_KEY, REVOKED_TOKENS_KEY},
+        EdgePersistence,
+    },
     types::{EdgeToken, TokenType},
 };
 
@@ -63,3 +66,39 @@ async fn redis_saves_and_restores_edge_tokens_correctly() {
     let saved_tokens = redis_persister.load_tokens().await.unwrap();
     assert_eq!(saved_tokens.len(), 2);
 }
+
+#[tokio::test]
+async fn redis_expires_persisted_features_when_ttl_is_configured() {
+    let (client, url, _node) = setup_redis().await;
+    let redis_persister = RedisPersister::new(&url, TEST_TIMEOUT, TEST_TIMEOUT)
+        .unwrap()
+        .with_ttl(Some(Duration::from_secs(60)));
+    let features = ClientFeatures {
+        features: vec![],
+        query: None,
+        segments: None,
+        version: 2,
+    };
+    redis_persister
+        .save_features(vec![("development".into(), features)])
+        .await
+        .unwrap();
+    let mut conn = client.get_multiplexed_tokio_connection().await.unwrap();
+    let ttl: i64 = conn.ttl(FEATURES_KEY).await.unwrap();
+    assert!(ttl > 0 && ttl <= 60);
+}
+
+#[tokio::test]
+async fn redis_keeps_revoked_tokens_when_ttl_is_configured() {
+    let (client, url, _node) = setup_redis().await;
+    let redis_persister = RedisPersister::new(&url, TEST_TIMEOUT, TEST_TIMEOUT)
+        .unwrap()
+        .with_ttl(Some(Duration::from_secs(60)));
+    redis_persister
+        .save_revoked_tokens(vec!["*:development.leakedsecret".into()])
+        .await
+        .unwrap();
+    let mut conn = client.get_multiplexed_tokio_connection().await.unwrap();
+    let ttl: i64 = conn.ttl(REVOKED_TOKENS_KEY).await.unwrap();
+    assert_eq!(ttl, -1);
+}